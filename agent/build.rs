@@ -3,6 +3,18 @@ use std::process::Command;
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
         .out_dir("lib/src/api/")
+        .message_attribute(
+            ".",
+            "#[derive(serde::Deserialize, serde::Serialize)] #[serde(default)]",
+        )
+        .type_attribute(
+            ".grpc_definitions.RegisterResponse.response",
+            "#[derive(serde::Deserialize, serde::Serialize)] #[serde(rename_all = \"snake_case\")]",
+        )
+        .type_attribute(
+            ".grpc_definitions.SerialMessage.payload",
+            "#[derive(serde::Deserialize, serde::Serialize)] #[serde(rename_all = \"snake_case\")]",
+        )
         .compile(&["../shared/proto/lambdo.proto"], &["../shared/proto"])?;
 
     let _ = Command::new(std::env::var("RUSTFMT").unwrap_or_else(|_| "rustfmt".to_owned()))
@@ -3,12 +3,13 @@ use std::net::IpAddr;
 use anyhow::{anyhow, Result};
 use log::{error, info, trace};
 
-use crate::api::grpc_definitions::{register_response::Response, RegisterRequest};
+use crate::api::grpc_definitions::register_response::Response;
 
 use super::grpc_definitions::{
-    lambdo_api_service_client::LambdoApiServiceClient, Code, StatusMessage,
+    lambdo_api_service_client::LambdoApiServiceClient, Code, OutputChunk, StatusMessage,
 };
 
+#[derive(Clone)]
 pub struct Client {
     client: LambdoApiServiceClient<tonic::transport::Channel>,
 }
@@ -35,11 +36,11 @@ impl Client {
         panic!("Failed to connect to gRPC server");
     }
 
-    pub async fn register(&mut self, port: u16) -> Result<String> {
+    pub async fn register(&mut self, port: u16, token: Option<&str>) -> Result<String> {
         info!("Registering to lambdo..");
         let register_response = self
             .client
-            .register(RegisterRequest { port: port.into() })
+            .register(super::register_request(port, token))
             .await?;
         trace!("Register response: {:?}", register_response);
 
@@ -58,4 +59,11 @@ impl Client {
             .await
             .map_or_else(|e| Err(anyhow!("Error sending status: {}", e)), |_| Ok(()))
     }
+
+    pub async fn output(&mut self, chunk: OutputChunk) -> Result<()> {
+        self.client
+            .output(chunk)
+            .await
+            .map_or_else(|e| Err(anyhow!("Error sending output: {}", e)), |_| Ok(()))
+    }
 }
@@ -1,4 +1,33 @@
 pub mod client;
 #[rustfmt::skip]
 pub mod grpc_definitions;
+pub mod serial;
 pub mod server;
+
+use grpc_definitions::{Capability, RegisterRequest};
+
+pub use lambdo_shared::PROTOCOL_VERSION;
+
+/// Version of the agent
+pub const AGENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The optional features supported by this agent
+pub fn capabilities() -> Vec<Capability> {
+    vec![Capability::BinaryFiles, Capability::Streaming]
+}
+
+/// Build the request used to register to lambdo
+///
+/// # Arguments
+///
+/// * `port` - The port on which the agent gRPC server listens
+/// * `token` - The token identifying the VM, if lambdo gave one
+pub fn register_request(port: u16, token: Option<&str>) -> RegisterRequest {
+    RegisterRequest {
+        port: port.into(),
+        agent_version: AGENT_VERSION.to_string(),
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: capabilities().into_iter().map(Into::into).collect(),
+        token: token.unwrap_or_default().to_string(),
+    }
+}
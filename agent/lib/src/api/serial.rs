@@ -0,0 +1,456 @@
+use std::io::{self, Read, Write};
+
+use anyhow::{anyhow, Result};
+use lambdo_shared::serial::{encode_frame, Decoded, FrameDecoder, FrameError};
+use log::{debug, error, info, trace, warn};
+use serialport::TTYPort;
+use thiserror::Error;
+use tokio::{runtime::Handle, sync::mpsc::UnboundedSender};
+use tracing::instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::{
+    config::SerialConfig,
+    init::{self, NetworkConfig},
+    runner_engine, telemetry,
+};
+
+use super::grpc_definitions::{
+    register_response::Response, serial_message::Payload, Code, ExecuteRequest, ExecuteResponse,
+    Identity, OutputChunk, SerialMessage, StatusMessage,
+};
+
+#[derive(Error, Debug)]
+pub enum SerialError {
+    #[error("serial io error")]
+    Io(#[from] io::Error),
+    #[error("cannot open serial device")]
+    Open(#[from] serialport::Error),
+    #[error("invalid serial frame")]
+    Frame(#[from] FrameError),
+    #[error("serial line closed")]
+    Closed,
+}
+
+/// A framed transport of protocol messages over a serial line
+pub struct SerialTransport<T: Read + Write> {
+    port: T,
+    decoder: FrameDecoder<SerialMessage>,
+}
+
+impl<T: Read + Write> SerialTransport<T> {
+    pub fn new(port: T) -> Self {
+        Self {
+            port,
+            decoder: FrameDecoder::new(),
+        }
+    }
+
+    /// Send a message on the serial line
+    pub fn send(&mut self, message: &SerialMessage) -> Result<(), SerialError> {
+        trace!("Sending serial message: {:?}", message);
+        self.port.write_all(&encode_frame(message))?;
+        self.port.flush()?;
+        Ok(())
+    }
+
+    /// Wait for the next message on the serial line, skipping console output
+    pub fn recv(&mut self) -> Result<SerialMessage, SerialError> {
+        let mut buffer = [0; 4096];
+
+        loop {
+            match self.decoder.next_decoded() {
+                Ok(Some(Decoded::Message(message))) => {
+                    trace!("Received serial message: {:?}", message);
+                    return Ok(message);
+                }
+                Ok(Some(Decoded::Console(bytes))) => {
+                    trace!("Skipping {} bytes of console output", bytes.len());
+                    continue;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Dropping serial frame: {}", e);
+                    continue;
+                }
+            }
+
+            match self.port.read(&mut buffer) {
+                Ok(0) => return Err(SerialError::Closed),
+                Ok(read) => self.decoder.push(&buffer[..read]),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// The agent server speaking the lambdo protocol over a serial line
+pub struct SerialServer<T: Read + Write> {
+    transport: SerialTransport<T>,
+    id: Option<String>,
+    token: Option<String>,
+    runtime: Handle,
+}
+
+impl SerialServer<TTYPort> {
+    /// Open the serial device described in the configuration
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The serial configuration of the agent
+    /// * `token` - The token identifying the VM, if lambdo gave one
+    /// * `runtime` - The runtime used to run the commands of the requests
+    pub fn open(
+        config: &SerialConfig,
+        token: Option<String>,
+        runtime: Handle,
+    ) -> Result<Self, SerialError> {
+        info!(
+            "Opening serial device {} at {} bauds",
+            config.device, config.baud_rate
+        );
+        let port = serialport::new(config.device.as_str(), config.baud_rate)
+            .timeout(std::time::Duration::from_secs(60))
+            .open_native()?;
+
+        Ok(Self::new(port, token, runtime))
+    }
+}
+
+impl<T: Read + Write> SerialServer<T> {
+    pub fn new(port: T, token: Option<String>, runtime: Handle) -> Self {
+        Self {
+            transport: SerialTransport::new(port),
+            id: None,
+            token,
+            runtime,
+        }
+    }
+
+    /// Register to lambdo and send the ready status
+    ///
+    /// # Returns
+    ///
+    /// * `Result<String>` - The ID given by lambdo or an error
+    pub fn register(&mut self) -> Result<String> {
+        info!("Registering to lambdo over serial..");
+        self.transport.send(&SerialMessage {
+            payload: Some(Payload::RegisterRequest(super::register_request(
+                0,
+                self.token.as_deref(),
+            ))),
+        })?;
+
+        let id = loop {
+            match self.transport.recv()?.payload {
+                Some(Payload::RegisterResponse(response)) => match response.response {
+                    Some(Response::Id(id)) => break id,
+                    Some(Response::Error(error)) => {
+                        return Err(anyhow!("Error registering over serial: {}", error))
+                    }
+                    None => return Err(anyhow!("Empty register response")),
+                },
+                other => warn!("Unexpected message while registering: {:?}", other),
+            }
+        };
+        info!("Agent registered with ID: {}", id);
+
+        self.send_status(&id, Code::Ready)?;
+        self.id = Some(id.clone());
+
+        Ok(id)
+    }
+
+    /// Serve execution requests until the serial line is closed
+    pub fn serve(&mut self) -> Result<()> {
+        if self.id.is_none() {
+            self.register()?;
+        }
+
+        loop {
+            let request = match self.transport.recv() {
+                Ok(message) => match message.payload {
+                    Some(Payload::ExecuteRequest(request)) => request,
+                    Some(Payload::Identity(identity)) => {
+                        self.reidentify(identity)?;
+                        continue;
+                    }
+                    other => {
+                        warn!("Unexpected message on serial line: {:?}", other);
+                        continue;
+                    }
+                },
+                Err(SerialError::Closed) => {
+                    info!("Serial line closed");
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            info!("Received execution request over serial");
+            debug!("Received request: {:?}", request);
+
+            let request_id = request.id.clone();
+            match self.run(request) {
+                Ok(response) => {
+                    self.transport.send(&SerialMessage {
+                        payload: Some(Payload::ExecuteResponse(response)),
+                    })?;
+                }
+                Err(e) => {
+                    error!("Failed to run request: {}", e);
+                    // Lambdo waits for a response to the request, even a failed one
+                    self.transport.send(&SerialMessage {
+                        payload: Some(Payload::ExecuteResponse(ExecuteResponse {
+                            id: request_id,
+                            error: e.to_string(),
+                            ..Default::default()
+                        })),
+                    })?;
+                    let id = self.id.clone().unwrap_or_default();
+                    self.send_status(&id, Code::Error)?;
+                }
+            }
+        }
+    }
+
+    /// Take the identity given to a VM restored from a snapshot of this one
+    ///
+    /// The network is moved to the address of the new VM, then the agent
+    /// registers again with the token of the new VM to get its ID.
+    fn reidentify(&mut self, identity: Identity) -> Result<()> {
+        info!("Restored from a snapshot, taking a new identity");
+
+        if !identity.ip.is_empty() {
+            // The identity uses the format of the kernel `ip=` parameter
+            let param = format!("ip={}::{}", identity.ip, identity.gateway);
+            match NetworkConfig::from_cmdline(&param) {
+                Ok(network) => init::configure_network(network.as_ref())
+                    .unwrap_or_else(|e| error!("Failed to configure the network: {:?}", e)),
+                Err(e) => error!("Invalid address in identity: {}", e),
+            }
+        }
+
+        self.token = Some(identity.token);
+        self.register()?;
+
+        Ok(())
+    }
+
+    /// Run a request, sending its output on the serial line as it comes
+    fn run(&mut self, request: ExecuteRequest) -> Result<ExecuteResponse> {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let transport = &mut self.transport;
+
+        self.runtime.block_on(async move {
+            let execution = Self::execute(request, sender);
+            tokio::pin!(execution);
+
+            let result = loop {
+                tokio::select! {
+                    result = &mut execution => break result,
+                    Some(chunk) = receiver.recv() => transport.send(&SerialMessage {
+                        payload: Some(Payload::Output(chunk)),
+                    })?,
+                }
+            };
+
+            // The output left behind by the last step comes before the response
+            while let Ok(chunk) = receiver.try_recv() {
+                transport.send(&SerialMessage {
+                    payload: Some(Payload::Output(chunk)),
+                })?;
+            }
+
+            result
+        })
+    }
+
+    #[instrument(skip_all)]
+    async fn execute(
+        request: ExecuteRequest,
+        output: UnboundedSender<OutputChunk>,
+    ) -> Result<ExecuteResponse> {
+        // Join the trace of the execution started by lambdo
+        tracing::Span::current()
+            .set_parent(telemetry::extract_serial_context(&request.trace_context));
+
+        let mut runner_engine =
+            runner_engine::service::RunnerEngine::new(request).with_output(output);
+        runner_engine.create_workspace()?;
+
+        let response = runner_engine.run().await?;
+        debug!("Response from runner engine: {:?}", response);
+
+        Ok(response)
+    }
+
+    fn send_status(&mut self, id: &str, code: Code) -> Result<()> {
+        self.transport
+            .send(&SerialMessage {
+                payload: Some(Payload::Status(StatusMessage {
+                    id: id.to_string(),
+                    code: code.into(),
+                })),
+            })
+            .map_err(|e| anyhow!("Error sending status: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::grpc_definitions::{ExecuteRequestStep, RegisterResponse};
+
+    fn status_message(id: &str) -> SerialMessage {
+        SerialMessage {
+            payload: Some(Payload::Status(StatusMessage {
+                id: id.to_string(),
+                code: Code::Ready.into(),
+            })),
+        }
+    }
+
+    /// Test a full register and execute exchange over a pty pair
+    #[test]
+    fn serial_server_over_pty() {
+        let (master, slave) = TTYPort::pair().unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let handle = runtime.handle().clone();
+
+        let server = std::thread::spawn(move || {
+            let mut server = SerialServer::new(slave, Some("abcd".to_string()), handle);
+            let id = server.register().unwrap();
+            assert_eq!(id, "4bf68974-c315-4c41-aee2-3dc2920e76e9");
+            server.serve()
+        });
+
+        let mut api = SerialTransport::new(master);
+        match api.recv().unwrap().payload {
+            Some(Payload::RegisterRequest(request)) => {
+                assert_eq!(request.protocol_version, crate::api::PROTOCOL_VERSION);
+                assert_eq!(request.token, "abcd");
+            }
+            other => panic!("expected a register request, got {:?}", other),
+        }
+        api.send(&SerialMessage {
+            payload: Some(Payload::RegisterResponse(RegisterResponse {
+                response: Some(Response::Id(
+                    "4bf68974-c315-4c41-aee2-3dc2920e76e9".to_string(),
+                )),
+            })),
+        })
+        .unwrap();
+
+        assert_eq!(
+            api.recv().unwrap(),
+            status_message("4bf68974-c315-4c41-aee2-3dc2920e76e9")
+        );
+
+        api.send(&SerialMessage {
+            payload: Some(Payload::ExecuteRequest(ExecuteRequest {
+                id: "request".to_string(),
+                files: Vec::new(),
+                steps: vec![ExecuteRequestStep {
+                    command: "echo 'Hello over serial'".to_string(),
+                    enable_output: true,
+                }],
+                artifacts: Vec::new(),
+                artifacts_max_size: 0,
+                ..Default::default()
+            })),
+        })
+        .unwrap();
+
+        // The output comes before the response
+        match api.recv().unwrap().payload {
+            Some(Payload::Output(chunk)) => {
+                assert_eq!(chunk.id, "request");
+                assert_eq!(chunk.stdout, "Hello over serial\n");
+            }
+            other => panic!("expected some output, got {:?}", other),
+        }
+        match api.recv().unwrap().payload {
+            Some(Payload::ExecuteResponse(response)) => {
+                assert_eq!(response.id, "request");
+                assert_eq!(response.steps[0].exit_code, 0);
+                assert_eq!(response.steps[0].stdout, "Hello over serial\n");
+                assert!(response.error.is_empty());
+            }
+            other => panic!("expected an execute response, got {:?}", other),
+        }
+
+        // A failed request still gets its response, before the error status
+        api.send(&SerialMessage {
+            payload: Some(Payload::ExecuteRequest(ExecuteRequest {
+                id: "failing".to_string(),
+                artifacts: vec!["[".to_string()],
+                ..Default::default()
+            })),
+        })
+        .unwrap();
+
+        match api.recv().unwrap().payload {
+            Some(Payload::ExecuteResponse(response)) => {
+                assert_eq!(response.id, "failing");
+                assert!(response.error.contains("Invalid artifact pattern"));
+            }
+            other => panic!("expected an execute response, got {:?}", other),
+        }
+        match api.recv().unwrap().payload {
+            Some(Payload::Status(status)) => {
+                assert_eq!(status.id, "4bf68974-c315-4c41-aee2-3dc2920e76e9");
+                assert_eq!(status.code, i32::from(Code::Error));
+            }
+            other => panic!("expected an error status, got {:?}", other),
+        }
+
+        drop(api);
+        server.join().unwrap().ok();
+    }
+
+    /// Test that a restored agent registers again with the token of its new identity
+    #[test]
+    fn serial_server_reidentifies() {
+        let (master, slave) = TTYPort::pair().unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let handle = runtime.handle().clone();
+
+        let server = std::thread::spawn(move || {
+            let mut server = SerialServer::new(slave, None, handle);
+            server.id = Some("snapshotted".to_string());
+            server.serve()
+        });
+
+        let mut api = SerialTransport::new(master);
+        api.send(&SerialMessage {
+            payload: Some(Payload::Identity(Identity {
+                ip: String::new(),
+                gateway: String::new(),
+                token: "clone-token".to_string(),
+            })),
+        })
+        .unwrap();
+
+        match api.recv().unwrap().payload {
+            Some(Payload::RegisterRequest(request)) => {
+                assert_eq!(request.protocol_version, crate::api::PROTOCOL_VERSION);
+                assert_eq!(request.token, "clone-token");
+            }
+            other => panic!("expected a register request, got {:?}", other),
+        }
+        api.send(&SerialMessage {
+            payload: Some(Payload::RegisterResponse(RegisterResponse {
+                response: Some(Response::Id("clone".to_string())),
+            })),
+        })
+        .unwrap();
+
+        assert_eq!(api.recv().unwrap(), status_message("clone"));
+
+        drop(api);
+        server.join().unwrap().ok();
+    }
+}
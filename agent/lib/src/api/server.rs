@@ -3,8 +3,10 @@ use std::{net::IpAddr, str::FromStr, sync::Arc};
 use log::{debug, error, info, trace};
 use tokio::sync::Mutex;
 use tonic::{Request, Response, Status};
+use tracing::instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
-use crate::{config::AgentConfig, runner_engine};
+use crate::{config::AgentConfig, runner_engine, telemetry};
 
 use super::{
     client::Client,
@@ -35,7 +37,10 @@ impl LambdoAgentServer {
         let id = {
             let mut counter = 1;
             loop {
-                match client.register(config.grpc.local_port).await {
+                match client
+                    .register(config.grpc.local_port, config.token.as_deref())
+                    .await
+                {
                     Ok(id) => break id,
                     Err(e) => {
                         error!("Failed to register to gRPC server, {} try: {}", counter, e);
@@ -75,18 +80,33 @@ impl LambdoAgentService for LambdoAgentServer {
         Err(Status::unimplemented("Not implemented yet"))
     }
 
+    #[instrument(skip_all, fields(vm = %self.id))]
     async fn execute(
         &self,
         request: Request<ExecuteRequest>,
     ) -> Result<Response<ExecuteResponse>, Status> {
+        // Join the trace of the execution started by lambdo
+        tracing::Span::current().set_parent(telemetry::extract_context(request.metadata()));
         info!("Received request execution request");
 
         let request = request.into_inner();
         debug!("Received request: {:?}", request);
 
-        let mut runner_engine = runner_engine::service::RunnerEngine::new(request);
         let mut self_client = self.client.lock().await;
 
+        // The output is sent to lambdo as it comes, before the response
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut output_client = self_client.clone();
+        let output = tokio::spawn(async move {
+            while let Some(chunk) = receiver.recv().await {
+                if let Err(e) = output_client.output(chunk).await {
+                    error!("Failed to send output to gRPC server: {}", e);
+                }
+            }
+        });
+        let mut runner_engine =
+            runner_engine::service::RunnerEngine::new(request).with_output(sender);
+
         if let Err(e) = runner_engine.create_workspace() {
             error!("Failed to create workspace: {}", e);
             self_client
@@ -99,13 +119,21 @@ impl LambdoAgentService for LambdoAgentServer {
             return Err(Status::internal("Failed to create workspace"));
         };
 
-        match runner_engine.run() {
+        let result = runner_engine.run().await;
+        drop(runner_engine);
+        if let Err(e) = output.await {
+            error!("Failed to send output to gRPC server: {}", e);
+        }
+
+        match result {
             Ok(response) => {
                 debug!("Response from runner engine: {:?}", response);
 
                 Ok(Response::new(ExecuteResponse {
                     id: self.id.clone(),
                     steps: response.steps,
+                    artifacts: response.artifacts,
+                    ..Default::default()
                 }))
             }
             Err(e) => {
@@ -1,12 +1,25 @@
 use anyhow::Result;
-use log::{debug, error, trace};
+use lambdo_shared::BOOT_ARGS_PATH;
+use log::{debug, info, trace, warn};
 use serde::{Deserialize, Serialize};
 use std::{
-    fs::File,
+    fs::{self, File},
     io::{self, BufReader},
 };
 use thiserror::Error;
 
+/// Prefix of the agent parameters on the kernel command line
+const CMDLINE_PREFIX: &str = "lambdo.";
+
+/// Prefix of the agent environment variables
+const ENV_PREFIX: &str = "LAMBDO_AGENT_";
+
+/// The current api version of the agent config file
+pub const API_VERSION: &str = "lambdo.io/v1beta1";
+
+/// The older api versions still accepted, the format did not change since
+const OLD_API_VERSIONS: [&str; 1] = ["lambdo.io/v1alpha1"];
+
 const fn default_remote_port() -> u16 {
     50051
 }
@@ -15,6 +28,10 @@ const fn default_local_port() -> u16 {
     0
 }
 
+const fn default_baud_rate() -> u32 {
+    115200
+}
+
 #[derive(Error, Debug)]
 pub enum AgentConfigError {
     #[error("cannot load config file")]
@@ -25,6 +42,10 @@ pub enum AgentConfigError {
     KindNotSupported,
     #[error("unsupported config api version")]
     VersionNotSupported,
+    #[error("invalid value {value:?} for {key}")]
+    InvalidValue { key: String, value: String },
+    #[error("no remote host configured and no default gateway found")]
+    NoRemoteHost,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
@@ -37,6 +58,15 @@ pub struct AgentConfig {
     /// The gRPC configuration
     #[serde(default = "default_grpc")]
     pub grpc: GRPCConfig,
+    /// The serial configuration, the agent talks over the serial line instead of gRPC if set
+    #[serde(default)]
+    pub serial: Option<SerialConfig>,
+    /// The token identifying the VM to lambdo
+    #[serde(default)]
+    pub token: Option<String>,
+    /// The tracing configuration
+    #[serde(default)]
+    pub tracing: TracingConfig,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
@@ -44,8 +74,8 @@ pub struct GRPCConfig {
     /// The remote gRPC port
     #[serde(default = "default_remote_port")]
     pub remote_port: u16,
-    /// The remote gRPC host
-    #[serde(default = "default_gateway_ip")]
+    /// The remote gRPC host, the default gateway if empty
+    #[serde(default)]
     pub remote_host: String,
     /// The local gRPC port
     #[serde(default = "default_local_port")]
@@ -55,31 +85,89 @@ pub struct GRPCConfig {
     pub local_host: String,
 }
 
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct SerialConfig {
+    /// The serial device to use
+    pub device: String,
+    /// The baud rate of the serial device
+    #[serde(default = "default_baud_rate")]
+    pub baud_rate: u32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+pub struct TracingConfig {
+    /// Where the spans are exported
+    #[serde(default)]
+    pub exporter: TracingExporter,
+    /// The OTLP collector endpoint, `http://localhost:4317` if not set
+    #[serde(default)]
+    pub endpoint: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TracingExporter {
+    /// The spans are not exported, only the logs are printed
+    #[default]
+    None,
+    /// The spans are printed on stdout, one JSON object per line
+    Stdout,
+    /// The spans are sent to an OpenTelemetry collector, needs the `otlp` feature
+    Otlp,
+}
+
 fn default_local_host() -> String {
     "0.0.0.0".to_string()
 }
 
-fn default_gateway_ip() -> String {
+fn default_gateway_ip() -> Option<String> {
     trace!("getting default gateway ip address");
-    let gateway = default_net::get_default_gateway().unwrap_or_else(|e| {
-        error!("Failed to get default gateway ip address");
-        panic!("{}", e)
-    });
-    debug!("using default gateway ip address: {}", gateway.ip_addr);
-    gateway.ip_addr.to_string()
+    match default_net::get_default_gateway() {
+        Ok(gateway) => {
+            debug!("using default gateway ip address: {}", gateway.ip_addr);
+            Some(gateway.ip_addr.to_string())
+        }
+        Err(e) => {
+            warn!("Failed to get default gateway ip address: {}", e);
+            None
+        }
+    }
 }
 
 fn default_grpc() -> GRPCConfig {
     GRPCConfig {
         remote_port: default_remote_port(),
-        remote_host: default_gateway_ip(),
+        remote_host: String::new(),
         local_port: default_local_port(),
         local_host: default_local_host(),
     }
 }
 
+impl Default for AgentConfig {
+    fn default() -> Self {
+        AgentConfig {
+            apiVersion: API_VERSION.to_string(),
+            kind: "AgentConfig".to_string(),
+            grpc: default_grpc(),
+            serial: None,
+            token: None,
+            tracing: TracingConfig::default(),
+        }
+    }
+}
+
 impl AgentConfig {
-    /// Load a AgentConfig from a file.
+    /// Load a AgentConfig from a file, the kernel command line and the environment.
+    ///
+    /// The sources are merged in this order, the last one wins:
+    ///
+    /// 1. The config file, the defaults are used if it does not exist
+    /// 2. The `lambdo.<key>=<value>` parameters of `/proc/cmdline`, then the ones lambdo
+    ///    appended to the initramfs, see [`BOOT_ARGS_PATH`]
+    /// 3. The `LAMBDO_AGENT_<KEY>` environment variables
+    ///
+    /// The supported keys are `api_host`, `api_port`, `local_host`, `local_port`,
+    /// `token`, `serial_device`, `serial_baud_rate`, `tracing_exporter` and `tracing_endpoint`.
     ///
     /// Arguments:
     ///
@@ -89,19 +177,219 @@ impl AgentConfig {
     ///
     /// A Result<AgentConfig>
     pub fn load(path: &str) -> Result<Self> {
-        let file = File::open(path).map_err(AgentConfigError::Load)?;
-        let reader = BufReader::new(file);
-        let config: AgentConfig =
-            serde_yaml::from_reader(reader).map_err(AgentConfigError::Parse)?;
+        let mut config = match File::open(path) {
+            Ok(file) => Self::from_reader(BufReader::new(file))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("No config file at {}, using the defaults", path);
+                Self::default()
+            }
+            Err(e) => return Err(AgentConfigError::Load(e).into()),
+        };
+
+        let cmdline = fs::read_to_string("/proc/cmdline").unwrap_or_else(|e| {
+            debug!("Failed to read the kernel command line: {}", e);
+            String::new()
+        });
+        config.merge_cmdline(&cmdline)?;
+        match fs::read_to_string(BOOT_ARGS_PATH) {
+            Ok(boot_args) => config.merge_cmdline(&boot_args)?,
+            Err(e) => debug!("No boot arguments in {}: {}", BOOT_ARGS_PATH, e),
+        }
+        config.merge_env(std::env::vars())?;
+
+        if config.grpc.remote_host.is_empty() {
+            config.grpc.remote_host = default_gateway_ip().ok_or(AgentConfigError::NoRemoteHost)?;
+        }
+
+        Ok(config)
+    }
+
+    /// Parse and check a AgentConfig, converted to the current api version
+    pub fn from_reader<R: io::Read>(reader: R) -> Result<Self, AgentConfigError> {
+        let mut config: AgentConfig = serde_yaml::from_reader(reader)?;
 
         if config.kind != "AgentConfig" {
-            return Err(AgentConfigError::KindNotSupported.into());
+            return Err(AgentConfigError::KindNotSupported);
+        }
+
+        if OLD_API_VERSIONS.contains(&config.apiVersion.as_str()) {
+            debug!("Converting the config from {}", config.apiVersion);
+            config.apiVersion = API_VERSION.to_string();
         }
 
-        if config.apiVersion != "lambdo.io/v1alpha1" {
-            return Err(AgentConfigError::VersionNotSupported.into());
+        if config.apiVersion != API_VERSION {
+            return Err(AgentConfigError::VersionNotSupported);
         }
 
         Ok(config)
     }
+
+    /// Override the config with the `lambdo.*` parameters of a kernel command line
+    pub fn merge_cmdline(&mut self, cmdline: &str) -> Result<(), AgentConfigError> {
+        for param in cmdline.split_whitespace() {
+            if let Some((key, value)) = param
+                .strip_prefix(CMDLINE_PREFIX)
+                .and_then(|param| param.split_once('='))
+            {
+                self.set(key, value)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Override the config with the `LAMBDO_AGENT_*` environment variables
+    pub fn merge_env<I: IntoIterator<Item = (String, String)>>(
+        &mut self,
+        vars: I,
+    ) -> Result<(), AgentConfigError> {
+        for (name, value) in vars {
+            if let Some(key) = name.strip_prefix(ENV_PREFIX) {
+                self.set(&key.to_lowercase(), &value)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), AgentConfigError> {
+        let invalid = || AgentConfigError::InvalidValue {
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+        trace!("overriding {} with {:?}", key, value);
+
+        match key {
+            "api_host" => self.grpc.remote_host = value.to_string(),
+            "api_port" => self.grpc.remote_port = value.parse().map_err(|_| invalid())?,
+            "local_host" => self.grpc.local_host = value.to_string(),
+            "local_port" => self.grpc.local_port = value.parse().map_err(|_| invalid())?,
+            "token" => self.token = Some(value.to_string()),
+            "serial_device" => {
+                let baud_rate = self
+                    .serial
+                    .as_ref()
+                    .map_or_else(default_baud_rate, |serial| serial.baud_rate);
+                self.serial = Some(SerialConfig {
+                    device: value.to_string(),
+                    baud_rate,
+                });
+            }
+            "serial_baud_rate" => match self.serial.as_mut() {
+                Some(serial) => serial.baud_rate = value.parse().map_err(|_| invalid())?,
+                None => warn!("Ignoring {} without a serial device", key),
+            },
+            "tracing_exporter" => {
+                self.tracing.exporter = match value {
+                    "none" => TracingExporter::None,
+                    "stdout" => TracingExporter::Stdout,
+                    "otlp" => TracingExporter::Otlp,
+                    _ => return Err(invalid()),
+                }
+            }
+            "tracing_endpoint" => self.tracing.endpoint = Some(value.to_string()),
+            _ => warn!("Ignoring unknown config key {}", key),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "
+apiVersion: lambdo.io/v1alpha1
+kind: AgentConfig
+grpc:
+  remote_port: 50051
+  remote_host: 10.0.0.1
+";
+
+    /// Test that the environment wins over the kernel command line, which wins over the file
+    #[test]
+    fn config_sources_precedence() {
+        let mut config = AgentConfig::from_reader(CONFIG.as_bytes()).unwrap();
+
+        config
+            .merge_cmdline("console=ttyS0 lambdo.api_host=192.168.10.1 lambdo.api_port=50052 lambdo.token=abcd")
+            .unwrap();
+        config
+            .merge_env(vec![
+                ("LAMBDO_AGENT_API_PORT".to_string(), "50053".to_string()),
+                ("PATH".to_string(), "/bin".to_string()),
+            ])
+            .unwrap();
+
+        assert_eq!(config.grpc.remote_host, "192.168.10.1");
+        assert_eq!(config.grpc.remote_port, 50053);
+        assert_eq!(config.grpc.local_port, 0);
+        assert_eq!(config.token.as_deref(), Some("abcd"));
+        assert_eq!(config.serial, None);
+    }
+
+    /// Test that invalid values are rejected
+    #[test]
+    fn config_invalid_value() {
+        let mut config = AgentConfig::default();
+
+        assert!(matches!(
+            config.merge_cmdline("lambdo.api_port=http"),
+            Err(AgentConfigError::InvalidValue { .. })
+        ));
+
+        config
+            .merge_cmdline("lambdo.serial_device=/dev/ttyS1 lambdo.serial_baud_rate=9600")
+            .unwrap();
+        assert_eq!(
+            config.serial,
+            Some(SerialConfig {
+                device: "/dev/ttyS1".to_string(),
+                baud_rate: 9600,
+            })
+        );
+    }
+
+    /// Test that the tracing exporter can be set on the kernel command line
+    #[test]
+    fn config_tracing_exporter() {
+        let mut config = AgentConfig::from_reader(CONFIG.as_bytes()).unwrap();
+        assert_eq!(config.tracing.exporter, TracingExporter::None);
+
+        config
+            .merge_cmdline(
+                "lambdo.tracing_exporter=otlp lambdo.tracing_endpoint=http://10.0.0.1:4317",
+            )
+            .unwrap();
+        assert_eq!(config.tracing.exporter, TracingExporter::Otlp);
+        assert_eq!(
+            config.tracing.endpoint.as_deref(),
+            Some("http://10.0.0.1:4317")
+        );
+
+        assert!(matches!(
+            config.merge_cmdline("lambdo.tracing_exporter=jaeger"),
+            Err(AgentConfigError::InvalidValue { .. })
+        ));
+    }
+
+    /// Test that the old api version is converted and the unknown ones are rejected
+    #[test]
+    fn config_api_versions() {
+        let config = AgentConfig::from_reader(CONFIG.as_bytes()).unwrap();
+        assert_eq!(config.apiVersion, API_VERSION);
+
+        let current = CONFIG.replace("lambdo.io/v1alpha1", API_VERSION);
+        assert_eq!(
+            AgentConfig::from_reader(current.as_bytes()).unwrap(),
+            config
+        );
+
+        let unknown = CONFIG.replace("lambdo.io/v1alpha1", "lambdo.io/v2");
+        assert!(matches!(
+            AgentConfig::from_reader(unknown.as_bytes()),
+            Err(AgentConfigError::VersionNotSupported)
+        ));
+    }
 }
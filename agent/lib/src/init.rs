@@ -0,0 +1,444 @@
+use std::{
+    fs,
+    net::Ipv4Addr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    process::Command,
+};
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, error, info, warn};
+use nix::{
+    errno::Errno,
+    mount::{mount, MsFlags},
+    sys::{
+        reboot::{reboot, RebootMode},
+        signal::{kill, SigSet, Signal},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{alarm, sethostname, sync, Pid},
+};
+
+/// Path of the agent config file inside the initramfs
+pub const INITRAMFS_CONFIG_PATH: &str = "/config.yaml";
+
+/// File listing the directories of the cache archive, mounted read-only
+const READ_ONLY_LIST_PATH: &str = "/etc/lambdo/read-only";
+
+/// Hostname used when the kernel command line does not provide one
+const DEFAULT_HOSTNAME: &str = "lambdo";
+
+/// Network device used when the kernel command line does not provide one
+const DEFAULT_DEVICE: &str = "eth0";
+
+/// Time given to the agent to stop before it is killed, in seconds
+const SHUTDOWN_TIMEOUT: u32 = 5;
+
+/// Pseudo filesystems mounted by the init: source, target and filesystem type
+const FILESYSTEMS: [(&str, &str, &str); 3] = [
+    ("proc", "/proc", "proc"),
+    ("sysfs", "/sys", "sysfs"),
+    ("devtmpfs", "/dev", "devtmpfs"),
+];
+
+/// The network configuration given by the `ip=` parameter of the kernel command line
+///
+/// The parameter uses the kernel format:
+/// `ip=<client-ip>:<server-ip>:<gw-ip>:<netmask>:<hostname>:<device>:<autoconf>`
+#[derive(Debug, PartialEq)]
+pub struct NetworkConfig {
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Option<Ipv4Addr>,
+    pub hostname: Option<String>,
+    pub device: String,
+}
+
+impl NetworkConfig {
+    /// Parse the network configuration from the kernel command line
+    ///
+    /// # Arguments
+    ///
+    /// * `cmdline` - The content of `/proc/cmdline`
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<NetworkConfig>>` - The network configuration, `None` if there is no static one
+    pub fn from_cmdline(cmdline: &str) -> Result<Option<Self>> {
+        let param = match cmdline
+            .split_whitespace()
+            .find_map(|param| param.strip_prefix("ip="))
+        {
+            Some(param) => param,
+            None => return Ok(None),
+        };
+
+        let fields = param.split(':').collect::<Vec<&str>>();
+        let field = |index: usize| fields.get(index).copied().filter(|f| !f.is_empty());
+
+        // `ip=dhcp`, `ip=off` and friends do not describe a static configuration
+        let (address, prefix) = match field(0) {
+            Some(address) if address.contains('.') => match address.split_once('/') {
+                Some((address, prefix)) => (address, Some(prefix)),
+                None => (address, None),
+            },
+            _ => return Ok(None),
+        };
+        let address = address
+            .parse::<Ipv4Addr>()
+            .map_err(|e| anyhow!("Invalid address {} in ip= parameter: {}", address, e))?;
+
+        let netmask = match (field(3), prefix) {
+            (Some(netmask), _) => netmask
+                .parse::<Ipv4Addr>()
+                .map_err(|e| anyhow!("Invalid netmask {} in ip= parameter: {}", netmask, e))?,
+            (None, Some(prefix)) => {
+                let prefix = prefix
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|prefix| *prefix <= 32)
+                    .ok_or_else(|| anyhow!("Invalid prefix {} in ip= parameter", prefix))?;
+                Ipv4Addr::from(u32::MAX.checked_shl(32 - prefix).unwrap_or(0))
+            }
+            (None, None) => Ipv4Addr::new(255, 255, 255, 0),
+        };
+
+        let gateway = field(2)
+            .map(|gateway| {
+                gateway
+                    .parse::<Ipv4Addr>()
+                    .map_err(|e| anyhow!("Invalid gateway {} in ip= parameter: {}", gateway, e))
+            })
+            .transpose()?;
+
+        Ok(Some(Self {
+            address,
+            netmask,
+            gateway,
+            hostname: field(4).map(str::to_string),
+            device: field(5).unwrap_or(DEFAULT_DEVICE).to_string(),
+        }))
+    }
+}
+
+/// Run the duties of the init process, then supervise the agent until it stops
+///
+/// The agent is started as a child process so that the init can reap every
+/// orphaned process without stealing the exit status of the commands it runs.
+///
+/// # Arguments
+///
+/// * `agent_args` - The arguments given to the agent process
+pub fn run(agent_args: &[String]) -> Result<()> {
+    info!("Running as init");
+
+    mount_filesystems();
+    mount_read_only();
+
+    let cmdline = fs::read_to_string("/proc/cmdline").unwrap_or_else(|e| {
+        warn!("Failed to read the kernel command line: {}", e);
+        String::new()
+    });
+    let network = NetworkConfig::from_cmdline(&cmdline).unwrap_or_else(|e| {
+        error!("Failed to parse the network configuration: {}", e);
+        None
+    });
+
+    let hostname = network
+        .as_ref()
+        .and_then(|network| network.hostname.as_deref())
+        .unwrap_or(DEFAULT_HOSTNAME);
+    if let Err(e) = sethostname(hostname) {
+        warn!("Failed to set the hostname to {}: {}", hostname, e);
+    }
+
+    if let Err(e) = configure_network(network.as_ref()) {
+        error!("Failed to configure the network: {:?}", e);
+    }
+
+    // Signals are handled synchronously, the mask is reset in the agent process by `Command`
+    let mut signals = SigSet::empty();
+    for signal in [
+        Signal::SIGCHLD,
+        Signal::SIGTERM,
+        Signal::SIGINT,
+        Signal::SIGPWR,
+        Signal::SIGALRM,
+    ] {
+        signals.add(signal);
+    }
+    signals.thread_block()?;
+
+    let agent = Command::new(std::env::current_exe()?)
+        .args(agent_args)
+        .spawn()
+        .map_err(|e| anyhow!("Failed to start the agent: {}", e))?;
+    let agent = Pid::from_raw(agent.id() as i32);
+    info!("Agent started with PID {}", agent);
+
+    let mut terminating = false;
+    loop {
+        match signals.wait()? {
+            Signal::SIGCHLD => {
+                if reap(agent)? {
+                    return Ok(());
+                }
+            }
+            Signal::SIGALRM => {
+                warn!("Agent did not stop in time, killing it");
+                kill(agent, Signal::SIGKILL)?;
+            }
+            signal if !terminating => {
+                info!("Received {}, stopping the agent", signal);
+                kill(agent, Signal::SIGTERM)?;
+                alarm::set(SHUTDOWN_TIMEOUT);
+                terminating = true;
+            }
+            signal => debug!("Ignoring {}, already stopping", signal),
+        }
+    }
+}
+
+/// Stop the remaining processes and power off the VM
+pub fn power_off() -> Result<()> {
+    info!("Powering off");
+
+    // Errors are ignored, there may be no other process left
+    let _ = kill(Pid::from_raw(-1), Signal::SIGTERM);
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    let _ = kill(Pid::from_raw(-1), Signal::SIGKILL);
+
+    sync();
+    reboot(RebootMode::RB_POWER_OFF)?;
+
+    Ok(())
+}
+
+/// Reap every exited child
+///
+/// # Arguments
+///
+/// * `agent` - The PID of the agent process
+///
+/// # Returns
+///
+/// * `Result<bool>` - Whether the agent process exited
+fn reap(agent: Pid) -> Result<bool> {
+    let mut agent_exited = false;
+
+    loop {
+        match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) | Err(Errno::ECHILD) => return Ok(agent_exited),
+            Ok(status) if status.pid() == Some(agent) => {
+                info!("Agent exited: {:?}", status);
+                agent_exited = true;
+            }
+            Ok(status) => debug!("Reaped orphaned process: {:?}", status),
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(anyhow!("Failed to reap processes: {}", e)),
+        }
+    }
+}
+
+fn mount_filesystems() {
+    for (source, target, fstype) in FILESYSTEMS {
+        if let Err(e) = fs::create_dir_all(target) {
+            warn!("Failed to create {}: {}", target, e);
+            continue;
+        }
+
+        match mount(
+            Some(source),
+            target,
+            Some(fstype),
+            MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+            None::<&str>,
+        ) {
+            Ok(()) => debug!("Mounted {} on {}", fstype, target),
+            Err(Errno::EBUSY) => debug!("{} is already mounted", target),
+            Err(e) => warn!("Failed to mount {} on {}: {}", fstype, target, e),
+        }
+    }
+}
+
+/// Bind the directories shipped by the cache archive read-only over themselves
+fn mount_read_only() {
+    let list = match fs::read_to_string(READ_ONLY_LIST_PATH) {
+        Ok(list) => list,
+        Err(e) => {
+            debug!("No read-only directories to mount: {}", e);
+            return;
+        }
+    };
+
+    for target in list.lines().filter(|line| !line.is_empty()) {
+        // A read-only bind mount is set up in two steps, the flags of the first are ignored
+        let result = mount(
+            Some(target),
+            target,
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .and_then(|_| {
+            mount(
+                None::<&str>,
+                target,
+                None::<&str>,
+                MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+                None::<&str>,
+            )
+        });
+
+        match result {
+            Ok(()) => debug!("Mounted {} read-only", target),
+            Err(e) => warn!("Failed to mount {} read-only: {}", target, e),
+        }
+    }
+}
+
+/// Bring up the loopback interface and apply a static network configuration
+///
+/// # Arguments
+///
+/// * `network` - The static configuration, only the loopback is set up if `None`
+pub fn configure_network(network: Option<&NetworkConfig>) -> Result<()> {
+    // SAFETY: the returned descriptor is checked and owned right away
+    let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if socket < 0 {
+        return Err(anyhow!("Failed to open socket: {}", Errno::last()));
+    }
+    // SAFETY: the descriptor is valid and not owned by anything else
+    let socket = unsafe { OwnedFd::from_raw_fd(socket) };
+
+    set_interface_up(&socket, "lo").context("Failed to bring up the loopback interface")?;
+
+    let network = match network {
+        Some(network) => network,
+        None => {
+            info!("No static network configuration on the kernel command line");
+            return Ok(());
+        }
+    };
+
+    info!(
+        "Configuring {} with {}/{}",
+        network.device, network.address, network.netmask
+    );
+
+    let mut request = interface_request(&network.device)?;
+    request.ifr_ifru.ifru_addr = socket_address(network.address);
+    ioctl(&socket, libc::SIOCSIFADDR as _, &mut request)
+        .with_context(|| format!("Failed to set the address of {}", network.device))?;
+
+    request.ifr_ifru.ifru_netmask = socket_address(network.netmask);
+    ioctl(&socket, libc::SIOCSIFNETMASK as _, &mut request)
+        .with_context(|| format!("Failed to set the netmask of {}", network.device))?;
+
+    set_interface_up(&socket, &network.device)
+        .with_context(|| format!("Failed to bring up {}", network.device))?;
+
+    if let Some(gateway) = network.gateway {
+        info!("Adding default route via {}", gateway);
+
+        // SAFETY: rtentry is a plain C structure, zeroed is a valid value
+        let mut route: libc::rtentry = unsafe { std::mem::zeroed() };
+        route.rt_dst = socket_address(Ipv4Addr::UNSPECIFIED);
+        route.rt_genmask = socket_address(Ipv4Addr::UNSPECIFIED);
+        route.rt_gateway = socket_address(gateway);
+        route.rt_flags = libc::RTF_UP | libc::RTF_GATEWAY;
+
+        match ioctl(&socket, libc::SIOCADDRT as _, &mut route) {
+            // A VM restored from a snapshot keeps the route of the snapshotted VM
+            Err(_) if Errno::last() == Errno::EEXIST => debug!("Default route already exists"),
+            result => result.context("Failed to add the default route")?,
+        }
+    }
+
+    Ok(())
+}
+
+fn set_interface_up(socket: &OwnedFd, name: &str) -> Result<()> {
+    let mut request = interface_request(name)?;
+    ioctl(socket, libc::SIOCGIFFLAGS as _, &mut request)?;
+
+    // SAFETY: the flags were just filled by the kernel
+    unsafe {
+        request.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+    }
+    ioctl(socket, libc::SIOCSIFFLAGS as _, &mut request)
+}
+
+fn interface_request(name: &str) -> Result<libc::ifreq> {
+    if name.len() >= libc::IFNAMSIZ {
+        return Err(anyhow!("Interface name {} is too long", name));
+    }
+
+    // SAFETY: ifreq is a plain C structure, zeroed is a valid value
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in request.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+
+    Ok(request)
+}
+
+fn socket_address(address: Ipv4Addr) -> libc::sockaddr {
+    let address = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: 0,
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(address).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+
+    // SAFETY: sockaddr_in and sockaddr have the same size
+    unsafe { std::mem::transmute::<libc::sockaddr_in, libc::sockaddr>(address) }
+}
+
+fn ioctl<T>(socket: &OwnedFd, request: libc::Ioctl, argument: &mut T) -> Result<()> {
+    // SAFETY: the argument matches the structure expected by the request
+    let result = unsafe { libc::ioctl(socket.as_raw_fd(), request, argument as *mut T) };
+    if result < 0 {
+        return Err(anyhow!("ioctl failed: {}", Errno::last()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test the parsing of the kernel `ip=` parameter
+    #[test]
+    fn network_config_from_cmdline() {
+        let cmdline = "console=ttyS0 reboot=k panic=1 ip=192.168.10.2::192.168.10.1:255.255.255.0:lambdo-vm:eth0:off";
+
+        assert_eq!(
+            NetworkConfig::from_cmdline(cmdline).unwrap(),
+            Some(NetworkConfig {
+                address: Ipv4Addr::new(192, 168, 10, 2),
+                netmask: Ipv4Addr::new(255, 255, 255, 0),
+                gateway: Some(Ipv4Addr::new(192, 168, 10, 1)),
+                hostname: Some("lambdo-vm".to_string()),
+                device: "eth0".to_string(),
+            })
+        );
+    }
+
+    /// Test that CIDR addresses, dynamic and missing configurations are handled
+    #[test]
+    fn network_config_variants() {
+        let config = NetworkConfig::from_cmdline("ip=10.0.0.5/16")
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.netmask, Ipv4Addr::new(255, 255, 0, 0));
+        assert_eq!(config.gateway, None);
+        assert_eq!(config.device, DEFAULT_DEVICE);
+
+        assert_eq!(NetworkConfig::from_cmdline("ip=dhcp").unwrap(), None);
+        assert_eq!(NetworkConfig::from_cmdline("console=ttyS0").unwrap(), None);
+        assert!(NetworkConfig::from_cmdline("ip=10.0.0.300").is_err());
+    }
+}
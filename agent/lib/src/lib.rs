@@ -1,3 +1,5 @@
 pub mod api;
 pub mod config;
+pub mod init;
 pub mod runner_engine;
+pub mod telemetry;
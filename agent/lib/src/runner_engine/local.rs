@@ -0,0 +1,177 @@
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::api::grpc_definitions::{ExecuteRequest, ExecuteRequestStep, FileModel};
+
+/// A step, in the format used by the languages of the lambdo config
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct StepDefinition {
+    /// The name of the step
+    pub name: Option<String>,
+    /// The command to execute, `{{filename}}` is replaced by the entrypoint
+    pub command: String,
+    /// The output configuration
+    #[serde(default)]
+    pub output: StepOutputDefinition,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct StepOutputDefinition {
+    /// Is the output enabled ?
+    pub enabled: bool,
+    /// Is the output a debug output ?
+    #[serde(default)]
+    pub debug: bool,
+}
+
+impl Default for StepOutputDefinition {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            debug: false,
+        }
+    }
+}
+
+/// Load an execution request from a JSON file
+///
+/// # Arguments
+///
+/// * `path` - The path of the request file
+///
+/// # Returns
+///
+/// * `Result<ExecuteRequest>` - The request or an error
+pub fn request_from_file(path: &Path) -> Result<ExecuteRequest> {
+    let file = File::open(path)
+        .map_err(|e| anyhow!("Failed to open request file {}: {}", path.display(), e))?;
+
+    serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| anyhow!("Failed to parse request file {}: {}", path.display(), e))
+}
+
+/// Build an execution request from a directory and a steps file
+///
+/// # Arguments
+///
+/// * `id` - The ID of the request
+/// * `files` - The directory holding the files of the workspace
+/// * `steps` - The YAML file holding the steps
+/// * `entrypoint` - The file replacing `{{filename}}`, the first file of the directory if not set
+///
+/// # Returns
+///
+/// * `Result<ExecuteRequest>` - The request or an error
+pub fn request_from_dir(
+    id: &str,
+    files: &Path,
+    steps: &Path,
+    entrypoint: Option<&str>,
+) -> Result<ExecuteRequest> {
+    let mut file_models = Vec::new();
+    read_dir(files, files, &mut file_models)?;
+    file_models.sort_by(|a, b| a.filename.cmp(&b.filename));
+
+    let steps_file = File::open(steps)
+        .map_err(|e| anyhow!("Failed to open steps file {}: {}", steps.display(), e))?;
+    let step_definitions: Vec<StepDefinition> = serde_yaml::from_reader(steps_file)
+        .map_err(|e| anyhow!("Failed to parse steps file {}: {}", steps.display(), e))?;
+
+    let entrypoint = entrypoint
+        .map(str::to_string)
+        .or_else(|| file_models.first().map(|file| file.filename.clone()))
+        .unwrap_or_default();
+    debug!("Using {} as entrypoint", entrypoint);
+
+    Ok(ExecuteRequest {
+        id: id.to_string(),
+        files: file_models,
+        steps: step_definitions
+            .into_iter()
+            .map(|step| ExecuteRequestStep {
+                command: step.command.replace("{{filename}}", &entrypoint),
+                enable_output: step.output.enabled,
+            })
+            .collect(),
+        ..Default::default()
+    })
+}
+
+fn read_dir(root: &Path, dir: &Path, file_models: &mut Vec<FileModel>) -> Result<()> {
+    let entries =
+        fs::read_dir(dir).map_err(|e| anyhow!("Failed to read {}: {}", dir.display(), e))?;
+
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            read_dir(root, &path, file_models)?;
+            continue;
+        }
+
+        let content = fs::read_to_string(&path)
+            .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+        let filename = path
+            .strip_prefix(root)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .to_string();
+
+        file_models.push(FileModel { filename, content });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that a directory and a steps file become a request
+    #[test]
+    fn request_built_from_dir() {
+        let root = std::env::temp_dir().join(format!("lambdo-local-{}", std::process::id()));
+        fs::create_dir_all(root.join("files/lib")).unwrap();
+        fs::write(root.join("files/main.py"), "import lib.util").unwrap();
+        fs::write(root.join("files/lib/util.py"), "").unwrap();
+        fs::write(
+            root.join("steps.yaml"),
+            "
+- name: Run the code
+  command: python3 {{filename}}
+  output:
+    enabled: true
+    debug: false
+- command: echo done
+",
+        )
+        .unwrap();
+
+        let request = request_from_dir(
+            "local",
+            &root.join("files"),
+            &root.join("steps.yaml"),
+            Some("main.py"),
+        )
+        .unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(request.id, "local");
+        assert_eq!(
+            request
+                .files
+                .iter()
+                .map(|file| file.filename.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["lib/util.py", "main.py"]
+        );
+        assert_eq!(request.steps[0].command, "python3 main.py");
+        assert!(request.steps[1].enable_output);
+    }
+}
@@ -1,2 +1,3 @@
+pub mod local;
 pub mod model;
 pub mod service;
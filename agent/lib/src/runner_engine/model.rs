@@ -32,19 +32,22 @@ impl FileModel {
 ///
 /// * `stdout` - The stdout of the command
 /// * `stderr` - The stderr of the command
+/// * `combined` - The stdout and stderr lines of the command, in the order they were written
 /// * `exit_code` - The exit code of the command
 #[derive(Deserialize, Serialize, Debug)]
 pub struct CodeReturn {
     pub stdout: String,
     pub stderr: String,
+    pub combined: String,
     pub exit_code: i32,
 }
 
 impl CodeReturn {
-    pub fn new(stdout: String, stderr: String, exit_code: i32) -> Self {
+    pub fn new(stdout: String, stderr: String, combined: String, exit_code: i32) -> Self {
         Self {
             stdout,
             stderr,
+            combined,
             exit_code,
         }
     }
@@ -1,21 +1,36 @@
 use super::model::CodeReturn;
-use crate::api::grpc_definitions::{ExecuteRequest, ExecuteResponse, ExecuteResponseStep};
+use crate::api::grpc_definitions::{
+    Artifact, ExecuteRequest, ExecuteResponse, ExecuteResponseStep, OutputChunk,
+};
 use crate::runner_engine::model::FileModel;
 use anyhow::{anyhow, Ok, Result};
-use log::{error, info};
+use log::{debug, error, info, warn};
 use std::io::Write;
 use std::{
     fs::File,
     path::{Path, PathBuf},
+    process::Stdio,
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
     process::Command,
+    select,
+    sync::mpsc::UnboundedSender,
 };
+use tracing::instrument;
 
 /// The path where the workspace will be created
-const WORKSPACE_PATH: &str = "/tmp";
+pub const WORKSPACE_PATH: &str = "/tmp";
+
+/// The maximum total size of the artifacts, used when the request does not set one
+const DEFAULT_ARTIFACTS_MAX_SIZE: u64 = 10 * 1024 * 1024;
 
 /// The RunnerEngine API
 pub struct RunnerEngine {
     pub request_message: ExecuteRequest,
+    pub workspace: PathBuf,
+    /// Where the output of the steps is sent as it comes, if it is streamed
+    pub output: Option<UnboundedSender<OutputChunk>>,
 }
 
 impl RunnerEngine {
@@ -29,7 +44,41 @@ impl RunnerEngine {
     ///
     /// * `Self` - The new instance of RunnerEngine
     pub fn new(request_message: ExecuteRequest) -> Self {
-        Self { request_message }
+        Self {
+            request_message,
+            workspace: PathBuf::from(WORKSPACE_PATH),
+            output: None,
+        }
+    }
+
+    /// Use another directory than the default one as workspace
+    ///
+    /// # Arguments
+    ///
+    /// * `workspace` - The directory in which the files are created and the commands run
+    ///
+    /// # Returns
+    ///
+    /// * `Self` - The instance of RunnerEngine
+    pub fn with_workspace<P: Into<PathBuf>>(mut self, workspace: P) -> Self {
+        self.workspace = workspace.into();
+        self
+    }
+
+    /// Send the output of the steps as it comes, on top of returning it in the response
+    ///
+    /// The hidden stdout of the steps without output is not sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - Where the output of the steps is sent
+    ///
+    /// # Returns
+    ///
+    /// * `Self` - The instance of RunnerEngine
+    pub fn with_output(mut self, output: UnboundedSender<OutputChunk>) -> Self {
+        self.output = Some(output);
+        self
     }
 
     /// Create the workspace for the code execution
@@ -37,18 +86,19 @@ impl RunnerEngine {
     /// # Returns
     ///
     /// * `Result<()>` - Nothing or an error
+    #[instrument(skip_all, fields(id = %self.request_message.id))]
     pub fn create_workspace(&mut self) -> Result<()> {
         info!("Creating workspace for code execution");
 
         // Create a vector of FileModel and a root path
         let mut file_models: Vec<FileModel> = Vec::new();
-        let root_path = PathBuf::from(WORKSPACE_PATH);
+        let root_path = self.workspace.clone();
 
         self.request_message.files.iter().for_each(|file| {
             let mut file_path = PathBuf::from(&file.filename);
             file_path.pop();
 
-            // Add `/tmp` before each path
+            // Add the workspace before each path
             file_path = root_path.join(file_path);
 
             // Take the file name and add it to the vector of files
@@ -120,41 +170,124 @@ impl RunnerEngine {
     /// # Returns
     ///
     /// * `Result<ResponseMessage>` - The response message or an error
-    pub fn run(&mut self) -> Result<ExecuteResponse> {
+    #[instrument(skip_all, fields(id = %self.request_message.id))]
+    pub async fn run(&mut self) -> Result<ExecuteResponse> {
         info!("Running all steps");
         let mut steps: Vec<ExecuteResponseStep> = Vec::new();
 
         // For each commands in the request, run it
         let steps_to_process = self.request_message.steps.clone();
 
-        for step in steps_to_process {
+        for (index, step) in steps_to_process.into_iter().enumerate() {
             let command = step.command.as_str();
-            let code_return = self.run_one(command)?;
+            let code_return = self
+                .run_command(command, Some((index as u32, step.enable_output)))
+                .await?;
 
-            // Hide Stdout if enable_output is false
-            let stdout = if step.enable_output {
-                code_return.stdout
+            // Hide Stdout if enable_output is false, the combined output is then only stderr
+            let (stdout, combined) = if step.enable_output {
+                (code_return.stdout, code_return.combined)
             } else {
-                String::new()
+                (String::new(), code_return.stderr.clone())
             };
             let response_step = ExecuteResponseStep {
                 command: command.to_string(),
                 exit_code: code_return.exit_code,
                 stdout,
                 stderr: code_return.stderr,
+                combined,
             };
 
             steps.push(response_step);
         }
 
+        let artifacts = self.collect_artifacts()?;
+
         let data = ExecuteResponse {
             id: self.request_message.id.clone(),
             steps,
+            artifacts,
+            ..Default::default()
         };
 
         Ok(data)
     }
 
+    /// Collect the workspace files matching the artifact patterns of the request
+    ///
+    /// Files that do not fit in the remaining size budget are returned without content
+    /// and flagged as truncated.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<Artifact>>` - The collected artifacts or an error
+    #[instrument(skip_all)]
+    pub fn collect_artifacts(&self) -> Result<Vec<Artifact>> {
+        let mut artifacts: Vec<Artifact> = Vec::new();
+        let mut remaining = match self.request_message.artifacts_max_size {
+            0 => DEFAULT_ARTIFACTS_MAX_SIZE,
+            max_size => max_size,
+        };
+        let root_path = &self.workspace;
+
+        for pattern in &self.request_message.artifacts {
+            // Only allow patterns inside the workspace
+            if Path::new(pattern).is_absolute() || pattern.split('/').any(|part| part == "..") {
+                warn!(
+                    "Ignoring artifact pattern outside of the workspace: {}",
+                    pattern
+                );
+                continue;
+            }
+
+            let full_pattern = root_path.join(pattern);
+            let paths = glob::glob(&full_pattern.to_string_lossy())
+                .map_err(|e| anyhow!("Invalid artifact pattern {}: {}", pattern, e))?;
+
+            for path in paths.flatten().filter(|path| path.is_file()) {
+                let relative_path = path
+                    .strip_prefix(root_path)
+                    .unwrap_or(&path)
+                    .to_string_lossy()
+                    .to_string();
+                if artifacts
+                    .iter()
+                    .any(|artifact| artifact.path == relative_path)
+                {
+                    continue;
+                }
+
+                let size = std::fs::metadata(&path)
+                    .map_err(|e| anyhow!("Failed to read artifact metadata: {}", e))?
+                    .len();
+
+                let artifact = if size <= remaining {
+                    remaining -= size;
+                    Artifact {
+                        path: relative_path,
+                        content: std::fs::read(&path)
+                            .map_err(|e| anyhow!("Failed to read artifact: {}", e))?,
+                        size,
+                        truncated: false,
+                    }
+                } else {
+                    warn!("Artifact {} exceeds the size limit", relative_path);
+                    Artifact {
+                        path: relative_path,
+                        content: Vec::new(),
+                        size,
+                        truncated: true,
+                    }
+                };
+
+                debug!("Collected artifact: {} ({} bytes)", artifact.path, size);
+                artifacts.push(artifact);
+            }
+        }
+
+        Ok(artifacts)
+    }
+
     /// Run a command
     ///
     /// # Arguments
@@ -164,29 +297,121 @@ impl RunnerEngine {
     /// # Returns
     ///
     /// * `Result<CodeReturn>` - The code return or an error
-    pub fn run_one(&mut self, command: &str) -> Result<CodeReturn> {
+    #[instrument(skip(self))]
+    pub async fn run_one(&mut self, command: &str) -> Result<CodeReturn> {
+        self.run_command(command, None).await
+    }
+
+    /// Run a command, streaming its output if it is a step of the request
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command to run
+    /// * `step` - The index of the step and whether its stdout is shown
+    ///
+    /// # Returns
+    ///
+    /// * `Result<CodeReturn>` - The code return or an error
+    async fn run_command(
+        &mut self,
+        command: &str,
+        step: Option<(u32, bool)>,
+    ) -> Result<CodeReturn> {
         info!("Running command : {}", command);
 
-        let child_process = Command::new("/bin/sh")
+        // The child is killed if the execution is cancelled
+        let mut child_process = Command::new("/bin/sh")
             .args(["-c", command])
-            .current_dir(WORKSPACE_PATH)
-            .output()
+            .current_dir(&self.workspace)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
             .map_err(|e| anyhow!("Failed to spawn command : {}", e))?;
 
+        let mut stdout_reader = BufReader::new(
+            child_process
+                .stdout
+                .take()
+                .ok_or_else(|| anyhow!("Failed to retrieve stdout stream"))?,
+        );
+        let mut stderr_reader = BufReader::new(
+            child_process
+                .stderr
+                .take()
+                .ok_or_else(|| anyhow!("Failed to retrieve stderr stream"))?,
+        );
+
+        let mut stdout = String::new();
+        let mut stderr = String::new();
+        let mut combined = String::new();
+
+        // Partially read lines stay in these buffers when the other stream wins the race
+        let mut stdout_line = Vec::new();
+        let mut stderr_line = Vec::new();
+        let mut stdout_open = true;
+        let mut stderr_open = true;
+
+        while stdout_open || stderr_open {
+            select! {
+                read = stdout_reader.read_until(b'\n', &mut stdout_line), if stdout_open => {
+                    let read = read.map_err(|e| anyhow!("Failed to retrieve stdout stream : {}", e))?;
+                    if read == 0 {
+                        stdout_open = false;
+                    }
+                    let line = String::from_utf8_lossy(&stdout_line);
+                    if let Some((index, true)) = step {
+                        self.send_output(index, &line, "");
+                    }
+                    stdout.push_str(&line);
+                    combined.push_str(&line);
+                    stdout_line.clear();
+                }
+                read = stderr_reader.read_until(b'\n', &mut stderr_line), if stderr_open => {
+                    let read = read.map_err(|e| anyhow!("Failed to retrieve stderr stream : {}", e))?;
+                    if read == 0 {
+                        stderr_open = false;
+                    }
+                    let line = String::from_utf8_lossy(&stderr_line);
+                    if let Some((index, _)) = step {
+                        self.send_output(index, "", &line);
+                    }
+                    stderr.push_str(&line);
+                    combined.push_str(&line);
+                    stderr_line.clear();
+                }
+            }
+        }
+
         let exit_code = child_process
-            .status
+            .wait()
+            .await
+            .map_err(|e| anyhow!("Failed to wait for command : {}", e))?
             .code()
             .ok_or_else(|| anyhow!("Failed to retrieve exit_code"))?;
-        let stdout = String::from_utf8(child_process.stdout)
-            .map_err(|e| anyhow!("Failed to retrieve stdout stream : {}", e))?;
-        let stderr = String::from_utf8(child_process.stderr)
-            .map_err(|e| anyhow!("Failed to retrieve stderr stream : {}", e))?;
 
-        let code_return = CodeReturn::new(stdout, stderr, exit_code);
+        let code_return = CodeReturn::new(stdout, stderr, combined, exit_code);
 
         info!("Code execution finished: {:?}", code_return);
         Ok(code_return)
     }
+
+    /// Send a piece of the output of a step, if the output is streamed
+    fn send_output(&self, step: u32, stdout: &str, stderr: &str) {
+        if stdout.is_empty() && stderr.is_empty() {
+            return;
+        }
+        if let Some(output) = &self.output {
+            // The receiver is gone if the execution was abandoned, the response tells the rest
+            let _ = output.send(OutputChunk {
+                id: self.request_message.id.clone(),
+                step,
+                stdout: stdout.to_string(),
+                stderr: stderr.to_string(),
+            });
+        }
+    }
 }
 
 #[cfg(test)]
@@ -224,8 +449,8 @@ mod tests {
     }
 
     /// Test the creation of a file
-    #[test]
-    fn workload_runs_correctly() {
+    #[tokio::test]
+    async fn workload_runs_correctly() {
         let files: Vec<FileModel> = Vec::new();
         let mut steps: Vec<ExecuteRequestStep> = Vec::new();
         let step = ExecuteRequestStep {
@@ -237,11 +462,14 @@ mod tests {
             id: "4bf68974-c315-4c41-aee2-3dc2920e76e9".to_string(),
             files,
             steps,
+            artifacts: Vec::new(),
+            artifacts_max_size: 0,
+            ..Default::default()
         };
 
         let mut api = RunnerEngine::new(request_data);
 
-        let res = api.run().unwrap();
+        let res = api.run().await.unwrap();
 
         assert_eq!(res.steps[0].exit_code, 0);
         assert_eq!(res.steps[0].stderr, "This is stderr\n");
@@ -266,6 +494,9 @@ mod tests {
             id: "4bf68974-c315-4c41-aee2-3dc2920e76e9".to_string(),
             files,
             steps,
+            artifacts: Vec::new(),
+            artifacts_max_size: 0,
+            ..Default::default()
         };
 
         RunnerEngine::new(request_data).create_workspace().unwrap();
@@ -282,4 +513,138 @@ mod tests {
         assert!(file.metadata().unwrap().is_file());
         assert_eq!(content, "Hello World!");
     }
+
+    /// Test that interleaved stdout and stderr lines all end up in the combined output
+    ///
+    /// The two pipes are read concurrently, so the order of the lines of different
+    /// streams in the combined output is not checked.
+    #[tokio::test]
+    async fn combined_output_has_every_line() {
+        let steps = vec![
+            ExecuteRequestStep {
+                command: "echo out1; echo err1 >&2; echo out2; printf err2 >&2; exit 3".to_string(),
+                enable_output: true,
+            },
+            ExecuteRequestStep {
+                command: "echo hidden; echo shown >&2".to_string(),
+                enable_output: false,
+            },
+        ];
+        let request_data = ExecuteRequest {
+            id: "4bf68974-c315-4c41-aee2-3dc2920e76e9".to_string(),
+            files: Vec::new(),
+            steps,
+            artifacts: Vec::new(),
+            artifacts_max_size: 0,
+            ..Default::default()
+        };
+
+        let res = RunnerEngine::new(request_data).run().await.unwrap();
+
+        assert_eq!(res.steps[0].exit_code, 3);
+        assert_eq!(res.steps[0].stdout, "out1\nout2\n");
+        assert_eq!(res.steps[0].stderr, "err1\nerr2");
+        let combined = &res.steps[0].combined;
+        assert_eq!(combined.len(), "out1\nerr1\nout2\nerr2".len());
+        for line in ["out1\n", "err1\n", "out2\n", "err2"] {
+            assert!(combined.contains(line), "{:?} misses {:?}", combined, line);
+        }
+        // Each stream keeps its own order
+        assert!(combined.find("out1").unwrap() < combined.find("out2").unwrap());
+        assert!(combined.find("err1").unwrap() < combined.find("err2").unwrap());
+
+        assert_eq!(res.steps[1].stdout, "");
+        assert_eq!(res.steps[1].combined, "shown\n");
+    }
+
+    /// Test that the output of the steps is sent while they run
+    #[tokio::test]
+    async fn output_streamed_as_it_comes() {
+        let gate = PathBuf::from(WORKSPACE_PATH).join(native_rand_string(20));
+        let steps = vec![
+            ExecuteRequestStep {
+                command: format!(
+                    "echo first; while [ ! -f {} ]; do sleep 0.01; done",
+                    gate.display()
+                ),
+                enable_output: true,
+            },
+            ExecuteRequestStep {
+                command: "echo hidden; echo shown >&2".to_string(),
+                enable_output: false,
+            },
+        ];
+        let request_data = ExecuteRequest {
+            id: "4bf68974-c315-4c41-aee2-3dc2920e76e9".to_string(),
+            steps,
+            ..Default::default()
+        };
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let run = tokio::spawn(async move {
+            RunnerEngine::new(request_data)
+                .with_output(sender)
+                .run()
+                .await
+        });
+
+        // The first step waits for the gate, its first line is already there
+        let chunk = receiver.recv().await.unwrap();
+        assert_eq!(chunk.id, "4bf68974-c315-4c41-aee2-3dc2920e76e9");
+        assert_eq!((chunk.step, chunk.stdout.as_str()), (0, "first\n"));
+        File::create(&gate).unwrap();
+
+        let res = run.await.unwrap().unwrap();
+        std::fs::remove_file(&gate).unwrap();
+        assert_eq!(res.steps[0].stdout, "first\n");
+
+        // The hidden stdout is not sent either
+        let chunk = receiver.recv().await.unwrap();
+        assert_eq!(
+            (chunk.step, chunk.stdout.as_str(), chunk.stderr.as_str()),
+            (1, "", "shown\n")
+        );
+        assert!(receiver.recv().await.is_none());
+    }
+
+    /// Test the collection of artifacts after the steps
+    #[tokio::test]
+    async fn artifacts_collected_with_size_limit() {
+        let dir = native_rand_string(20);
+        let steps = vec![ExecuteRequestStep {
+            command: format!(
+                "mkdir -p {dir} && printf 'small' > {dir}/small.txt && printf 'way too large' > {dir}/large.txt && printf 'ignored' > {dir}/other.log"
+            ),
+            enable_output: true,
+        }];
+        let request_data = ExecuteRequest {
+            id: "4bf68974-c315-4c41-aee2-3dc2920e76e9".to_string(),
+            files: Vec::new(),
+            steps,
+            artifacts: vec![format!("{}/*.txt", dir), "../etc/*".to_string()],
+            artifacts_max_size: 8,
+            ..Default::default()
+        };
+
+        let res = RunnerEngine::new(request_data).run().await.unwrap();
+
+        assert_eq!(res.artifacts.len(), 2);
+
+        let large = res
+            .artifacts
+            .iter()
+            .find(|artifact| artifact.path == format!("{}/large.txt", dir))
+            .unwrap();
+        assert!(large.truncated);
+        assert!(large.content.is_empty());
+        assert_eq!(large.size, 13);
+
+        let small = res
+            .artifacts
+            .iter()
+            .find(|artifact| artifact.path == format!("{}/small.txt", dir))
+            .unwrap();
+        assert!(!small.truncated);
+        assert_eq!(small.content, b"small");
+    }
 }
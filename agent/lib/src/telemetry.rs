@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use lambdo_shared::telemetry::{otlp_tracer, stdout_tracer};
+use opentelemetry::{global, propagation::Extractor, Context};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use tonic::metadata::MetadataMap;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Layer, Registry};
+
+use crate::config::{TracingConfig, TracingExporter};
+
+/// The name under which the spans are exported
+const SERVICE_NAME: &str = "lambdo-agent";
+
+/// Set up the logs and the export of the traces
+///
+/// The logs are printed on stderr and filtered by `RUST_LOG`. The spans, along with the
+/// logs emitted within them, are sent to the configured exporter.
+///
+/// # Arguments
+///
+/// * `config` - The tracing configuration
+pub fn init(config: &TracingConfig) -> Result<()> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let tracer = match config.exporter {
+        TracingExporter::None => None,
+        TracingExporter::Stdout => Some(stdout_tracer(SERVICE_NAME)),
+        TracingExporter::Otlp => Some(otlp_tracer(SERVICE_NAME, config.endpoint.as_deref())?),
+    };
+
+    let subscriber = Registry::default()
+        .with(tracer.map(|tracer| {
+            tracing_opentelemetry::layer()
+                .with_tracer(tracer)
+                .with_filter(LevelFilter::INFO)
+        }))
+        .with(log_layer());
+
+    tracing::subscriber::set_global_default(subscriber)
+        .map_err(|e| anyhow!("failed to set up tracing: {}", e))
+}
+
+/// Run a function with the logs printed, for what happens before the tracing is set up
+pub fn with_logs<T>(f: impl FnOnce() -> T) -> T {
+    tracing::subscriber::with_default(Registry::default().with(log_layer()), f)
+}
+
+/// Export the spans that are still buffered
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Read the trace context sent by lambdo in the metadata of a gRPC request
+pub fn extract_context(metadata: &MetadataMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&MetadataExtractor(metadata)))
+}
+
+/// Read the trace context sent by lambdo along a request over the serial line
+pub fn extract_serial_context(trace_context: &HashMap<String, String>) -> Context {
+    lambdo_shared::telemetry::extract_context(trace_context)
+}
+
+/// Reads the trace context from gRPC metadata
+struct MetadataExtractor<'a>(&'a MetadataMap);
+
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .keys()
+            .filter_map(|key| match key {
+                tonic::metadata::KeyRef::Ascii(key) => Some(key.as_str()),
+                tonic::metadata::KeyRef::Binary(_) => None,
+            })
+            .collect()
+    }
+}
+
+fn log_layer<S>() -> impl Layer<S>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_filter(EnvFilter::from_default_env())
+}
@@ -1,13 +1,17 @@
 use agent_lib::{
     api::{
         grpc_definitions::lambdo_agent_service_server::LambdoAgentServiceServer,
-        server::LambdoAgentServer,
+        serial::SerialServer, server::LambdoAgentServer,
     },
-    config::AgentConfig,
+    config::{AgentConfig, TracingConfig},
+    init,
+    runner_engine::{local, service::RunnerEngine},
+    telemetry,
 };
-use anyhow::Result;
-use clap::Parser;
-use log::{debug, error, info, trace};
+use anyhow::{anyhow, Result};
+use clap::{Args, Parser, Subcommand};
+use log::{debug, error, info, trace, warn};
+use std::path::PathBuf;
 use tokio::net::TcpListener;
 
 /// Agent CLI options
@@ -18,24 +22,160 @@ use tokio::net::TcpListener;
     about = "A Serverless runtime in Rust"
 )]
 pub struct AgentOpts {
-    /// Config file path
-    #[clap(short, long, default_value = "/etc/lambdo/agent/config.yaml")]
-    config: String,
+    /// Config file path, defaults to the one of the initramfs in init mode
+    #[clap(short, long)]
+    config: Option<String>,
+    /// Run as the init process of the VM, implied when running as PID 1
+    #[clap(long)]
+    init: bool,
+    #[clap(subcommand)]
+    command: Option<AgentCommand>,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Initialize logger
-    env_logger::init();
-    info!("Starting agent");
+#[derive(Subcommand)]
+enum AgentCommand {
+    /// Run a request once on this host and print the response as JSON
+    Run(RunOpts),
+}
+
+#[derive(Args)]
+struct RunOpts {
+    /// Execution request file, in JSON
+    #[clap(long, conflicts_with = "files", required_unless_present = "files")]
+    request: Option<PathBuf>,
+    /// Directory holding the files of the workspace
+    #[clap(long, requires = "steps")]
+    files: Option<PathBuf>,
+    /// Steps file, in the format of the language steps of the lambdo config
+    #[clap(long, requires = "files")]
+    steps: Option<PathBuf>,
+    /// File replacing `{{filename}}` in the steps, the first file of the directory by default
+    #[clap(long, requires = "files")]
+    entrypoint: Option<String>,
+    /// Directory in which the request runs, a temporary one by default
+    #[clap(long)]
+    workspace: Option<PathBuf>,
+}
+
+const DEFAULT_CONFIG_PATH: &str = "/etc/lambdo/agent/config.yaml";
+
+fn main() -> Result<()> {
+    // Forward the logs to the tracing subscriber
+    tracing_log::LogTracer::init()?;
 
     // Parse CLI options
     let options = AgentOpts::parse();
-    debug!("loading config file at {}", options.config);
+    let is_pid1 = std::process::id() == 1;
+
+    if let Some(AgentCommand::Run(run_opts)) = options.command {
+        telemetry::init(&TracingConfig::default())?;
+        let exit_code = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?
+            .block_on(run_once(run_opts))?;
+        std::process::exit(exit_code);
+    }
+
+    if options.init || is_pid1 {
+        telemetry::init(&TracingConfig::default())?;
+        let config = options
+            .config
+            .unwrap_or_else(|| init::INITRAMFS_CONFIG_PATH.to_string());
+
+        if let Err(e) = init::run(&["--config".to_string(), config]) {
+            error!("Init failure: {:?}", e);
+        }
+
+        // Exiting as PID 1 would make the kernel panic
+        if is_pid1 {
+            init::power_off()?;
+        } else {
+            warn!("Not running as PID 1, exiting instead of powering off");
+        }
+        return Ok(());
+    }
+
+    let config_path = options
+        .config
+        .unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_string());
+
+    // The tracing configuration is only known once the config is loaded
+    let config = telemetry::with_logs(|| {
+        info!("Starting agent");
+        debug!("loading config file at {}", config_path);
+        let config = AgentConfig::load(config_path.as_str())?;
+        trace!("config loaded successfully with content: {:#?}", config);
+        Ok::<_, anyhow::Error>(config)
+    })?;
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(async {
+            telemetry::init(&config.tracing)?;
+            let result = run(config).await;
+            telemetry::shutdown();
+            result
+        })
+}
+
+/// Run a request without registering to lambdo
+///
+/// # Returns
+///
+/// * `Result<i32>` - The exit code of the last step or an error
+async fn run_once(opts: RunOpts) -> Result<i32> {
+    let id = format!("local-{}", std::process::id());
+    let request = match (opts.request, opts.files, opts.steps) {
+        (Some(request), _, _) => local::request_from_file(&request)?,
+        (None, Some(files), Some(steps)) => {
+            local::request_from_dir(&id, &files, &steps, opts.entrypoint.as_deref())?
+        }
+        _ => {
+            return Err(anyhow!(
+                "Either --request or --files and --steps are required"
+            ))
+        }
+    };
+
+    // A temporary workspace is removed once the request ran
+    let (workspace, temporary) = match opts.workspace {
+        Some(workspace) => (workspace, false),
+        None => (std::env::temp_dir().join(format!("lambdo-{}", id)), true),
+    };
+    std::fs::create_dir_all(&workspace)?;
+    info!("Running request {} in {}", request.id, workspace.display());
+
+    let mut runner_engine = RunnerEngine::new(request).with_workspace(&workspace);
+    runner_engine.create_workspace()?;
+    let response = runner_engine.run().await;
+
+    if temporary {
+        std::fs::remove_dir_all(&workspace)?;
+    }
+    let response = response?;
+
+    println!("{}", serde_json::to_string_pretty(&response)?);
+
+    Ok(response.steps.last().map_or(0, |step| step.exit_code))
+}
+
+async fn run(mut config: AgentConfig) -> Result<()> {
+    // Use the serial line instead of the network if configured
+    if let Some(serial) = config.serial {
+        info!("Using serial transport on {}", serial.device);
+        let runtime = tokio::runtime::Handle::current();
+        let token = config.token.clone();
+        tokio::task::spawn_blocking(move || SerialServer::open(&serial, token, runtime)?.serve())
+            .await
+            .unwrap_or_else(|e| {
+                error!("Serial server failure");
+                panic!("{}", e)
+            })?;
 
-    // Load config file
-    let mut config = AgentConfig::load(options.config.as_str())?;
-    trace!("config loaded successfully with content: {:#?}", config);
+        info!("Stopping agent");
+        return Ok(());
+    }
 
     // Initialize gRPC server
     let tcp_socket = TcpListener::bind(format!(
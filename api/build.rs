@@ -8,6 +8,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             ".grpc_definitions.RegisterResponse.response",
             "#[derive(serde::Deserialize, serde::Serialize)] #[serde(rename_all = \"snake_case\")]",
         )
+        .type_attribute(
+            ".grpc_definitions.SerialMessage.payload",
+            "#[derive(serde::Deserialize, serde::Serialize)] #[serde(rename_all = \"snake_case\")]",
+        )
         .compile(&["../shared/proto/lambdo.proto"], &["../shared/proto"])?;
 
     let _ = Command::new(std::env::var("RUSTFMT").unwrap_or_else(|_| "rustfmt".to_owned()))
@@ -0,0 +1,431 @@
+use actix_web::{delete, get, http::header, post, web, HttpRequest, HttpResponse};
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+
+use crate::{
+    api::service::LambdoApiService,
+    auth::{secrets_match, Authenticator},
+    model::{WarmResponse, DEFAULT_MEMORY},
+    shutdown::Shutdown,
+    vm_manager::{
+        kill_vm, run_vm,
+        state::{LambdoState, VMDirectory},
+        Error,
+    },
+};
+
+/// Maximum number of VMs booted by a single warm request
+const MAX_WARM_COUNT: u32 = 16;
+
+#[derive(Deserialize, Debug)]
+pub struct WarmQuery {
+    /// The number of VMs to boot
+    #[serde(default = "default_warm_count")]
+    pub count: u32,
+}
+
+const fn default_warm_count() -> u32 {
+    1
+}
+
+/// Check the bearer token of an admin request
+///
+/// # Returns
+///
+/// * `Option<HttpResponse>` - The response refusing the request, `None` if it is allowed
+fn refuse(request: &HttpRequest, api_service: &LambdoApiService) -> Option<HttpResponse> {
+    let token = match api_service.config().api.admin_token.clone() {
+        Some(token) => token,
+        None => {
+            debug!("Admin request refused, no admin token configured");
+            return Some(HttpResponse::Forbidden().body("The admin API is disabled"));
+        }
+    };
+
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|value| secrets_match(value, &token))
+        .unwrap_or(false);
+
+    if !authorized {
+        warn!("Admin request with an invalid token on {}", request.path());
+        return Some(
+            HttpResponse::Unauthorized()
+                .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+                .finish(),
+        );
+    }
+
+    None
+}
+
+/// Stop accepting executions, wait for the running ones, then stop the server
+#[post("/admin/drain")]
+pub async fn post_drain_route(
+    request: HttpRequest,
+    api_service: web::Data<LambdoApiService>,
+    shutdown: web::Data<Shutdown>,
+) -> HttpResponse {
+    if let Some(response) = refuse(&request, &api_service) {
+        return response;
+    }
+
+    shutdown.request();
+    HttpResponse::Accepted().finish()
+}
+
+/// List the VMs
+#[get("/admin/vms")]
+pub async fn get_vms_route(
+    request: HttpRequest,
+    api_service: web::Data<LambdoApiService>,
+    directory: web::Data<VMDirectory>,
+) -> HttpResponse {
+    if let Some(response) = refuse(&request, &api_service) {
+        return response;
+    }
+
+    HttpResponse::Ok().json(directory.summaries())
+}
+
+/// Get the usage of the API keys
+#[get("/admin/keys")]
+pub async fn get_keys_route(
+    request: HttpRequest,
+    api_service: web::Data<LambdoApiService>,
+    authenticator: Option<web::Data<Authenticator>>,
+) -> HttpResponse {
+    if let Some(response) = refuse(&request, &api_service) {
+        return response;
+    }
+
+    match authenticator {
+        Some(authenticator) => HttpResponse::Ok().json(authenticator.usage()),
+        None => HttpResponse::NotFound().body("No API key configured"),
+    }
+}
+
+/// Kill a VM
+#[delete("/admin/vms/{id}")]
+pub async fn delete_vm_route(
+    request: HttpRequest,
+    id: web::Path<String>,
+    api_service: web::Data<LambdoApiService>,
+    state: web::Data<Mutex<LambdoState>>,
+) -> HttpResponse {
+    if let Some(response) = refuse(&request, &api_service) {
+        return response;
+    }
+
+    let mut state = state.lock().await;
+    match kill_vm(&mut state, &id) {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(Error::VmNotFound) => HttpResponse::NotFound().body("VM not found"),
+        Err(e) => {
+            error!("Error while killing VM {}: {}", id, e);
+            HttpResponse::InternalServerError().body(e.to_string())
+        }
+    }
+}
+
+/// Get the console output captured for a VM
+#[get("/admin/vms/{id}/console")]
+pub async fn get_vm_console_route(
+    request: HttpRequest,
+    id: web::Path<String>,
+    api_service: web::Data<LambdoApiService>,
+    directory: web::Data<VMDirectory>,
+) -> HttpResponse {
+    if let Some(response) = refuse(&request, &api_service) {
+        return response;
+    }
+
+    let console = match directory.console(&id) {
+        Some(Some(console)) => console,
+        Some(None) => return HttpResponse::NotFound().body("No console output captured"),
+        None => return HttpResponse::NotFound().body("VM not found"),
+    };
+
+    match tokio::fs::read(&console).await {
+        Ok(content) => HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .body(content),
+        Err(e) => {
+            debug!("Failed to read console output {}: {}", console, e);
+            HttpResponse::NotFound().body("No console output captured")
+        }
+    }
+}
+
+/// Boot VMs of a language ahead of the requests
+#[post("/admin/languages/{name}/warm")]
+pub async fn post_warm_route(
+    request: HttpRequest,
+    name: web::Path<String>,
+    query: web::Query<WarmQuery>,
+    api_service: web::Data<LambdoApiService>,
+    state: web::Data<Mutex<LambdoState>>,
+) -> HttpResponse {
+    if let Some(response) = refuse(&request, &api_service) {
+        return response;
+    }
+
+    if query.count == 0 || query.count > MAX_WARM_COUNT {
+        return HttpResponse::BadRequest().body(format!(
+            "The count must be between 1 and {}",
+            MAX_WARM_COUNT
+        ));
+    }
+
+    let mut state = state.lock().await;
+    if state.draining {
+        warn!("Refusing to warm up VMs, the server is draining");
+        return HttpResponse::ServiceUnavailable().body("The server is shutting down");
+    }
+
+    let language_settings = match api_service
+        .config()
+        .languages
+        .iter()
+        .find(|language| language.name == *name)
+    {
+        Some(language) => language.clone().into(),
+        None => return HttpResponse::NotFound().body("Language not found"),
+    };
+
+    info!("Warming up {} VMs for language {}", query.count, name);
+    let mut ids = Vec::new();
+    for _ in 0..query.count {
+        match run_vm(&mut state, &language_settings, DEFAULT_MEMORY, false).await {
+            Ok(id) => ids.push(id),
+            Err(e) => {
+                error!("Error while warming up a VM: {}", e);
+                return HttpResponse::InternalServerError().body(e.to_string());
+            }
+        }
+    }
+
+    HttpResponse::Created().json(WarmResponse { ids })
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, RwLock};
+
+    use actix_web::{
+        http::{header, StatusCode},
+        test::TestRequest,
+        web, App,
+    };
+    use tokio::sync::Mutex;
+
+    use super::{
+        delete_vm_route, get_vm_console_route, get_vms_route, post_drain_route, post_warm_route,
+    };
+    use crate::{
+        api::service::LambdoApiService,
+        config::{LambdoConfig, LambdoNetworkPolicy},
+        model::LanguageSettings,
+        shutdown::Shutdown,
+        vm_manager::{
+            state::{LambdoState, VMState},
+            MockVMManagerTrait,
+        },
+    };
+
+    fn generate_lambdo_config(admin_token: &str) -> LambdoConfig {
+        serde_yaml::from_str(&format!(
+            "
+apiVersion: lambdo.io/v1beta1
+kind: Config
+vmm:
+  kernel: vmlinux.bin
+api:
+  web_host: 0.0.0.0
+  web_port: 3000
+  grpc_host: 0.0.0.0
+  grpc_port: 50051
+  admin_token: {}
+  state_file: {}/lambdo-admin-{}.json
+agent:
+  path: /usr/local/bin/lambdo-agent
+  config: /etc/lambdo/agent.yaml
+languages: []
+",
+            admin_token,
+            std::env::temp_dir().display(),
+            std::process::id()
+        ))
+        .unwrap()
+    }
+
+    fn generate_vm_state(state: &LambdoState, id: &str) -> VMState {
+        let mut vm = VMState::new(
+            id.to_string(),
+            crate::vm_manager::VMMOpts {
+                kernel: "vmlinux.bin".to_string(),
+                cpus: 1,
+                memory: 1024,
+                console: None,
+                socket: None,
+                initramfs: None,
+                tap: None,
+                ip: Some("192.168.10.2/24".parse().unwrap()),
+                ipv6: None,
+                gateway: None,
+                boot_args: Vec::new(),
+            },
+            LanguageSettings {
+                name: "NODE".to_string(),
+                version: "12".to_string(),
+                initramfs: "node-12.img".to_string(),
+                cache_image: None,
+                network: LambdoNetworkPolicy::None,
+            },
+            state.channel.0.clone(),
+            state.directory.clone(),
+            false,
+        );
+        vm.reserved = true;
+        vm
+    }
+
+    #[actix_web::test]
+    async fn test_admin_authentication() {
+        let config = generate_lambdo_config("secret");
+        let lambdo_state = LambdoState::new(config.clone());
+        let directory = lambdo_state.directory.clone();
+        let state = Arc::new(Mutex::new(lambdo_state));
+        let shutdown = Shutdown::default();
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(LambdoApiService {
+                    config: RwLock::new(config),
+                    vm_manager: Box::new(MockVMManagerTrait::new()),
+                }))
+                .app_data(web::Data::from(state))
+                .app_data(web::Data::new(directory))
+                .app_data(web::Data::new(shutdown.clone()))
+                .service(get_vms_route)
+                .service(post_drain_route),
+        )
+        .await;
+
+        let request = TestRequest::get().uri("/admin/vms").to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let request = TestRequest::post()
+            .uri("/admin/drain")
+            .insert_header((header::AUTHORIZATION, "Bearer wrong"))
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let request = TestRequest::post()
+            .uri("/admin/drain")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        shutdown.requested().await;
+    }
+
+    #[actix_web::test]
+    async fn test_admin_manage_vms() {
+        let config = generate_lambdo_config("secret");
+        let state_file = config.api.state_file.clone();
+        let mut lambdo_state = LambdoState::new(config.clone());
+
+        let console = std::env::temp_dir().join(format!("lambdo-console-{}", std::process::id()));
+        std::fs::write(&console, "Linux version 6.1").unwrap();
+        let mut vm = generate_vm_state(&lambdo_state, "first");
+        vm.vm_opts.console = Some(console.to_string_lossy().to_string());
+        lambdo_state.vms.push(vm);
+        let vm = generate_vm_state(&lambdo_state, "second");
+        lambdo_state.vms.push(vm);
+        lambdo_state.persist();
+
+        let directory = lambdo_state.directory.clone();
+        let state = Arc::new(Mutex::new(lambdo_state));
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(LambdoApiService {
+                    config: RwLock::new(config),
+                    vm_manager: Box::new(MockVMManagerTrait::new()),
+                }))
+                .app_data(web::Data::from(state.clone()))
+                .app_data(web::Data::new(directory))
+                .service(get_vms_route)
+                .service(get_vm_console_route)
+                .service(delete_vm_route),
+        )
+        .await;
+        let authorization = (header::AUTHORIZATION, "Bearer secret");
+
+        let request = TestRequest::get()
+            .uri("/admin/vms")
+            .insert_header(authorization.clone())
+            .to_request();
+        let vms: serde_json::Value = actix_web::test::call_and_read_body_json(&app, request).await;
+        assert_eq!(vms[0]["id"], "first");
+        assert_eq!(vms[0]["status"], "waiting");
+        assert_eq!(vms[0]["ip"], "192.168.10.2/24");
+        assert_eq!(vms[0]["reserved"], true);
+        assert_eq!(vms[1]["id"], "second");
+
+        let request = TestRequest::get()
+            .uri("/admin/vms/first/console")
+            .insert_header(authorization.clone())
+            .to_request();
+        let body = actix_web::test::call_and_read_body(&app, request).await;
+        assert_eq!(body, "Linux version 6.1");
+
+        let request = TestRequest::delete()
+            .uri("/admin/vms/first")
+            .insert_header(authorization.clone())
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let request = TestRequest::delete()
+            .uri("/admin/vms/first")
+            .insert_header(authorization.clone())
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // The console output goes with the VM
+        assert!(!console.exists());
+        assert_eq!(state.lock().await.vms.len(), 1);
+        std::fs::remove_file(state_file).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_admin_warm_refused_while_draining() {
+        let config = generate_lambdo_config("secret");
+        let mut lambdo_state = LambdoState::new(config.clone());
+        lambdo_state.draining = true;
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(LambdoApiService {
+                    config: RwLock::new(config),
+                    vm_manager: Box::new(MockVMManagerTrait::new()),
+                }))
+                .app_data(web::Data::new(Mutex::new(lambdo_state)))
+                .service(post_warm_route),
+        )
+        .await;
+
+        let request = TestRequest::post()
+            .uri("/admin/languages/NODE/warm")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,152 @@
+use std::{error::Error, sync::Arc};
+
+use actix_web::{get, middleware::from_fn, post, web, web::Bytes, HttpRequest, HttpResponse};
+use futures_util::StreamExt;
+use log::{debug, trace};
+use tokio::{select, sync::mpsc};
+use tracing::{field, instrument, Span};
+
+use crate::{
+    api::{admit, execute, service::LambdoApiServiceTrait},
+    audit::AuditLog,
+    auth::{identify, Grant},
+    jobs::{self, Jobs},
+    model::{Execution, RunRequest},
+    scheduler::Scheduler,
+    shutdown::Shutdown,
+};
+
+/// Run code in the background, its result is kept for the client to fetch
+#[post("/jobs", wrap = "from_fn(crate::auth::authenticate)")]
+#[instrument(
+    skip_all,
+    fields(id = field::Empty, language = %run_body.language, version = %run_body.version)
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn post_job_route(
+    request: HttpRequest,
+    run_body: web::Json<RunRequest>,
+    api_service: web::Data<dyn LambdoApiServiceTrait>,
+    audit: web::Data<AuditLog>,
+    shutdown: web::Data<Shutdown>,
+    scheduler: web::Data<Scheduler>,
+    jobs: web::Data<Jobs>,
+    grant: Option<web::ReqData<Arc<Grant>>>,
+) -> HttpResponse {
+    debug!(
+        "Received job request from http (language: {}, version: {})",
+        run_body.language, run_body.version
+    );
+    trace!("Request body: {:?}", run_body);
+
+    let grant = grant.map(|grant| grant.into_inner());
+    let (admission, client) = match admit(
+        &request,
+        &run_body,
+        &shutdown,
+        &scheduler,
+        api_service.get_ref(),
+        grant.as_deref(),
+    ) {
+        Ok(admitted) => admitted,
+        Err(response) => return response,
+    };
+
+    let mut execution = Execution::new(run_body.into_inner());
+    if let Some(grant) = &grant {
+        execution.limits = grant.limits();
+    }
+    // The output is recorded as the agent streams it
+    let (output, mut chunks) = mpsc::unbounded_channel();
+    execution.output = Some(output);
+    let id = execution.id.clone();
+    Span::current().record("id", id.as_str());
+    let job = jobs.submit(
+        &execution,
+        grant.as_ref().map(|grant| grant.name().to_string()),
+    );
+
+    let background_jobs = jobs.clone();
+    actix_web::rt::spawn(async move {
+        let slot = admission.wait().await;
+        background_jobs.start(&execution.id);
+        let id = execution.id.clone();
+        let execution = execute(execution, slot, api_service.get_ref(), &audit, client);
+        tokio::pin!(execution);
+        let result = loop {
+            select! {
+                result = &mut execution => break result,
+                Some(chunk) = chunks.recv() => {
+                    background_jobs.output(&id, chunk.stdout, chunk.stderr);
+                }
+            }
+        };
+        // The agents send all the output before the response
+        while let Ok(chunk) = chunks.try_recv() {
+            background_jobs.output(&id, chunk.stdout, chunk.stderr);
+        }
+        background_jobs.end(&id, result);
+        // The job counts as running for its API key until it ended
+        drop(grant);
+    });
+
+    HttpResponse::Accepted()
+        .insert_header(("X-Request-Id", id))
+        .json(job)
+}
+
+/// List the jobs of the client
+#[get("/jobs")]
+pub async fn get_jobs_route(request: HttpRequest, jobs: web::Data<Jobs>) -> HttpResponse {
+    let owner = match identify(&request) {
+        Ok(owner) => owner,
+        Err(response) => return response,
+    };
+
+    HttpResponse::Ok().json(jobs.list(owner.as_deref()))
+}
+
+/// Get the status of a job, and its result once it ended
+#[get("/jobs/{id}")]
+pub async fn get_job_route(
+    request: HttpRequest,
+    id: web::Path<String>,
+    jobs: web::Data<Jobs>,
+) -> HttpResponse {
+    let owner = match identify(&request) {
+        Ok(owner) => owner,
+        Err(response) => return response,
+    };
+
+    match jobs.get(&id, owner.as_deref()) {
+        Some(job) => HttpResponse::Ok().json(job),
+        None => HttpResponse::NotFound().body("Job not found"),
+    }
+}
+
+/// Stream the events of a job until it ends, one JSON object per line
+#[get("/jobs/{id}/stream")]
+pub async fn get_job_stream_route(
+    request: HttpRequest,
+    id: web::Path<String>,
+    jobs: web::Data<Jobs>,
+) -> HttpResponse {
+    let owner = match identify(&request) {
+        Ok(owner) => owner,
+        Err(response) => return response,
+    };
+
+    let receiver = match jobs.follow(&id, owner.as_deref()) {
+        Some(receiver) => receiver,
+        None => return HttpResponse::NotFound().body("Job not found"),
+    };
+    let lines = jobs::events(receiver).map(|event| {
+        let mut line = serde_json::to_vec(&event)?;
+        line.push(b'\n');
+        Ok::<_, Box<dyn Error>>(Bytes::from(line))
+    });
+
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(lines)
+}
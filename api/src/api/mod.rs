@@ -1,19 +1,36 @@
+pub mod admin;
+pub mod jobs;
 pub mod service;
 
-use actix_web::{post, web, Responder};
+use actix_web::{get, http::header, middleware::from_fn, post, web, HttpRequest, HttpResponse};
 use log::{debug, error, info, trace, warn};
 
 use crate::{
-    api::service::{LambdoApiService, LambdoApiServiceTrait},
-    model::{RunRequest, RunResponse},
-    vm_manager::{self, grpc_definitions::ExecuteResponse},
+    api::service::LambdoApiServiceTrait,
+    audit::{self, AuditLog, AuditRecord},
+    auth::Grant,
+    metrics::Metrics,
+    model::{Execution, RunFormat, RunQuery, RunRequest, RunResponse},
+    scheduler::{Admission, Overloaded, Scheduler, Slot},
+    shutdown::Shutdown,
+    vm_manager::{self, grpc_definitions::ExecuteResponse, state::VMDirectory},
 };
-use std::error::Error;
+use std::{error::Error, sync::Arc, time::Instant};
+use tracing::{field, instrument, Span};
 
-async fn run_code(run_resquest: RunRequest, service: &dyn LambdoApiServiceTrait) -> RunResponse {
-    let response = service.run_code(run_resquest).await;
+/// Run a request and turn its result into the response of the API
+///
+/// # Returns
+///
+/// * `(RunResponse, Option<String>)` - The response and the ID of the VM that ran the code, if any
+async fn run_code(
+    execution: Execution,
+    service: &dyn LambdoApiServiceTrait,
+) -> (RunResponse, Option<String>) {
+    let response = service.run_code(execution).await;
 
-    match response {
+    let vm_id = response.as_ref().ok().map(|response| response.id.clone());
+    let response = match response {
         Ok(response) => {
             info!("Execution ended for {:?}", response.id);
             trace!("Response: {:?}", response);
@@ -26,6 +43,9 @@ async fn run_code(run_resquest: RunRequest, service: &dyn LambdoApiServiceTrait)
                     status: 128,
                     stdout: "".to_string(),
                     stderr: "Timeout".to_string(),
+                    combined: "Timeout".to_string(),
+                    artifacts: vec![],
+                    queued_ms: 0,
                 }
             }
             _ => {
@@ -34,27 +54,256 @@ async fn run_code(run_resquest: RunRequest, service: &dyn LambdoApiServiceTrait)
                     status: 1,
                     stdout: "".to_string(),
                     stderr: "Internal server error".to_string(),
+                    combined: "Internal server error".to_string(),
+                    artifacts: vec![],
+                    queued_ms: 0,
                 }
             }
         },
+    };
+
+    (response, vm_id)
+}
+
+/// Admit an execution, or refuse it
+///
+/// The refused executions do not count against the quota of their API key.
+///
+/// # Arguments
+///
+/// * `request` - The HTTP request asking for the execution
+/// * `run_request` - The code to run
+/// * `shutdown` - Tells whether the server is draining
+/// * `scheduler` - The queue of the executions
+/// * `service` - The service running the code, it knows the languages
+/// * `grant` - The API key of the client, if the API requires one
+///
+/// # Returns
+///
+/// * `Result<(Admission, Option<String>), HttpResponse>` - The admission and who asked for the execution, or the answer refusing it
+#[allow(clippy::result_large_err)]
+fn admit(
+    request: &HttpRequest,
+    run_request: &RunRequest,
+    shutdown: &Shutdown,
+    scheduler: &Scheduler,
+    service: &dyn LambdoApiServiceTrait,
+    grant: Option<&Grant>,
+) -> Result<(Admission, Option<String>), HttpResponse> {
+    // Only the admitted executions count against the quota of the key
+    let refund = || {
+        if let Some(grant) = grant {
+            grant.refund();
+        }
+    };
+
+    if shutdown.is_draining() {
+        warn!("Refusing execution request, the server is draining");
+        refund();
+        return Err(HttpResponse::ServiceUnavailable().body("The server is shutting down"));
+    }
+
+    if run_request.code.is_empty() {
+        warn!("Refusing execution request, it has no code");
+        refund();
+        return Err(HttpResponse::BadRequest().body("The request has no code to run"));
+    }
+
+    if !service
+        .languages()
+        .iter()
+        .any(|language| language.name == run_request.language)
+    {
+        warn!(
+            "Refusing execution request, unknown language {}",
+            run_request.language
+        );
+        refund();
+        return Err(HttpResponse::NotFound().body("Language not found"));
+    }
+
+    if let Some(grant) = grant {
+        if !grant.allows(&run_request.language) {
+            warn!(
+                "Refusing execution request, key {} cannot run {}",
+                grant.name(),
+                run_request.language
+            );
+            refund();
+            return Err(HttpResponse::Forbidden().body("The API key cannot run this language"));
+        }
+    }
+
+    let client = match grant {
+        Some(grant) => Some(grant.name().to_string()),
+        None => request.peer_addr().map(|address| address.ip().to_string()),
+    };
+    match scheduler.enqueue(&run_request.language, client.as_deref().unwrap_or_default()) {
+        Ok(admission) => Ok((admission, client)),
+        Err(Overloaded::QueueFull(retry_after)) => {
+            warn!("Refusing execution request, the queue is full");
+            refund();
+            Err(HttpResponse::TooManyRequests()
+                .insert_header((
+                    header::RETRY_AFTER,
+                    retry_after.as_secs().max(1).to_string(),
+                ))
+                .body("Too many executions waiting"))
+        }
     }
 }
 
-#[post("/run")]
+/// Run an execution in its slot and record it in the audit log
+///
+/// # Arguments
+///
+/// * `execution` - The execution to run
+/// * `slot` - The slot of the execution, released once it ended
+/// * `service` - The service running the code
+/// * `audit` - The audit log
+/// * `client` - Who asked for the execution
+async fn execute(
+    execution: Execution,
+    slot: Slot,
+    service: &dyn LambdoApiServiceTrait,
+    audit: &AuditLog,
+    client: Option<String>,
+) -> RunResponse {
+    let start = Instant::now();
+    let request_id = execution.id.clone();
+    let language = execution.request.language.clone();
+    let version = execution.request.version.clone();
+    let code_hash = audit::code_hash(&execution.request.code);
+
+    let (mut result, vm_id) = run_code(execution, service).await;
+    result.queued_ms = slot.queued().as_millis() as u64;
+    drop(slot);
+
+    audit.record(&AuditRecord {
+        timestamp: audit::now(),
+        request_id,
+        language,
+        version,
+        code_hash,
+        client,
+        vm_id,
+        status: result.status,
+        duration_ms: start.elapsed().as_millis() as u64,
+        truncated_artifacts: result
+            .artifacts
+            .iter()
+            .filter(|artifact| artifact.truncated)
+            .map(|artifact| artifact.path.clone())
+            .collect(),
+    });
+
+    result
+}
+
+#[post("/run", wrap = "from_fn(crate::auth::authenticate)")]
+#[instrument(
+    skip_all,
+    fields(id = field::Empty, language = %run_body.language, version = %run_body.version)
+)]
+#[allow(clippy::too_many_arguments)]
 pub async fn post_run_route(
+    request: HttpRequest,
     run_body: web::Json<RunRequest>,
-    api_service: web::Data<LambdoApiService>,
-) -> Result<impl Responder, Box<dyn Error>> {
+    run_query: web::Query<RunQuery>,
+    api_service: web::Data<dyn LambdoApiServiceTrait>,
+    audit: web::Data<AuditLog>,
+    shutdown: web::Data<Shutdown>,
+    scheduler: web::Data<Scheduler>,
+    grant: Option<web::ReqData<Arc<Grant>>>,
+) -> Result<HttpResponse, Box<dyn Error>> {
     debug!(
         "Received code execution request from http (language: {}, version: {})",
         run_body.language, run_body.version
     );
     trace!("Request body: {:?}", run_body);
 
-    let service = api_service.get_ref();
-    let result = run_code(run_body.into_inner(), service);
+    let grant = grant.map(|grant| grant.into_inner());
+    let (admission, client) = match admit(
+        &request,
+        &run_body,
+        &shutdown,
+        &scheduler,
+        api_service.get_ref(),
+        grant.as_deref(),
+    ) {
+        Ok(admitted) => admitted,
+        Err(response) => return Ok(response),
+    };
+
+    let mut execution = Execution::new(run_body.into_inner());
+    if let Some(grant) = &grant {
+        execution.limits = grant.limits();
+    }
+    let request_id = execution.id.clone();
+    Span::current().record("id", request_id.as_str());
+
+    let slot = admission.wait().await;
+    let result = execute(execution, slot, api_service.get_ref(), &audit, client).await;
+
+    match run_query.format {
+        RunFormat::Json => Ok(HttpResponse::Ok()
+            .insert_header(("X-Request-Id", request_id))
+            .json(result)),
+        RunFormat::Tar => Ok(HttpResponse::Ok()
+            .content_type("application/x-tar")
+            .insert_header(("X-Request-Id", request_id))
+            .insert_header(("X-Lambdo-Status", result.status.to_string()))
+            .insert_header(("X-Lambdo-Queued-Ms", result.queued_ms.to_string()))
+            .body(build_archive(&result)?)),
+    }
+}
+
+/// List the languages that can be run
+#[get("/languages")]
+pub async fn get_languages_route(
+    api_service: web::Data<dyn LambdoApiServiceTrait>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(api_service.languages())
+}
+
+/// Export the metrics in the Prometheus text format
+#[get("/metrics")]
+pub async fn get_metrics_route(
+    metrics: web::Data<Metrics>,
+    directory: web::Data<VMDirectory>,
+) -> HttpResponse {
+    metrics.observe_vms(&directory.summaries());
+
+    match metrics.encode() {
+        Ok(encoded) => HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(encoded),
+        Err(e) => {
+            error!("Error while encoding metrics: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Build a tar archive holding the outputs and the artifacts of an execution
+fn build_archive(response: &RunResponse) -> std::io::Result<Vec<u8>> {
+    let mut archive = tar::Builder::new(Vec::new());
+
+    let mut append = |path: &str, content: &[u8]| {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        archive.append_data(&mut header, path, content)
+    };
+
+    append("stdout", response.stdout.as_bytes())?;
+    append("stderr", response.stderr.as_bytes())?;
+    for artifact in response.artifacts.iter().filter(|a| !a.truncated) {
+        append(&format!("artifacts/{}", artifact.path), &artifact.content)?;
+    }
 
-    Ok(web::Json(result.await))
+    archive.into_inner()
 }
 
 fn parse_response(response: ExecuteResponse) -> RunResponse {
@@ -63,16 +312,21 @@ fn parse_response(response: ExecuteResponse) -> RunResponse {
             status: 1,
             stdout: "".to_string(),
             stderr: "Nothing was run".to_string(),
+            combined: "Nothing was run".to_string(),
+            artifacts: vec![],
+            queued_ms: 0,
         };
     }
 
     let mut stdout = String::new();
     let mut stderr = String::new();
+    let mut combined = String::new();
     for step in response.steps.as_slice() {
         if !step.stdout.is_empty() {
             stdout.push_str(step.stdout.as_str());
         }
         stderr.push_str(step.stderr.as_str());
+        combined.push_str(step.combined.as_str());
     }
 
     RunResponse {
@@ -82,6 +336,9 @@ fn parse_response(response: ExecuteResponse) -> RunResponse {
             .unwrap_or(1),
         stdout,
         stderr,
+        combined,
+        artifacts: response.artifacts.into_iter().map(Into::into).collect(),
+        queued_ms: 0,
     }
 }
 
@@ -89,13 +346,43 @@ fn parse_response(response: ExecuteResponse) -> RunResponse {
 mod test {
     use std::vec;
 
+    use std::io::Read;
+    use std::sync::Arc;
+
+    use actix_web::{http::StatusCode, test::TestRequest, web, App, HttpServer};
+    use lambdo_client::{Client, ClientError};
+
     use crate::{
-        api::{parse_response, run_code},
-        model::RunRequest,
-        vm_manager::grpc_definitions::{ExecuteResponse, ExecuteResponseStep, FileModel},
+        api::{
+            build_archive, get_languages_route,
+            jobs::{get_job_route, get_job_stream_route, get_jobs_route, post_job_route},
+            parse_response, post_run_route, run_code,
+        },
+        audit::AuditLog,
+        jobs::Jobs,
+        model::{
+            CodeFile, Execution, ExecutionLimits, JobEvent, JobStatus, LanguageSummary, RunRequest,
+        },
+        scheduler::Scheduler,
+        shutdown::Shutdown,
+        vm_manager::grpc_definitions::{Artifact, ExecuteResponse, ExecuteResponseStep},
     };
 
-    use super::service::MockLambdoApiServiceTrait;
+    use super::service::{LambdoApiServiceTrait, MockLambdoApiServiceTrait};
+
+    fn generate_run_request(code: Vec<CodeFile>) -> RunRequest {
+        RunRequest {
+            language: "NODE".to_string(),
+            version: "12".to_string(),
+            input: "".to_string(),
+            code,
+            artifacts: vec![],
+        }
+    }
+
+    fn service_data(service: MockLambdoApiServiceTrait) -> web::Data<dyn LambdoApiServiceTrait> {
+        web::Data::from(Arc::new(service) as Arc<dyn LambdoApiServiceTrait>)
+    }
 
     #[test]
     fn test_parse_response_stdout() {
@@ -106,15 +393,19 @@ mod test {
                     command: "echo Hello".to_string(),
                     stdout: "Hello".to_string(),
                     stderr: "".to_string(),
+                    combined: "Hello".to_string(),
                     exit_code: 0,
                 },
                 ExecuteResponseStep {
                     command: "echo World".to_string(),
                     stdout: "World".to_string(),
                     stderr: "".to_string(),
+                    combined: "World".to_string(),
                     exit_code: 0,
                 },
             ],
+            artifacts: vec![],
+            ..Default::default()
         };
 
         let parsed = parse_response(response);
@@ -133,24 +424,89 @@ mod test {
                     command: "echo Hello".to_string(),
                     stdout: "Hello".to_string(),
                     stderr: "".to_string(),
+                    combined: "Hello".to_string(),
                     exit_code: 0,
                 },
                 ExecuteResponseStep {
                     command: "echo World".to_string(),
                     stdout: "".to_string(),
                     stderr: "Error".to_string(),
+                    combined: "Error".to_string(),
                     exit_code: 1,
                 },
             ],
+            artifacts: vec![],
+            ..Default::default()
         };
 
         let parsed = parse_response(response);
 
         assert_eq!(parsed.stdout, "Hello");
         assert_eq!(parsed.stderr, "Error");
+        assert_eq!(parsed.combined, "HelloError");
         assert_eq!(parsed.status, 1);
     }
 
+    #[test]
+    fn test_parse_response_artifacts() {
+        let response = ExecuteResponse {
+            id: "test".to_string(),
+            steps: vec![ExecuteResponseStep {
+                command: "gcc main.c".to_string(),
+                stdout: "".to_string(),
+                stderr: "".to_string(),
+                combined: "".to_string(),
+                exit_code: 0,
+            }],
+            artifacts: vec![
+                Artifact {
+                    path: "a.out".to_string(),
+                    content: vec![0x7f, b'E', b'L', b'F'],
+                    size: 4,
+                    truncated: false,
+                },
+                Artifact {
+                    path: "core".to_string(),
+                    content: vec![],
+                    size: 1 << 30,
+                    truncated: true,
+                },
+            ],
+            ..Default::default()
+        };
+
+        let parsed = parse_response(response);
+        assert_eq!(parsed.artifacts.len(), 2);
+
+        let json = serde_json::to_value(&parsed).unwrap();
+        assert_eq!(json["artifacts"][0]["path"], "a.out");
+        assert_eq!(json["artifacts"][0]["content"], "f0VMRg==");
+        assert_eq!(json["artifacts"][1]["truncated"], true);
+
+        let archive = build_archive(&parsed).unwrap();
+        let mut archive = tar::Archive::new(archive.as_slice());
+        let mut entries = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut content = Vec::new();
+                entry.read_to_end(&mut content).unwrap();
+                (entry.path().unwrap().to_string_lossy().to_string(), content)
+            })
+            .collect::<Vec<_>>();
+        entries.sort();
+
+        assert_eq!(
+            entries,
+            vec![
+                ("artifacts/a.out".to_string(), vec![0x7f, b'E', b'L', b'F']),
+                ("stderr".to_string(), vec![]),
+                ("stdout".to_string(), vec![]),
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn test_run_code_with_no_steps() {
         let mut mock_service = MockLambdoApiServiceTrait::new();
@@ -158,17 +514,20 @@ mod test {
             Ok(ExecuteResponse {
                 id: "test".to_string(),
                 steps: vec![],
+                artifacts: vec![],
+                ..Default::default()
             })
         });
 
-        let run_request = RunRequest {
-            language: "Node".to_string(),
-            version: "1".to_string(),
-            code: vec![],
-            input: "".to_string(),
+        let execution = Execution {
+            id: "request".to_string(),
+            limits: ExecutionLimits::default(),
+            request: generate_run_request(vec![]),
+            output: None,
         };
 
-        let response = run_code(run_request, &mock_service).await;
+        let (response, vm_id) = run_code(execution, &mock_service).await;
+        assert_eq!(vm_id.as_deref(), Some("test"));
         assert_eq!(response.status, 1);
         assert_eq!(response.stdout, "");
         assert_eq!(response.stderr, "Nothing was run");
@@ -185,31 +544,261 @@ mod test {
                         command: "echo Hello".to_string(),
                         stdout: "Hello".to_string(),
                         stderr: "".to_string(),
+                        combined: "Hello".to_string(),
                         exit_code: 0,
                     },
                     ExecuteResponseStep {
                         command: "echo World".to_string(),
                         stdout: "World".to_string(),
                         stderr: "".to_string(),
+                        combined: "World".to_string(),
                         exit_code: 0,
                     },
                 ],
+                artifacts: vec![],
+                ..Default::default()
             })
         });
 
-        let run_request = RunRequest {
-            language: "Node".to_string(),
-            version: "1".to_string(),
-            code: vec![FileModel {
-                filename: "test.js".to_string(),
-                content: "console.log('Hello World')".to_string(),
-            }],
-            input: "test.js".to_string(),
-        };
+        let execution = Execution::new(generate_run_request(vec![CodeFile {
+            filename: "test.js".to_string(),
+            content: "console.log('Hello World')".to_string(),
+        }]));
 
-        let response = run_code(run_request, &mock_service).await;
+        let (response, _) = run_code(execution, &mock_service).await;
         assert_eq!(response.status, 0);
         assert_eq!(response.stdout, "HelloWorld");
         assert_eq!(response.stderr, "");
     }
+
+    #[actix_web::test]
+    async fn test_run_refused_while_draining() {
+        let mut api_service = MockLambdoApiServiceTrait::new();
+        api_service.expect_run_code().never();
+
+        let shutdown = Shutdown::default();
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(service_data(api_service))
+                .app_data(web::Data::new(shutdown.clone()))
+                .app_data(web::Data::new(AuditLog::disabled()))
+                .app_data(web::Data::new(Scheduler::new(1, 1)))
+                .app_data(web::Data::new(Jobs::default()))
+                .service(post_run_route)
+                .service(post_job_route),
+        )
+        .await;
+
+        shutdown.start_draining();
+        for uri in ["/run", "/jobs"] {
+            let request = TestRequest::post()
+                .uri(uri)
+                .set_json(serde_json::json!({
+                    "language": "NODE",
+                    "version": "1.0",
+                    "input": "",
+                    "code": [{"filename": "main.js", "content": "console.log('Hello')"}]
+                }))
+                .to_request();
+            let response = actix_web::test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_run_refuses_bad_requests() {
+        let mut api_service = MockLambdoApiServiceTrait::new();
+        api_service.expect_run_code().never();
+        api_service.expect_languages().returning(|| {
+            vec![LanguageSummary {
+                name: "NODE".to_string(),
+                version: "12".to_string(),
+            }]
+        });
+
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(service_data(api_service))
+                .app_data(web::Data::new(Shutdown::default()))
+                .app_data(web::Data::new(AuditLog::disabled()))
+                .app_data(web::Data::new(Scheduler::new(1, 1)))
+                .app_data(web::Data::new(Jobs::default()))
+                .service(post_run_route)
+                .service(post_job_route),
+        )
+        .await;
+
+        for uri in ["/run", "/jobs"] {
+            let request = TestRequest::post()
+                .uri(uri)
+                .set_json(generate_run_request(vec![]))
+                .to_request();
+            let response = actix_web::test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+            let mut run_request = generate_run_request(vec![CodeFile {
+                filename: "main.cob".to_string(),
+                content: "".to_string(),
+            }]);
+            run_request.language = "COBOL".to_string();
+            let request = TestRequest::post()
+                .uri(uri)
+                .set_json(run_request)
+                .to_request();
+            let response = actix_web::test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_get_languages() {
+        let mut api_service = MockLambdoApiServiceTrait::new();
+        api_service.expect_languages().once().returning(|| {
+            vec![LanguageSummary {
+                name: "NODE".to_string(),
+                version: "12".to_string(),
+            }]
+        });
+
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(service_data(api_service))
+                .service(get_languages_route),
+        )
+        .await;
+
+        let request = TestRequest::get().uri("/languages").to_request();
+        let languages: serde_json::Value =
+            actix_web::test::call_and_read_body_json(&app, request).await;
+        assert_eq!(
+            languages,
+            serde_json::json!([{"name": "NODE", "version": "12"}])
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_client_against_api() {
+        let mut api_service = MockLambdoApiServiceTrait::new();
+        api_service.expect_languages().returning(|| {
+            vec![LanguageSummary {
+                name: "NODE".to_string(),
+                version: "12".to_string(),
+            }]
+        });
+        api_service
+            .expect_run_code()
+            .times(2)
+            .returning(|execution| {
+                assert_eq!(execution.request.code[0].filename, "main.js");
+                assert_eq!(execution.request.artifacts, ["out.txt"]);
+                Ok(ExecuteResponse {
+                    id: "vm".to_string(),
+                    steps: vec![ExecuteResponseStep {
+                        command: "/usr/local/bin/node main.js".to_string(),
+                        stdout: "Hello".to_string(),
+                        stderr: "".to_string(),
+                        combined: "Hello".to_string(),
+                        exit_code: 0,
+                    }],
+                    artifacts: vec![Artifact {
+                        path: "out.txt".to_string(),
+                        content: b"done".to_vec(),
+                        size: 4,
+                        truncated: false,
+                    }],
+                    ..Default::default()
+                })
+            });
+        let api_service = service_data(api_service);
+
+        let shutdown = Shutdown::default();
+        let app_shutdown = web::Data::new(shutdown.clone());
+        let audit = web::Data::new(AuditLog::disabled());
+        let scheduler = web::Data::new(Scheduler::new(1, 1));
+        let jobs = web::Data::new(Jobs::default());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(api_service.clone())
+                .app_data(app_shutdown.clone())
+                .app_data(audit.clone())
+                .app_data(scheduler.clone())
+                .app_data(jobs.clone())
+                .service(post_run_route)
+                .service(post_job_route)
+                .service(get_jobs_route)
+                .service(get_job_route)
+                .service(get_job_stream_route)
+                .service(get_languages_route)
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let address = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let client = Client::new(&format!("http://{}", address)).with_retries(0);
+        let languages = client.languages().await.unwrap();
+        assert_eq!(
+            languages,
+            vec![LanguageSummary {
+                name: "NODE".to_string(),
+                version: "12".to_string(),
+            }]
+        );
+
+        let mut request = generate_run_request(vec![CodeFile {
+            filename: "main.js".to_string(),
+            content: "console.log('Hello')".to_string(),
+        }]);
+        request.artifacts = vec!["out.txt".to_string()];
+        let (response, request_id) = client.run(&request).await.unwrap();
+        assert!(request_id.is_some());
+        assert_eq!(response.status, 0);
+        assert_eq!(response.stdout, "Hello");
+        assert_eq!(response.artifacts[0].content, b"done");
+
+        let job = client.submit_job(&request).await.unwrap();
+        let mut stream = client.stream(&job.id).await.unwrap();
+        let mut events = Vec::new();
+        while let Some(event) = stream.next().await.unwrap() {
+            events.push(event);
+        }
+        assert_eq!(
+            events.last(),
+            Some(&JobEvent::Ended {
+                status: 0,
+                queued_ms: 0,
+            })
+        );
+        assert!(events.contains(&JobEvent::Output {
+            stdout: "Hello".to_string(),
+            stderr: "".to_string(),
+        }));
+
+        let ended = client.job(&job.id).await.unwrap();
+        assert_eq!(ended.status, JobStatus::Ended);
+        assert_eq!(
+            ended.response.unwrap().artifacts[0].content,
+            b"done".to_vec()
+        );
+        assert_eq!(
+            client.jobs().await.unwrap(),
+            vec![client.job(&job.id).await.unwrap()]
+        );
+        assert!(matches!(
+            client.job("unknown").await,
+            Err(ClientError::NotFound(_))
+        ));
+
+        shutdown.start_draining();
+        let refused = client.run(&request).await;
+        assert!(matches!(refused, Err(ClientError::Unavailable(_))));
+        let refused = client.submit_job(&request).await;
+        assert!(matches!(refused, Err(ClientError::Unavailable(_))));
+
+        handle.stop(true).await;
+    }
 }
@@ -1,3 +1,5 @@
+use std::sync::{RwLock, RwLockReadGuard};
+
 use crate::{
     config::{LambdoConfig, LambdoLanguageConfig},
     vm_manager::{
@@ -6,20 +8,24 @@ use crate::{
     },
     vm_manager::{state::LambdoStateRef, Error, VMManager},
 };
-use log::{debug, trace};
+use log::{debug, trace, warn};
 use mockall::automock;
-use uuid::Uuid;
+use tracing::instrument;
 
-use crate::model::RunRequest;
+use crate::model::{Execution, LanguageSummary};
 
 #[automock]
 #[async_trait::async_trait]
 pub trait LambdoApiServiceTrait: Send + Sync {
-    async fn run_code(&self, request: RunRequest) -> Result<ExecuteResponse, Error>;
+    async fn run_code(&self, execution: Execution) -> Result<ExecuteResponse, Error>;
+
+    /// The languages that can be run
+    fn languages(&self) -> Vec<LanguageSummary>;
 }
 
 pub struct LambdoApiService {
-    pub config: LambdoConfig,
+    /// The config, its languages are replaced when it is reloaded
+    pub config: RwLock<LambdoConfig>,
     pub vm_manager: Box<dyn VMManagerTrait>,
 }
 
@@ -29,7 +35,7 @@ impl LambdoApiService {
         let vm_manager =
             VMManager::from_state(std::sync::Arc::new(tokio::sync::Mutex::new(state))).await?;
         Ok(LambdoApiService {
-            config,
+            config: RwLock::new(config),
             vm_manager: Box::new(vm_manager),
         })
     }
@@ -38,29 +44,70 @@ impl LambdoApiService {
         let config = state.lock().await.config.clone();
         let vm_manager = VMManager::from_state(state).await?;
         Ok(LambdoApiService {
-            config,
+            config: RwLock::new(config),
             vm_manager: Box::new(vm_manager),
         })
     }
 
-    fn find_language(
-        &self,
-        language: &String,
-    ) -> Result<LambdoLanguageConfig, Box<dyn std::error::Error>> {
-        let language_list = &self.config.languages;
+    /// The config in use
+    pub fn config(&self) -> RwLockReadGuard<'_, LambdoConfig> {
+        self.config.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Replace the languages with the ones of a new config
+    ///
+    /// The VMs of the added and changed languages are replaced, the other settings
+    /// need a restart of the API and are left as they are.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The new config, already validated
+    pub async fn reload(&self, config: LambdoConfig) -> Result<(), Error> {
+        {
+            let mut current = self.config.write().unwrap_or_else(|e| e.into_inner());
+            let unchanged = LambdoConfig {
+                languages: current.languages.clone(),
+                ..config.clone()
+            };
+            if unchanged != *current {
+                warn!("Only the languages are reloaded, restart lambdo to apply the other changes");
+            }
+            current.languages = config.languages.clone();
+        }
+
+        self.vm_manager.reload_languages(config.languages).await
+    }
+
+    fn find_language(&self, language: &String) -> Result<LambdoLanguageConfig, Error> {
+        let language_list = &self.config().languages;
         for lang in language_list {
             if &*lang.name == language {
                 return Ok(lang.clone());
             }
         }
-        Err("Language not found".into())
+        Err(Error::LanguageNotFound)
     }
 
     fn generate_steps(
         language_settings: &LambdoLanguageConfig,
         entrypoint: &str,
+        files: &[FileModel],
     ) -> Vec<ExecuteRequestStep> {
         let mut steps: Vec<ExecuteRequestStep> = Vec::new();
+
+        // Install the dependencies first if the code comes with a manifest
+        if let Some(dependencies) = &language_settings.dependencies {
+            if files
+                .iter()
+                .any(|file| dependencies.manifests.contains(&file.filename))
+            {
+                steps.push(ExecuteRequestStep {
+                    command: dependencies.install_command(),
+                    enable_output: false,
+                });
+            }
+        }
+
         for step in &language_settings.steps {
             let command = step.command.replace("{{filename}}", entrypoint);
 
@@ -75,42 +122,79 @@ impl LambdoApiService {
 
 #[async_trait::async_trait]
 impl LambdoApiServiceTrait for LambdoApiService {
-    async fn run_code(&self, request: RunRequest) -> Result<ExecuteResponse, Error> {
-        let entrypoint = request.code[0].filename.clone();
-
-        let language_settings = self.find_language(&request.language).unwrap();
-        let steps = Self::generate_steps(&language_settings, &entrypoint);
-        let file = FileModel {
-            filename: entrypoint.to_string(),
-            content: request.code[0].content.clone(),
+    #[instrument(
+        skip_all,
+        fields(language = %execution.request.language, version = %execution.request.version)
+    )]
+    async fn run_code(&self, execution: Execution) -> Result<ExecuteResponse, Error> {
+        let request = execution.request;
+        let entrypoint = match request.code.first() {
+            Some(file) => file.filename.clone(),
+            None => return Err(Error::NoCode),
         };
+
+        let language_settings = self.find_language(&request.language)?;
+        let mut files = request
+            .code
+            .into_iter()
+            .map(FileModel::from)
+            .collect::<Vec<_>>();
+        let steps = Self::generate_steps(&language_settings, &entrypoint, &files);
         let input_filename = "input.input";
 
         let input = FileModel {
             filename: input_filename.to_string(),
-            content: request.input.clone(),
+            content: request.input,
         };
 
+        files.push(input);
+
+        let mut artifacts = language_settings.artifacts.clone();
+        for pattern in &request.artifacts {
+            if !artifacts.contains(pattern) {
+                artifacts.push(pattern.clone());
+            }
+        }
+
         let request_data = ExecuteRequest {
-            id: Uuid::new_v4().to_string(),
+            id: execution.id,
             steps,
-            files: vec![file, input],
+            files,
+            artifacts,
+            artifacts_max_size: language_settings.artifacts_max_size,
+            ..Default::default()
         };
         trace!("Request message to VMM: {:?}", request_data);
 
         let response = self
             .vm_manager
-            .run_code(request_data, language_settings.into())
+            .run_code(
+                request_data,
+                language_settings.into(),
+                execution.limits,
+                execution.output,
+            )
             .await;
         debug!("Response from VMM: {:?}", response);
 
         response
     }
+
+    fn languages(&self) -> Vec<LanguageSummary> {
+        self.config()
+            .languages
+            .iter()
+            .map(LanguageSummary::from)
+            .collect()
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use std::sync::Arc;
+    use std::{
+        sync::{Arc, RwLock},
+        time::Duration,
+    };
 
     use mockall::predicate;
     use tokio::sync::Mutex;
@@ -120,30 +204,43 @@ mod test {
         api::service::LambdoApiServiceTrait,
         config::{
             LambdoAgentConfig, LambdoApiConfig, LambdoConfig, LambdoLanguageConfig,
-            LambdoLanguageStepConfig, LambdoLanguageStepOutputConfig, LambdoVMMConfig,
+            LambdoLanguageDependenciesConfig, LambdoLanguageStepConfig,
+            LambdoLanguageStepOutputConfig, LambdoLoggingConfig, LambdoNetworkPolicy,
+            LambdoTracingConfig, LambdoVMMConfig, LambdoVMMTransport,
         },
-        model::{LanguageSettings, RunRequest},
+        model::{CodeFile, Execution, ExecutionLimits, LanguageSettings, RunRequest},
         vm_manager::{
             grpc_definitions::{ExecuteRequest, ExecuteResponse, ExecuteResponseStep, FileModel},
-            state::LambdoState,
-            MockVMManagerTrait, VMManager,
+            state::{LambdoState, OutputSender},
+            Error, MockVMManagerTrait, VMManager,
         },
     };
 
     fn generate_lambdo_test_config() -> LambdoConfig {
         LambdoConfig {
-            apiVersion: "lambdo.io/v1alpha1".to_string(),
+            apiVersion: "lambdo.io/v1beta1".to_string(),
             kind: "Config".to_string(),
             api: LambdoApiConfig {
                 web_host: "0.0.0.0".to_string(),
                 web_port: 3000,
                 grpc_host: "0.0.0.0".to_string(),
-                gprc_port: 50051,
+                grpc_port: 50051,
                 bridge: "lambdo0".to_string(),
                 bridge_address: "0.0.0.0".to_string(),
+                ip_range: None,
+                bridge_address_v6: None,
+                ip_range_v6: None,
+                state_file: "/var/lib/lambdo/state.json".to_string(),
+                drain_timeout: 30,
+                admin_token: None,
+                max_running: 16,
+                max_queued: 64,
+                firewall: true,
+                watch_config: true,
             },
             vmm: LambdoVMMConfig {
                 kernel: "/var/lib/lambdo/kernel/vmlinux.bin".to_string(),
+                transport: LambdoVMMTransport::Grpc,
             },
             agent: LambdoAgentConfig {
                 path: "/usr/local/bin/lambdo-agent".to_string(),
@@ -180,6 +277,16 @@ mod test {
                             },
                         },
                     ],
+                    artifacts: vec!["*.png".to_string()],
+                    artifacts_max_size: 1024,
+                    dependencies: Some(LambdoLanguageDependenciesConfig {
+                        manifests: vec!["package.json".to_string()],
+                        command: "npm install --offline --cache {{cache}}".to_string(),
+                        cache: "/var/cache/lambdo/npm".to_string(),
+                        mirror: None,
+                        cache_image: None,
+                    }),
+                    network: LambdoNetworkPolicy::HostOnly,
                 },
                 LambdoLanguageConfig {
                     name: "PYTHON".to_string(),
@@ -193,8 +300,17 @@ mod test {
                             debug: false,
                         },
                     }],
+                    artifacts: vec![],
+                    artifacts_max_size: 1024,
+                    dependencies: None,
+                    network: LambdoNetworkPolicy::None,
                 },
             ],
+            languages_dir: None,
+            tracing: LambdoTracingConfig::default(),
+            logging: LambdoLoggingConfig::default(),
+            auth: None,
+            language_files: Vec::new(),
         }
     }
 
@@ -205,6 +321,10 @@ mod test {
             version: "1.0".to_string(),
             initramfs: "test".to_string(),
             steps: generate_lambdo_test_config().languages[0].steps.clone(),
+            artifacts: vec![],
+            artifacts_max_size: 1024,
+            dependencies: None,
+            network: LambdoNetworkPolicy::None,
         };
         let entrypoint = "index.js";
 
@@ -214,7 +334,7 @@ mod test {
             "cat index.js > index.js".to_string(),
         ];
 
-        let steps = LambdoApiService::generate_steps(&language_settings, &entrypoint);
+        let steps = LambdoApiService::generate_steps(&language_settings, &entrypoint, &[]);
 
         assert_eq!(steps.len(), 3);
         for (i, step) in steps.iter().enumerate() {
@@ -222,11 +342,40 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_generate_steps_with_dependencies() {
+        let language_settings = generate_lambdo_test_config().languages[0].clone();
+        let files = vec![
+            FileModel {
+                filename: "index.js".to_string(),
+                content: "require('left-pad')".to_string(),
+            },
+            FileModel {
+                filename: "package.json".to_string(),
+                content: "{}".to_string(),
+            },
+        ];
+
+        let steps = LambdoApiService::generate_steps(&language_settings, "index.js", &files);
+
+        assert_eq!(steps.len(), 4);
+        assert_eq!(
+            steps[0].command,
+            "npm install --offline --cache /var/cache/lambdo/npm"
+        );
+        assert!(!steps[0].enable_output);
+        assert_eq!(steps[1].command, "echo index.js");
+
+        // Without a manifest, nothing is installed
+        let steps = LambdoApiService::generate_steps(&language_settings, "index.js", &files[..1]);
+        assert_eq!(steps.len(), 3);
+    }
+
     #[test]
     fn test_find_language() {
         let config = generate_lambdo_test_config();
         let service = LambdoApiService {
-            config: config.clone(),
+            config: RwLock::new(config.clone()),
             vm_manager: Box::new(VMManager {
                 state: Arc::new(Mutex::new(LambdoState::new(config))),
             }),
@@ -239,22 +388,52 @@ mod test {
         assert_eq!(language_settings.steps[0].name, Some("step 1".to_string()));
     }
 
+    #[test]
+    fn test_languages() {
+        let service = LambdoApiService {
+            config: RwLock::new(generate_lambdo_test_config()),
+            vm_manager: Box::new(MockVMManagerTrait::new()),
+        };
+
+        let languages = service
+            .languages()
+            .into_iter()
+            .map(|language| (language.name, language.version))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            languages,
+            vec![
+                ("NODE".to_string(), "1.0".to_string()),
+                ("PYTHON".to_string(), "3.0".to_string())
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn test_run_code() {
         let config = generate_lambdo_test_config();
 
         let language = "NODE".to_string();
-        let code = vec![FileModel {
+        let code = vec![CodeFile {
             filename: "index.js".to_string(),
             content: "console.log('hello world')".to_string(),
         }];
         let input = "hello".to_string();
 
-        let request = RunRequest {
-            version: "1.0".to_string(),
-            language: language.clone(),
-            code,
-            input,
+        let execution = Execution {
+            id: "request".to_string(),
+            limits: ExecutionLimits {
+                timeout: Duration::from_secs(5),
+                memory: 512,
+            },
+            request: RunRequest {
+                version: "1.0".to_string(),
+                language: language.clone(),
+                code,
+                input,
+                artifacts: vec!["report/*.xml".to_string(), "*.png".to_string()],
+            },
+            output: None,
         };
 
         let expected_language_settings = config.languages[0].clone();
@@ -267,21 +446,26 @@ mod test {
                     command: "echo index.js".to_string(),
                     stdout: "index.js\n".to_string(),
                     stderr: "".to_string(),
+                    combined: "index.js\n".to_string(),
                     exit_code: 0,
                 },
                 ExecuteResponseStep {
                     command: "echo hello".to_string(),
                     stdout: "hello\n".to_string(),
                     stderr: "".to_string(),
+                    combined: "hello\n".to_string(),
                     exit_code: 0,
                 },
                 ExecuteResponseStep {
                     command: "cat index.js > index.js".to_string(),
                     stdout: "".to_string(),
                     stderr: "".to_string(),
+                    combined: "".to_string(),
                     exit_code: 0,
                 },
             ],
+            artifacts: vec![],
+            ..Default::default()
         };
 
         let response = expected_response.clone();
@@ -290,22 +474,96 @@ mod test {
             .expect_run_code()
             .with(
                 predicate::function(|req: &ExecuteRequest| {
-                    req.files[0].filename == "index.js" && req.steps[0].command == "echo index.js"
+                    req.id == "request"
+                        && req.files[0].filename == "index.js"
+                        && req.steps[0].command == "echo index.js"
+                        && req.artifacts == ["*.png", "report/*.xml"]
+                        && req.artifacts_max_size == 1024
                 }),
                 predicate::function(move |lang: &LanguageSettings| {
                     lang.name == language && lang.version == expected_language_settings.version
                 }),
+                predicate::eq(ExecutionLimits {
+                    timeout: Duration::from_secs(5),
+                    memory: 512,
+                }),
+                predicate::function(|output: &Option<OutputSender>| output.is_none()),
             )
             .times(1)
-            .returning(move |_, _| Ok(response.clone()));
+            .returning(move |_, _, _, _| Ok(response.clone()));
 
         let service = LambdoApiService {
-            config: config.clone(),
+            config: RwLock::new(config.clone()),
             vm_manager: Box::new(mock_vm_manager),
         };
 
-        let response = service.run_code(request).await.unwrap();
+        let response = service.run_code(execution).await.unwrap();
 
         assert_eq!(response, expected_response);
     }
+
+    #[tokio::test]
+    async fn test_run_code_refuses_bad_requests() {
+        let mut mock_vm_manager = MockVMManagerTrait::new();
+        mock_vm_manager.expect_run_code().never();
+        let service = LambdoApiService {
+            config: RwLock::new(generate_lambdo_test_config()),
+            vm_manager: Box::new(mock_vm_manager),
+        };
+        let request = RunRequest {
+            version: "1.0".to_string(),
+            language: "NODE".to_string(),
+            code: vec![],
+            input: "".to_string(),
+            artifacts: vec![],
+        };
+
+        let error = service
+            .run_code(Execution::new(request.clone()))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::NoCode));
+
+        let error = service
+            .run_code(Execution::new(RunRequest {
+                language: "COBOL".to_string(),
+                code: vec![CodeFile {
+                    filename: "main.cob".to_string(),
+                    content: "".to_string(),
+                }],
+                ..request
+            }))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::LanguageNotFound));
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let config = generate_lambdo_test_config();
+        let mut new_config = config.clone();
+        new_config.languages[0].initramfs = "/var/lib/lambdo/initramfs/node-14.img".to_string();
+        new_config.languages.remove(1);
+        new_config.api.web_port = 8080;
+
+        let mut mock_vm_manager = MockVMManagerTrait::new();
+        let languages = new_config.languages.clone();
+        mock_vm_manager
+            .expect_reload_languages()
+            .with(predicate::eq(languages))
+            .times(1)
+            .returning(|_| Ok(()));
+        let service = LambdoApiService {
+            config: RwLock::new(config),
+            vm_manager: Box::new(mock_vm_manager),
+        };
+
+        service.reload(new_config).await.unwrap();
+
+        let language = service.find_language(&"NODE".to_string()).unwrap();
+        assert_eq!(language.initramfs, "/var/lib/lambdo/initramfs/node-14.img");
+        assert!(service.find_language(&"PYTHON".to_string()).is_err());
+        // Only the languages are reloaded
+        assert_eq!(service.config().api.web_port, 3000);
+    }
 }
@@ -0,0 +1,267 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{config::LambdoAuditConfig, model::CodeFile};
+
+/// An execution, as recorded in the audit log
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    /// When the execution ended, in milliseconds since the epoch
+    pub timestamp: u64,
+    pub request_id: String,
+    pub language: String,
+    pub version: String,
+    /// The SHA-256 of the code files, see [`code_hash`]
+    pub code_hash: String,
+    /// Who asked for the execution
+    pub client: Option<String>,
+    /// The VM that ran the code, unknown if the execution failed
+    pub vm_id: Option<String>,
+    /// The exit status given back to the client
+    pub status: u8,
+    pub duration_ms: u64,
+    /// The artifacts returned without their content because of the size limit
+    pub truncated_artifacts: Vec<String>,
+}
+
+/// The append-only log of the executions, one JSON record per line
+pub struct AuditLog {
+    file: Option<Mutex<RotatingFile>>,
+}
+
+impl AuditLog {
+    /// Open the audit log, nothing is recorded if it is not configured
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The audit log configuration
+    pub fn new(config: Option<&LambdoAuditConfig>) -> Result<Self> {
+        let file = match config {
+            Some(config) => Some(Mutex::new(RotatingFile::open(
+                Path::new(&config.path),
+                config.max_size,
+                config.max_files,
+            )?)),
+            None => None,
+        };
+
+        Ok(AuditLog { file })
+    }
+
+    pub fn disabled() -> Self {
+        AuditLog { file: None }
+    }
+
+    /// Append a record, a failure is logged but does not fail the execution
+    pub fn record(&self, record: &AuditRecord) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize audit record: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        debug!("Recording execution {} in the audit log", record.request_id);
+        let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.append(&line) {
+            error!("Failed to write audit record: {}", e);
+        }
+    }
+}
+
+/// Hash the code of a request, the same code always gives the same hash
+///
+/// # Arguments
+///
+/// * `files` - The code files of the request
+///
+/// # Returns
+///
+/// * `String` - The hex encoded SHA-256 of the file names and contents
+pub fn code_hash(files: &[CodeFile]) -> String {
+    let mut hasher = Sha256::new();
+    for file in files {
+        hasher.update(file.filename.as_bytes());
+        hasher.update([0]);
+        hasher.update(file.content.as_bytes());
+        hasher.update([0]);
+    }
+
+    format!("{:x}", hasher.finalize())
+}
+
+/// The current time, in milliseconds since the epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+/// A file moved aside once it reaches its maximum size
+///
+/// The rotated files are named after the current one with a suffix, `.1` being the most
+/// recent, the oldest ones are removed.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, max_files: usize) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| anyhow!("failed to create {}: {}", parent.display(), e))?;
+        }
+
+        let file = Self::open_file(path)
+            .map_err(|e| anyhow!("failed to open {}: {}", path.display(), e))?;
+        let size = file.metadata()?.len();
+
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn open_file(path: &Path) -> io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn append(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line)?;
+        self.file.flush()?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        debug!("Rotating {}", self.path.display());
+        for index in (1..self.max_files).rev() {
+            let rotated = self.rotated(index);
+            if rotated.exists() {
+                fs::rename(&rotated, self.rotated(index + 1))?;
+            }
+        }
+
+        if self.max_files > 0 {
+            fs::rename(&self.path, self.rotated(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+
+        self.file = Self::open_file(&self.path)?;
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn generate_record(request_id: &str) -> AuditRecord {
+        AuditRecord {
+            timestamp: 1700000000000,
+            request_id: request_id.to_string(),
+            language: "NODE".to_string(),
+            version: "12".to_string(),
+            code_hash: code_hash(&[CodeFile {
+                filename: "main.js".to_string(),
+                content: "console.log('Hello')".to_string(),
+            }]),
+            client: Some("127.0.0.1".to_string()),
+            vm_id: Some("vm".to_string()),
+            status: 0,
+            duration_ms: 300,
+            truncated_artifacts: vec![],
+        }
+    }
+
+    #[test]
+    fn test_audit_log_rotation() {
+        let directory = std::env::temp_dir().join(format!("lambdo-audit-{}", std::process::id()));
+        let path = directory.join("audit.log");
+        let record_size = serde_json::to_vec(&generate_record("0")).unwrap().len() as u64 + 1;
+        let audit = AuditLog::new(Some(&LambdoAuditConfig {
+            path: path.to_string_lossy().to_string(),
+            max_size: record_size * 2,
+            max_files: 2,
+        }))
+        .unwrap();
+
+        for id in 0..7 {
+            audit.record(&generate_record(&id.to_string()));
+        }
+
+        let read = |path: &Path| {
+            fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .map(|line| {
+                    serde_json::from_str::<AuditRecord>(line)
+                        .unwrap()
+                        .request_id
+                })
+                .collect::<Vec<_>>()
+        };
+        let current = read(&path);
+        let first = read(&directory.join("audit.log.1"));
+        let second = read(&directory.join("audit.log.2"));
+        let dropped = directory.join("audit.log.3").exists();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(current, vec!["6"]);
+        assert_eq!(first, vec!["4", "5"]);
+        assert_eq!(second, vec!["2", "3"]);
+        assert!(!dropped);
+    }
+
+    #[test]
+    fn test_code_hash() {
+        let files = |content: &str| {
+            vec![CodeFile {
+                filename: "main.js".to_string(),
+                content: content.to_string(),
+            }]
+        };
+
+        assert_eq!(code_hash(&files("a")), code_hash(&files("a")));
+        assert_ne!(code_hash(&files("a")), code_hash(&files("b")));
+        assert_eq!(code_hash(&files("a")).len(), 64);
+    }
+}
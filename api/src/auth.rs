@@ -0,0 +1,488 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{self, HeaderMap},
+    middleware::Next,
+    web, HttpMessage, HttpRequest, HttpResponse,
+};
+use anyhow::{anyhow, Result};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use log::{debug, warn};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::{LambdoApiKeyConfig, LambdoAuthConfig},
+    model::{ExecutionLimits, KeyUsage},
+};
+
+/// The header holding the API key of a request
+const API_KEY_HEADER: &str = "X-Api-Key";
+
+/// The number of seconds in a day, the quotas are reset at midnight UTC
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// The claims expected in a bearer JWT
+#[derive(Deserialize, Debug)]
+struct Claims {
+    /// The name of the API key the token stands for
+    sub: String,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    running: u32,
+    /// The day `today` counts the executions of, in days since the epoch
+    day: u64,
+    today: u64,
+    total: u64,
+}
+
+/// Why a request was refused
+#[derive(Debug, PartialEq)]
+pub enum Refusal {
+    /// The daily quota of the key is exhausted, until the given time
+    QuotaExceeded(Duration),
+    /// The key already runs as many executions as it is allowed to
+    TooManyRunning,
+}
+
+/// Checks the API keys of the requests and counts their usage
+pub struct Authenticator {
+    keys: Vec<Arc<LambdoApiKeyConfig>>,
+    jwt: Option<DecodingKey>,
+    usage: Arc<Mutex<HashMap<String, Counters>>>,
+}
+
+impl Authenticator {
+    /// Load the API keys, from the configuration and the keys file
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The authentication configuration
+    pub fn new(config: &LambdoAuthConfig) -> Result<Self> {
+        let mut keys = config.keys.clone();
+        if let Some(path) = &config.keys_file {
+            let file = std::fs::File::open(path)
+                .map_err(|e| anyhow!("failed to open keys file {}: {}", path, e))?;
+            let file_keys: Vec<LambdoApiKeyConfig> = serde_yaml::from_reader(file)
+                .map_err(|e| anyhow!("invalid keys file {}: {}", path, e))?;
+            keys.extend(file_keys);
+        }
+        debug!("Loaded {} API keys", keys.len());
+
+        Ok(Authenticator {
+            keys: keys.into_iter().map(Arc::new).collect(),
+            jwt: config
+                .jwt_secret
+                .as_ref()
+                .map(|secret| DecodingKey::from_secret(secret.as_bytes())),
+            usage: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Find the API key of a request
+    ///
+    /// The key is either sent in the `X-Api-Key` header, or as a bearer token which can
+    /// also be a JWT signed with the configured secret.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Arc<LambdoApiKeyConfig>>` - The key, `None` if the request has no valid one
+    pub fn authenticate(&self, headers: &HeaderMap) -> Option<Arc<LambdoApiKeyConfig>> {
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+
+        if let Some(key) = header(API_KEY_HEADER) {
+            return self.find_key(key);
+        }
+
+        let token = header(header::AUTHORIZATION.as_str())?.strip_prefix("Bearer ")?;
+        if let Some(key) = self.find_key(token) {
+            return Some(key);
+        }
+
+        let claims = jsonwebtoken::decode::<Claims>(
+            token,
+            self.jwt.as_ref()?,
+            &Validation::new(Algorithm::HS256),
+        )
+        .map_err(|e| debug!("Invalid bearer token: {}", e))
+        .ok()?
+        .claims;
+        self.find(|config| config.name == claims.sub)
+    }
+
+    fn find_key(&self, secret: &str) -> Option<Arc<LambdoApiKeyConfig>> {
+        self.find(|config| {
+            config
+                .key
+                .as_deref()
+                .is_some_and(|key| secrets_match(secret, key))
+        })
+    }
+
+    fn find(
+        &self,
+        predicate: impl Fn(&LambdoApiKeyConfig) -> bool,
+    ) -> Option<Arc<LambdoApiKeyConfig>> {
+        self.keys.iter().find(|key| predicate(key)).cloned()
+    }
+
+    /// Count a new execution of a key, if its quotas allow it
+    ///
+    /// The execution counts against the daily quota unless the grant is refunded.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Grant, Refusal>` - The grant, releasing the execution once dropped
+    pub fn acquire(&self, key: Arc<LambdoApiKeyConfig>) -> Result<Grant, Refusal> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        let mut usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        let counters = usage.entry(key.name.clone()).or_default();
+
+        if counters.day != now / SECONDS_PER_DAY {
+            counters.day = now / SECONDS_PER_DAY;
+            counters.today = 0;
+        }
+
+        if key.daily_quota.is_some_and(|quota| counters.today >= quota) {
+            let tomorrow = (counters.day + 1) * SECONDS_PER_DAY;
+            return Err(Refusal::QuotaExceeded(Duration::from_secs(tomorrow - now)));
+        }
+        if key
+            .max_concurrency
+            .is_some_and(|max| counters.running >= max)
+        {
+            return Err(Refusal::TooManyRunning);
+        }
+
+        counters.running += 1;
+        counters.today += 1;
+        counters.total += 1;
+
+        Ok(Grant {
+            key,
+            usage: self.usage.clone(),
+            refunded: AtomicBool::new(false),
+        })
+    }
+
+    /// The usage of every key, in the order of the configuration
+    pub fn usage(&self) -> Vec<KeyUsage> {
+        let usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        let today = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs())
+            / SECONDS_PER_DAY;
+
+        self.keys
+            .iter()
+            .map(|key| {
+                let counters = usage.get(&key.name);
+                KeyUsage {
+                    name: key.name.clone(),
+                    running: counters.map_or(0, |counters| counters.running),
+                    today: counters
+                        .filter(|counters| counters.day == today)
+                        .map_or(0, |counters| counters.today),
+                    total: counters.map_or(0, |counters| counters.total),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Compare a secret sent by a client with the expected one
+///
+/// Both are hashed first, so that the time taken depends neither on their
+/// length nor on where they differ.
+pub fn secrets_match(secret: &str, expected: &str) -> bool {
+    let secret = Sha256::digest(secret.as_bytes());
+    let expected = Sha256::digest(expected.as_bytes());
+
+    secret
+        .iter()
+        .zip(expected.iter())
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
+
+/// An execution allowed for an API key
+pub struct Grant {
+    key: Arc<LambdoApiKeyConfig>,
+    usage: Arc<Mutex<HashMap<String, Counters>>>,
+    refunded: AtomicBool,
+}
+
+impl Grant {
+    /// The name of the key
+    pub fn name(&self) -> &str {
+        &self.key.name
+    }
+
+    /// Whether the key can run code of a language
+    pub fn allows(&self, language: &str) -> bool {
+        self.key.languages.is_empty() || self.key.languages.iter().any(|name| name == language)
+    }
+
+    /// Take the execution back from the usage of the key, once the request is refused
+    pub fn refund(&self) {
+        if self.refunded.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let mut usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(counters) = usage.get_mut(&self.key.name) {
+            counters.today = counters.today.saturating_sub(1);
+            counters.total = counters.total.saturating_sub(1);
+        }
+    }
+
+    /// The limits of the executions of the key, the defaults can only be lowered
+    pub fn limits(&self) -> ExecutionLimits {
+        let defaults = ExecutionLimits::default();
+        ExecutionLimits {
+            timeout: self.key.max_timeout.map_or(defaults.timeout, |timeout| {
+                defaults.timeout.min(Duration::from_secs(timeout))
+            }),
+            memory: self
+                .key
+                .max_memory
+                .map_or(defaults.memory, |memory| defaults.memory.min(memory)),
+        }
+    }
+}
+
+impl Drop for Grant {
+    fn drop(&mut self) {
+        let mut usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(counters) = usage.get_mut(&self.key.name) {
+            counters.running = counters.running.saturating_sub(1);
+        }
+    }
+}
+
+/// The name of the API key of a request, without using its quota
+///
+/// # Returns
+///
+/// * `Result<Option<String>, HttpResponse>` - The name of the key if the API requires one, or the answer refusing the request
+#[allow(clippy::result_large_err)]
+pub fn identify(request: &HttpRequest) -> Result<Option<String>, HttpResponse> {
+    let authenticator = match request.app_data::<web::Data<Authenticator>>() {
+        Some(authenticator) => authenticator,
+        None => return Ok(None),
+    };
+
+    match authenticator.authenticate(request.headers()) {
+        Some(key) => Ok(Some(key.name.clone())),
+        None => {
+            warn!("Request without a valid API key on {}", request.path());
+            Err(HttpResponse::Unauthorized()
+                .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+                .finish())
+        }
+    }
+}
+
+/// Refuse the requests without a valid API key or over their quotas
+///
+/// The requests go through when no [`Authenticator`] is registered. Otherwise the
+/// [`Grant`] of the request is available to the handler as `web::ReqData<Arc<Grant>>`.
+pub async fn authenticate(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let authenticator = match request.app_data::<web::Data<Authenticator>>() {
+        Some(authenticator) => authenticator.clone(),
+        None => return Ok(next.call(request).await?.map_into_boxed_body()),
+    };
+
+    let key = match authenticator.authenticate(request.headers()) {
+        Some(key) => key,
+        None => {
+            warn!("Request without a valid API key on {}", request.path());
+            return Ok(request.into_response(
+                HttpResponse::Unauthorized()
+                    .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+                    .finish(),
+            ));
+        }
+    };
+
+    let grant = match authenticator.acquire(key.clone()) {
+        Ok(grant) => Arc::new(grant),
+        Err(refusal) => {
+            debug!("Request of key {} refused: {:?}", key.name, refusal);
+            let response = match refusal {
+                Refusal::QuotaExceeded(retry_after) => HttpResponse::TooManyRequests()
+                    .insert_header((header::RETRY_AFTER, retry_after.as_secs().to_string()))
+                    .body("Daily quota exceeded"),
+                Refusal::TooManyRunning => {
+                    HttpResponse::TooManyRequests().body("Too many executions running")
+                }
+            };
+            return Ok(request.into_response(response));
+        }
+    };
+
+    request.extensions_mut().insert(grant);
+    let response = next.call(request).await?;
+    // Release the execution now rather than when the request is recycled
+    response.request().extensions_mut().remove::<Arc<Grant>>();
+
+    Ok(response.map_into_boxed_body())
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::http::header::{self, HeaderMap, HeaderValue};
+    use jsonwebtoken::{EncodingKey, Header};
+
+    use super::*;
+
+    fn generate_auth_config() -> LambdoAuthConfig {
+        serde_yaml::from_str(
+            "
+keys:
+  - name: ci
+    key: ci-key
+    languages: [NODE]
+    max_concurrency: 1
+    max_timeout: 5
+    max_memory: 512
+  - name: nightly
+    daily_quota: 2
+jwt_secret: secret
+",
+        )
+        .unwrap()
+    }
+
+    fn headers(name: header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let authenticator = Authenticator::new(&generate_auth_config()).unwrap();
+        let token = |sub: &str, secret: &str| {
+            jsonwebtoken::encode(
+                &Header::default(),
+                &serde_json::json!({ "sub": sub, "exp": 4102444800_u64 }),
+                &EncodingKey::from_secret(secret.as_bytes()),
+            )
+            .unwrap()
+        };
+        let name = |headers: HeaderMap| {
+            authenticator
+                .authenticate(&headers)
+                .map(|key| key.name.clone())
+        };
+        let api_key = header::HeaderName::from_static("x-api-key");
+
+        assert_eq!(name(headers(api_key.clone(), "ci-key")), Some("ci".into()));
+        assert_eq!(name(headers(api_key, "wrong")), None);
+        assert_eq!(
+            name(headers(header::AUTHORIZATION, "Bearer ci-key")),
+            Some("ci".into())
+        );
+        assert_eq!(
+            name(headers(
+                header::AUTHORIZATION,
+                &format!("Bearer {}", token("nightly", "secret"))
+            )),
+            Some("nightly".into())
+        );
+        assert_eq!(
+            name(headers(
+                header::AUTHORIZATION,
+                &format!("Bearer {}", token("nightly", "wrong"))
+            )),
+            None
+        );
+        assert_eq!(name(HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_acquire_quotas() {
+        let authenticator = Authenticator::new(&generate_auth_config()).unwrap();
+        let ci = authenticator.keys[0].clone();
+        let nightly = authenticator.keys[1].clone();
+
+        let grant = authenticator.acquire(ci.clone()).unwrap();
+        assert!(grant.allows("NODE"));
+        assert!(!grant.allows("PYTHON"));
+        assert_eq!(
+            grant.limits(),
+            ExecutionLimits {
+                timeout: Duration::from_secs(5),
+                memory: 512,
+            }
+        );
+        assert_eq!(
+            authenticator.acquire(ci.clone()).err(),
+            Some(Refusal::TooManyRunning)
+        );
+        drop(grant);
+        drop(authenticator.acquire(ci).unwrap());
+
+        drop(authenticator.acquire(nightly.clone()).unwrap());
+        drop(authenticator.acquire(nightly.clone()).unwrap());
+        assert!(matches!(
+            authenticator.acquire(nightly).err(),
+            Some(Refusal::QuotaExceeded(_))
+        ));
+
+        let usage = authenticator.usage();
+        assert_eq!(
+            usage[0],
+            KeyUsage {
+                name: "ci".to_string(),
+                running: 0,
+                today: 2,
+                total: 2,
+            }
+        );
+        assert_eq!(usage[1].today, 2);
+    }
+
+    #[test]
+    fn test_refund() {
+        let authenticator = Authenticator::new(&generate_auth_config()).unwrap();
+        let nightly = authenticator.keys[1].clone();
+
+        let grant = authenticator.acquire(nightly.clone()).unwrap();
+        grant.refund();
+        grant.refund();
+        drop(grant);
+        assert_eq!(authenticator.usage()[1].today, 0);
+        assert_eq!(authenticator.usage()[1].total, 0);
+
+        // The refunded execution does not count against the quota
+        drop(authenticator.acquire(nightly.clone()).unwrap());
+        drop(authenticator.acquire(nightly).unwrap());
+        assert_eq!(authenticator.usage()[1].today, 2);
+    }
+
+    #[test]
+    fn test_secrets_match() {
+        assert!(secrets_match("ci-key", "ci-key"));
+        assert!(!secrets_match("ci-key", "ci-kez"));
+        assert!(!secrets_match("ci", "ci-key"));
+        assert!(!secrets_match("", "ci-key"));
+    }
+}
@@ -0,0 +1,1319 @@
+use anyhow::Result;
+use cidr::Ipv4Cidr;
+use log::{debug, trace};
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, Schema, SchemaObject},
+    JsonSchema,
+};
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use thiserror::Error;
+
+use crate::vm_manager::ipam::Ipam;
+
+pub mod v1alpha1;
+
+/// The current api version of the config and language files
+pub const API_VERSION: &str = "lambdo.io/v1beta1";
+
+/// Prefix of the environment variables overriding the config
+const ENV_PREFIX: &str = "LAMBDO_";
+
+/// Separator of the keys in the name of the environment variables
+const ENV_SEPARATOR: &str = "__";
+
+#[derive(Error, Debug)]
+pub enum LambdoConfigError {
+    #[error("cannot load config file")]
+    Load(#[from] io::Error),
+    #[error("cannot parse config file")]
+    Parse(#[from] serde_yaml::Error),
+    #[error("unsupported config kind")]
+    KindNotSupported,
+    #[error("unsupported config api version")]
+    VersionNotSupported,
+    #[error("invalid config file:\n{}", format_problems(.0))]
+    Invalid(Vec<String>),
+    #[error("invalid environment variable {name}: {reason}")]
+    InvalidOverride { name: String, reason: String },
+    #[error("cannot read languages directory {path}")]
+    LanguagesDir {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("cannot load language file {path}")]
+    LanguageFile {
+        path: String,
+        #[source]
+        source: Box<LambdoConfigError>,
+    },
+    #[error("language {name} of {path} is already defined by {first}")]
+    LanguageConflict {
+        name: String,
+        path: String,
+        first: String,
+    },
+}
+
+fn format_problems(problems: &[String]) -> String {
+    problems
+        .iter()
+        .map(|problem| format!("  - {}", problem))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The fields telling how to parse the rest of a config or language file
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct LambdoHeader {
+    apiVersion: String,
+    kind: String,
+}
+
+impl LambdoHeader {
+    fn parse(contents: &str, kind: &str) -> Result<Self, LambdoConfigError> {
+        let header: LambdoHeader = serde_yaml::from_str(contents)?;
+
+        if header.kind != kind {
+            return Err(LambdoConfigError::KindNotSupported);
+        }
+
+        if header.apiVersion != API_VERSION && header.apiVersion != v1alpha1::API_VERSION {
+            return Err(LambdoConfigError::VersionNotSupported);
+        }
+
+        Ok(header)
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+#[allow(non_snake_case)]
+pub struct LambdoConfig {
+    /// The api version of the lambdo config file
+    pub apiVersion: String,
+    /// The kind of the lambdo config file
+    pub kind: String,
+    /// The lambdo vmm configuration
+    pub vmm: LambdoVMMConfig,
+    /// The lambdo api configuration
+    pub api: LambdoApiConfig,
+    /// The lambdo agent configuration
+    pub agent: LambdoAgentConfig,
+    /// The lambdo languages configuration
+    #[serde(default)]
+    pub languages: Vec<LambdoLanguageConfig>,
+    /// The directory of the language files, relative to the config file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub languages_dir: Option<String>,
+    /// The lambdo tracing configuration
+    #[serde(default)]
+    pub tracing: LambdoTracingConfig,
+    /// The lambdo logging configuration
+    #[serde(default)]
+    pub logging: LambdoLoggingConfig,
+    /// The authentication of the executions, anyone can run code if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<LambdoAuthConfig>,
+    /// The files of the last languages, the ones loaded from `languages_dir`
+    #[serde(skip)]
+    pub language_files: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct LambdoAuthConfig {
+    /// The API keys allowed to run code
+    #[serde(default)]
+    pub keys: Vec<LambdoApiKeyConfig>,
+    /// A YAML file holding a list of additional API keys
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keys_file: Option<String>,
+    /// The secret of the HS256 bearer JWTs, their subject is the name of an API key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwt_secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LambdoApiKeyConfig {
+    /// The name of the key, as shown by the admin API
+    pub name: String,
+    /// The key sent in the `X-Api-Key` header or as bearer token, only JWTs are accepted if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// The languages the key can run, all of them if empty
+    #[serde(default)]
+    pub languages: Vec<String>,
+    /// The maximum number of executions running at once
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrency: Option<u32>,
+    /// The maximum duration of an execution, in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_timeout: Option<u64>,
+    /// The maximum memory of the VMs running the executions, in MiB
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_memory: Option<u32>,
+    /// The maximum number of executions per day
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily_quota: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct LambdoLoggingConfig {
+    /// The format of the logs printed on stderr
+    #[serde(default)]
+    pub format: LambdoLogFormat,
+    /// The audit log recording every execution, disabled if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit: Option<LambdoAuditConfig>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LambdoLogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LambdoAuditConfig {
+    /// The file the records are appended to
+    pub path: String,
+    /// The size of the file from which it is rotated, in bytes
+    #[serde(default = "default_audit_max_size")]
+    pub max_size: u64,
+    /// The number of rotated files kept next to the current one
+    #[serde(default = "default_audit_max_files")]
+    pub max_files: usize,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct LambdoTracingConfig {
+    /// Where the spans are exported
+    #[serde(default)]
+    pub exporter: LambdoTracingExporter,
+    /// The OTLP collector endpoint, `http://localhost:4317` if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LambdoTracingExporter {
+    /// The spans are not exported, only the logs are printed
+    #[default]
+    None,
+    /// The spans are printed on stdout, one JSON object per line
+    Stdout,
+    /// The spans are sent to an OpenTelemetry collector, needs the `otlp` feature
+    Otlp,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LambdoVMMConfig {
+    /// The kernel path to use for the vmm
+    pub kernel: String,
+    /// The transport used to talk with the agents
+    #[serde(default)]
+    pub transport: LambdoVMMTransport,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LambdoVMMTransport {
+    /// gRPC over the bridge network
+    #[default]
+    Grpc,
+    /// Framed messages over the VMM console socket
+    Serial,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LambdoApiConfig {
+    /// Bridge to bind to
+    #[serde(default = "default_bridge")]
+    pub bridge: String,
+    /// Address of the bridge
+    #[serde(default = "default_bridge_address")]
+    pub bridge_address: String,
+    /// The addresses given to the VMs, the whole bridge network if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_range: Option<LambdoIpRangeConfig>,
+    /// IPv6 address of the bridge, the VMs only get an IPv4 address if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bridge_address_v6: Option<String>,
+    /// The IPv6 addresses given to the VMs, the start of the bridge network if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_range_v6: Option<LambdoIpRangeConfig>,
+    /// The host on which the API server will listen
+    pub web_host: String,
+    /// The port on which the API server will listen
+    pub web_port: u16,
+    /// The host on which the gRPC server will listen
+    pub grpc_host: String,
+    /// The port on which the gRPC server will listen
+    pub grpc_port: u16,
+    /// The file recording the VMs, to clean up after them when the API restarts
+    #[serde(default = "default_state_file")]
+    pub state_file: String,
+    /// The time given to the running executions to finish when the server stops, in seconds
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+    /// The bearer token of the admin endpoints, they are disabled if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin_token: Option<String>,
+    /// The maximum number of executions running at once, the others are queued
+    #[serde(default = "default_max_running")]
+    pub max_running: usize,
+    /// The maximum number of executions waiting for each language, the others are refused
+    #[serde(default = "default_max_queued")]
+    pub max_queued: usize,
+    /// Enforce the network policies of the languages with nftables
+    #[serde(default = "default_firewall")]
+    pub firewall: bool,
+    /// Reload the languages when their files change, they are also reloaded on SIGHUP
+    #[serde(default = "default_watch_config")]
+    pub watch_config: bool,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LambdoIpRangeConfig {
+    /// The first address of the range
+    pub start: String,
+    /// The last address of the range, included
+    pub end: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LambdoAgentConfig {
+    /// The path to the agent binary
+    pub path: String,
+    /// The path to the agent configuration file
+    pub config: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LambdoLanguageConfig {
+    /// The name of the language
+    pub name: String,
+    /// The version of the language
+    #[schemars(schema_with = "version_schema")]
+    pub version: String,
+    /// The initramfs path to use for the language
+    pub initramfs: String,
+    /// The steps to execute
+    pub steps: Vec<LambdoLanguageStepConfig>,
+    /// The glob patterns of the workspace files to return after the execution
+    #[serde(default)]
+    pub artifacts: Vec<String>,
+    /// The maximum total size of the returned artifacts, in bytes
+    #[serde(default = "default_artifacts_max_size")]
+    pub artifacts_max_size: u64,
+    /// The installation of the dependencies sent with the code
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dependencies: Option<LambdoLanguageDependenciesConfig>,
+    /// What the VMs of the language can reach on the network
+    #[serde(default)]
+    pub network: LambdoNetworkPolicy,
+}
+
+/// A language defined in its own file, in the `languages_dir` of the config
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+#[allow(non_snake_case)]
+pub struct LambdoLanguageFile {
+    /// The api version of the language file
+    pub apiVersion: String,
+    /// The kind of the language file
+    pub kind: String,
+    /// The language
+    pub language: LambdoLanguageConfig,
+}
+
+impl LambdoLanguageFile {
+    /// Load the language of a language file
+    ///
+    /// Arguments:
+    ///
+    /// * `path`: The path to the language file.
+    pub fn load(path: &Path) -> Result<LambdoLanguageConfig, LambdoConfigError> {
+        Ok(Self::parse(&fs::read_to_string(path)?)?.language)
+    }
+
+    /// Parse a language file of any supported api version, converted to the current one
+    pub fn parse(contents: &str) -> Result<Self, LambdoConfigError> {
+        LambdoHeader::parse(contents, "Language")?;
+
+        // The language files are the same in both api versions
+        let file: LambdoLanguageFile = serde_yaml::from_str(contents)?;
+        Ok(LambdoLanguageFile {
+            apiVersion: API_VERSION.to_string(),
+            ..file
+        })
+    }
+}
+
+/// What the VMs of a language can reach, besides the API
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone, Default)]
+#[serde(tag = "policy", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum LambdoNetworkPolicy {
+    /// Nothing but the API
+    #[default]
+    None,
+    /// The host, through the bridge address
+    HostOnly,
+    /// The listed networks, the host has to route the traffic
+    Allowlist {
+        #[serde(default)]
+        allow: Vec<LambdoNetworkRule>,
+    },
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LambdoNetworkRule {
+    /// The IPv4 network, like `10.0.0.0/8`
+    pub cidr: String,
+    /// The TCP and UDP ports, all of them if empty
+    #[serde(default)]
+    pub ports: Vec<u16>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LambdoLanguageDependenciesConfig {
+    /// The files of the request triggering the installation, like `package.json`
+    pub manifests: Vec<String>,
+    /// The install command, `{{cache}}` and `{{mirror}}` are replaced by the directories below
+    pub command: String,
+    /// The package cache directory inside the VM
+    pub cache: String,
+    /// The local package mirror directory inside the VM, for when the network is off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<String>,
+    /// The archive holding the cache and mirror directories, built with `initramfs --cache`
+    ///
+    /// It is added after the initramfs of the VMs, which mount its directories read-only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_image: Option<String>,
+}
+
+impl LambdoLanguageDependenciesConfig {
+    /// The install command with the directories filled in
+    pub fn install_command(&self) -> String {
+        let command = self.command.replace("{{cache}}", &self.cache);
+        match &self.mirror {
+            Some(mirror) => command.replace("{{mirror}}", mirror),
+            None => command,
+        }
+    }
+}
+
+/// The differences between the languages of two configs, by name
+#[derive(Debug, Default, PartialEq)]
+pub struct LanguageChanges {
+    /// The languages of the new config only
+    pub added: Vec<LambdoLanguageConfig>,
+    /// The languages defined differently in the new config, as `(old, new)`
+    pub changed: Vec<(LambdoLanguageConfig, LambdoLanguageConfig)>,
+    /// The languages of the old config only
+    pub removed: Vec<LambdoLanguageConfig>,
+}
+
+impl LanguageChanges {
+    /// Compare the languages of two configs
+    ///
+    /// # Arguments
+    ///
+    /// * `old` - The languages in use
+    /// * `new` - The languages replacing them
+    pub fn new(old: &[LambdoLanguageConfig], new: &[LambdoLanguageConfig]) -> Self {
+        let mut changes = LanguageChanges::default();
+
+        for language in new {
+            match old.iter().find(|old| old.name == language.name) {
+                Some(old) if old != language => {
+                    changes.changed.push((old.clone(), language.clone()));
+                }
+                Some(_) => {}
+                None => changes.added.push(language.clone()),
+            }
+        }
+        changes.removed = old
+            .iter()
+            .filter(|old| !new.iter().any(|language| language.name == old.name))
+            .cloned()
+            .collect();
+
+        changes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+
+    /// The old definitions of the changed and removed languages, their VMs are outdated
+    pub fn outdated(&self) -> impl Iterator<Item = &LambdoLanguageConfig> {
+        self.changed
+            .iter()
+            .map(|(old, _)| old)
+            .chain(self.removed.iter())
+    }
+
+    /// The new definitions of the added and changed languages, they need new VMs
+    pub fn updated(&self) -> impl Iterator<Item = &LambdoLanguageConfig> {
+        self.added
+            .iter()
+            .chain(self.changed.iter().map(|(_, new)| new))
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LambdoLanguageStepConfig {
+    /// The name of the step
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The command to execute
+    pub command: String,
+    /// The output configuration
+    pub output: LambdoLanguageStepOutputConfig,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LambdoLanguageStepOutputConfig {
+    /// Is the output enabled ?
+    pub enabled: bool,
+    /// Is the output a debug output ?
+    pub debug: bool,
+}
+
+/// The versions are often numbers in YAML, they are read as strings
+fn version_schema(_: &mut SchemaGenerator) -> Schema {
+    SchemaObject {
+        instance_type: Some(vec![InstanceType::String, InstanceType::Number].into()),
+        ..Default::default()
+    }
+    .into()
+}
+
+const fn default_artifacts_max_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_bridge() -> String {
+    String::from("lambdo0")
+}
+
+fn default_bridge_address() -> String {
+    String::from("192.168.10.1/24")
+}
+
+fn default_state_file() -> String {
+    String::from("/var/lib/lambdo/state.json")
+}
+
+const fn default_drain_timeout() -> u64 {
+    30
+}
+
+const fn default_firewall() -> bool {
+    true
+}
+
+const fn default_watch_config() -> bool {
+    true
+}
+
+const fn default_max_running() -> usize {
+    16
+}
+
+const fn default_max_queued() -> usize {
+    64
+}
+
+const fn default_audit_max_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+const fn default_audit_max_files() -> usize {
+    5
+}
+
+impl LambdoConfig {
+    /// Load a LambdoConfig from a file.
+    ///
+    /// Arguments:
+    ///
+    /// * `path`: The path to the config file.
+    ///
+    /// Returns:
+    ///
+    /// A Result<LambdoConfig>
+    pub fn load(path: &str) -> Result<Self> {
+        let contents = fs::read_to_string(path).map_err(LambdoConfigError::Load)?;
+        let mut config = Self::parse(&contents)?;
+
+        config.merge_env(std::env::vars())?;
+        if let Some(directory) = config.languages_dir_path(path) {
+            config.merge_languages_dir(&directory)?;
+        }
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Parse a config file of any supported api version, converted to the current one
+    ///
+    /// Arguments:
+    ///
+    /// * `contents`: The content of the config file.
+    pub fn parse(contents: &str) -> Result<Self, LambdoConfigError> {
+        let header = LambdoHeader::parse(contents, "Config")?;
+
+        if header.apiVersion == v1alpha1::API_VERSION {
+            debug!("Converting the config from {}", v1alpha1::API_VERSION);
+            let config: v1alpha1::LambdoConfig = serde_yaml::from_str(contents)?;
+            return Ok(config.into());
+        }
+
+        Ok(serde_yaml::from_str(contents)?)
+    }
+
+    /// Rewrite a config file and its language files in the current api version
+    ///
+    /// The comments are not kept, the original of each rewritten file is kept
+    /// next to it with a `.bak` extension.
+    ///
+    /// Arguments:
+    ///
+    /// * `path`: The path to the config file.
+    ///
+    /// Returns:
+    ///
+    /// A Result<Vec<String>>, with the paths of the rewritten files
+    pub fn migrate(path: &str) -> Result<Vec<String>> {
+        let mut migrated = Vec::new();
+
+        let contents = fs::read_to_string(path).map_err(LambdoConfigError::Load)?;
+        let config = Self::parse(&contents)?;
+        if LambdoHeader::parse(&contents, "Config")?.apiVersion != API_VERSION {
+            rewrite(Path::new(path), &contents, &serde_yaml::to_string(&config)?)?;
+            migrated.push(path.to_string());
+        }
+
+        if let Some(directory) = config.languages_dir_path(path) {
+            for file in language_files(&directory)? {
+                let contents = fs::read_to_string(&file)?;
+                let language = LambdoLanguageFile::parse(&contents)?;
+                if LambdoHeader::parse(&contents, "Language")?.apiVersion != API_VERSION {
+                    rewrite(&file, &contents, &serde_yaml::to_string(&language)?)?;
+                    migrated.push(file.display().to_string());
+                }
+            }
+        }
+
+        Ok(migrated)
+    }
+
+    /// Override the config with the `LAMBDO_<SECTION>__<KEY>` environment variables
+    ///
+    /// The keys are separated by two underscores, `LAMBDO_API__WEB_PORT` sets
+    /// `api.web_port`. The values replacing a number, a boolean or a list are parsed
+    /// as YAML, the others are strings.
+    /// The variables with a single key, like the `LAMBDO_AGENT_*` ones, are ignored.
+    pub fn merge_env<I: IntoIterator<Item = (String, String)>>(
+        &mut self,
+        vars: I,
+    ) -> Result<(), LambdoConfigError> {
+        let overrides = vars
+            .into_iter()
+            .filter_map(|(name, value)| {
+                let keys = name
+                    .strip_prefix(ENV_PREFIX)?
+                    .split(ENV_SEPARATOR)
+                    .map(|key| key.to_lowercase())
+                    .collect::<Vec<_>>();
+                (keys.len() > 1).then_some((name, keys, value))
+            })
+            .collect::<Vec<_>>();
+        if overrides.is_empty() {
+            return Ok(());
+        }
+
+        let mut config = serde_yaml::to_value(&*self)?;
+        for (name, keys, value) in overrides {
+            trace!("overriding {} with {:?}", keys.join("."), value);
+            let invalid = |reason: String| LambdoConfigError::InvalidOverride {
+                name: name.clone(),
+                reason,
+            };
+            if keys[0] == "languages" {
+                return Err(invalid(
+                    "the languages cannot be overridden, use languages_dir".to_string(),
+                ));
+            }
+
+            let mut current = &mut config;
+            for (index, key) in keys.iter().enumerate() {
+                // The sections that are not set are filled in
+                if current.is_null() {
+                    *current = Value::Mapping(Mapping::new());
+                }
+                let section = current.as_mapping_mut().ok_or_else(|| {
+                    invalid(format!("{} is not a section", keys[..index].join(".")))
+                })?;
+                current = section
+                    .entry(Value::String(key.clone()))
+                    .or_insert(Value::Null);
+            }
+
+            *current = match current {
+                Value::Null | Value::String(_) => Value::String(value),
+                _ => serde_yaml::from_str(&value).map_err(|e| invalid(e.to_string()))?,
+            };
+        }
+
+        let language_files = std::mem::take(&mut self.language_files);
+        *self = serde_yaml::from_value(config)?;
+        self.language_files = language_files;
+        Ok(())
+    }
+
+    /// The directory of the language files, if any
+    ///
+    /// Arguments:
+    ///
+    /// * `path`: The path to the config file, a relative directory is relative to it.
+    pub fn languages_dir_path(&self, path: &str) -> Option<PathBuf> {
+        let directory = self.languages_dir.as_ref()?;
+        Some(
+            Path::new(path)
+                .parent()
+                .unwrap_or_else(|| Path::new(""))
+                .join(directory),
+        )
+    }
+
+    /// Append the languages of the `.yaml` and `.yml` files of a directory
+    ///
+    /// The files are read in the order of their names, a language defined by
+    /// the config or by another file is rejected.
+    ///
+    /// Arguments:
+    ///
+    /// * `directory`: The directory of the language files.
+    pub fn merge_languages_dir(&mut self, directory: &Path) -> Result<(), LambdoConfigError> {
+        for path in language_files(directory)? {
+            let file = path.display().to_string();
+            let language = LambdoLanguageFile::load(&path).map_err(|source| {
+                LambdoConfigError::LanguageFile {
+                    path: file.clone(),
+                    source: Box::new(source),
+                }
+            })?;
+
+            if let Some(index) = self
+                .languages
+                .iter()
+                .position(|defined| defined.name == language.name)
+            {
+                return Err(LambdoConfigError::LanguageConflict {
+                    name: language.name,
+                    path: file,
+                    first: self.language_location(index),
+                });
+            }
+
+            debug!("Loaded language {} from {}", language.name, file);
+            self.languages.push(language);
+            self.language_files.push(file);
+        }
+
+        Ok(())
+    }
+
+    /// Where a language is defined, for the error messages
+    fn language_location(&self, index: usize) -> String {
+        let inline = self.languages.len() - self.language_files.len();
+        match index.checked_sub(inline) {
+            Some(file) => format!("{}: language", self.language_files[file]),
+            None => format!("languages[{}]", index),
+        }
+    }
+
+    /// Check that the config can be used, beyond its syntax
+    ///
+    /// Every problem is reported, not only the first one.
+    ///
+    /// Returns:
+    ///
+    /// A Result<(), LambdoConfigError>, with the list of the problems if any
+    pub fn validate(&self) -> Result<(), LambdoConfigError> {
+        let mut problems = Vec::new();
+
+        if self.api.bridge.is_empty() || self.api.bridge.len() > 15 {
+            problems.push(format!(
+                "api.bridge: {:?} must be between 1 and 15 characters long",
+                self.api.bridge
+            ));
+        }
+        if let Err(e) = Ipam::v4(&self.api) {
+            problems.push(format!("api.bridge_address: {}", e));
+        }
+        if let Err(e) = Ipam::v6(&self.api) {
+            problems.push(format!("api.bridge_address_v6: {}", e));
+        }
+        if self.api.max_running == 0 {
+            problems.push("api.max_running: at least one execution has to run at once".to_string());
+        }
+
+        if !Path::new(&self.vmm.kernel).is_file() {
+            problems.push(format!("vmm.kernel: {} does not exist", self.vmm.kernel));
+        }
+
+        let mut languages = HashMap::new();
+        for (index, language) in self.languages.iter().enumerate() {
+            let path = self.language_location(index);
+            if let Some(first) = languages.insert(&language.name, index) {
+                problems.push(format!(
+                    "{}.name: {} is already defined by {}",
+                    path,
+                    language.name,
+                    self.language_location(first)
+                ));
+            }
+            if !Path::new(&language.initramfs).is_file() {
+                problems.push(format!(
+                    "{}.initramfs: {} does not exist",
+                    path, language.initramfs
+                ));
+            }
+            if language.steps.is_empty() {
+                problems.push(format!("{}.steps: at least one step is needed", path));
+            }
+            for (step_index, step) in language.steps.iter().enumerate() {
+                if step.command.trim().is_empty() {
+                    problems.push(format!(
+                        "{}.steps[{}].command: the command is empty",
+                        path, step_index
+                    ));
+                }
+            }
+            if let Some(dependencies) = &language.dependencies {
+                if dependencies.manifests.is_empty() {
+                    problems.push(format!(
+                        "{}.dependencies.manifests: at least one manifest is needed",
+                        path
+                    ));
+                }
+                if dependencies.mirror.is_none() && dependencies.command.contains("{{mirror}}") {
+                    problems.push(format!(
+                        "{}.dependencies.command: {{{{mirror}}}} is used but no mirror is set",
+                        path
+                    ));
+                }
+                if let Some(cache_image) = &dependencies.cache_image {
+                    if !Path::new(cache_image).is_file() {
+                        problems.push(format!(
+                            "{}.dependencies.cache_image: {} does not exist",
+                            path, cache_image
+                        ));
+                    }
+                }
+            }
+            if let LambdoNetworkPolicy::Allowlist { allow } = &language.network {
+                for (rule_index, rule) in allow.iter().enumerate() {
+                    if let Err(e) = Ipv4Cidr::from_str(&rule.cidr) {
+                        problems.push(format!(
+                            "{}.network.allow[{}].cidr: invalid network {}: {}",
+                            path, rule_index, rule.cidr, e
+                        ));
+                    }
+                }
+            }
+        }
+
+        if let Some(auth) = &self.auth {
+            let mut names = HashMap::new();
+            for (index, key) in auth.keys.iter().enumerate() {
+                let path = format!("auth.keys[{}]", index);
+                if let Some(first) = names.insert(&key.name, index) {
+                    problems.push(format!(
+                        "{}.name: {} is already defined by auth.keys[{}]",
+                        path, key.name, first
+                    ));
+                }
+                for name in &key.languages {
+                    if !languages.contains_key(name) {
+                        problems.push(format!("{}.languages: unknown language {}", path, name));
+                    }
+                }
+            }
+            if let Some(keys_file) = &auth.keys_file {
+                if !Path::new(keys_file).is_file() {
+                    problems.push(format!("auth.keys_file: {} does not exist", keys_file));
+                }
+            }
+        }
+
+        if cfg!(not(feature = "otlp")) && self.tracing.exporter == LambdoTracingExporter::Otlp {
+            problems.push(
+                "tracing.exporter: the otlp exporter needs lambdo to be built with the otlp feature"
+                    .to_string(),
+            );
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(LambdoConfigError::Invalid(problems))
+        }
+    }
+}
+
+/// The `.yaml` and `.yml` files of a languages directory, in the order of their names
+fn language_files(directory: &Path) -> Result<Vec<PathBuf>, LambdoConfigError> {
+    let mut paths = fs::read_dir(directory)
+        .and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<io::Result<Vec<_>>>()
+        })
+        .map_err(|source| LambdoConfigError::LanguagesDir {
+            path: directory.display().to_string(),
+            source,
+        })?;
+    paths.retain(|path| {
+        path.is_file()
+            && matches!(
+                path.extension().and_then(|extension| extension.to_str()),
+                Some("yaml" | "yml")
+            )
+    });
+    paths.sort();
+
+    Ok(paths)
+}
+
+/// Replace a file, keeping the original next to it with a `.bak` extension
+fn rewrite(path: &Path, original: &str, contents: &str) -> io::Result<()> {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    fs::write(backup, original)?;
+    fs::write(path, contents)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::{
+        LambdoConfig, LambdoConfigError, LambdoLanguageFile, LambdoLogFormat, LanguageChanges,
+        API_VERSION,
+    };
+
+    /// Write a config in the `lambdo.io/v1alpha1` format
+    fn to_v1alpha1(config: &str) -> String {
+        config
+            .replace(API_VERSION, super::v1alpha1::API_VERSION)
+            .replace("  grpc_port:", "  gprc_port:")
+            .replace("  bridge_address:", "  ip:")
+    }
+
+    fn generate_config(directory: &std::path::Path, extra: &str) -> String {
+        format!(
+            r#"
+apiVersion: lambdo.io/v1beta1
+kind: Config
+api:
+  web_host: 0.0.0.0
+  web_port: 3000
+  grpc_host: 0.0.0.0
+  grpc_port: 50051
+  bridge_address: 10.0.50.1/24
+vmm:
+  kernel: {kernel}
+agent:
+  path: /usr/local/bin/lambdo-agent
+  config: /etc/lambdo/agent.yaml
+languages:
+  - name: NODE
+    version: 12
+    initramfs: {initramfs}
+    steps:
+      - command: /usr/local/bin/node {{{{filename}}}}
+        output:
+          enabled: true
+          debug: false
+{extra}"#,
+            kernel = directory.join("vmlinux.bin").display(),
+            initramfs = directory.join("node-12.img").display(),
+            extra = extra
+        )
+    }
+
+    fn generate_directory(name: &str) -> std::path::PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("lambdo-config-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("vmlinux.bin"), "").unwrap();
+        fs::write(directory.join("node-12.img"), "").unwrap();
+        directory
+    }
+
+    #[test]
+    fn test_valid_config() {
+        let directory = generate_directory("valid");
+        let config: LambdoConfig = serde_yaml::from_str(&generate_config(&directory, "")).unwrap();
+        let result = config.validate();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert!(result.is_ok(), "{:?}", result);
+        assert_eq!(config.api.bridge_address, "10.0.50.1/24");
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        let directory = generate_directory("unknown");
+        let config = generate_config(&directory, "").replace("  bridge_address:", "  bridge_ip:");
+        fs::remove_dir_all(&directory).unwrap();
+
+        let error = serde_yaml::from_str::<LambdoConfig>(&config)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("unknown field `bridge_ip`"), "{}", error);
+    }
+
+    #[test]
+    fn test_every_problem_is_reported() {
+        let directory = generate_directory("invalid");
+        let config = generate_config(
+            &directory,
+            r#"  - name: NODE
+    version: 14
+    initramfs: /nonexistent/node-14.img
+    steps: []
+    dependencies:
+      manifests: [package.json]
+      command: npm install --registry {{mirror}}
+      cache: /var/cache/lambdo/npm
+      cache_image: /nonexistent/npm-cache.img
+    network:
+      policy: allowlist
+      allow:
+        - cidr: 10.0.0.0/33
+auth:
+  keys:
+    - name: ci
+      languages: [PYTHON]
+"#,
+        )
+        .replace("10.0.50.1/24", "10.0.50.1/33");
+        let config: LambdoConfig = serde_yaml::from_str(&config).unwrap();
+        let result = config.validate();
+        fs::remove_dir_all(&directory).unwrap();
+
+        let problems = match result {
+            Err(LambdoConfigError::Invalid(problems)) => problems,
+            result => panic!("unexpected result {:?}", result),
+        };
+        assert_eq!(problems.len(), 8, "{:#?}", problems);
+        assert!(problems[0].starts_with("api.bridge_address: invalid bridge address"));
+        assert_eq!(
+            problems[1..6],
+            [
+                "languages[1].name: NODE is already defined by languages[0]",
+                "languages[1].initramfs: /nonexistent/node-14.img does not exist",
+                "languages[1].steps: at least one step is needed",
+                "languages[1].dependencies.command: {{mirror}} is used but no mirror is set",
+                "languages[1].dependencies.cache_image: /nonexistent/npm-cache.img does not exist",
+            ]
+        );
+        assert!(problems[6].starts_with("languages[1].network.allow[0].cidr: invalid network"));
+        assert_eq!(
+            problems[7],
+            "auth.keys[0].languages: unknown language PYTHON"
+        );
+    }
+
+    #[test]
+    fn test_language_changes() {
+        let directory = generate_directory("changes");
+        let config: LambdoConfig = serde_yaml::from_str(&generate_config(
+            &directory,
+            r#"  - name: PYTHON
+    version: 3.8
+    initramfs: python-3.img
+    steps: []
+"#,
+        ))
+        .unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        let old = config.languages.clone();
+        assert!(LanguageChanges::new(&old, &old).is_empty());
+
+        let mut node = old[0].clone();
+        node.initramfs = "node-14.img".to_string();
+        let mut rust_language = old[1].clone();
+        rust_language.name = "RUST".to_string();
+        let new = vec![rust_language.clone(), node.clone()];
+
+        let changes = LanguageChanges::new(&old, &new);
+        assert_eq!(changes.added, vec![rust_language.clone()]);
+        assert_eq!(changes.changed, vec![(old[0].clone(), node.clone())]);
+        assert_eq!(changes.removed, vec![old[1].clone()]);
+        assert_eq!(
+            changes
+                .outdated()
+                .map(|language| language.name.as_str())
+                .collect::<Vec<_>>(),
+            ["NODE", "PYTHON"]
+        );
+        assert_eq!(
+            changes
+                .updated()
+                .map(|language| language.name.as_str())
+                .collect::<Vec<_>>(),
+            ["RUST", "NODE"]
+        );
+    }
+
+    fn generate_language_file(
+        directory: &std::path::Path,
+        name: &str,
+        language: &str,
+        initramfs: &std::path::Path,
+    ) {
+        fs::write(
+            directory.join(name),
+            format!(
+                r#"
+apiVersion: lambdo.io/v1beta1
+kind: Language
+language:
+  name: {language}
+  version: 3.8
+  initramfs: {initramfs}
+  steps:
+    - command: /usr/bin/python3 {{{{filename}}}}
+      output:
+        enabled: true
+        debug: false
+"#,
+                language = language,
+                initramfs = initramfs.display(),
+            ),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_languages_dir() {
+        let directory = generate_directory("languages-dir");
+        let languages_dir = directory.join("languages.d");
+        fs::create_dir_all(&languages_dir).unwrap();
+        generate_language_file(
+            &languages_dir,
+            "python.yaml",
+            "PYTHON",
+            &directory.join("node-12.img"),
+        );
+        generate_language_file(
+            &languages_dir,
+            "go.yml",
+            "GO",
+            &directory.join("node-12.img"),
+        );
+        fs::write(languages_dir.join("README"), "not a language").unwrap();
+        fs::write(
+            directory.join("config.yaml"),
+            generate_config(&directory, "languages_dir: languages.d\n"),
+        )
+        .unwrap();
+
+        let config = LambdoConfig::load(directory.join("config.yaml").to_str().unwrap());
+
+        generate_language_file(
+            &languages_dir,
+            "zz-node.yaml",
+            "NODE",
+            &directory.join("node-12.img"),
+        );
+        let conflict = LambdoConfig::load(directory.join("config.yaml").to_str().unwrap());
+        fs::remove_dir_all(&directory).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(
+            config
+                .languages
+                .iter()
+                .map(|language| language.name.as_str())
+                .collect::<Vec<_>>(),
+            ["NODE", "GO", "PYTHON"]
+        );
+        assert_eq!(config.languages[2].version, "3.8");
+        assert_eq!(
+            config.language_files,
+            [
+                languages_dir.join("go.yml").display().to_string(),
+                languages_dir.join("python.yaml").display().to_string()
+            ]
+        );
+        assert_eq!(
+            conflict.unwrap_err().to_string(),
+            format!(
+                "language NODE of {} is already defined by languages[0]",
+                languages_dir.join("zz-node.yaml").display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_language_file_kind() {
+        let directory = generate_directory("language-kind");
+        generate_language_file(
+            &directory,
+            "python.yaml",
+            "PYTHON",
+            &directory.join("node-12.img"),
+        );
+        let config = fs::read_to_string(directory.join("python.yaml")).unwrap();
+        fs::write(
+            directory.join("python.yaml"),
+            config.replace("kind: Language", "kind: Config"),
+        )
+        .unwrap();
+
+        let result = LambdoLanguageFile::load(&directory.join("python.yaml"));
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert!(matches!(result, Err(LambdoConfigError::KindNotSupported)));
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let directory = generate_directory("env");
+        let mut config: LambdoConfig =
+            serde_yaml::from_str(&generate_config(&directory, "")).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        config
+            .merge_env(vec![
+                ("LAMBDO_API__WEB_PORT".to_string(), "8080".to_string()),
+                ("LAMBDO_API__ADMIN_TOKEN".to_string(), "1234".to_string()),
+                ("LAMBDO_API__BRIDGE".to_string(), "1234".to_string()),
+                ("LAMBDO_LOGGING__FORMAT".to_string(), "json".to_string()),
+                ("LAMBDO_AGENT_API_PORT".to_string(), "50053".to_string()),
+                ("PATH".to_string(), "/bin".to_string()),
+            ])
+            .unwrap();
+        assert_eq!(config.api.web_port, 8080);
+        assert_eq!(config.api.admin_token.as_deref(), Some("1234"));
+        assert_eq!(config.api.bridge, "1234");
+        assert_eq!(config.logging.format, LambdoLogFormat::Json);
+        assert_eq!(config.languages[0].version, "12");
+
+        for (name, value) in [
+            ("LAMBDO_API__WEB_PORT", "http"),
+            ("LAMBDO_API__WEB_PORTS", "8080"),
+            ("LAMBDO_API__WEB_PORT__NUMBER", "8080"),
+            ("LAMBDO_LANGUAGES__NAME", "NODE"),
+        ] {
+            assert!(
+                config
+                    .merge_env(vec![(name.to_string(), value.to_string())])
+                    .is_err(),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(config.api.web_port, 8080);
+    }
+
+    #[test]
+    fn test_v1alpha1_config() {
+        let directory = generate_directory("v1alpha1");
+        let config = generate_config(&directory, "");
+        fs::remove_dir_all(&directory).unwrap();
+
+        let current = LambdoConfig::parse(&config).unwrap();
+        let converted = LambdoConfig::parse(&to_v1alpha1(&config)).unwrap();
+        assert_eq!(converted, current);
+        assert_eq!(converted.apiVersion, API_VERSION);
+        assert_eq!(converted.api.grpc_port, 50051);
+
+        // The old names are not part of the current format
+        let error = LambdoConfig::parse(&config.replace("  grpc_port:", "  gprc_port:"))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(error, "cannot parse config file");
+        assert!(matches!(
+            LambdoConfig::parse(&config.replace(API_VERSION, "lambdo.io/v2")),
+            Err(LambdoConfigError::VersionNotSupported)
+        ));
+    }
+
+    #[test]
+    fn test_migrate() {
+        let directory = generate_directory("migrate");
+        let languages_dir = directory.join("languages.d");
+        fs::create_dir_all(&languages_dir).unwrap();
+        generate_language_file(
+            &languages_dir,
+            "python.yaml",
+            "PYTHON",
+            &directory.join("node-12.img"),
+        );
+        let language = languages_dir.join("python.yaml");
+        fs::write(
+            &language,
+            to_v1alpha1(&fs::read_to_string(&language).unwrap()),
+        )
+        .unwrap();
+        let path = directory.join("config.yaml");
+        let old = to_v1alpha1(&generate_config(&directory, "languages_dir: languages.d\n"));
+        fs::write(&path, &old).unwrap();
+        let path = path.to_str().unwrap();
+
+        let loaded = LambdoConfig::load(path).unwrap();
+        let migrated = LambdoConfig::migrate(path).unwrap();
+        let reloaded = LambdoConfig::load(path).unwrap();
+        let backup = fs::read_to_string(format!("{}.bak", path)).unwrap();
+        let contents = fs::read_to_string(path).unwrap();
+        let migrated_again = LambdoConfig::migrate(path).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(migrated, [path.to_string(), language.display().to_string()]);
+        assert_eq!(reloaded, loaded);
+        assert_eq!(backup, old);
+        assert!(contents.starts_with("apiVersion: lambdo.io/v1beta1\n"));
+        assert!(contents.contains("  grpc_port: 50051\n"));
+        assert!(!contents.contains("null"));
+        assert!(migrated_again.is_empty());
+    }
+}
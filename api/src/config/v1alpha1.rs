@@ -0,0 +1,135 @@
+//! The `lambdo.io/v1alpha1` format of the config file
+//!
+//! It differs from the current one by the `api` section, whose gRPC port is
+//! `gprc_port` and whose bridge address can also be set with `ip`.
+
+use serde::Deserialize;
+
+use super::{
+    LambdoAgentConfig, LambdoAuthConfig, LambdoIpRangeConfig, LambdoLanguageConfig,
+    LambdoLoggingConfig, LambdoTracingConfig, LambdoVMMConfig,
+};
+
+/// The api version of this format
+pub const API_VERSION: &str = "lambdo.io/v1alpha1";
+
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+#[allow(non_snake_case)]
+pub struct LambdoConfig {
+    /// The api version of the lambdo config file
+    pub apiVersion: String,
+    /// The kind of the lambdo config file
+    pub kind: String,
+    /// The lambdo vmm configuration
+    pub vmm: LambdoVMMConfig,
+    /// The lambdo api configuration
+    pub api: LambdoApiConfig,
+    /// The lambdo agent configuration
+    pub agent: LambdoAgentConfig,
+    /// The lambdo languages configuration
+    #[serde(default)]
+    pub languages: Vec<LambdoLanguageConfig>,
+    /// The directory of the language files, relative to the config file
+    #[serde(default)]
+    pub languages_dir: Option<String>,
+    /// The lambdo tracing configuration
+    #[serde(default)]
+    pub tracing: LambdoTracingConfig,
+    /// The lambdo logging configuration
+    #[serde(default)]
+    pub logging: LambdoLoggingConfig,
+    /// The authentication of the executions, anyone can run code if not set
+    #[serde(default)]
+    pub auth: Option<LambdoAuthConfig>,
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LambdoApiConfig {
+    /// Bridge to bind to
+    #[serde(default = "super::default_bridge")]
+    pub bridge: String,
+    /// Address of the bridge
+    #[serde(default = "super::default_bridge_address", alias = "ip")]
+    pub bridge_address: String,
+    /// The addresses given to the VMs, the whole bridge network if not set
+    #[serde(default)]
+    pub ip_range: Option<LambdoIpRangeConfig>,
+    /// IPv6 address of the bridge, the VMs only get an IPv4 address if not set
+    #[serde(default)]
+    pub bridge_address_v6: Option<String>,
+    /// The IPv6 addresses given to the VMs, the start of the bridge network if not set
+    #[serde(default)]
+    pub ip_range_v6: Option<LambdoIpRangeConfig>,
+    /// The host on which the API server will listen
+    pub web_host: String,
+    /// The port on which the API server will listen
+    pub web_port: u16,
+    /// The host on which the gRPC server will listen
+    pub grpc_host: String,
+    /// The port on which the gRPC server will listen
+    pub gprc_port: u16,
+    /// The file recording the VMs, to clean up after them when the API restarts
+    #[serde(default = "super::default_state_file")]
+    pub state_file: String,
+    /// The time given to the running executions to finish when the server stops, in seconds
+    #[serde(default = "super::default_drain_timeout")]
+    pub drain_timeout: u64,
+    /// The bearer token of the admin endpoints, they are disabled if not set
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// The maximum number of executions running at once, the others are queued
+    #[serde(default = "super::default_max_running")]
+    pub max_running: usize,
+    /// The maximum number of executions waiting for each language, the others are refused
+    #[serde(default = "super::default_max_queued")]
+    pub max_queued: usize,
+    /// Enforce the network policies of the languages with nftables
+    #[serde(default = "super::default_firewall")]
+    pub firewall: bool,
+    /// Reload the languages when their files change, they are also reloaded on SIGHUP
+    #[serde(default = "super::default_watch_config")]
+    pub watch_config: bool,
+}
+
+impl From<LambdoConfig> for super::LambdoConfig {
+    fn from(config: LambdoConfig) -> Self {
+        super::LambdoConfig {
+            apiVersion: super::API_VERSION.to_string(),
+            kind: config.kind,
+            vmm: config.vmm,
+            api: config.api.into(),
+            agent: config.agent,
+            languages: config.languages,
+            languages_dir: config.languages_dir,
+            tracing: config.tracing,
+            logging: config.logging,
+            auth: config.auth,
+            language_files: Vec::new(),
+        }
+    }
+}
+
+impl From<LambdoApiConfig> for super::LambdoApiConfig {
+    fn from(api: LambdoApiConfig) -> Self {
+        super::LambdoApiConfig {
+            bridge: api.bridge,
+            bridge_address: api.bridge_address,
+            ip_range: api.ip_range,
+            bridge_address_v6: api.bridge_address_v6,
+            ip_range_v6: api.ip_range_v6,
+            web_host: api.web_host,
+            web_port: api.web_port,
+            grpc_host: api.grpc_host,
+            grpc_port: api.gprc_port,
+            state_file: api.state_file,
+            drain_timeout: api.drain_timeout,
+            admin_token: api.admin_token,
+            max_running: api.max_running,
+            max_queued: api.max_queued,
+            firewall: api.firewall,
+            watch_config: api.watch_config,
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use futures_util::{stream, Stream};
+use log::debug;
+use tokio::sync::watch;
+
+use crate::model::{Execution, JobEvent, JobStatus, JobSummary, RunResponse};
+
+/// The number of ended jobs kept for their clients, the oldest ones are forgotten first
+pub const MAX_ENDED_JOBS: usize = 1024;
+
+/// The executions run in the background, followed by their clients
+#[derive(Default)]
+pub struct Jobs {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    jobs: HashMap<String, Job>,
+    /// The IDs of the ended jobs, the oldest first
+    ended: VecDeque<String>,
+}
+
+struct Job {
+    /// The API key that submitted the job, only its owner can follow it
+    owner: Option<String>,
+    sender: watch::Sender<JobProgress>,
+}
+
+/// A job along with the output the agent streamed so far
+pub struct JobProgress {
+    pub summary: JobSummary,
+    /// The output events sent while the job runs, in order
+    output: Vec<JobEvent>,
+}
+
+impl Jobs {
+    /// Record a queued job
+    ///
+    /// # Arguments
+    ///
+    /// * `execution` - The execution run by the job
+    /// * `owner` - The name of the API key that submitted the job, if the API requires one
+    pub fn submit(&self, execution: &Execution, owner: Option<String>) -> JobSummary {
+        let summary = JobSummary {
+            id: execution.id.clone(),
+            language: execution.request.language.clone(),
+            version: execution.request.version.clone(),
+            status: JobStatus::Queued,
+            response: None,
+        };
+        let (sender, _) = watch::channel(JobProgress {
+            summary: summary.clone(),
+            output: Vec::new(),
+        });
+
+        self.lock()
+            .jobs
+            .insert(summary.id.clone(), Job { owner, sender });
+        summary
+    }
+
+    /// Mark a job as running
+    pub fn start(&self, id: &str) {
+        if let Some(job) = self.lock().jobs.get(id) {
+            job.sender
+                .send_modify(|job| job.summary.status = JobStatus::Running);
+        }
+    }
+
+    /// Record a piece of the output of a running job
+    pub fn output(&self, id: &str, stdout: String, stderr: String) {
+        if let Some(job) = self.lock().jobs.get(id) {
+            job.sender
+                .send_modify(|job| job.output.push(JobEvent::Output { stdout, stderr }));
+        }
+    }
+
+    /// Record the result of a job, the oldest ended jobs are forgotten
+    pub fn end(&self, id: &str, response: RunResponse) {
+        let mut inner = self.lock();
+        let job = match inner.jobs.get(id) {
+            Some(job) => job,
+            None => return,
+        };
+        job.sender.send_modify(|job| {
+            job.summary.status = JobStatus::Ended;
+            job.summary.response = Some(response);
+        });
+
+        inner.ended.push_back(id.to_string());
+        while inner.ended.len() > MAX_ENDED_JOBS {
+            if let Some(oldest) = inner.ended.pop_front() {
+                debug!("Forgetting job {}", oldest);
+                inner.jobs.remove(&oldest);
+            }
+        }
+    }
+
+    /// A job, if the client can see it
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the job
+    /// * `owner` - The name of the API key of the client, if the API requires one
+    pub fn get(&self, id: &str, owner: Option<&str>) -> Option<JobSummary> {
+        self.follow(id, owner)
+            .map(|receiver| receiver.borrow().summary.clone())
+    }
+
+    /// The jobs the client can see, the queued and running ones first
+    pub fn list(&self, owner: Option<&str>) -> Vec<JobSummary> {
+        let mut jobs = self
+            .lock()
+            .jobs
+            .values()
+            .filter(|job| owner.is_none() || job.owner.as_deref() == owner)
+            .map(|job| job.sender.borrow().summary.clone())
+            .collect::<Vec<_>>();
+        jobs.sort_by_key(|job| (job.status == JobStatus::Ended, job.id.clone()));
+        jobs
+    }
+
+    /// Follow the changes of a job, if the client can see it
+    pub fn follow(&self, id: &str, owner: Option<&str>) -> Option<watch::Receiver<JobProgress>> {
+        self.lock()
+            .jobs
+            .get(id)
+            .filter(|job| owner.is_none() || job.owner.as_deref() == owner)
+            .map(|job| job.sender.subscribe())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The events of a job, from its current status until it ends
+///
+/// The output streamed before the client followed the job is sent again. The stream
+/// stops early if the job is forgotten.
+pub fn events(receiver: watch::Receiver<JobProgress>) -> impl Stream<Item = JobEvent> {
+    let state = (receiver, None, 0, VecDeque::new());
+    stream::unfold(
+        state,
+        |(mut receiver, mut sent, mut streamed, mut pending)| async move {
+            loop {
+                if let Some(event) = pending.pop_front() {
+                    return Some((event, (receiver, sent, streamed, pending)));
+                }
+                if sent == Some(JobStatus::Ended) {
+                    return None;
+                }
+
+                {
+                    let job = receiver.borrow_and_update();
+                    pending.extend(events_of(&job, sent, streamed));
+                    sent = Some(job.summary.status);
+                    streamed = job.output.len();
+                }
+                if !pending.is_empty() || sent == Some(JobStatus::Ended) {
+                    continue;
+                }
+
+                if receiver.changed().await.is_err() {
+                    return None;
+                }
+            }
+        },
+    )
+}
+
+/// The events telling a client what happened to a job since it was last told
+///
+/// # Arguments
+///
+/// * `job` - The job as it is now
+/// * `sent` - The last status the client was told about
+/// * `streamed` - The number of output events the client was sent
+fn events_of(job: &JobProgress, sent: Option<JobStatus>, streamed: usize) -> Vec<JobEvent> {
+    let mut events = Vec::new();
+    match job.summary.status {
+        status if sent == Some(status) => {}
+        JobStatus::Queued => events.push(JobEvent::Queued),
+        JobStatus::Running => events.push(JobEvent::Running),
+        JobStatus::Ended => {}
+    }
+    events.extend(job.output.iter().skip(streamed).cloned());
+
+    if let (JobStatus::Ended, Some(response)) = (job.summary.status, &job.summary.response) {
+        // The agents without the streaming capability send the output with the response
+        if job.output.is_empty() {
+            events.push(JobEvent::Output {
+                stdout: response.stdout.clone(),
+                stderr: response.stderr.clone(),
+            });
+        }
+        events.push(JobEvent::Ended {
+            status: response.status,
+            queued_ms: response.queued_ms,
+        });
+    }
+    events
+}
+
+#[cfg(test)]
+mod test {
+    use futures_util::StreamExt;
+
+    use super::{events, Jobs, MAX_ENDED_JOBS};
+    use crate::model::{Execution, JobEvent, JobStatus, RunRequest, RunResponse};
+
+    fn generate_execution() -> Execution {
+        Execution::new(RunRequest {
+            language: "NODE".to_string(),
+            version: "12".to_string(),
+            input: "".to_string(),
+            code: vec![],
+            artifacts: vec![],
+        })
+    }
+
+    fn generate_response(status: u8) -> RunResponse {
+        RunResponse {
+            status,
+            stdout: "Hello".to_string(),
+            stderr: "".to_string(),
+            combined: "Hello".to_string(),
+            artifacts: vec![],
+            queued_ms: 3,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_job_events() {
+        let jobs = Jobs::default();
+        let execution = generate_execution();
+        jobs.submit(&execution, None);
+        let stream = events(jobs.follow(&execution.id, None).unwrap());
+
+        jobs.start(&execution.id);
+        jobs.end(&execution.id, generate_response(0));
+
+        // The statuses the stream did not see in time are skipped
+        assert_eq!(
+            stream.collect::<Vec<_>>().await,
+            vec![
+                JobEvent::Output {
+                    stdout: "Hello".to_string(),
+                    stderr: "".to_string(),
+                },
+                JobEvent::Ended {
+                    status: 0,
+                    queued_ms: 3,
+                },
+            ]
+        );
+
+        let job = jobs.get(&execution.id, None).unwrap();
+        assert_eq!(job.status, JobStatus::Ended);
+        assert_eq!(job.response, Some(generate_response(0)));
+    }
+
+    #[tokio::test]
+    async fn test_job_events_follow_the_job() {
+        let jobs = Jobs::default();
+        let execution = generate_execution();
+        jobs.submit(&execution, None);
+        let mut stream = Box::pin(events(jobs.follow(&execution.id, None).unwrap()));
+
+        assert_eq!(stream.next().await, Some(JobEvent::Queued));
+        jobs.start(&execution.id);
+        assert_eq!(stream.next().await, Some(JobEvent::Running));
+        jobs.end(&execution.id, generate_response(1));
+        assert!(matches!(stream.next().await, Some(JobEvent::Output { .. })));
+        assert!(matches!(
+            stream.next().await,
+            Some(JobEvent::Ended { status: 1, .. })
+        ));
+        assert_eq!(stream.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_job_events_stream_the_output() {
+        let jobs = Jobs::default();
+        let execution = generate_execution();
+        jobs.submit(&execution, None);
+        let mut stream = Box::pin(events(jobs.follow(&execution.id, None).unwrap()));
+        let output = |stdout: &str| JobEvent::Output {
+            stdout: stdout.to_string(),
+            stderr: "".to_string(),
+        };
+
+        assert_eq!(stream.next().await, Some(JobEvent::Queued));
+        jobs.start(&execution.id);
+        jobs.output(&execution.id, "Hel".to_string(), "".to_string());
+        assert_eq!(stream.next().await, Some(JobEvent::Running));
+        assert_eq!(stream.next().await, Some(output("Hel")));
+        jobs.output(&execution.id, "lo".to_string(), "".to_string());
+        assert_eq!(stream.next().await, Some(output("lo")));
+
+        // The output was sent as it came, not again with the response
+        jobs.end(&execution.id, generate_response(0));
+        assert_eq!(
+            stream.next().await,
+            Some(JobEvent::Ended {
+                status: 0,
+                queued_ms: 3,
+            })
+        );
+        assert_eq!(stream.next().await, None);
+
+        // A client following the job later is sent the output too
+        assert_eq!(
+            events(jobs.follow(&execution.id, None).unwrap())
+                .collect::<Vec<_>>()
+                .await,
+            vec![
+                output("Hel"),
+                output("lo"),
+                JobEvent::Ended {
+                    status: 0,
+                    queued_ms: 3,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jobs_of_their_owner() {
+        let jobs = Jobs::default();
+        let mine = generate_execution();
+        let theirs = generate_execution();
+        jobs.submit(&mine, Some("ci".to_string()));
+        jobs.submit(&theirs, Some("nightly".to_string()));
+
+        assert!(jobs.get(&mine.id, Some("ci")).is_some());
+        assert!(jobs.get(&theirs.id, Some("ci")).is_none());
+        assert!(jobs.follow(&theirs.id, Some("ci")).is_none());
+        assert_eq!(jobs.list(Some("ci")).len(), 1);
+        // Without API keys, every job can be seen
+        assert_eq!(jobs.list(None).len(), 2);
+    }
+
+    #[test]
+    fn test_ended_jobs_are_forgotten() {
+        let jobs = Jobs::default();
+        let first = generate_execution();
+        jobs.submit(&first, None);
+        jobs.end(&first.id, generate_response(0));
+
+        for _ in 0..MAX_ENDED_JOBS {
+            let execution = generate_execution();
+            jobs.submit(&execution, None);
+            jobs.end(&execution.id, generate_response(0));
+        }
+
+        assert!(jobs.get(&first.id, None).is_none());
+        assert_eq!(jobs.list(None).len(), MAX_ENDED_JOBS);
+    }
+}
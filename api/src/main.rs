@@ -1,23 +1,49 @@
 pub mod api;
+pub mod audit;
+pub mod auth;
 pub mod config;
+pub mod jobs;
+pub mod metrics;
 pub mod model;
+pub mod reload;
+pub mod scheduler;
+pub mod shutdown;
+pub mod telemetry;
 pub mod vm_manager;
 
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use config::LambdoConfig;
+use config::{LambdoConfig, LambdoLanguageFile};
 use thiserror::Error;
 
 use crate::{
-    api::{post_run_route, service::LambdoApiService},
+    api::{
+        admin::{
+            delete_vm_route, get_keys_route, get_vm_console_route, get_vms_route, post_drain_route,
+            post_warm_route,
+        },
+        get_languages_route, get_metrics_route,
+        jobs::{get_job_route, get_job_stream_route, get_jobs_route, post_job_route},
+        post_run_route,
+        service::{LambdoApiService, LambdoApiServiceTrait},
+    },
+    audit::AuditLog,
+    auth::Authenticator,
+    jobs::Jobs,
+    scheduler::Scheduler,
+    shutdown::Shutdown,
     vm_manager::grpc_definitions::lambdo_api_service_server::LambdoApiServiceServer,
     vm_manager::state::LambdoState,
     vm_manager::VMListener,
+    vm_manager::{run_in_process, VMMOpts},
 };
 use actix_web::{web, App, HttpServer};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use log::{debug, error, info, trace};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify};
 
 #[derive(Parser)]
 #[clap(
@@ -27,8 +53,35 @@ use tokio::sync::Mutex;
 )]
 pub struct LambdoOpts {
     /// Config file path
-    #[clap(short, long, default_value = "/etc/lambdo/config.yaml")]
+    #[clap(short, long, default_value = "/etc/lambdo/config.yaml", global = true)]
     config: String,
+    #[clap(subcommand)]
+    command: Option<LambdoCommand>,
+}
+
+#[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)]
+enum LambdoCommand {
+    /// Manage the config file
+    #[clap(subcommand)]
+    Config(ConfigCommand),
+    /// Run the VMM of a VM, started by the API for each VM
+    #[clap(hide = true)]
+    Vmm(VMMOpts),
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Validate the config file without starting the servers
+    Check,
+    /// Rewrite the config file and its language files in the current api version
+    Migrate,
+    /// Print the JSON Schema of the config file, for the editors
+    Schema {
+        /// Print the schema of the language files instead
+        #[clap(long)]
+        language: bool,
+    },
 }
 
 #[derive(Error, Debug)]
@@ -41,21 +94,40 @@ pub enum LambdoError {
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init();
+    // Forward the logs to the tracing subscriber
+    tracing_log::LogTracer::init().expect("failed to forward the logs");
     let options = LambdoOpts::parse();
 
-    info!("starting up ...");
+    match options.command {
+        Some(LambdoCommand::Config(ConfigCommand::Check)) => return check_config(&options.config),
+        Some(LambdoCommand::Config(ConfigCommand::Migrate)) => {
+            return migrate_config(&options.config)
+        }
+        Some(LambdoCommand::Config(ConfigCommand::Schema { language })) => {
+            return print_schema(language)
+        }
+        Some(LambdoCommand::Vmm(opts)) => return run_vmm(opts),
+        None => {}
+    }
 
-    debug!("loading config file at {}", options.config);
     let config = LambdoConfig::load(options.config.as_str()).unwrap();
+    telemetry::init(&config.tracing, config.logging.format).unwrap();
+
+    info!("starting up ...");
+    debug!("loaded config file at {}", options.config);
     trace!(
         "config file loaded successfully with content: {:#?}",
         config
     );
 
     info!("setting up");
-    let lambdo_state = Arc::new(Mutex::new(LambdoState::new(config.clone())));
+    let lambdo_state = LambdoState::new(config.clone());
+    // Read by the admin API and the metrics without waiting for the executions
+    let app_directory = web::Data::new(lambdo_state.directory.clone());
+    let app_metrics = web::Data::new(lambdo_state.metrics.clone());
+    let lambdo_state = Arc::new(Mutex::new(lambdo_state));
     let lambdo_state_clone = lambdo_state.clone();
+    let app_lambdo_state = web::Data::from(lambdo_state.clone());
 
     let api_service = LambdoApiService::new_with_state(lambdo_state)
         .await
@@ -66,33 +138,197 @@ async fn main() -> std::io::Result<()> {
 
     info!("everything is set up, starting servers");
 
+    let shutdown = Shutdown::default();
+    let grpc_stop = Arc::new(Notify::new());
+
     let grpc_host = config.api.grpc_host.clone();
-    let grpc_port = config.api.gprc_port;
-    // TODO: Shut down the web server when the gRPC server is down, and vice versa
-    tokio::spawn(async move {
+    let grpc_port = config.api.grpc_port;
+    let grpc_stopped = grpc_stop.clone();
+    let mut grpc_server = tokio::spawn(async move {
         let addr = format!("{}:{}", grpc_host, grpc_port).parse().unwrap();
         info!("Starting gRPC server on {}", addr);
         let vm_handler = VMListener::new(lambdo_state_clone);
         tonic::transport::Server::builder()
             .add_service(LambdoApiServiceServer::new(vm_handler))
-            .serve(addr)
+            .serve_with_shutdown(addr, grpc_stopped.notified())
             .await
-            .unwrap_or_else(|e| {
-                error!("GRPC Server failure");
-                panic!("{}", e)
-            });
     });
 
     let http_host = &config.api.web_host;
     let http_port = config.api.web_port;
+    let drain_timeout = Duration::from_secs(config.api.drain_timeout);
     let app_state = web::Data::new(api_service);
+    // The executions only need the service, the admin API and the reloads need its state
+    let app_service: web::Data<dyn LambdoApiServiceTrait> =
+        web::Data::from(app_state.clone().into_inner() as Arc<dyn LambdoApiServiceTrait>);
+    let app_jobs = web::Data::new(Jobs::default());
+    let app_shutdown = web::Data::new(shutdown.clone());
+    let app_audit = web::Data::new(
+        AuditLog::new(config.logging.audit.as_ref())
+            .map_err(|e| {
+                error!("failed to open the audit log: {}", e);
+            })
+            .unwrap(),
+    );
+    let app_scheduler = web::Data::new(Scheduler::new(
+        config.api.max_running,
+        config.api.max_queued,
+    ));
+    let app_authenticator = config.auth.as_ref().map(|auth| {
+        web::Data::new(
+            Authenticator::new(auth)
+                .map_err(|e| {
+                    error!("failed to load the API keys: {}", e);
+                })
+                .unwrap(),
+        )
+    });
+    let reloader = tokio::spawn(reload::watch(
+        options.config.clone(),
+        config.api.watch_config,
+        app_state.clone(),
+    ));
+
     info!("Starting web server on {}:{}", http_host, http_port);
-    HttpServer::new(move || {
-        App::new()
-            .app_data(app_state.clone())
+    let app_state_clone = app_state.clone();
+    let http_server = HttpServer::new(move || {
+        let app = match &app_authenticator {
+            Some(authenticator) => App::new().app_data(authenticator.clone()),
+            None => App::new(),
+        };
+        app.app_data(app_state_clone.clone())
+            .app_data(app_service.clone())
+            .app_data(app_jobs.clone())
+            .app_data(app_shutdown.clone())
+            .app_data(app_audit.clone())
+            .app_data(app_scheduler.clone())
+            .app_data(app_lambdo_state.clone())
+            .app_data(app_directory.clone())
+            .app_data(app_metrics.clone())
             .service(post_run_route)
+            .service(post_job_route)
+            .service(get_jobs_route)
+            .service(get_job_route)
+            .service(get_job_stream_route)
+            .service(get_languages_route)
+            .service(get_metrics_route)
+            .service(post_drain_route)
+            .service(get_vms_route)
+            .service(get_keys_route)
+            .service(delete_vm_route)
+            .service(get_vm_console_route)
+            .service(post_warm_route)
     })
+    .disable_signals()
+    .shutdown_timeout(config.api.drain_timeout)
     .bind((http_host.clone(), http_port))?
-    .run()
-    .await
+    .run();
+    let http_handle = http_server.handle();
+    let mut http_server = tokio::spawn(http_server);
+
+    // Whichever comes first: a server failure, a signal or a drain request
+    let mut failed = false;
+    tokio::select! {
+        result = &mut grpc_server => {
+            error!("gRPC server stopped: {:?}", result);
+            failed = true;
+        }
+        result = &mut http_server => {
+            error!("Web server stopped: {:?}", result);
+            failed = true;
+        }
+        result = shutdown::signal_received() => {
+            if let Err(e) = result {
+                error!("Failed to listen to signals: {}", e);
+                failed = true;
+            }
+        }
+        _ = shutdown.requested() => {}
+    }
+
+    info!("Draining, no execution is accepted anymore");
+    shutdown.start_draining();
+
+    // The web server and the VMs share the drain timeout
+    let deadline = Instant::now() + drain_timeout;
+    http_handle.stop(true).await;
+    if let Err(e) = app_state
+        .vm_manager
+        .shutdown(deadline.saturating_duration_since(Instant::now()))
+        .await
+    {
+        error!("Failed to tear down the VMs: {}", e);
+        failed = true;
+    }
+
+    reloader.abort();
+    grpc_stop.notify_one();
+    if !grpc_server.is_finished() {
+        grpc_server.await.ok();
+    }
+
+    if failed {
+        error!("Stopped after a failure");
+        telemetry::shutdown();
+        std::process::exit(1);
+    }
+
+    info!("Stopped");
+    telemetry::shutdown();
+    Ok(())
+}
+
+/// Run the VMM of a VM until it stops, exiting with an error if it fails
+fn run_vmm(opts: VMMOpts) -> std::io::Result<()> {
+    if let Err(e) = run_in_process(opts) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Validate a config file, exiting with an error if it cannot be used
+fn check_config(path: &str) -> std::io::Result<()> {
+    match LambdoConfig::load(path) {
+        Ok(_) => {
+            println!("{} is valid", path);
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("{}: {:#}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Rewrite a config file in the current api version, exiting with an error if it cannot be read
+fn migrate_config(path: &str) -> std::io::Result<()> {
+    match LambdoConfig::migrate(path) {
+        Ok(migrated) if migrated.is_empty() => {
+            println!("{} is already in {}", path, config::API_VERSION);
+            Ok(())
+        }
+        Ok(migrated) => {
+            for file in migrated {
+                println!("{} migrated to {}", file, config::API_VERSION);
+            }
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("{}: {:#}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Print the JSON Schema of the config or language files
+fn print_schema(language: bool) -> std::io::Result<()> {
+    let schema = if language {
+        schemars::schema_for!(LambdoLanguageFile)
+    } else {
+        schemars::schema_for!(LambdoConfig)
+    };
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+    Ok(())
 }
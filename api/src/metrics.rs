@@ -0,0 +1,187 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use lambdo_client::model::VMSummary;
+
+use crate::vm_manager::{grpc_definitions::ExecuteResponse, state::VMStatus, Error};
+
+/// The statuses a VM goes through, as exported in the pool gauge
+const VM_STATUSES: [(VMStatus, &str); 4] = [
+    (VMStatus::Waiting, "waiting"),
+    (VMStatus::Ready, "ready"),
+    (VMStatus::Running, "running"),
+    (VMStatus::Ended, "ended"),
+];
+
+/// The figures exported on `/metrics`, in the Prometheus text format
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    /// Executions, by language and outcome
+    pub requests: IntCounterVec,
+    /// Duration of the executions, by language and outcome
+    pub request_duration: HistogramVec,
+    /// Time between the start of a VM and its agent being ready, by language
+    pub boot_duration: HistogramVec,
+    /// VMs of the pool, by status
+    pub vms: IntGaugeVec,
+    /// IPv4 addresses of the bridge network given to VMs
+    pub ips_used: IntGauge,
+    /// IPv4 addresses of the bridge network available for VMs
+    pub ips_total: IntGauge,
+    /// Executions that timed out
+    pub timeouts: IntCounter,
+    /// Errors reported by the agents
+    pub agent_errors: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("lambdo".to_string()), None)
+            // Safe since the registry has no other prefix
+            .unwrap();
+
+        let requests = IntCounterVec::new(
+            Opts::new("requests_total", "Executions, by language and outcome"),
+            &["language", "outcome"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "request_duration_seconds",
+                "Duration of the executions, by language and outcome",
+            ),
+            &["language", "outcome"],
+        )
+        .unwrap();
+        let boot_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "vm_boot_duration_seconds",
+                "Time between the start of a VM and its agent being ready",
+            )
+            .buckets(vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]),
+            &["language"],
+        )
+        .unwrap();
+        let vms =
+            IntGaugeVec::new(Opts::new("vms", "VMs of the pool, by status"), &["status"]).unwrap();
+        let ips_used = IntGauge::new("ip_pool_used", "Addresses given to VMs").unwrap();
+        let ips_total = IntGauge::new("ip_pool_size", "Addresses available for VMs").unwrap();
+        let timeouts = IntCounter::new("timeouts_total", "Executions that timed out").unwrap();
+        let agent_errors =
+            IntCounter::new("agent_errors_total", "Errors reported by the agents").unwrap();
+
+        // Safe since every metric has its own name
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry
+            .register(Box::new(request_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(boot_duration.clone())).unwrap();
+        registry.register(Box::new(vms.clone())).unwrap();
+        registry.register(Box::new(ips_used.clone())).unwrap();
+        registry.register(Box::new(ips_total.clone())).unwrap();
+        registry.register(Box::new(timeouts.clone())).unwrap();
+        registry.register(Box::new(agent_errors.clone())).unwrap();
+
+        Metrics {
+            registry,
+            requests,
+            request_duration,
+            boot_duration,
+            vms,
+            ips_used,
+            ips_total,
+            timeouts,
+            agent_errors,
+        }
+    }
+
+    /// Record the outcome of an execution
+    ///
+    /// # Arguments
+    ///
+    /// * `language` - The language of the execution
+    /// * `result` - The result given back to the API
+    /// * `duration` - The time spent on the execution, VM boot included
+    pub fn observe_request(
+        &self,
+        language: &str,
+        result: &Result<ExecuteResponse, Error>,
+        duration: Duration,
+    ) {
+        let outcome = match result {
+            Ok(_) => "success",
+            Err(Error::Timeout) => {
+                self.timeouts.inc();
+                "timeout"
+            }
+            Err(_) => "error",
+        };
+
+        self.requests.with_label_values(&[language, outcome]).inc();
+        self.request_duration
+            .with_label_values(&[language, outcome])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Update the gauge of the VMs of the pool, by status
+    ///
+    /// The address gauges are updated by the state whenever VMs are added or removed.
+    pub fn observe_vms(&self, vms: &[VMSummary]) {
+        for (status, label) in VM_STATUSES {
+            let count = vms.iter().filter(|vm| vm.status == status).count();
+            self.vms.with_label_values(&[label]).set(count as i64);
+        }
+    }
+
+    /// Encode the metrics in the Prometheus text format
+    pub fn encode(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| anyhow!("failed to encode metrics: {}", e))?;
+
+        String::from_utf8(buffer).map_err(|e| anyhow!("invalid metrics encoding: {}", e))
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::Metrics;
+    use crate::vm_manager::{grpc_definitions::ExecuteResponse, Error};
+
+    #[test]
+    fn test_observe_requests() {
+        let metrics = Metrics::new();
+
+        metrics.observe_request(
+            "NODE",
+            &Ok(ExecuteResponse::default()),
+            Duration::from_millis(300),
+        );
+        metrics.observe_request("NODE", &Err(Error::Timeout), Duration::from_secs(15));
+        metrics.agent_errors.inc();
+
+        let encoded = metrics.encode().unwrap();
+        assert!(encoded.contains(r#"lambdo_requests_total{language="NODE",outcome="success"} 1"#));
+        assert!(encoded.contains(r#"lambdo_requests_total{language="NODE",outcome="timeout"} 1"#));
+        assert!(encoded.contains(
+            r#"lambdo_request_duration_seconds_count{language="NODE",outcome="timeout"} 1"#
+        ));
+        assert!(encoded.contains("lambdo_timeouts_total 1"));
+        assert!(encoded.contains("lambdo_agent_errors_total 1"));
+    }
+}
@@ -1,22 +1,101 @@
 use serde::Deserialize;
 use serde::Serialize;
+use std::time::Duration;
+use uuid::Uuid;
 
-use crate::config::LambdoLanguageConfig;
-use crate::vm_manager::grpc_definitions::FileModel;
+use crate::config::{LambdoLanguageConfig, LambdoNetworkPolicy};
+use crate::vm_manager::grpc_definitions::{Artifact, FileModel};
+use crate::vm_manager::state::{OutputSender, VMState};
+
+pub use lambdo_client::model::{
+    CodeFile, JobEvent, JobStatus, JobSummary, KeyUsage, LanguageSummary, RunArtifact, RunRequest,
+    RunResponse, VMSummary, WarmResponse,
+};
+
+/// An execution accepted by the API, the request of its client along with what the API gave it
+#[derive(Debug, Clone)]
+pub struct Execution {
+    /// The ID of the execution, given by the API
+    pub id: String,
+    /// The limits of the execution, given by the API key of the client
+    pub limits: ExecutionLimits,
+    pub request: RunRequest,
+    /// Where the output is sent as the agent streams it, if a client follows the execution
+    pub output: Option<OutputSender>,
+}
+
+impl Execution {
+    /// Give an ID to the request of a client, it runs within the default limits
+    pub fn new(request: RunRequest) -> Self {
+        Execution {
+            id: Uuid::new_v4().to_string(),
+            limits: ExecutionLimits::default(),
+            request,
+            output: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RunFormat {
+    /// The artifacts are returned as base64 entries of the JSON response
+    #[default]
+    Json,
+    /// The artifacts are returned as a tar archive
+    Tar,
+}
 
 #[derive(Deserialize, Debug)]
-pub struct RunRequest {
-    pub language: String,
-    pub version: String,
-    pub input: String,
-    pub code: Vec<FileModel>,
+pub struct RunQuery {
+    #[serde(default)]
+    pub format: RunFormat,
+}
+
+impl From<CodeFile> for FileModel {
+    fn from(file: CodeFile) -> Self {
+        FileModel {
+            filename: file.filename,
+            content: file.content,
+        }
+    }
+}
+
+impl From<Artifact> for RunArtifact {
+    fn from(artifact: Artifact) -> Self {
+        RunArtifact {
+            path: artifact.path,
+            content: artifact.content,
+            size: artifact.size,
+            truncated: artifact.truncated,
+        }
+    }
+}
+
+impl From<&VMState> for VMSummary {
+    fn from(vm: &VMState) -> Self {
+        VMSummary {
+            id: vm.id.clone(),
+            language: vm.language_settings.name.clone(),
+            version: vm.language_settings.version.clone(),
+            status: vm.get_state(),
+            ip: vm.vm_opts.ip.map(|ip| ip.to_string()),
+            tap: vm.vm_opts.tap.clone(),
+            reserved: vm.reserved,
+            age: vm.age().as_secs(),
+            last_request: vm.request.as_ref().map(|request| request.id.clone()),
+            last_request_age: vm.last_execution().map(|age| age.as_secs()),
+        }
+    }
 }
 
-#[derive(Serialize, Debug)]
-pub struct RunResponse {
-    pub status: u8,
-    pub stdout: String,
-    pub stderr: String,
+impl From<&LambdoLanguageConfig> for LanguageSummary {
+    fn from(language: &LambdoLanguageConfig) -> Self {
+        LanguageSummary {
+            name: language.name.clone(),
+            version: language.version.clone(),
+        }
+    }
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -24,6 +103,33 @@ pub struct LanguageSettings {
     pub name: String,
     pub version: String,
     pub initramfs: String,
+    /// The archive added after the initramfs, holding the package cache
+    pub cache_image: Option<String>,
+    pub network: LambdoNetworkPolicy,
+}
+
+/// The time an execution is given when its API key does not lower it
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The memory of the VMs when the API key of the execution does not lower it, in MiB
+pub const DEFAULT_MEMORY: u32 = 1024;
+
+/// The limits an execution runs within
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExecutionLimits {
+    /// The time after which the execution is abandoned
+    pub timeout: Duration,
+    /// The maximum memory of the VM running the execution, in MiB
+    pub memory: u32,
+}
+
+impl Default for ExecutionLimits {
+    fn default() -> Self {
+        ExecutionLimits {
+            timeout: DEFAULT_TIMEOUT,
+            memory: DEFAULT_MEMORY,
+        }
+    }
 }
 
 impl From<LambdoLanguageConfig> for LanguageSettings {
@@ -32,6 +138,10 @@ impl From<LambdoLanguageConfig> for LanguageSettings {
             name: config.name,
             version: config.version,
             initramfs: config.initramfs,
+            cache_image: config
+                .dependencies
+                .and_then(|dependencies| dependencies.cache_image),
+            network: config.network,
         }
     }
 }
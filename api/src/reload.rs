@@ -0,0 +1,88 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use actix_web::web;
+use log::{debug, error, info};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{api::service::LambdoApiService, config::LambdoConfig};
+
+/// How often the config file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Reload the languages on SIGHUP, or when the config file or a language file changes
+///
+/// A config that cannot be loaded is rejected, the current one is kept.
+///
+/// # Arguments
+///
+/// * `path` - The path of the config file
+/// * `watch_file` - Whether to reload when the config file or a language file changes
+/// * `api_service` - The service the new languages are given to
+pub async fn watch(
+    path: String,
+    watch_file: bool,
+    api_service: web::Data<LambdoApiService>,
+) -> std::io::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+    let mut modified = modified_at(&path, &api_service);
+
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => info!("Received SIGHUP, reloading {}", path),
+            _ = interval.tick(), if watch_file => {
+                let current = modified_at(&path, &api_service);
+                if current == modified {
+                    continue;
+                }
+                modified = current;
+                info!("{} changed, reloading", path);
+            }
+        }
+
+        let config = match LambdoConfig::load(&path) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Keeping the current config, {} is rejected: {:#}", path, e);
+                continue;
+            }
+        };
+        match api_service.reload(config).await {
+            Ok(()) => info!("Reloaded {}", path),
+            Err(e) => error!("Failed to reload the languages: {}", e),
+        }
+    }
+}
+
+/// When the config file and the language files were last modified, if they can be read
+fn modified_at(path: &str, api_service: &LambdoApiService) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let mut paths = vec![PathBuf::from(path)];
+    let languages_dir = api_service.config().languages_dir_path(path);
+    if let Some(directory) = languages_dir {
+        // The directory changes when a file is added or removed
+        paths.push(directory.clone());
+        if let Ok(entries) = fs::read_dir(&directory) {
+            paths.extend(entries.filter_map(|entry| entry.ok().map(|entry| entry.path())));
+        }
+    }
+    paths.sort();
+
+    paths
+        .into_iter()
+        .map(|path| {
+            let modified = modified(&path);
+            (path, modified)
+        })
+        .collect()
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .map_err(|e| debug!("Failed to read the metadata of {}: {}", path.display(), e))
+        .ok()
+}
@@ -0,0 +1,433 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{debug, trace};
+use tokio::sync::oneshot;
+
+/// The time a client is told to wait when no execution has ended yet
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Why an execution was not admitted
+#[derive(Debug, PartialEq)]
+pub enum Overloaded {
+    /// The queue of the language is full, the client should retry after the given time
+    QueueFull(Duration),
+}
+
+/// Limits the executions running at once, and queues the others
+///
+/// The waiting executions are queued by language, each queue being bounded. Once an
+/// execution ends, the next one is taken from the clients in turn, so that a client
+/// sending a burst of requests does not hold back the others.
+pub struct Scheduler {
+    max_running: usize,
+    max_queued: usize,
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    running: usize,
+    next_ticket: u64,
+    /// The clients with waiting executions, the first one is served next
+    clients: VecDeque<ClientQueue>,
+    /// The number of waiting executions, by language
+    queued: HashMap<String, usize>,
+    /// The average duration of the executions, to tell the clients when to retry
+    average: Option<Duration>,
+}
+
+struct ClientQueue {
+    client: String,
+    waiters: VecDeque<Waiter>,
+}
+
+struct Waiter {
+    ticket: u64,
+    language: String,
+    sender: oneshot::Sender<()>,
+}
+
+impl Scheduler {
+    /// # Arguments
+    ///
+    /// * `max_running` - The maximum number of executions running at once
+    /// * `max_queued` - The maximum number of executions waiting, for each language
+    pub fn new(max_running: usize, max_queued: usize) -> Self {
+        Scheduler {
+            max_running,
+            max_queued,
+            inner: Arc::new(Mutex::new(Inner::default())),
+        }
+    }
+
+    /// Wait for an execution to be allowed to run
+    ///
+    /// # Arguments
+    ///
+    /// * `language` - The language of the execution
+    /// * `client` - Who asked for the execution, the clients are served in turn
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Slot, Overloaded>` - The slot of the execution, released once dropped
+    pub async fn admit(&self, language: &str, client: &str) -> Result<Slot, Overloaded> {
+        Ok(self.enqueue(language, client)?.wait().await)
+    }
+
+    /// Queue an execution without waiting for its turn
+    ///
+    /// # Arguments
+    ///
+    /// * `language` - The language of the execution
+    /// * `client` - Who asked for the execution, the clients are served in turn
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Admission, Overloaded>` - The place of the execution, removed from the queue if dropped
+    pub fn enqueue(&self, language: &str, client: &str) -> Result<Admission, Overloaded> {
+        let start = Instant::now();
+        let mut inner = self.lock();
+        if inner.running < self.max_running && inner.clients.is_empty() {
+            inner.running += 1;
+            trace!("Execution admitted, {} running", inner.running);
+            return Ok(Admission(Turn::Now(Slot {
+                queued: start.elapsed(),
+                start: Instant::now(),
+                inner: self.inner.clone(),
+                max_running: self.max_running,
+            })));
+        }
+
+        let queued = inner.queued.entry(language.to_string()).or_default();
+        if *queued >= self.max_queued {
+            debug!("Queue of {} is full, refusing execution", language);
+            return Err(Overloaded::QueueFull(inner.retry_after(self.max_running)));
+        }
+        *queued += 1;
+
+        let (sender, receiver) = oneshot::channel();
+        inner.next_ticket += 1;
+        let waiter = Waiter {
+            ticket: inner.next_ticket,
+            language: language.to_string(),
+            sender,
+        };
+        match inner
+            .clients
+            .iter_mut()
+            .find(|queue| queue.client == client)
+        {
+            Some(queue) => queue.waiters.push_back(waiter),
+            None => inner.clients.push_back(ClientQueue {
+                client: client.to_string(),
+                waiters: VecDeque::from([waiter]),
+            }),
+        }
+        debug!("Execution of {} queued for {}", language, client);
+
+        let ticket = Ticket {
+            id: inner.next_ticket,
+            receiver,
+            granted: false,
+            inner: self.inner.clone(),
+            max_running: self.max_running,
+        };
+        Ok(Admission(Turn::Later {
+            start,
+            ticket,
+            inner: self.inner.clone(),
+            max_running: self.max_running,
+        }))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Inner {
+    /// Give the freed slots to the next waiting executions
+    fn dispatch(&mut self, max_running: usize) {
+        while self.running < max_running {
+            let mut queue = match self.clients.pop_front() {
+                Some(queue) => queue,
+                None => return,
+            };
+
+            if let Some(waiter) = queue.waiters.pop_front() {
+                if let Some(queued) = self.queued.get_mut(&waiter.language) {
+                    *queued -= 1;
+                }
+                // The waiter is gone if its request was cancelled
+                if waiter.sender.send(()).is_ok() {
+                    self.running += 1;
+                }
+            }
+
+            if !queue.waiters.is_empty() {
+                self.clients.push_back(queue);
+            }
+        }
+    }
+
+    /// Remove a waiting execution
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Whether it was still waiting
+    fn cancel(&mut self, ticket: u64) -> bool {
+        for (index, queue) in self.clients.iter_mut().enumerate() {
+            if let Some(position) = queue.waiters.iter().position(|w| w.ticket == ticket) {
+                // Safe since the position was just found
+                let waiter = queue.waiters.remove(position).unwrap();
+                if let Some(queued) = self.queued.get_mut(&waiter.language) {
+                    *queued -= 1;
+                }
+                if queue.waiters.is_empty() {
+                    self.clients.remove(index);
+                }
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn release(&mut self, duration: Duration, max_running: usize) {
+        self.running -= 1;
+        self.average = Some(match self.average {
+            Some(average) => (average * 7 + duration) / 8,
+            None => duration,
+        });
+        self.dispatch(max_running);
+    }
+
+    /// Estimate when a slot will be free for a new execution
+    fn retry_after(&self, max_running: usize) -> Duration {
+        let waiting = self.clients.iter().map(|q| q.waiters.len()).sum::<usize>();
+        match self.average {
+            Some(average) => {
+                let rounds = waiting / max_running.max(1) + 1;
+                (average * rounds as u32).max(DEFAULT_RETRY_AFTER)
+            }
+            None => DEFAULT_RETRY_AFTER,
+        }
+    }
+}
+
+/// A queued execution, removed from the queue if dropped before its turn
+struct Ticket {
+    id: u64,
+    receiver: oneshot::Receiver<()>,
+    granted: bool,
+    inner: Arc<Mutex<Inner>>,
+    max_running: usize,
+}
+
+impl Ticket {
+    async fn wait(mut self) {
+        // The sender is only dropped once the slot was given
+        let _ = (&mut self.receiver).await;
+        self.granted = true;
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if self.granted {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        // The slot may have been given right before the request was cancelled
+        if !inner.cancel(self.id) && self.receiver.try_recv().is_ok() {
+            inner.running -= 1;
+            inner.dispatch(self.max_running);
+        }
+    }
+}
+
+/// An execution that was not refused, running once its turn came
+///
+/// Dropping it gives back its slot, or removes it from the queue.
+pub struct Admission(Turn);
+
+enum Turn {
+    /// Admitted right away
+    Now(Slot),
+    /// Waiting in the queue since the given time
+    Later {
+        start: Instant,
+        ticket: Ticket,
+        inner: Arc<Mutex<Inner>>,
+        max_running: usize,
+    },
+}
+
+impl Admission {
+    /// Wait for the turn of the execution
+    pub async fn wait(self) -> Slot {
+        match self.0 {
+            Turn::Now(slot) => slot,
+            Turn::Later {
+                start,
+                ticket,
+                inner,
+                max_running,
+            } => {
+                ticket.wait().await;
+                Slot {
+                    queued: start.elapsed(),
+                    start: Instant::now(),
+                    inner,
+                    max_running,
+                }
+            }
+        }
+    }
+}
+
+/// A running execution, the next one is started once dropped
+pub struct Slot {
+    queued: Duration,
+    start: Instant,
+    inner: Arc<Mutex<Inner>>,
+    max_running: usize,
+}
+
+impl Slot {
+    /// The time the execution waited in the queue
+    pub fn queued(&self) -> Duration {
+        self.queued
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.release(self.start.elapsed(), self.max_running);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::sync::Mutex;
+
+    use super::{Overloaded, Scheduler};
+
+    #[tokio::test]
+    async fn test_admit_fairly() {
+        let scheduler = Arc::new(Scheduler::new(1, 8));
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let slot = scheduler.admit("NODE", "first").await.unwrap();
+
+        let mut handles = Vec::new();
+        for (client, name) in [
+            ("burst", "burst-1"),
+            ("burst", "burst-2"),
+            ("burst", "burst-3"),
+            ("other", "other-1"),
+        ] {
+            let scheduler = scheduler.clone();
+            let order = order.clone();
+            handles.push(tokio::spawn(async move {
+                let slot = scheduler.admit("NODE", client).await.unwrap();
+                order.lock().await.push(name);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                drop(slot);
+            }));
+            // Let the request reach the queue before the next one
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        drop(slot);
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        assert_eq!(
+            *order.lock().await,
+            vec!["burst-1", "other-1", "burst-2", "burst-3"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admit_queue_full() {
+        let scheduler = Arc::new(Scheduler::new(1, 1));
+        let slot = scheduler.admit("NODE", "client").await.unwrap();
+
+        let queued = {
+            let scheduler = scheduler.clone();
+            tokio::spawn(async move { scheduler.admit("NODE", "client").await.map(|_| ()) })
+        };
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        assert!(matches!(
+            scheduler.admit("NODE", "client").await.err(),
+            Some(Overloaded::QueueFull(_))
+        ));
+
+        // The queues are bounded by language
+        let python = {
+            let scheduler = scheduler.clone();
+            tokio::spawn(async move { scheduler.admit("PYTHON", "client").await.map(|_| ()) })
+        };
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert!(!python.is_finished());
+
+        drop(slot);
+        assert_eq!(queued.await.unwrap(), Ok(()));
+        assert_eq!(python.await.unwrap(), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_admit_cancelled() {
+        let scheduler = Arc::new(Scheduler::new(1, 1));
+        let slot = scheduler.admit("NODE", "client").await.unwrap();
+
+        let cancelled = {
+            let scheduler = scheduler.clone();
+            tokio::spawn(async move { scheduler.admit("NODE", "client").await.map(|_| ()) })
+        };
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        cancelled.abort();
+        let _ = cancelled.await;
+
+        // The cancelled request left room in the queue
+        let queued = {
+            let scheduler = scheduler.clone();
+            tokio::spawn(async move { scheduler.admit("NODE", "client").await.map(|_| ()) })
+        };
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert!(!queued.is_finished());
+
+        drop(slot);
+        assert_eq!(queued.await.unwrap(), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_enqueue() {
+        let scheduler = Scheduler::new(1, 1);
+        let running = scheduler.enqueue("NODE", "client").unwrap();
+        let queued = scheduler.enqueue("NODE", "client").unwrap();
+        assert!(matches!(
+            scheduler.enqueue("NODE", "client").err(),
+            Some(Overloaded::QueueFull(_))
+        ));
+
+        // Dropping a queued execution leaves room in the queue
+        drop(queued);
+        let queued = scheduler.enqueue("NODE", "client").unwrap();
+
+        // Dropping an admitted execution gives its slot to the next one
+        drop(running);
+        let slot = tokio::time::timeout(Duration::from_secs(1), queued.wait()).await;
+        assert!(slot.is_ok());
+    }
+}
@@ -0,0 +1,79 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use log::info;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::Notify,
+};
+
+/// Coordinates the drain of the API before it stops
+///
+/// Once draining, the API refuses new executions while the running ones
+/// finish, then the VMs are torn down and the servers are stopped.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    draining: Arc<AtomicBool>,
+    requested: Arc<Notify>,
+}
+
+impl Shutdown {
+    /// Ask for the API to drain then stop
+    pub fn request(&self) {
+        info!("Drain requested");
+        self.requested.notify_one();
+    }
+
+    /// Wait until the drain is requested
+    pub async fn requested(&self) {
+        self.requested.notified().await
+    }
+
+    /// Refuse new executions from now on
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether new executions are refused
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+}
+
+/// Wait for a signal asking the API to stop
+///
+/// # Returns
+///
+/// * `std::io::Result<()>` - Once SIGTERM or SIGINT is received, an error if the handlers could not be set up
+pub async fn signal_received() -> std::io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM"),
+        _ = interrupt.recv() => info!("Received SIGINT"),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::Shutdown;
+
+    #[tokio::test]
+    async fn test_request_drain() {
+        let shutdown = Shutdown::default();
+        let waiter = shutdown.clone();
+
+        // The request is kept until someone waits for it
+        shutdown.request();
+        waiter.requested().await;
+
+        assert!(!waiter.is_draining());
+        shutdown.start_draining();
+        assert!(waiter.is_draining());
+    }
+}
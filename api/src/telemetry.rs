@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use lambdo_shared::telemetry::{otlp_tracer, stdout_tracer};
+use opentelemetry::{global, propagation::Injector, Context};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use tracing::level_filters::LevelFilter;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Layer, Registry};
+
+use crate::config::{LambdoLogFormat, LambdoTracingConfig, LambdoTracingExporter};
+
+/// The name under which the spans are exported
+const SERVICE_NAME: &str = "lambdo";
+
+/// Set up the logs and the export of the traces
+///
+/// The logs are printed on stderr and filtered by `RUST_LOG`. The spans, along with the
+/// logs emitted within them, are sent to the configured exporter.
+///
+/// # Arguments
+///
+/// * `config` - The tracing configuration
+/// * `format` - The format of the logs
+pub fn init(config: &LambdoTracingConfig, format: LambdoLogFormat) -> Result<()> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let tracer = match config.exporter {
+        LambdoTracingExporter::None => None,
+        LambdoTracingExporter::Stdout => Some(stdout_tracer(SERVICE_NAME)),
+        LambdoTracingExporter::Otlp => Some(otlp_tracer(SERVICE_NAME, config.endpoint.as_deref())?),
+    };
+
+    let subscriber = Registry::default()
+        .with(tracer.map(|tracer| {
+            tracing_opentelemetry::layer()
+                .with_tracer(tracer)
+                .with_filter(LevelFilter::INFO)
+        }))
+        .with((format == LambdoLogFormat::Text).then(|| {
+            tracing_subscriber::fmt::layer()
+                .with_writer(std::io::stderr)
+                .with_filter(EnvFilter::from_default_env())
+        }))
+        .with((format == LambdoLogFormat::Json).then(|| {
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_writer(std::io::stderr)
+                .with_filter(EnvFilter::from_default_env())
+        }));
+
+    tracing::subscriber::set_global_default(subscriber)
+        .map_err(|e| anyhow!("failed to set up tracing: {}", e))
+}
+
+/// Export the spans that are still buffered
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Add the trace context of the current span to the metadata of a gRPC request
+pub fn inject_context(metadata: &mut MetadataMap) {
+    inject(&tracing::Span::current().context(), metadata);
+}
+
+/// Add the trace context of the current span to a request sent over the serial line
+pub fn inject_serial_context(trace_context: &mut HashMap<String, String>) {
+    lambdo_shared::telemetry::inject_context(&tracing::Span::current().context(), trace_context);
+}
+
+fn inject(context: &Context, metadata: &mut MetadataMap) {
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(context, &mut MetadataInjector(metadata))
+    });
+}
+
+/// Writes the trace context as gRPC metadata
+struct MetadataInjector<'a>(&'a mut MetadataMap);
+
+impl Injector for MetadataInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (
+            MetadataKey::from_bytes(key.as_bytes()),
+            MetadataValue::try_from(&value),
+        ) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use opentelemetry::{
+        global,
+        trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState},
+        Context,
+    };
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use tonic::metadata::MetadataMap;
+
+    use super::inject;
+
+    #[test]
+    fn test_inject_context() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let context = Context::new().with_remote_span_context(SpanContext::new(
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(),
+            SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        ));
+
+        let mut metadata = MetadataMap::new();
+        inject(&context, &mut metadata);
+
+        assert_eq!(
+            metadata.get("traceparent").unwrap(),
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        );
+    }
+}
@@ -0,0 +1,537 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Result};
+use cidr::{IpInet, Ipv4Inet, Ipv6Inet};
+use log::{debug, trace};
+
+use crate::config::{LambdoApiConfig, LambdoIpRangeConfig};
+
+/// The largest number of addresses a pool can hold
+///
+/// An IPv6 network is far too large for a bitmap, only its start is used when no range
+/// is given.
+pub const MAX_POOL_SIZE: usize = 1 << 16;
+
+/// The addresses of the bridge network given to the VMs
+///
+/// One bit per address of the range tells whether it is taken. The network, broadcast
+/// and bridge addresses are set aside once and for all, the others are taken when a VM
+/// is started and given back once it is gone. The search for a free address starts
+/// after the last one given, a released address is not reused right away.
+#[derive(Debug, Clone)]
+pub struct Ipam {
+    /// The address of the bridge, with the length of the network
+    gateway: IpInet,
+    /// The first address of the range
+    first: u128,
+    /// The number of addresses in the range
+    size: usize,
+    /// One bit per address of the range, set if it is taken or set aside
+    bitmap: Vec<u64>,
+    /// The addresses of the range set aside, by index
+    reserved: Vec<usize>,
+    /// The number of addresses given to VMs
+    used: usize,
+    /// The index from which the next free address is looked for
+    next: usize,
+}
+
+impl Default for Ipam {
+    /// An empty pool, no address can be given
+    fn default() -> Self {
+        Ipam {
+            gateway: IpInet::V4(Ipv4Inet::new(Ipv4Addr::UNSPECIFIED, 32).unwrap()),
+            first: 0,
+            size: 0,
+            bitmap: Vec::new(),
+            reserved: Vec::new(),
+            used: 0,
+            next: 0,
+        }
+    }
+}
+
+impl Ipam {
+    /// Create a pool from the addresses of a network
+    ///
+    /// # Arguments
+    ///
+    /// * `gateway` - The address of the bridge, with the length of the network
+    /// * `range` - The first and last addresses given to the VMs, the whole network if not set
+    pub fn new(gateway: IpInet, range: Option<(IpAddr, IpAddr)>) -> Result<Self> {
+        let network_first = to_bits(gateway.first_address());
+        let network_last = to_bits(gateway.last_address());
+
+        let (first, last) = match range {
+            Some((start, end)) => {
+                for address in [start, end] {
+                    if !gateway.contains(&address) {
+                        return Err(anyhow!(
+                            "address {} is not in network {}",
+                            address,
+                            gateway.network()
+                        ));
+                    }
+                }
+
+                let (first, last) = (to_bits(start), to_bits(end));
+                if first > last {
+                    return Err(anyhow!("range {} - {} is empty", start, end));
+                }
+                if last - first >= MAX_POOL_SIZE as u128 {
+                    return Err(anyhow!(
+                        "range {} - {} holds more than {} addresses",
+                        start,
+                        end,
+                        MAX_POOL_SIZE
+                    ));
+                }
+                (first, last)
+            }
+            None => {
+                let last = network_last.min(network_first + MAX_POOL_SIZE as u128 - 1);
+                if last < network_last {
+                    debug!(
+                        "network {} is too large, only its first {} addresses are used",
+                        gateway.network(),
+                        MAX_POOL_SIZE
+                    );
+                }
+                (network_first, last)
+            }
+        };
+
+        let size = (last - first + 1) as usize;
+        let mut ipam = Ipam {
+            gateway,
+            first,
+            size,
+            bitmap: vec![0; size.div_ceil(64)],
+            reserved: Vec::new(),
+            used: 0,
+            next: 0,
+        };
+
+        // The bits past the end of the range are never free
+        if !size.is_multiple_of(64) {
+            *ipam.bitmap.last_mut().unwrap() = u64::MAX << (size % 64);
+        }
+
+        let mut reserved = vec![network_first, to_bits(gateway.address())];
+        // Only IPv4 networks have a broadcast address
+        if gateway.is_ipv4() {
+            reserved.push(network_last);
+        }
+        for bits in reserved {
+            if let Some(index) = ipam.index_of_bits(bits) {
+                if !ipam.is_set(index) {
+                    trace!("setting aside {}", ipam.address(index));
+                    ipam.set(index, true);
+                    ipam.reserved.push(index);
+                }
+            }
+        }
+
+        if ipam.capacity() == 0 {
+            return Err(anyhow!(
+                "no address left for the VMs in network {}",
+                gateway.network()
+            ));
+        }
+
+        Ok(ipam)
+    }
+
+    /// Create the IPv4 pool of the VMs from the API configuration
+    pub fn v4(config: &LambdoApiConfig) -> Result<Self> {
+        let gateway = Ipv4Inet::from_str(&config.bridge_address)
+            .map_err(|e| anyhow!("invalid bridge address: {}", e))?;
+        let range = match &config.ip_range {
+            Some(range) => Some(parse_range::<Ipv4Addr>(range)?),
+            None => None,
+        };
+
+        Ipam::new(IpInet::V4(gateway), range)
+    }
+
+    /// Create the IPv6 pool of the VMs from the API configuration
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<Ipam>>` - The pool, `None` if the bridge has no IPv6 address
+    pub fn v6(config: &LambdoApiConfig) -> Result<Option<Self>> {
+        let gateway = match &config.bridge_address_v6 {
+            Some(address) => Ipv6Inet::from_str(address)
+                .map_err(|e| anyhow!("invalid bridge IPv6 address: {}", e))?,
+            None => return Ok(None),
+        };
+        let range = match &config.ip_range_v6 {
+            Some(range) => Some(parse_range::<Ipv6Addr>(range)?),
+            None => None,
+        };
+
+        Ipam::new(IpInet::V6(gateway), range).map(Some)
+    }
+
+    /// Take the next free address
+    ///
+    /// # Returns
+    ///
+    /// * `Option<IpInet>` - The address with the length of the network, `None` if the pool is exhausted
+    pub fn allocate(&mut self) -> Option<IpInet> {
+        if self.used + self.reserved.len() >= self.size {
+            return None;
+        }
+
+        let words = self.bitmap.len();
+        let start = self.next / 64;
+        // The word of the start is looked at twice: from the start, then before it
+        for step in 0..=words {
+            let word = (start + step) % words;
+            let mut free = !self.bitmap[word];
+            if step == 0 {
+                free &= u64::MAX << (self.next % 64);
+            }
+            if free == 0 {
+                continue;
+            }
+
+            let index = word * 64 + free.trailing_zeros() as usize;
+            self.set(index, true);
+            self.used += 1;
+            self.next = (index + 1) % self.size;
+
+            let address = self.address(index);
+            debug!("allocated address {}", address);
+            return Some(address);
+        }
+
+        None
+    }
+
+    /// Take a given address, like the one of a VM that survived a restart
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Whether the address was free and is now taken
+    pub fn claim(&mut self, address: IpAddr) -> bool {
+        match self.index_of(address) {
+            Some(index) if !self.is_set(index) => {
+                self.set(index, true);
+                self.used += 1;
+                debug!("claimed address {}", address);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Give back an address
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Whether the address was taken, the ones set aside cannot be released
+    pub fn release(&mut self, address: IpAddr) -> bool {
+        match self.index_of(address) {
+            Some(index) if self.is_set(index) && !self.reserved.contains(&index) => {
+                self.set(index, false);
+                self.used -= 1;
+                debug!("released address {}", address);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether an address is taken, or set aside
+    pub fn is_taken(&self, address: IpAddr) -> bool {
+        self.index_of(address)
+            .is_some_and(|index| self.is_set(index))
+    }
+
+    /// The number of addresses given to VMs
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// The number of addresses that can be given to VMs
+    pub fn capacity(&self) -> usize {
+        self.size - self.reserved.len()
+    }
+
+    fn index_of(&self, address: IpAddr) -> Option<usize> {
+        if address.is_ipv4() != self.gateway.is_ipv4() {
+            return None;
+        }
+        self.index_of_bits(to_bits(address))
+    }
+
+    fn index_of_bits(&self, bits: u128) -> Option<usize> {
+        bits.checked_sub(self.first)
+            .filter(|offset| *offset < self.size as u128)
+            .map(|offset| offset as usize)
+    }
+
+    fn address(&self, index: usize) -> IpInet {
+        let bits = self.first + index as u128;
+        let address = match self.gateway {
+            IpInet::V4(_) => IpAddr::V4(Ipv4Addr::from(bits as u32)),
+            IpInet::V6(_) => IpAddr::V6(Ipv6Addr::from(bits)),
+        };
+
+        // Safe since the address has the family of the network
+        IpInet::new(address, self.gateway.network_length()).unwrap()
+    }
+
+    fn is_set(&self, index: usize) -> bool {
+        self.bitmap[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set(&mut self, index: usize, taken: bool) {
+        if taken {
+            self.bitmap[index / 64] |= 1 << (index % 64);
+        } else {
+            self.bitmap[index / 64] &= !(1 << (index % 64));
+        }
+    }
+}
+
+fn to_bits(address: IpAddr) -> u128 {
+    match address {
+        IpAddr::V4(address) => u32::from(address) as u128,
+        IpAddr::V6(address) => u128::from(address),
+    }
+}
+
+fn parse_range<A>(range: &LambdoIpRangeConfig) -> Result<(IpAddr, IpAddr)>
+where
+    A: FromStr + Into<IpAddr>,
+    A::Err: std::fmt::Display,
+{
+    let parse = |address: &str| {
+        A::from_str(address)
+            .map(Into::into)
+            .map_err(|e| anyhow!("invalid address {} in range: {}", address, e))
+    };
+
+    Ok((parse(&range.start)?, parse(&range.end)?))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashSet, net::IpAddr, str::FromStr};
+
+    use cidr::IpInet;
+    use proptest::prelude::*;
+
+    use super::{Ipam, MAX_POOL_SIZE};
+
+    fn generate_ipam(gateway: &str) -> Ipam {
+        Ipam::new(IpInet::from_str(gateway).unwrap(), None).unwrap()
+    }
+
+    fn address(address: &str) -> IpAddr {
+        IpAddr::from_str(address).unwrap()
+    }
+
+    #[test]
+    fn test_reserved_addresses() {
+        let mut ipam = generate_ipam("192.168.10.1/29");
+        assert_eq!(ipam.capacity(), 5);
+
+        let given = std::iter::from_fn(|| ipam.allocate())
+            .map(|ip| ip.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            given,
+            vec![
+                "192.168.10.2/29",
+                "192.168.10.3/29",
+                "192.168.10.4/29",
+                "192.168.10.5/29",
+                "192.168.10.6/29"
+            ]
+        );
+        assert!(ipam.is_taken(address("192.168.10.0")));
+        assert!(!ipam.release(address("192.168.10.1")));
+        assert!(!ipam.release(address("192.168.10.7")));
+    }
+
+    #[test]
+    fn test_release_and_reuse() {
+        let mut ipam = generate_ipam("10.0.0.1/30");
+        assert_eq!(ipam.allocate().unwrap().to_string(), "10.0.0.2/30");
+        assert_eq!(ipam.allocate(), None);
+
+        assert!(ipam.release(address("10.0.0.2")));
+        assert!(!ipam.release(address("10.0.0.2")));
+        assert_eq!(ipam.used(), 0);
+        assert_eq!(ipam.allocate().unwrap().to_string(), "10.0.0.2/30");
+    }
+
+    #[test]
+    fn test_next_fit() {
+        let mut ipam = generate_ipam("192.168.10.1/24");
+        let first = ipam.allocate().unwrap();
+        ipam.allocate().unwrap();
+        ipam.release(first.address());
+
+        // The released address only comes back once the others are taken
+        assert_eq!(ipam.allocate().unwrap().to_string(), "192.168.10.4/24");
+    }
+
+    #[test]
+    fn test_range() {
+        let gateway = IpInet::from_str("192.168.10.1/24").unwrap();
+        let mut ipam = Ipam::new(
+            gateway,
+            Some((address("192.168.10.100"), address("192.168.10.101"))),
+        )
+        .unwrap();
+        assert_eq!(ipam.capacity(), 2);
+        assert!(ipam.claim(address("192.168.10.101")));
+        assert!(!ipam.claim(address("192.168.10.101")));
+        assert!(!ipam.claim(address("192.168.10.50")));
+        assert_eq!(ipam.allocate().unwrap().to_string(), "192.168.10.100/24");
+        assert_eq!(ipam.allocate(), None);
+
+        assert!(Ipam::new(
+            gateway,
+            Some((address("192.168.10.2"), address("10.0.0.1")))
+        )
+        .is_err());
+        assert!(Ipam::new(
+            gateway,
+            Some((address("192.168.10.20"), address("192.168.10.10")))
+        )
+        .is_err());
+        assert!(Ipam::new(
+            gateway,
+            Some((address("192.168.10.1"), address("192.168.10.1")))
+        )
+        .is_err());
+        assert!(Ipam::new(IpInet::from_str("192.168.10.1/32").unwrap(), None).is_err());
+    }
+
+    #[test]
+    fn test_ipv6() {
+        let mut ipam = generate_ipam("fd00:6c61:6d62:646f::1/64");
+        assert_eq!(ipam.capacity(), MAX_POOL_SIZE - 2);
+        assert_eq!(
+            ipam.allocate().unwrap().to_string(),
+            "fd00:6c61:6d62:646f::2/64"
+        );
+        assert!(!ipam.claim(address("192.168.10.2")));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_addresses_are_unique_and_in_range(
+            length in 20u8..=30,
+            host in 1u32..4,
+            operations in prop::collection::vec(any::<Option<prop::sample::Index>>(), 1..500),
+        ) {
+            let gateway = IpInet::new(address("172.16.0.0"), length).unwrap();
+            let gateway = IpInet::new(
+                match gateway.first_address() {
+                    IpAddr::V4(first) => IpAddr::V4((u32::from(first) + host).into()),
+                    IpAddr::V6(_) => unreachable!(),
+                },
+                length,
+            )
+            .unwrap();
+            let mut ipam = Ipam::new(gateway, None).unwrap();
+            let mut given = Vec::<IpAddr>::new();
+
+            for operation in operations {
+                match operation {
+                    // Some: release one of the given addresses, None: allocate one
+                    Some(index) if !given.is_empty() => {
+                        let released = given.swap_remove(index.index(given.len()));
+                        prop_assert!(ipam.release(released));
+                    }
+                    _ => match ipam.allocate() {
+                        Some(ip) => {
+                            prop_assert_eq!(ip.network_length(), length);
+                            prop_assert!(gateway.contains(&ip.address()));
+                            prop_assert_ne!(ip.address(), gateway.address());
+                            prop_assert_ne!(ip.address(), gateway.first_address());
+                            prop_assert_ne!(ip.address(), gateway.last_address());
+                            prop_assert!(!given.contains(&ip.address()));
+                            given.push(ip.address());
+                        }
+                        None => prop_assert_eq!(given.len(), ipam.capacity()),
+                    },
+                }
+
+                prop_assert_eq!(ipam.used(), given.len());
+            }
+        }
+
+        #[test]
+        fn prop_ipv6_addresses_are_unique_and_in_range(
+            length in 112u8..=126,
+            host in 1u128..4,
+            operations in prop::collection::vec(any::<Option<prop::sample::Index>>(), 1..500),
+        ) {
+            let gateway = IpInet::new(address("fd00:6c61:6d62:646f::"), length).unwrap();
+            let gateway = IpInet::new(
+                match gateway.first_address() {
+                    IpAddr::V6(first) => IpAddr::V6((u128::from(first) + host).into()),
+                    IpAddr::V4(_) => unreachable!(),
+                },
+                length,
+            )
+            .unwrap();
+            let mut ipam = Ipam::new(gateway, None).unwrap();
+            let mut given = Vec::<IpAddr>::new();
+
+            for operation in operations {
+                match operation {
+                    // Some: release one of the given addresses, None: allocate one
+                    Some(index) if !given.is_empty() => {
+                        let released = given.swap_remove(index.index(given.len()));
+                        prop_assert!(ipam.release(released));
+                    }
+                    _ => match ipam.allocate() {
+                        Some(ip) => {
+                            prop_assert!(ip.is_ipv6());
+                            prop_assert_eq!(ip.network_length(), length);
+                            prop_assert!(gateway.contains(&ip.address()));
+                            prop_assert_ne!(ip.address(), gateway.address());
+                            prop_assert_ne!(ip.address(), gateway.first_address());
+                            prop_assert!(!given.contains(&ip.address()));
+                            given.push(ip.address());
+                        }
+                        None => prop_assert_eq!(given.len(), ipam.capacity()),
+                    },
+                }
+
+                prop_assert_eq!(ipam.used(), given.len());
+            }
+        }
+
+        #[test]
+        fn prop_pool_is_exhausted_exactly(
+            start in 2u8..100,
+            count in 1u8..100,
+        ) {
+            let gateway = IpInet::from_str("10.1.0.1/24").unwrap();
+            let range = (
+                IpAddr::from([10, 1, 0, start]),
+                IpAddr::from([10, 1, 0, start + count - 1]),
+            );
+            let mut ipam = Ipam::new(gateway, Some(range)).unwrap();
+
+            let given = std::iter::from_fn(|| ipam.allocate())
+                .map(|ip| ip.address())
+                .collect::<HashSet<_>>();
+            prop_assert_eq!(given.len(), count as usize);
+            prop_assert_eq!(ipam.capacity(), count as usize);
+            prop_assert!(given.iter().all(|ip| *ip >= range.0 && *ip <= range.1));
+        }
+    }
+}
@@ -1,23 +1,39 @@
+pub mod ipam;
 pub mod state;
+pub mod store;
 use mockall::automock;
 use network_interface::{NetworkInterface, NetworkInterfaceConfig};
 use tokio::process::Command;
+use tracing::instrument;
 
 pub use vmm::grpc_definitions;
 pub use vmm::grpc_server::VMListener;
 pub use vmm::Error;
+pub use vmm::{kill_vm, release_vm, run_in_process, run_vm, VMMOpts};
 
 use anyhow::anyhow;
 
 use log::{debug, error, info, trace, warn};
-use std::{net::IpAddr, str::FromStr};
+use std::{
+    net::IpAddr,
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
-use crate::{model::LanguageSettings, vm_manager::state::VMStatus};
+use crate::{
+    config::{LambdoLanguageConfig, LanguageChanges},
+    model::{ExecutionLimits, LanguageSettings, DEFAULT_MEMORY},
+    vm_manager::state::VMStatus,
+};
 
 use self::{
-    grpc_definitions::{ExecuteRequest, ExecuteResponse},
-    state::LambdoStateRef,
-    vmm::run_vm,
+    grpc_definitions::{
+        lambdo_api_service_server::LambdoApiService, register_response, serial_message::Payload,
+        Capability, ExecuteRequest, ExecuteResponse, RegisterResponse, SerialMessage,
+    },
+    ipam::Ipam,
+    state::{LambdoStateRef, OutputSender},
+    vmm::{recover, setup_firewall, teardown},
 };
 
 mod vmm;
@@ -29,11 +45,40 @@ pub trait VMManagerTrait: Sync + Send {
     where
         Self: Sized;
 
+    /// Run a request on a VM of the language
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request sent to the agent
+    /// * `language_settings` - The language of the request
+    /// * `limits` - The limits of the execution
+    /// * `output` - Where the output is sent as the agent streams it, if it is followed
     async fn run_code(
         &self,
         request: ExecuteRequest,
         language_settings: LanguageSettings,
+        limits: ExecutionLimits,
+        output: Option<OutputSender>,
     ) -> Result<ExecuteResponse, Error>;
+
+    /// Wait for the running executions, then tear down all the VMs
+    ///
+    /// The VMs are torn down even if the executions are still running at the deadline.
+    ///
+    /// # Arguments
+    ///
+    /// * `deadline` - The time left to the running executions to finish
+    async fn shutdown(&self, deadline: Duration) -> Result<(), Error>;
+
+    /// Replace the languages, the VMs of the changed and removed ones are replaced
+    ///
+    /// The VMs running an execution are left to finish it. A VM that cannot be killed or a
+    /// language that cannot boot does not stop the others, all the errors are returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `languages` - The languages of the new config
+    async fn reload_languages(&self, languages: Vec<LambdoLanguageConfig>) -> Result<(), Error>;
 }
 
 pub struct VMManager {
@@ -51,26 +96,180 @@ impl VMManagerTrait for VMManager {
                 error!("Error while setting up bridge: {:?}", e);
                 Error::NetSetupError(e)
             })?;
+            state.ipam = Ipam::v4(&state.config.api).map_err(|e| {
+                error!(
+                    "Error while setting up the IPv4 addresses of the VMs: {:?}",
+                    e
+                );
+                Error::NetSetupError(e)
+            })?;
+            state.ipam_v6 = Ipam::v6(&state.config.api).map_err(|e| {
+                error!(
+                    "Error while setting up the IPv6 addresses of the VMs: {:?}",
+                    e
+                );
+                Error::NetSetupError(e)
+            })?;
+            recover(&mut state).map_err(|e| {
+                error!("Error while cleaning up after the previous run: {:?}", e);
+                Error::NetSetupError(e)
+            })?;
+            setup_firewall(&state).map_err(|e| {
+                error!("Error while setting up the firewall: {:?}", e);
+                Error::NetSetupError(e)
+            })?;
+
+            if state.backend.snapshot_dir().is_some() && !state.snapshots_enabled() {
+                warn!("Snapshots need the serial transport, disabling them");
+            }
             let languages = state.config.languages.clone();
 
             for language_settings in &languages {
-                run_vm(&mut state, &language_settings.clone().into(), false)
-                    .await
-                    .map_err(|e| {
-                        error!("Error while setting up language: {:?}", e);
-                        e
-                    })?;
+                run_vm(
+                    &mut state,
+                    &language_settings.clone().into(),
+                    DEFAULT_MEMORY,
+                    false,
+                )
+                .await
+                .map_err(|e| {
+                    error!("Error while setting up language: {:?}", e);
+                    e
+                })?;
             }
         }
         vmm_manager.event_listener().await;
+        vmm_manager.store_listener().await;
+        vmm_manager.serial_listener().await;
 
         Ok(vmm_manager)
     }
 
+    #[instrument(skip_all, fields(language = %language_settings.name))]
     async fn run_code(
         &self,
         request: ExecuteRequest,
         language_settings: LanguageSettings,
+        limits: ExecutionLimits,
+        output: Option<OutputSender>,
+    ) -> Result<ExecuteResponse, Error> {
+        let metrics = self.state.lock().await.metrics.clone();
+        let start = Instant::now();
+
+        let result = self
+            .execute(request, &language_settings, limits, output)
+            .await;
+        metrics.observe_request(&language_settings.name, &result, start.elapsed());
+
+        result
+    }
+
+    async fn shutdown(&self, deadline: Duration) -> Result<(), Error> {
+        info!("Waiting up to {:?} for the running executions", deadline);
+
+        // Executions hold the state until they are done
+        let (mut state, result) = match tokio::time::timeout(deadline, self.state.lock()).await {
+            Ok(state) => (state, Ok(())),
+            Err(_) => {
+                warn!(
+                    "Executions still running after {:?}, stopping anyway",
+                    deadline
+                );
+                // The executions left are bounded by their own timeout
+                (self.state.lock().await, Err(Error::Timeout))
+            }
+        };
+
+        // No VM is warmed up anymore
+        state.draining = true;
+        teardown(&mut state).map_err(|e| {
+            error!("Error while tearing down the VMs: {:?}", e);
+            Error::NetSetupError(e)
+        })?;
+
+        result
+    }
+
+    async fn reload_languages(&self, languages: Vec<LambdoLanguageConfig>) -> Result<(), Error> {
+        let mut state = self.state.lock().await;
+        let changes = LanguageChanges::new(&state.config.languages, &languages);
+        if changes.is_empty() {
+            debug!("The languages did not change");
+            return Ok(());
+        }
+
+        let names = |languages: Vec<&LambdoLanguageConfig>| {
+            languages
+                .into_iter()
+                .map(|language| language.name.clone())
+                .collect::<Vec<_>>()
+        };
+        info!(
+            "Reloading the languages, added: {:?}, changed: {:?}, removed: {:?}",
+            names(changes.added.iter().collect()),
+            names(changes.changed.iter().map(|(_, new)| new).collect()),
+            names(changes.removed.iter().collect())
+        );
+        state.config.languages = languages;
+
+        // The VMs given to an execution are left alone, the others are not used anymore
+        let outdated = changes
+            .outdated()
+            .map(|language| language.name.as_str())
+            .collect::<Vec<_>>();
+        let ids = state
+            .vms
+            .iter()
+            .filter(|vm| {
+                outdated.contains(&vm.language_settings.name.as_str())
+                    && !vm.reserved
+                    && matches!(vm.get_state(), VMStatus::Waiting | VMStatus::Ready)
+            })
+            .map(|vm| vm.id.clone())
+            .collect::<Vec<_>>();
+        // Every VM and language is handled even if one of them fails
+        let mut errors = Vec::new();
+        for id in ids {
+            if let Err(e) = kill_vm(&mut state, &id) {
+                error!("Error while killing outdated VM {}: {:?}", id, e);
+                errors.push(e);
+            }
+        }
+        for language in changes.outdated() {
+            state.forget_snapshot(&language.clone().into());
+        }
+
+        if !state.draining {
+            for language in changes.updated() {
+                if let Err(e) =
+                    run_vm(&mut state, &language.clone().into(), DEFAULT_MEMORY, false).await
+                {
+                    error!("Error while setting up language {}: {:?}", language.name, e);
+                    errors.push(e);
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::ReloadFailed(errors))
+        }
+    }
+}
+
+impl VMManager {
+    /// Run a request on a ready VM of the language, booting one if there is none
+    ///
+    /// Only the VMs within the memory limit are used, a new one is booted with the limit
+    /// if none is ready. The state is only locked to pick the VM and to record the outcome,
+    /// not while the agent runs the request.
+    async fn execute(
+        &self,
+        request: ExecuteRequest,
+        language_settings: &LanguageSettings,
+        limits: ExecutionLimits,
+        output: Option<OutputSender>,
     ) -> Result<ExecuteResponse, Error> {
         let mut state = self.state.lock().await;
 
@@ -78,6 +277,7 @@ impl VMManagerTrait for VMManager {
         let vm = if let Some(vm) = state.vms.iter_mut().find(|vm| {
             vm.language_settings.name == language_settings.name
                 && vm.language_settings.version == language_settings.version
+                && vm.vm_opts.memory <= limits.memory
                 && !vm.reserved
                 && vm.get_state() == VMStatus::Ready
         }) {
@@ -86,7 +286,7 @@ impl VMManagerTrait for VMManager {
         } else {
             debug!("No VM found, creating one");
             let mut rx = state.channel.1.resubscribe();
-            let id = run_vm(&mut state, &language_settings, true)
+            let id = run_vm(&mut state, language_settings, limits.memory, true)
                 .await
                 .map_err(|e| {
                     error!("Error while running VM: {:?}", e);
@@ -124,13 +324,40 @@ impl VMManagerTrait for VMManager {
             return Err(Error::VmAlreadyEnded);
         }
 
-        let response = vm.execute(request).await?;
+        let mut request = request;
+        if !request.artifacts.is_empty() && !vm.has_capability(Capability::BinaryFiles) {
+            warn!(
+                "Agent of VM {} cannot return artifacts, skipping them",
+                vm.id
+            );
+            request.artifacts.clear();
+        }
+
+        // Without the capability, the output only comes with the response
+        let output = output.filter(|_| vm.has_capability(Capability::Streaming));
+
+        // The VM is running from now on, the state is not needed to wait for the agent
+        let id = vm.id.clone();
+        let serial = vm.serial.is_some();
+        let execution = vm.start_execution(&request);
+        // The agents reached over gRPC send their output to the API service of the VMs
+        if let (Some(output), false) = (&output, serial) {
+            state.outputs.insert(request.id.clone(), output.clone());
+        }
+        drop(state);
+
+        let request_id = request.id.clone();
+        let result = execution.run(request, limits.timeout, output).await;
 
-        Ok(response)
+        let mut state = self.state.lock().await;
+        state.outputs.remove(&request_id);
+        if let Some(vm) = state.vms.iter_mut().find(|vm| vm.id == id) {
+            vm.end_execution(&result);
+        }
+
+        result
     }
-}
 
-impl VMManager {
     pub async fn event_listener(&mut self) {
         let mut receiver = self.state.lock().await.channel.1.resubscribe();
         let state = self.state.clone();
@@ -141,8 +368,18 @@ impl VMManager {
                         error!("Error while receiving from channel: {:?}", e);
                         break;
                     }
+                    Ok((id, VMStatus::Ended)) => {
+                        let mut state = state.lock().await;
+                        if let Err(e) = release_vm(&mut state, &id) {
+                            debug!("Could not release VM {}: {:?}", id, e);
+                        }
+                    }
                     Ok((id, VMStatus::Running)) => {
                         let mut state = state.lock().await;
+                        if state.draining {
+                            debug!("Draining, not warming up a VM after {}", id);
+                            continue;
+                        }
                         let vm = match state.vms.iter().find(|vm| vm.id == id) {
                             Some(vm) if !vm.reserved => vm,
                             Some(_) => {
@@ -154,9 +391,26 @@ impl VMManager {
                                 continue;
                             }
                         };
-                        let language_settings = vm.language_settings.clone();
+                        // The language may have been reloaded since the VM was booted
+                        let language_settings: LanguageSettings = match state
+                            .config
+                            .languages
+                            .iter()
+                            .find(|language| language.name == vm.language_settings.name)
+                        {
+                            Some(language) => language.clone().into(),
+                            None => {
+                                debug!(
+                                    "Language {} was removed, not warming up a VM",
+                                    vm.language_settings.name
+                                );
+                                continue;
+                            }
+                        };
                         info!("Warming up new VM for language {}", language_settings.name);
-                        if let Err(e) = run_vm(&mut state, &language_settings, false).await {
+                        if let Err(e) =
+                            run_vm(&mut state, &language_settings, DEFAULT_MEMORY, false).await
+                        {
                             error!("Error while running VM: {:?}", e);
                         }
                     }
@@ -167,6 +421,96 @@ impl VMManager {
     }
 }
 
+impl VMManager {
+    /// Record the VMs in the state file whenever one of them changes status
+    pub async fn store_listener(&mut self) {
+        let mut receiver = self.state.lock().await.channel.1.resubscribe();
+        let state = self.state.clone();
+        tokio::task::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Err(e) => {
+                        error!("Error while receiving from channel: {:?}", e);
+                        break;
+                    }
+                    Ok(_) => state.lock().await.persist(),
+                }
+            }
+        });
+    }
+}
+
+impl VMManager {
+    /// Handle the messages sent by the agents over their console socket
+    pub async fn serial_listener(&mut self) {
+        let mut receiver = self.state.lock().await.serial_channel.1.resubscribe();
+        let state = self.state.clone();
+        let vm_listener = VMListener::new(state.clone());
+        tokio::task::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Err(e) => {
+                        error!("Error while receiving from serial channel: {:?}", e);
+                        break;
+                    }
+                    Ok((
+                        id,
+                        SerialMessage {
+                            payload: Some(Payload::RegisterRequest(request)),
+                        },
+                    )) => {
+                        info!("Received register request over serial from VM {}", id);
+                        let mut state = state.lock().await;
+                        let vm = match state.vms.iter_mut().find(|vm| vm.id == id) {
+                            Some(vm) => vm,
+                            None => {
+                                warn!("VM {} not found while listening to serial channel", id);
+                                continue;
+                            }
+                        };
+
+                        let response = match vm.register(request) {
+                            Ok(_) => register_response::Response::Id(vm.id.clone()),
+                            Err(e) => {
+                                error!("Failed to register VM: {}", e);
+                                register_response::Response::Error(format!(
+                                    "Failed to register VM: {}",
+                                    e
+                                ))
+                            }
+                        };
+
+                        if let Some(serial) = &vm.serial {
+                            serial
+                                .send(SerialMessage {
+                                    payload: Some(Payload::RegisterResponse(RegisterResponse {
+                                        response: Some(response),
+                                    })),
+                                })
+                                .unwrap_or_else(|e| {
+                                    error!("Failed to send register response: {}", e);
+                                });
+                        }
+                    }
+                    Ok((
+                        _,
+                        SerialMessage {
+                            payload: Some(Payload::Status(status)),
+                        },
+                    )) => {
+                        if let Err(e) = vm_listener.status(tonic::Request::new(status)).await {
+                            error!("Failed to handle serial status: {}", e);
+                        }
+                    }
+                    Ok((id, message)) => {
+                        warn!("Unexpected serial message from VM {}: {:?}", id, message);
+                    }
+                }
+            }
+        });
+    }
+}
+
 async fn setup_bridge(state: &state::LambdoState) -> anyhow::Result<()> {
     let config = &state.config;
     let bridge_name = &config.api.bridge;
@@ -242,6 +586,25 @@ async fn setup_bridge(state: &state::LambdoState) -> anyhow::Result<()> {
             .map_err(|e| anyhow!("error when adding bridge address: {}", e))?;
     }
 
+    if let Some(bridge_address_v6) = &config.api.bridge_address_v6 {
+        let bridge_address_v6 = cidr::Ipv6Inet::from_str(bridge_address_v6)
+            .map_err(|e| anyhow!("invalid bridge IPv6 address: {}", e))?;
+        debug!("adding bridge IPv6 address {}", bridge_address_v6);
+        // Fails if the address already exists, which is fine
+        Command::new("ip")
+            .args([
+                "-6",
+                "addr",
+                "add",
+                &bridge_address_v6.to_string(),
+                "dev",
+                bridge_name,
+            ])
+            .output()
+            .await
+            .map_err(|e| anyhow!("error when adding bridge IPv6 address: {}", e))?;
+    }
+
     debug!("bringing up bridge");
 
     Command::new("ip")
@@ -1,20 +1,49 @@
-use std::fmt::Debug;
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
 use anyhow::anyhow;
 use log::{debug, error, info, warn};
 use tokio::select;
+use tracing::instrument;
+use uuid::Uuid;
+
+pub use lambdo_client::model::VMStatus;
 
-use crate::{config::LambdoConfig, model::LanguageSettings, vm_manager::Error};
+use lambdo_client::model::VMSummary;
+
+use crate::{
+    config::{LambdoConfig, LambdoVMMTransport},
+    metrics::Metrics,
+    model::LanguageSettings,
+    telemetry,
+    vm_manager::{
+        ipam::Ipam,
+        store::{StateStore, VMRecord},
+        Error,
+    },
+};
 
 use super::{
     grpc_definitions::{
-        lambdo_agent_service_client::LambdoAgentServiceClient, ExecuteRequest, ExecuteResponse,
+        lambdo_agent_service_client::LambdoAgentServiceClient, serial_message::Payload, Capability,
+        ExecuteRequest, ExecuteResponse, Identity, OutputChunk, RegisterRequest, SerialMessage,
+    },
+    vmm::{
+        backend::{LumperBackend, Snapshot, VMMBackend},
+        serial::SerialClient,
+        VMMOpts, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
     },
-    vmm::VMMOpts,
 };
 
 pub type LambdoStateRef = std::sync::Arc<tokio::sync::Mutex<LambdoState>>;
 
+/// Where the output of an execution is sent as the agent streams it
+pub type OutputSender = tokio::sync::mpsc::UnboundedSender<OutputChunk>;
+
 pub struct LambdoState {
     pub vms: Vec<VMState>,
     pub config: LambdoConfig,
@@ -24,23 +53,237 @@ pub struct LambdoState {
         tokio::sync::broadcast::Sender<(String, VMStatus)>,
         tokio::sync::broadcast::Receiver<(String, VMStatus)>,
     ),
+    /// Messages sent by the agents over their console socket
+    #[allow(clippy::type_complexity)]
+    pub serial_channel: (
+        tokio::sync::broadcast::Sender<(String, SerialMessage)>,
+        tokio::sync::broadcast::Receiver<(String, SerialMessage)>,
+    ),
+    /// The VMM booting the VMs
+    pub backend: Arc<dyn VMMBackend>,
+    /// The snapshots of the ready VMs, by language
+    pub snapshots: HashMap<String, Snapshot>,
+    /// The file recording the VMs across restarts
+    pub store: StateStore,
+    /// Whether the API is stopping, no VM is started anymore
+    pub draining: bool,
+    /// The figures exported on `/metrics`
+    pub metrics: Metrics,
+    /// The VMs as listed by the admin API, readable without locking the state
+    pub directory: VMDirectory,
+    /// The IPv4 addresses of the VMs
+    pub ipam: Ipam,
+    /// The IPv6 addresses of the VMs, if the bridge has one
+    pub ipam_v6: Option<Ipam>,
+    /// Where the output of the running executions goes, by request ID
+    pub outputs: HashMap<String, OutputSender>,
 }
 
 impl LambdoState {
     pub fn new(config: LambdoConfig) -> Self {
         let (sender, receiver) = tokio::sync::broadcast::channel(128);
+        let (serial_sender, serial_receiver) = tokio::sync::broadcast::channel(128);
+        let store = StateStore::new(&config.api.state_file);
         LambdoState {
             vms: Vec::new(),
             config,
             channel: (sender, receiver),
+            serial_channel: (serial_sender, serial_receiver),
+            backend: Arc::new(LumperBackend),
+            snapshots: HashMap::new(),
+            store,
+            draining: false,
+            metrics: Metrics::new(),
+            directory: VMDirectory::default(),
+            ipam: Ipam::default(),
+            ipam_v6: None,
+            outputs: HashMap::new(),
         }
     }
 
+    pub fn with_backend(mut self, backend: Arc<dyn VMMBackend>) -> Self {
+        self.backend = backend;
+        self
+    }
+
     pub fn find_ready_vms(&mut self) -> Option<&mut VMState> {
         self.vms
             .iter_mut()
             .find(|vm| vm.get_state() == VMStatus::Ready)
     }
+
+    /// Record the VMs in the state file, the directory and the address gauges
+    pub fn persist(&self) {
+        let records = self.vms.iter().map(VMRecord::from).collect::<Vec<_>>();
+        if let Err(e) = self.store.save(&records) {
+            warn!("Failed to save the state of the VMs: {:?}", e);
+        }
+
+        self.directory.replace(&self.vms);
+        self.metrics.ips_used.set(self.ipam.used() as i64);
+        self.metrics.ips_total.set(self.ipam.capacity() as i64);
+    }
+
+    /// Whether new VMs are restored from snapshots
+    ///
+    /// The identity of a clone is sent over its console socket, so snapshots
+    /// need the serial transport on top of a backend supporting them.
+    pub fn snapshots_enabled(&self) -> bool {
+        self.config.vmm.transport == LambdoVMMTransport::Serial
+            && self.backend.snapshot_dir().is_some()
+    }
+
+    /// Boot a VM, restoring it from the snapshot of its language if there is one
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The VM to boot, its task is set once started
+    pub fn boot(&self, vm: &mut VMState) -> Result<(), Error> {
+        let opts = vm.vm_opts.clone();
+        let snapshot = self
+            .snapshots
+            .get(&snapshot_key(&vm.language_settings))
+            .filter(|_| self.snapshots_enabled());
+
+        let task = match snapshot {
+            Some(snapshot) => {
+                info!(
+                    "Restoring VM {} from the snapshot of VM {}",
+                    vm.id, snapshot.vm_id
+                );
+                vm.restored = true;
+                self.backend.restore(&vm.id, snapshot, opts)?
+            }
+            None => self.backend.start(&vm.id, opts)?,
+        };
+        vm.vm_task = Some(task);
+
+        Ok(())
+    }
+
+    /// Take a snapshot of a ready VM if its language does not have one yet
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the ready VM
+    pub fn take_snapshot(&mut self, id: &str) -> Result<(), Error> {
+        let directory = match self.backend.snapshot_dir() {
+            Some(directory) if self.snapshots_enabled() => directory,
+            _ => return Ok(()),
+        };
+
+        let vm = self
+            .vms
+            .iter()
+            .find(|vm| vm.id == id)
+            .ok_or(Error::VmNotFound)?;
+        let key = snapshot_key(&vm.language_settings);
+        // A clone has the same memory as the VM its snapshot comes from
+        if vm.restored || self.snapshots.contains_key(&key) {
+            return Ok(());
+        }
+
+        info!(
+            "Taking a snapshot of VM {} for language {} {}",
+            id, vm.language_settings.name, vm.language_settings.version
+        );
+        let snapshot = self.backend.snapshot(id, &directory.join(&key))?;
+        debug!("Snapshot of VM {}: {:?}", id, snapshot);
+        self.snapshots.insert(key, snapshot);
+
+        Ok(())
+    }
+
+    /// Stop restoring VMs from the snapshot of a language, once it was redefined
+    pub fn forget_snapshot(&mut self, language_settings: &LanguageSettings) {
+        if self
+            .snapshots
+            .remove(&snapshot_key(language_settings))
+            .is_some()
+        {
+            debug!(
+                "Forgot the snapshot of language {} {}",
+                language_settings.name, language_settings.version
+            );
+        }
+    }
+}
+
+fn snapshot_key(language_settings: &LanguageSettings) -> String {
+    format!("{}-{}", language_settings.name, language_settings.version)
+}
+
+/// The VMs of the state, as last seen by the admin API and the metrics
+///
+/// Executions hold the state until they are done, so the VMs are listed here
+/// behind a lock of their own: the VMs update their entry on every status
+/// change, and the list is replaced whenever VMs are added or removed.
+#[derive(Debug, Clone, Default)]
+pub struct VMDirectory {
+    entries: Arc<RwLock<Vec<VMEntry>>>,
+}
+
+#[derive(Debug, Clone)]
+struct VMEntry {
+    summary: VMSummary,
+    console: Option<String>,
+    start_timestamp: tokio::time::Instant,
+    execute_timestamp: Option<tokio::time::Instant>,
+}
+
+impl VMEntry {
+    fn new(vm: &VMState) -> Self {
+        VMEntry {
+            summary: VMSummary::from(vm),
+            console: vm.vm_opts.console.clone(),
+            start_timestamp: vm.start_timestamp,
+            execute_timestamp: vm.execute_timestamp,
+        }
+    }
+}
+
+impl VMDirectory {
+    /// Replace the listed VMs with the VMs of the state
+    pub fn replace(&self, vms: &[VMState]) {
+        let entries = vms.iter().map(VMEntry::new).collect();
+        *self.entries.write().unwrap_or_else(|e| e.into_inner()) = entries;
+    }
+
+    /// Update the entry of a VM, if it is still listed
+    fn update(&self, vm: &VMState) {
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = entries.iter_mut().find(|entry| entry.summary.id == vm.id) {
+            *entry = VMEntry::new(vm);
+        }
+    }
+
+    /// The summaries of the listed VMs, with their ages as of now
+    pub fn summaries(&self) -> Vec<VMSummary> {
+        let entries = self.entries.read().unwrap_or_else(|e| e.into_inner());
+        entries
+            .iter()
+            .map(|entry| VMSummary {
+                age: entry.start_timestamp.elapsed().as_secs(),
+                last_request_age: entry
+                    .execute_timestamp
+                    .map(|timestamp| timestamp.elapsed().as_secs()),
+                ..entry.summary.clone()
+            })
+            .collect()
+    }
+
+    /// The file capturing the console output of a VM
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Option<String>>` - `None` if the VM is not listed, the console file otherwise
+    pub fn console(&self, id: &str) -> Option<Option<String>> {
+        let entries = self.entries.read().unwrap_or_else(|e| e.into_inner());
+        entries
+            .iter()
+            .find(|entry| entry.summary.id == id)
+            .map(|entry| entry.console.clone())
+    }
 }
 
 #[derive(Debug)]
@@ -53,13 +296,96 @@ pub struct VMState {
     pub request: Option<ExecuteRequest>,
     pub response: Option<ExecuteResponse>,
     remote_port: Option<u16>,
+    agent_version: Option<String>,
+    capabilities: Vec<Capability>,
+    token: String,
     client: Option<LambdoAgentServiceClient<tonic::transport::Channel>>,
+    pub serial: Option<SerialClient>,
 
-    #[allow(dead_code)]
     start_timestamp: tokio::time::Instant,
     execute_timestamp: Option<tokio::time::Instant>,
     tx: tokio::sync::broadcast::Sender<(String, VMStatus)>,
+    directory: VMDirectory,
     pub reserved: bool,
+    /// Whether the VM was restored from a snapshot
+    pub restored: bool,
+    /// Whether the addresses and the tap interface of the VM were given back
+    pub released: bool,
+}
+
+/// A request being run on a VM, sent without holding the state of the API
+pub struct Execution {
+    id: String,
+    client: Option<LambdoAgentServiceClient<tonic::transport::Channel>>,
+    serial: Option<SerialClient>,
+}
+
+impl Execution {
+    /// Send the request to the agent and wait for its response
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request to run
+    /// * `timeout` - How long the agent may take to answer
+    /// * `output` - Where the output is sent as the agent streams it over the serial line,
+    ///   the agents reached over gRPC send it to the API service of the VMs instead
+    #[instrument(skip_all, fields(vm = %self.id))]
+    pub async fn run(
+        mut self,
+        request: ExecuteRequest,
+        timeout: Duration,
+        output: Option<OutputSender>,
+    ) -> Result<ExecuteResponse, super::vmm::Error> {
+        select! {
+            response = self.send_request(request.clone(), output) => {
+                let response = response.map_err(|e| {
+                    warn!("Error while executing request: {:?}", e);
+                    debug!("Request: {:?}", request);
+                    e
+                })?;
+
+                // The agents give back the ID of the request over the serial line
+                let response = ExecuteResponse {
+                    id: self.id.clone(),
+                    ..response
+                };
+                debug!("Response from VMM: {:?}", response);
+
+                Ok(response)
+            }
+
+            _ = tokio::time::sleep(timeout) => {
+                warn!("Timeout while executing request");
+                Err(Error::Timeout)
+            }
+        }
+    }
+
+    async fn send_request(
+        &mut self,
+        request: ExecuteRequest,
+        output: Option<OutputSender>,
+    ) -> Result<ExecuteResponse, super::vmm::Error> {
+        if let Some(serial) = &self.serial {
+            let mut request = request;
+            telemetry::inject_serial_context(&mut request.trace_context);
+            return serial.execute(request, output).await;
+        }
+
+        let mut request = tonic::Request::new(request);
+        telemetry::inject_context(request.metadata_mut());
+
+        self.client
+            .as_mut()
+            .ok_or(Error::GrpcError)?
+            .execute(request)
+            .await
+            .map(|response| response.into_inner())
+            .map_err(|e| {
+                debug!("gRPC error: {:?}", e);
+                Error::ExecutionError
+            })
+    }
 }
 
 impl VMState {
@@ -68,6 +394,7 @@ impl VMState {
         vm_opts: VMMOpts,
         language_config: LanguageSettings,
         tx: tokio::sync::broadcast::Sender<(String, VMStatus)>,
+        directory: VMDirectory,
         reserved: bool,
     ) -> Self {
         VMState {
@@ -79,11 +406,18 @@ impl VMState {
             request: None,
             response: None,
             remote_port: None,
+            agent_version: None,
+            capabilities: Vec::new(),
+            token: Uuid::new_v4().simple().to_string(),
             client: None,
+            serial: None,
             start_timestamp: tokio::time::Instant::now(),
             execute_timestamp: None,
             tx,
+            directory,
             reserved,
+            restored: false,
+            released: false,
         }
     }
 
@@ -91,43 +425,32 @@ impl VMState {
         self.state
     }
 
-    pub async fn execute(
-        &mut self,
-        request: ExecuteRequest,
-    ) -> Result<ExecuteResponse, super::vmm::Error> {
+    /// Mark the VM as running the request and get what is needed to send it
+    ///
+    /// The request is sent through the returned `Execution` once the state is
+    /// unlocked, so that the other VMs can be used meanwhile.
+    pub fn start_execution(&mut self, request: &ExecuteRequest) -> Execution {
         self.request = Some(request.clone());
         self.set_state(VMStatus::Running);
 
         info!("Running payload on {}", self.id);
 
-        select! {
-            response =
-            self
-            .client
-            .as_mut()
-            .unwrap()
-            .execute(request.clone())
-             => {
-                let response = response.map_err(|e| {
-                    warn!("Error while executing request: {:?}", e);
-                    debug!("Request: {:?}", request);
-                    self.set_state(VMStatus::Ended);
-                    Error::ExecutionError
-                })?
-                .into_inner();
-
-                self.response = Some(response.clone());
-                debug!("Response from VMM: {:?}", response);
+        Execution {
+            id: self.id.clone(),
+            client: self.client.clone(),
+            serial: self.serial.clone(),
+        }
+    }
 
-                self.set_state(VMStatus::Ended);
-                Ok(response)
-            }
+    /// Record the outcome of the request sent through `start_execution`
+    pub fn end_execution(&mut self, result: &Result<ExecuteResponse, super::vmm::Error>) {
+        if let Ok(response) = result {
+            self.response = Some(response.clone());
+        }
 
-            _ = tokio::time::sleep(tokio::time::Duration::from_secs(15)) => {
-                warn!("Timeout while executing request");
-                self.set_state(VMStatus::Ended);
-                Err(Error::Timeout)
-            }
+        // The VM may have been killed meanwhile
+        if self.state != VMStatus::Ended {
+            self.set_state(VMStatus::Ended);
         }
     }
 
@@ -135,6 +458,11 @@ impl VMState {
         debug!("VM {} is ready", self.id);
         self.set_state(VMStatus::Ready);
 
+        // The agent is reached through the console socket, no need to connect
+        if self.serial.is_some() {
+            return Ok(());
+        }
+
         // Safe, since we created the VMState with an IP
         let ip = self.vm_opts.ip.unwrap().address();
         // Safe, since we got the port already at this stage
@@ -158,8 +486,35 @@ impl VMState {
         Ok(())
     }
 
-    pub fn register(&mut self, port: u32) -> Result<u16, anyhow::Error> {
-        match port.try_into() {
+    pub fn register(&mut self, request: RegisterRequest) -> Result<u16, anyhow::Error> {
+        // Every VM is given its token along with its boot arguments
+        if request.token != self.token {
+            error!("VM {} sent an invalid token", self.id);
+            return Err(anyhow!("Invalid VM token"));
+        }
+
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&request.protocol_version) {
+            error!(
+                "VM {} runs agent {:?} with incompatible protocol version {}",
+                self.id, request.agent_version, request.protocol_version
+            );
+            self.set_state(VMStatus::Ended);
+            return Err(anyhow!(
+                "Incompatible agent: protocol version {} is not supported (expected {} to {}), rebuild the initramfs with an up to date agent",
+                request.protocol_version,
+                MIN_PROTOCOL_VERSION,
+                PROTOCOL_VERSION
+            ));
+        }
+
+        self.capabilities = request.capabilities().collect();
+        debug!(
+            "VM {} runs agent {} with capabilities {:?}",
+            self.id, request.agent_version, self.capabilities
+        );
+        self.agent_version = Some(request.agent_version);
+
+        match request.port.try_into() {
             Ok(port) => {
                 self.remote_port = Some(port);
                 Ok(port)
@@ -172,6 +527,62 @@ impl VMState {
         }
     }
 
+    /// Stop using this VM, aborting its task kills its VMM
+    pub fn kill(&mut self) {
+        if let Some(task) = &self.vm_task {
+            task.abort();
+        }
+        self.set_state(VMStatus::Ended);
+    }
+
+    /// The time since the VM was created
+    pub fn age(&self) -> Duration {
+        self.start_timestamp.elapsed()
+    }
+
+    /// The time since the VM started its last execution
+    pub fn last_execution(&self) -> Option<Duration> {
+        self.execute_timestamp.map(|timestamp| timestamp.elapsed())
+    }
+
+    /// The token given to the agent to identify this VM
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// The identity of this VM, as sent to a clone restored from a snapshot
+    pub fn identity(&self) -> Identity {
+        Identity {
+            ip: self.vm_opts.ip.map(|ip| ip.to_string()).unwrap_or_default(),
+            gateway: self.vm_opts.gateway.clone().unwrap_or_default(),
+            token: self.token.clone(),
+        }
+    }
+
+    /// Send its identity to the agent of a VM restored from a snapshot
+    ///
+    /// The agent takes the new address and registers again with the new token to get the new ID.
+    pub fn send_identity(&self) -> Result<(), Error> {
+        debug!("Sending its identity to VM {}", self.id);
+        self.serial
+            .as_ref()
+            .ok_or_else(|| {
+                Error::SerialSetupError(anyhow!("VM {} has no console socket", self.id))
+            })?
+            .send(SerialMessage {
+                payload: Some(Payload::Identity(self.identity())),
+            })
+    }
+
+    pub fn agent_version(&self) -> Option<&str> {
+        self.agent_version.as_deref()
+    }
+
+    /// Whether the agent of this VM advertised the given capability
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
     fn set_state(&mut self, state: VMStatus) {
         match state {
             VMStatus::Ready => {
@@ -187,20 +598,374 @@ impl VMState {
             }
             VMStatus::Ended => {
                 debug!("VM {} has ended", self.id);
-                // TODO: Find a way to kill the VM
-                // Probably need to make edit lumper
                 self.state = state;
                 self.tx.send((self.id.clone(), self.state)).unwrap();
             }
             _ => {}
         }
+        self.directory.update(self);
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum VMStatus {
-    Waiting,
-    Ready,
-    Running,
-    Ended,
+#[cfg(test)]
+mod test {
+    use std::{path::PathBuf, sync::Arc, time::Duration};
+
+    use lambdo_shared::serial::{encode_frame, Decoded, FrameDecoder};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixListener,
+        sync::Mutex,
+    };
+
+    use super::{LambdoState, VMDirectory, VMState, VMStatus};
+    use crate::{
+        config::{LambdoConfig, LambdoNetworkPolicy},
+        model::LanguageSettings,
+        vm_manager::{
+            grpc_definitions::{
+                serial_message::Payload, Capability, ExecuteRequest, ExecuteResponse,
+                RegisterRequest, SerialMessage,
+            },
+            vmm::{
+                backend::{MockVMMBackend, Snapshot},
+                serial::SerialClient,
+                VMMOpts, PROTOCOL_VERSION,
+            },
+        },
+    };
+
+    fn generate_lambdo_state(transport: &str, backend: MockVMMBackend) -> LambdoState {
+        let config: LambdoConfig = serde_yaml::from_str(&format!(
+            "
+apiVersion: lambdo.io/v1beta1
+kind: Config
+vmm:
+  kernel: vmlinux.bin
+  transport: {}
+api:
+  web_host: 0.0.0.0
+  web_port: 3000
+  grpc_host: 0.0.0.0
+  grpc_port: 50051
+agent:
+  path: /usr/local/bin/lambdo-agent
+  config: /etc/lambdo/agent.yaml
+languages: []
+",
+            transport
+        ))
+        .unwrap();
+
+        LambdoState::new(config).with_backend(Arc::new(backend))
+    }
+
+    fn generate_snapshot() -> Snapshot {
+        Snapshot {
+            vm_id: "source".to_string(),
+            memory: PathBuf::from("/var/lib/lambdo/snapshots/NODE-12/memory"),
+            state: PathBuf::from("/var/lib/lambdo/snapshots/NODE-12/state"),
+        }
+    }
+
+    fn generate_vm_state() -> (
+        VMState,
+        tokio::sync::broadcast::Receiver<(String, VMStatus)>,
+    ) {
+        let (tx, rx) = tokio::sync::broadcast::channel(16);
+        let vm = VMState::new(
+            "test".to_string(),
+            VMMOpts {
+                kernel: "vmlinux.bin".to_string(),
+                cpus: 1,
+                memory: 1024,
+                console: None,
+                socket: None,
+                initramfs: None,
+                tap: None,
+                ip: None,
+                ipv6: None,
+                gateway: None,
+                boot_args: Vec::new(),
+            },
+            LanguageSettings {
+                name: "NODE".to_string(),
+                version: "12".to_string(),
+                initramfs: "node-12.img".to_string(),
+                cache_image: None,
+                network: LambdoNetworkPolicy::None,
+            },
+            tx,
+            VMDirectory::default(),
+            false,
+        );
+
+        (vm, rx)
+    }
+
+    #[tokio::test]
+    async fn test_register_compatible_agent() {
+        let (mut vm, _rx) = generate_vm_state();
+
+        let port = vm
+            .register(RegisterRequest {
+                port: 50052,
+                agent_version: "0.1.0".to_string(),
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: vec![Capability::Limits.into()],
+                token: vm.token().to_string(),
+            })
+            .unwrap();
+
+        assert_eq!(port, 50052);
+        assert_eq!(vm.agent_version(), Some("0.1.0"));
+        assert!(vm.has_capability(Capability::Limits));
+        assert!(!vm.has_capability(Capability::Streaming));
+    }
+
+    #[tokio::test]
+    async fn test_register_outdated_agent() {
+        let (mut vm, _rx) = generate_vm_state();
+
+        // Agents predating the negotiation send no protocol version
+        let result = vm.register(RegisterRequest {
+            port: 50052,
+            token: vm.token().to_string(),
+            ..Default::default()
+        });
+
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("protocol version 0 is not supported"));
+        assert_eq!(vm.get_state(), VMStatus::Ended);
+    }
+
+    #[tokio::test]
+    async fn test_register_newer_agent() {
+        let (mut vm, _rx) = generate_vm_state();
+
+        let result = vm.register(RegisterRequest {
+            port: 50052,
+            agent_version: "9.0.0".to_string(),
+            protocol_version: PROTOCOL_VERSION + 1,
+            capabilities: vec![],
+            token: vm.token().to_string(),
+        });
+
+        assert!(result.is_err());
+        assert_eq!(vm.get_state(), VMStatus::Ended);
+    }
+
+    #[tokio::test]
+    async fn test_directory_follows_the_vms() {
+        let (vm, _rx) = generate_vm_state();
+        let directory = vm.directory.clone();
+        let vms = tokio::sync::Mutex::new(vec![vm]);
+        directory.replace(&vms.lock().await);
+
+        // The directory is read while the VMs are locked by an execution
+        let mut locked = vms.lock().await;
+        locked[0].kill();
+        let summaries = directory.summaries();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].id, "test");
+        assert_eq!(summaries[0].status, VMStatus::Ended);
+
+        locked.clear();
+        directory.replace(&locked);
+        assert!(directory.summaries().is_empty());
+        assert_eq!(directory.console("test"), None);
+    }
+
+    #[tokio::test]
+    async fn test_register_invalid_token() {
+        let (mut vm, _rx) = generate_vm_state();
+
+        let result = vm.register(RegisterRequest {
+            port: 50052,
+            agent_version: "0.1.0".to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec![],
+            token: "not-the-token".to_string(),
+        });
+
+        assert!(result.is_err());
+        assert_eq!(vm.get_state(), VMStatus::Waiting);
+
+        // An agent that was not given the token cannot register either
+        let result = vm.register(RegisterRequest {
+            port: 50052,
+            agent_version: "0.1.0".to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec![],
+            token: String::new(),
+        });
+
+        assert!(result.is_err());
+        assert_eq!(vm.get_state(), VMStatus::Waiting);
+    }
+
+    #[tokio::test]
+    async fn test_boot_restores_from_snapshot() {
+        let mut backend = MockVMMBackend::new();
+        backend
+            .expect_snapshot_dir()
+            .return_const(Some(PathBuf::from("/var/lib/lambdo/snapshots")));
+        backend.expect_start().never();
+        backend
+            .expect_restore()
+            .withf(|id, snapshot, opts| {
+                id == "test" && snapshot.vm_id == "source" && opts.ip.is_some()
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(tokio::spawn(async { Ok(()) })));
+
+        let mut state = generate_lambdo_state("serial", backend);
+        state
+            .snapshots
+            .insert("NODE-12".to_string(), generate_snapshot());
+        let (mut vm, _rx) = generate_vm_state();
+        vm.vm_opts.ip = Some("192.168.10.3/24".parse().unwrap());
+        vm.vm_opts.gateway = Some("192.168.10.1".to_string());
+
+        state.boot(&mut vm).unwrap();
+
+        assert!(vm.restored);
+        assert!(vm.vm_task.is_some());
+
+        // The clone gets the address and token of the new VM
+        let identity = vm.identity();
+        assert_eq!(identity.token, vm.token());
+        assert_eq!(identity.ip, "192.168.10.3/24");
+        assert_eq!(identity.gateway, "192.168.10.1");
+    }
+
+    #[tokio::test]
+    async fn test_boot_without_snapshot() {
+        let mut backend = MockVMMBackend::new();
+        backend
+            .expect_snapshot_dir()
+            .return_const(Some(PathBuf::from("/var/lib/lambdo/snapshots")));
+        backend.expect_restore().never();
+        backend
+            .expect_start()
+            .withf(|id, _| id == "test")
+            .times(1)
+            .returning(|_, _| Ok(tokio::spawn(async { Ok(()) })));
+
+        let state = generate_lambdo_state("serial", backend);
+        let (mut vm, _rx) = generate_vm_state();
+
+        state.boot(&mut vm).unwrap();
+
+        assert!(!vm.restored);
+        assert!(vm.vm_task.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_taken_once_per_language() {
+        let mut backend = MockVMMBackend::new();
+        backend
+            .expect_snapshot_dir()
+            .return_const(Some(PathBuf::from("/var/lib/lambdo/snapshots")));
+        backend
+            .expect_snapshot()
+            .withf(|id, directory| id == "test" && directory.ends_with("snapshots/NODE-12"))
+            .times(1)
+            .returning(|_, _| Ok(generate_snapshot()));
+
+        let mut state = generate_lambdo_state("serial", backend);
+        let (vm, _rx) = generate_vm_state();
+        let (mut other_vm, _other_rx) = generate_vm_state();
+        other_vm.id = "other".to_string();
+        state.vms.push(vm);
+        state.vms.push(other_vm);
+
+        state.take_snapshot("test").unwrap();
+        state.take_snapshot("other").unwrap();
+
+        assert_eq!(state.snapshots.len(), 1);
+        assert_eq!(state.snapshots.get("NODE-12"), Some(&generate_snapshot()));
+    }
+
+    #[tokio::test]
+    async fn test_execution_does_not_hold_the_vm() {
+        let path = std::env::temp_dir().join(format!("lambdo-state-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let (events, _) = tokio::sync::broadcast::channel(1);
+        let (mut vm, _rx) = generate_vm_state();
+        vm.serial = Some(
+            SerialClient::connect(
+                "test".to_string(),
+                path.to_string_lossy().to_string(),
+                None,
+                events,
+            )
+            .await
+            .unwrap(),
+        );
+        let (mut agent, _) = listener.accept().await.unwrap();
+        let vm = Arc::new(Mutex::new(vm));
+
+        let request = ExecuteRequest {
+            id: "request".to_string(),
+            ..Default::default()
+        };
+        let execution = vm.lock().await.start_execution(&request);
+        let execution = tokio::spawn(execution.run(request, Duration::from_secs(5), None));
+
+        let mut decoder = FrameDecoder::<SerialMessage>::new();
+        let mut buffer = [0; 4096];
+        let request = loop {
+            if let Ok(Some(Decoded::Message(message))) = decoder.next_decoded() {
+                break message;
+            }
+            let read = agent.read(&mut buffer).await.unwrap();
+            decoder.push(&buffer[..read]);
+        };
+        assert!(matches!(
+            request.payload,
+            Some(Payload::ExecuteRequest(request)) if request.id == "request"
+        ));
+
+        // The agent is still running the request, the VM can be looked at meanwhile
+        assert_eq!(vm.try_lock().unwrap().get_state(), VMStatus::Running);
+
+        let response = SerialMessage {
+            payload: Some(Payload::ExecuteResponse(ExecuteResponse {
+                id: "request".to_string(),
+                ..Default::default()
+            })),
+        };
+        agent.write_all(&encode_frame(&response)).await.unwrap();
+        let result = execution.await.unwrap();
+        assert_eq!(result.as_ref().unwrap().id, "test");
+
+        let mut vm = vm.lock().await;
+        vm.end_execution(&result);
+        assert_eq!(vm.get_state(), VMStatus::Ended);
+        assert!(vm.response.is_some());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_snapshots_need_serial_transport() {
+        let mut backend = MockVMMBackend::new();
+        backend
+            .expect_snapshot_dir()
+            .return_const(Some(PathBuf::from("/var/lib/lambdo/snapshots")));
+        backend.expect_snapshot().never();
+
+        let mut state = generate_lambdo_state("grpc", backend);
+        let (vm, _rx) = generate_vm_state();
+        state.vms.push(vm);
+
+        assert!(!state.snapshots_enabled());
+        state.take_snapshot("test").unwrap();
+        assert!(state.snapshots.is_empty());
+    }
 }
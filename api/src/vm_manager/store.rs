@@ -0,0 +1,216 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Result};
+use log::{debug, trace};
+use serde::{Deserialize, Serialize};
+
+use super::state::{VMState, VMStatus};
+
+/// What is recorded about a VM to clean up after it once the API restarts
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VMRecord {
+    pub id: String,
+    pub language: String,
+    pub version: String,
+    pub status: VMStatus,
+    /// The tap interface of the VM
+    pub tap: Option<String>,
+    /// The IP address of the VM, with its prefix length
+    pub ip: Option<String>,
+    /// The IPv6 address of the VM, with its prefix length
+    #[serde(default)]
+    pub ipv6: Option<String>,
+    /// The console socket of the VM
+    pub socket: Option<String>,
+    /// The file capturing the console output of the VM
+    #[serde(default)]
+    pub console: Option<String>,
+}
+
+impl From<&VMState> for VMRecord {
+    fn from(vm: &VMState) -> Self {
+        VMRecord {
+            id: vm.id.clone(),
+            language: vm.language_settings.name.clone(),
+            version: vm.language_settings.version.clone(),
+            status: vm.get_state(),
+            tap: vm.vm_opts.tap.clone(),
+            ip: vm.vm_opts.ip.map(|ip| ip.to_string()),
+            ipv6: vm.vm_opts.ipv6.map(|ip| ip.to_string()),
+            socket: vm.vm_opts.socket.clone(),
+            console: vm.vm_opts.console.clone(),
+        }
+    }
+}
+
+/// The JSON file holding the records of the VMs
+#[derive(Debug, Clone)]
+pub struct StateStore {
+    path: PathBuf,
+}
+
+impl StateStore {
+    pub fn new(path: &str) -> Self {
+        StateStore {
+            path: PathBuf::from(path),
+        }
+    }
+
+    /// Load the records saved by the previous run
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<VMRecord>>` - The records, empty if nothing was saved yet
+    pub fn load(&self) -> Result<Vec<VMRecord>> {
+        if !self.path.exists() {
+            debug!("No state file at {}", self.path.display());
+            return Ok(Vec::new());
+        }
+
+        let file = File::open(&self.path)
+            .map_err(|e| anyhow!("failed to open {}: {}", self.path.display(), e))?;
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| anyhow!("failed to parse {}: {}", self.path.display(), e))
+    }
+
+    /// Replace the saved records
+    ///
+    /// The records are written to a temporary file renamed over the previous
+    /// one, a crash never leaves a truncated state file behind.
+    ///
+    /// # Arguments
+    ///
+    /// * `records` - The records of all the known VMs
+    pub fn save(&self, records: &[VMRecord]) -> Result<()> {
+        trace!("Saving {} VM records", records.len());
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| anyhow!("failed to create {}: {}", parent.display(), e))?;
+        }
+
+        let temporary = self.path.with_extension("tmp");
+        let file = File::create(&temporary)
+            .map_err(|e| anyhow!("failed to create {}: {}", temporary.display(), e))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), records)
+            .map_err(|e| anyhow!("failed to write {}: {}", temporary.display(), e))?;
+
+        fs::rename(&temporary, &self.path)
+            .map_err(|e| anyhow!("failed to replace {}: {}", self.path.display(), e))
+    }
+}
+
+/// The resources left behind by a previous run of the API
+#[derive(Debug, Default, PartialEq)]
+pub struct Leftovers {
+    /// The VMs that had not ended
+    pub vms: Vec<String>,
+    /// The tap interfaces to delete
+    pub taps: Vec<String>,
+    /// The console sockets and console output files to remove
+    pub files: Vec<String>,
+}
+
+/// Find what a previous run of the API left behind
+///
+/// # Arguments
+///
+/// * `records` - The records saved by the previous run
+/// * `bridge_interfaces` - The interfaces currently attached to the bridge
+pub fn leftovers(records: &[VMRecord], bridge_interfaces: &[String]) -> Leftovers {
+    let mut taps = records
+        .iter()
+        .filter_map(|record| record.tap.clone())
+        .chain(
+            bridge_interfaces
+                .iter()
+                .filter(|name| name.starts_with("tap-"))
+                .cloned(),
+        )
+        .collect::<Vec<String>>();
+    taps.sort();
+    taps.dedup();
+
+    Leftovers {
+        vms: records
+            .iter()
+            .filter(|record| record.status != VMStatus::Ended)
+            .map(|record| record.id.clone())
+            .collect(),
+        taps,
+        files: records
+            .iter()
+            .flat_map(|record| [record.socket.clone(), record.console.clone()])
+            .flatten()
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn generate_record(id: &str, status: VMStatus) -> VMRecord {
+        VMRecord {
+            id: id.to_string(),
+            language: "NODE".to_string(),
+            version: "12".to_string(),
+            status,
+            tap: Some(format!("tap-{}", id)),
+            ip: Some("192.168.10.2/24".to_string()),
+            ipv6: None,
+            socket: None,
+            console: None,
+        }
+    }
+
+    #[test]
+    fn test_store_round_trip() {
+        let directory = std::env::temp_dir().join(format!("lambdo-store-{}", std::process::id()));
+        let store = StateStore::new(directory.join("state.json").to_str().unwrap());
+
+        assert_eq!(store.load().unwrap(), Vec::new());
+
+        let records = vec![
+            generate_record("a", VMStatus::Ready),
+            generate_record("b", VMStatus::Ended),
+        ];
+        store.save(&records).unwrap();
+        let loaded = store.load().unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(loaded, records);
+    }
+
+    #[test]
+    fn test_leftovers() {
+        let mut with_socket = generate_record("b", VMStatus::Waiting);
+        with_socket.socket = Some("/tmp/lambdo-b.sock".to_string());
+        with_socket.console = Some("/tmp/lambdo-b.log".to_string());
+        let records = vec![generate_record("a", VMStatus::Ended), with_socket];
+        let bridge_interfaces = vec![
+            "tap-b".to_string(),
+            "tap-c".to_string(),
+            "veth0".to_string(),
+        ];
+
+        assert_eq!(
+            leftovers(&records, &bridge_interfaces),
+            Leftovers {
+                vms: vec!["b".to_string()],
+                taps: vec![
+                    "tap-a".to_string(),
+                    "tap-b".to_string(),
+                    "tap-c".to_string()
+                ],
+                files: vec![
+                    "/tmp/lambdo-b.sock".to_string(),
+                    "/tmp/lambdo-b.log".to_string()
+                ],
+            }
+        );
+    }
+}
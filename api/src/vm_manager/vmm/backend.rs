@@ -0,0 +1,93 @@
+use std::path::{Path, PathBuf};
+
+use mockall::automock;
+use tokio::task::JoinHandle;
+
+use super::{Error, VMMOpts};
+
+/// The task running a VM until it stops
+pub type VMTask = JoinHandle<Result<(), Error>>;
+
+/// A memory and device snapshot of a VM whose agent is ready
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    /// The ID of the VM the snapshot was taken from
+    pub vm_id: String,
+    /// The file holding the guest memory
+    pub memory: PathBuf,
+    /// The file holding the state of the vCPUs and devices
+    pub state: PathBuf,
+}
+
+/// The VMM used to boot, snapshot and restore the VMs
+#[automock]
+pub trait VMMBackend: Send + Sync {
+    /// Boot a new VM
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the VM
+    /// * `opts` - The options of the VM
+    ///
+    /// # Returns
+    ///
+    /// * `Result<VMTask, Error>` - The task running the VM or an error
+    fn start(&self, id: &str, opts: VMMOpts) -> Result<VMTask, Error>;
+
+    /// The directory where the backend keeps its snapshots
+    ///
+    /// # Returns
+    ///
+    /// * `Option<PathBuf>` - The directory, `None` if the backend cannot take and restore snapshots
+    fn snapshot_dir(&self) -> Option<PathBuf>;
+
+    /// Take a snapshot of a running VM
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the VM
+    /// * `directory` - The directory where the snapshot files are written
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Snapshot, Error>` - The snapshot or an error
+    fn snapshot(&self, id: &str, directory: &Path) -> Result<Snapshot, Error>;
+
+    /// Boot a clone of a VM from its snapshot
+    ///
+    /// The options hold the new tap and console socket, the guest still has the
+    /// identity of the snapshotted VM until it is told otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the clone
+    /// * `snapshot` - The snapshot to restore
+    /// * `opts` - The options of the clone
+    ///
+    /// # Returns
+    ///
+    /// * `Result<VMTask, Error>` - The task running the clone or an error
+    fn restore(&self, id: &str, snapshot: &Snapshot, opts: VMMOpts) -> Result<VMTask, Error>;
+}
+
+/// The backend booting the VMs with lumper
+pub struct LumperBackend;
+
+impl VMMBackend for LumperBackend {
+    fn start(&self, _id: &str, opts: VMMOpts) -> Result<VMTask, Error> {
+        super::run(opts)
+    }
+
+    // The pinned lumper revision can neither pause a running VMM nor load a saved state
+    fn snapshot_dir(&self) -> Option<PathBuf> {
+        None
+    }
+
+    fn snapshot(&self, _id: &str, _directory: &Path) -> Result<Snapshot, Error> {
+        Err(Error::SnapshotUnsupported)
+    }
+
+    fn restore(&self, _id: &str, _snapshot: &Snapshot, _opts: VMMOpts) -> Result<VMTask, Error> {
+        Err(Error::SnapshotUnsupported)
+    }
+}
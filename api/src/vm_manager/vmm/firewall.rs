@@ -0,0 +1,285 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Result};
+use cidr::{Ipv4Cidr, Ipv4Inet};
+use log::{debug, info, trace};
+
+use crate::config::LambdoNetworkPolicy;
+
+/// The nftables table holding the rules of lambdo
+///
+/// It is a `bridge` table: the frames of the VMs are filtered on the bridge port they come
+/// from, their tap interface, before being delivered to the host or routed.
+const TABLE: &str = "bridge lambdo";
+
+/// What a VM may send on the bridge
+#[derive(Debug)]
+pub(super) struct VMRules<'a> {
+    /// The tap interface of the VM, the rules are removed along with it
+    pub tap: &'a str,
+    /// The address given to the VM, it cannot send from another one
+    pub ip: Ipv4Inet,
+    /// The address of the bridge, on which the API listens
+    pub host: Ipv4Inet,
+    /// The port of the gRPC server, which the agent needs
+    pub grpc_port: u16,
+    pub policy: &'a LambdoNetworkPolicy,
+}
+
+/// Build the script replacing the table of lambdo with an empty one
+///
+/// The frames from the taps of the VMs go through the chain of their VM, the ones from
+/// an unknown tap are dropped. The traffic between two VMs is always dropped.
+///
+/// # Arguments
+///
+/// * `bridge` - The name of the bridge of the VMs
+pub(super) fn table_script(bridge: &str) -> String {
+    format!(
+        "table {table}
+delete table {table}
+table {table} {{
+    map vms {{
+        type ifname : verdict
+    }}
+    chain input {{
+        type filter hook input priority 0; policy accept;
+        meta ibrname \"{bridge}\" iifname vmap @vms
+        meta ibrname \"{bridge}\" drop
+    }}
+    chain forward {{
+        type filter hook forward priority 0; policy accept;
+        meta ibrname \"{bridge}\" drop
+    }}
+}}
+",
+        table = TABLE,
+        bridge = bridge
+    )
+}
+
+/// Build the script adding the chain of a VM
+///
+/// # Returns
+///
+/// * `Result<String>` - The script, an error if the policy holds an invalid network
+pub(super) fn vm_script(rules: &VMRules) -> Result<String> {
+    let chain = format!("add rule {} {}", TABLE, rules.tap);
+    let mut lines = vec![
+        format!("add chain {} {}", TABLE, rules.tap),
+        format!("{} ether type arp accept", chain),
+        format!("{} ether type != ip drop", chain),
+        format!("{} ip saddr != {} drop", chain, rules.ip.address()),
+        // The API also connects to the agent, its answers have to go through
+        format!("{} ct state established,related accept", chain),
+        format!(
+            "{} ip daddr {} tcp dport {} accept",
+            chain,
+            rules.host.address(),
+            rules.grpc_port
+        ),
+    ];
+
+    match rules.policy {
+        LambdoNetworkPolicy::None => {}
+        LambdoNetworkPolicy::HostOnly => {
+            lines.push(format!(
+                "{} ip daddr {} accept",
+                chain,
+                rules.host.address()
+            ));
+        }
+        LambdoNetworkPolicy::Allowlist { allow } => {
+            for rule in allow {
+                // Parsed so that nothing but a network ends up in the script
+                let cidr = Ipv4Cidr::from_str(&rule.cidr)
+                    .map_err(|e| anyhow!("invalid network {}: {}", rule.cidr, e))?;
+                if rule.ports.is_empty() {
+                    lines.push(format!("{} ip daddr {} accept", chain, cidr));
+                } else {
+                    let ports = rule
+                        .ports
+                        .iter()
+                        .map(|port| port.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    lines.push(format!(
+                        "{} ip daddr {} meta l4proto {{ tcp, udp }} th dport {{ {} }} accept",
+                        chain, cidr, ports
+                    ));
+                }
+            }
+        }
+    }
+
+    lines.push(format!("{} drop", chain));
+    lines.push(format!(
+        "add element {} vms {{ \"{}\" : jump {} }}",
+        TABLE, rules.tap, rules.tap
+    ));
+
+    Ok(lines.join("\n") + "\n")
+}
+
+/// Build the script removing the chain of a VM
+///
+/// # Arguments
+///
+/// * `tap` - The tap interface of the VM
+pub(super) fn removal_script(tap: &str) -> String {
+    format!(
+        "delete element {table} vms {{ \"{tap}\" }}\ndelete chain {table} {tap}\n",
+        table = TABLE,
+        tap = tap
+    )
+}
+
+/// Replace the table of lambdo, dropping the rules of a previous run
+pub(super) fn setup(bridge: &str) -> Result<()> {
+    debug!("setting up the nftables table of bridge {}", bridge);
+    apply(&table_script(bridge))?;
+
+    info!("network policies are enforced on bridge {}", bridge);
+    Ok(())
+}
+
+/// Apply the network policy of a VM, before it boots
+pub(super) fn allow_vm(rules: &VMRules) -> Result<()> {
+    debug!(
+        "applying network policy {:?} to {}",
+        rules.policy, rules.tap
+    );
+    apply(&vm_script(rules)?)
+}
+
+pub(super) fn remove_vm(tap: &str) -> Result<()> {
+    debug!("removing network policy of {}", tap);
+    apply(&removal_script(tap))
+}
+
+fn apply(script: &str) -> Result<()> {
+    trace!("applying nftables script:\n{}", script);
+    let mut child = Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("error when running nft, is nftables installed? {}", e))?;
+
+    // Safe since stdin was piped
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(script.as_bytes())
+        .map_err(|e| anyhow!("error when writing nftables rules: {}", e))?;
+
+    let output = child
+        .wait_with_output()
+        .map_err(|e| anyhow!("error when waiting for nft: {}", e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "error when applying nftables rules: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use cidr::Ipv4Inet;
+
+    use super::{removal_script, table_script, vm_script, VMRules};
+    use crate::config::{LambdoNetworkPolicy, LambdoNetworkRule};
+
+    fn generate_rules(policy: &LambdoNetworkPolicy) -> VMRules<'_> {
+        VMRules {
+            tap: "tap-1234",
+            ip: Ipv4Inet::from_str("192.168.10.2/24").unwrap(),
+            host: Ipv4Inet::from_str("192.168.10.1/24").unwrap(),
+            grpc_port: 50051,
+            policy,
+        }
+    }
+
+    #[test]
+    fn test_table_script() {
+        let script = table_script("lambdo0");
+
+        assert!(script.starts_with("table bridge lambdo\ndelete table bridge lambdo\n"));
+        assert!(script.contains("meta ibrname \"lambdo0\" iifname vmap @vms"));
+        // VM to VM traffic
+        assert!(script.contains(
+            "type filter hook forward priority 0; policy accept;\n        meta ibrname \"lambdo0\" drop"
+        ));
+    }
+
+    #[test]
+    fn test_vm_script_none() {
+        let script = vm_script(&generate_rules(&LambdoNetworkPolicy::None)).unwrap();
+
+        assert_eq!(
+            script,
+            "add chain bridge lambdo tap-1234
+add rule bridge lambdo tap-1234 ether type arp accept
+add rule bridge lambdo tap-1234 ether type != ip drop
+add rule bridge lambdo tap-1234 ip saddr != 192.168.10.2 drop
+add rule bridge lambdo tap-1234 ct state established,related accept
+add rule bridge lambdo tap-1234 ip daddr 192.168.10.1 tcp dport 50051 accept
+add rule bridge lambdo tap-1234 drop
+add element bridge lambdo vms { \"tap-1234\" : jump tap-1234 }
+"
+        );
+    }
+
+    #[test]
+    fn test_vm_script_policies() {
+        let host_only = vm_script(&generate_rules(&LambdoNetworkPolicy::HostOnly)).unwrap();
+        assert!(host_only
+            .contains("ip daddr 192.168.10.1 accept\nadd rule bridge lambdo tap-1234 drop\n"));
+
+        let allowlist = LambdoNetworkPolicy::Allowlist {
+            allow: vec![
+                LambdoNetworkRule {
+                    cidr: "10.0.0.0/8".to_string(),
+                    ports: vec![],
+                },
+                LambdoNetworkRule {
+                    cidr: "1.1.1.1/32".to_string(),
+                    ports: vec![53, 443],
+                },
+            ],
+        };
+        let script = vm_script(&generate_rules(&allowlist)).unwrap();
+        assert!(script.contains("tap-1234 ip daddr 10.0.0.0/8 accept\n"));
+        assert!(script.contains(
+            "tap-1234 ip daddr 1.1.1.1 meta l4proto { tcp, udp } th dport { 53, 443 } accept\n"
+        ));
+        assert!(!script.contains("ip daddr 192.168.10.1 accept"));
+
+        let invalid = LambdoNetworkPolicy::Allowlist {
+            allow: vec![LambdoNetworkRule {
+                cidr: "10.0.0.0/8 accept; flush ruleset".to_string(),
+                ports: vec![],
+            }],
+        };
+        assert!(vm_script(&generate_rules(&invalid)).is_err());
+    }
+
+    #[test]
+    fn test_removal_script() {
+        assert_eq!(
+            removal_script("tap-1234"),
+            "delete element bridge lambdo vms { \"tap-1234\" }\ndelete chain bridge lambdo tap-1234\n"
+        );
+    }
+}
@@ -1,8 +1,8 @@
 use std::sync::Arc;
 
 use super::grpc_definitions::{
-    lambdo_api_service_server::LambdoApiService, register_response, Code, Empty, RegisterRequest,
-    RegisterResponse, StatusMessage,
+    lambdo_api_service_server::LambdoApiService, register_response, Code, Empty, OutputChunk,
+    RegisterRequest, RegisterResponse, StatusMessage,
 };
 use log::{debug, error, info, trace};
 use tokio::sync::Mutex;
@@ -31,6 +31,7 @@ impl LambdoApiService for VMListener {
 
         let mut lambdo_state = self.lambdo_state.lock().await;
         let _tx = lambdo_state.channel.0.clone();
+        let metrics = lambdo_state.metrics.clone();
 
         let vm = match lambdo_state.vms.iter_mut().find(|vm| vm.id.eq(&request.id)) {
             Some(vm) => vm,
@@ -42,21 +43,52 @@ impl LambdoApiService for VMListener {
         debug!("VM {} send a status", vm.id);
 
         match request.code() {
-            Code::Ready => vm.ready().await.unwrap_or_else(|e| {
-                error!("Failed to handle VM ready status: {}", e);
-            }),
+            Code::Ready => {
+                if vm.get_state() == VMStatus::Waiting {
+                    metrics
+                        .boot_duration
+                        .with_label_values(&[&vm.language_settings.name])
+                        .observe(vm.age().as_secs_f64());
+                }
+                vm.ready().await.unwrap_or_else(|e| {
+                    error!("Failed to handle VM ready status: {}", e);
+                })
+            }
             Code::Error => {
                 error!("VM {} reported an error", vm.id);
+                metrics.agent_errors.inc();
             }
             Code::Run => {
                 info!("VM {} send sent a Run status", vm.id);
             }
         };
+
+        if request.code() == Code::Ready {
+            lambdo_state.take_snapshot(&request.id).unwrap_or_else(|e| {
+                error!("Failed to take a snapshot of VM {}: {}", request.id, e);
+            });
+        }
         debug!("Sending empty status response");
 
         Ok(Response::new(Empty {}))
     }
 
+    async fn output(&self, request: Request<OutputChunk>) -> Result<Response<Empty>, Status> {
+        let chunk = request.into_inner();
+        trace!("Received output of request {}", chunk.id);
+
+        let lambdo_state = self.lambdo_state.lock().await;
+        match lambdo_state.outputs.get(&chunk.id) {
+            // The client may have stopped following the execution
+            Some(output) => {
+                let _ = output.send(chunk);
+            }
+            None => debug!("Nobody follows the output of request {}", chunk.id),
+        }
+
+        Ok(Response::new(Empty {}))
+    }
+
     async fn register(
         &self,
         request: Request<RegisterRequest>,
@@ -97,12 +129,13 @@ impl LambdoApiService for VMListener {
                 }));
             }
             1 => {
-                if let Err(e) = vm[0].register(request.into_inner().port) {
+                if let Err(e) = vm[0].register(request.into_inner()) {
                     error!("Failed to register VM: {}", e);
                     Ok(Response::new(RegisterResponse {
-                        response: Some(register_response::Response::Error(
-                            "Failed to register VM".to_string(),
-                        )),
+                        response: Some(register_response::Response::Error(format!(
+                            "Failed to register VM: {}",
+                            e
+                        ))),
                     }))
                 } else {
                     Ok(Response::new(RegisterResponse {
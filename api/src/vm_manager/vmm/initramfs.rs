@@ -0,0 +1,237 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+};
+
+use anyhow::{anyhow, Context, Result};
+use lambdo_shared::BOOT_ARGS_PATH;
+use log::{debug, info};
+use uuid::Uuid;
+
+use crate::model::LanguageSettings;
+
+/// Directory in which the initramfs of the languages with a cache image are assembled
+pub const INITRAMFS_DIR: &str = "/tmp";
+
+/// Get the initramfs to boot the VMs of a language with
+///
+/// The cache image of the language, if any, is appended to its initramfs: the
+/// kernel extracts both archives, then the agent mounts the directories of the
+/// cache read-only. The assembled initramfs is kept until one of them changes.
+///
+/// # Arguments
+///
+/// * `directory` - Where the assembled initramfs are kept
+/// * `language_settings` - The language of the VMs
+///
+/// # Returns
+///
+/// * `Result<String>` - The path of the initramfs
+pub fn assemble(directory: &str, language_settings: &LanguageSettings) -> Result<String> {
+    let cache_image = match &language_settings.cache_image {
+        Some(cache_image) => cache_image,
+        None => return Ok(language_settings.initramfs.clone()),
+    };
+
+    let path = format!(
+        "{}/lambdo-initramfs-{}-{}.img",
+        directory, language_settings.name, language_settings.version
+    );
+    let sources = [language_settings.initramfs.as_str(), cache_image.as_str()];
+    if is_up_to_date(&path, &sources)? {
+        debug!("Reusing the initramfs {}", path);
+        return Ok(path);
+    }
+
+    info!(
+        "Appending the cache image {} to the initramfs {}",
+        cache_image, language_settings.initramfs
+    );
+    // VMs may boot at the same time, the initramfs is replaced at once
+    let partial = format!("{}.{}", path, Uuid::new_v4().simple());
+    let result = concatenate(&partial, &sources).and_then(|_| {
+        fs::rename(&partial, &path).with_context(|| format!("Failed to rename {}", partial))
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    result?;
+
+    Ok(path)
+}
+
+/// Whether a file exists and is newer than its sources
+fn is_up_to_date(path: &str, sources: &[&str]) -> Result<bool> {
+    let modified = match fs::metadata(path) {
+        Ok(metadata) => metadata.modified()?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(anyhow!(e).context(format!("Failed to read {}", path))),
+    };
+
+    for source in sources {
+        let source_modified = fs::metadata(source)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("Failed to read {}", source))?;
+        if source_modified > modified {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Copy an initramfs and append the boot arguments of a VM to it
+///
+/// The arguments are written to [`BOOT_ARGS_PATH`] by a cpio archive of their own,
+/// which the kernel extracts after the initramfs.
+///
+/// # Arguments
+///
+/// * `initramfs` - The initramfs of the language of the VM
+/// * `path` - Where to write the initramfs of the VM
+/// * `boot_args` - The `lambdo.*` boot arguments of the VM
+pub fn with_boot_args(initramfs: &str, path: &str, boot_args: &[String]) -> Result<()> {
+    let mut output = File::create(path).with_context(|| format!("Failed to create {}", path))?;
+    let mut input =
+        File::open(initramfs).with_context(|| format!("Failed to open {}", initramfs))?;
+    let copied = io::copy(&mut input, &mut output)
+        .with_context(|| format!("Failed to copy {}", initramfs))?;
+
+    // The kernel only looks for an archive at an offset aligned on 4 bytes
+    let padding = vec![0; (copied.next_multiple_of(4) - copied) as usize];
+    output
+        .write_all(&padding)
+        .and_then(|_| output.write_all(&boot_args_archive(boot_args)))
+        .with_context(|| format!("Failed to write the boot arguments to {}", path))
+}
+
+/// A cpio archive in the `newc` format holding the boot arguments
+fn boot_args_archive(boot_args: &[String]) -> Vec<u8> {
+    let path = BOOT_ARGS_PATH.trim_start_matches('/');
+    let content = format!("{}\n", boot_args.join(" "));
+    let mut archive = Vec::new();
+
+    if let Some((directory, _)) = path.rsplit_once('/') {
+        push_cpio_entry(&mut archive, 1, directory, 0o040755, b"");
+    }
+    push_cpio_entry(&mut archive, 2, path, 0o100400, content.as_bytes());
+    push_cpio_entry(&mut archive, 0, "TRAILER!!!", 0, b"");
+
+    archive
+}
+
+fn push_cpio_entry(archive: &mut Vec<u8>, inode: u32, name: &str, mode: u32, content: &[u8]) {
+    let links = if mode & 0o040000 != 0 { 2 } else { 1 };
+    // Magic, then inode, mode, uid, gid, links, mtime, size, device and name size in hex
+    let header = format!(
+        "070701{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}",
+        inode,
+        mode,
+        0,
+        0,
+        links,
+        0,
+        content.len(),
+        0,
+        0,
+        0,
+        0,
+        name.len() + 1,
+        0
+    );
+    archive.extend_from_slice(header.as_bytes());
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+    archive.extend_from_slice(content);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+}
+
+fn concatenate(path: &str, sources: &[&str]) -> Result<()> {
+    let mut output = File::create(path).with_context(|| format!("Failed to create {}", path))?;
+    for source in sources {
+        let mut input = File::open(source).with_context(|| format!("Failed to open {}", source))?;
+        io::copy(&mut input, &mut output).with_context(|| format!("Failed to copy {}", source))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::{assemble, with_boot_args};
+    use crate::{config::LambdoNetworkPolicy, model::LanguageSettings};
+
+    #[test]
+    fn test_assemble_initramfs() {
+        let directory =
+            std::env::temp_dir().join(format!("lambdo-initramfs-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let initramfs = directory.join("node-12.img");
+        let cache_image = directory.join("npm-cache.img");
+        fs::write(&initramfs, "initramfs").unwrap();
+        fs::write(&cache_image, "cache").unwrap();
+
+        let mut language_settings = LanguageSettings {
+            name: "NODE".to_string(),
+            version: "12".to_string(),
+            initramfs: initramfs.display().to_string(),
+            cache_image: None,
+            network: LambdoNetworkPolicy::None,
+        };
+        let directory_path = directory.display().to_string();
+
+        // Without a cache image, the initramfs of the language is used as is
+        let path = assemble(&directory_path, &language_settings).unwrap();
+        assert_eq!(path, language_settings.initramfs);
+
+        language_settings.cache_image = Some(cache_image.display().to_string());
+        let path = assemble(&directory_path, &language_settings).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        let reused = assemble(&directory_path, &language_settings).unwrap();
+        let files = fs::read_dir(&directory).unwrap().count();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(
+            path,
+            format!("{}/lambdo-initramfs-NODE-12.img", directory_path)
+        );
+        assert_eq!(content, "initramfscache");
+        assert_eq!(reused, path);
+        assert_eq!(files, 3);
+    }
+
+    #[test]
+    fn test_initramfs_with_boot_args() {
+        let directory =
+            std::env::temp_dir().join(format!("lambdo-boot-args-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let initramfs = directory.join("node-12.img");
+        let path = directory.join("boot.img");
+        fs::write(&initramfs, "initramfs").unwrap();
+
+        with_boot_args(
+            &initramfs.display().to_string(),
+            &path.display().to_string(),
+            &[
+                "lambdo.api_port=50051".to_string(),
+                "lambdo.token=abcd".to_string(),
+            ],
+        )
+        .unwrap();
+        let content = fs::read(&path).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        // The archive starts on the next multiple of 4 bytes
+        assert_eq!(&content[..12], b"initramfs\0\0\0");
+        let archive = String::from_utf8(content[12..].to_vec()).unwrap();
+        assert_eq!(archive.len() % 4, 0);
+        assert!(archive.starts_with("070701"));
+        assert_eq!(archive.matches("070701").count(), 3);
+        assert!(archive.contains("lambdo/cmdline\0"));
+        assert!(archive.contains("lambdo.api_port=50051 lambdo.token=abcd\n"));
+        assert!(archive.contains("TRAILER!!!\0"));
+    }
+}
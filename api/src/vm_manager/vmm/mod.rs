@@ -1,25 +1,48 @@
+pub mod backend;
+mod firewall;
 pub mod grpc_definitions;
 pub mod grpc_server;
+mod initramfs;
 mod net;
+pub mod serial;
 
-use std::{error::Error as STDError, fmt::Display, str::FromStr};
+use std::{error::Error as STDError, fmt::Display, process::ExitStatus, str::FromStr};
 
 use cidr::{IpInet, Ipv4Inet};
-use log::{debug, error, info, trace};
+use clap::Args;
+use log::{debug, error, info, trace, warn};
 use lumper::VMM;
-use tokio::task::JoinHandle;
+use tokio::process::Command;
+use tracing::{field, instrument, Span};
 use uuid::Uuid;
 
-use crate::{model::LanguageSettings, vm_manager::state::VMState};
+use crate::{
+    config::LambdoVMMTransport,
+    model::LanguageSettings,
+    vm_manager::{
+        state::VMState,
+        store::{self, Leftovers, VMRecord},
+        vmm::{backend::VMTask, serial::SerialClient},
+    },
+};
 
 use super::state::LambdoState;
 
+pub use lambdo_shared::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+/// Directory in which the console output of the VMs is captured
+pub const CONSOLE_DIR: &str = "/tmp";
+
 #[derive(Debug)]
 pub enum Error {
     VmmNew(lumper::Error),
     VmmConfigure(lumper::Error),
     VmmRun(lumper::Error),
+    VmmProcess(std::io::Error),
+    VmmExited(ExitStatus),
     NetSetupError(anyhow::Error),
+    SerialSetupError(anyhow::Error),
+    InitramfsError(anyhow::Error),
     BadAgentStatus,
     NoIPAvalaible,
     VmNotFound,
@@ -27,6 +50,10 @@ pub enum Error {
     GrpcError,
     ExecutionError,
     Timeout,
+    SnapshotUnsupported,
+    LanguageNotFound,
+    NoCode,
+    ReloadFailed(Vec<Error>),
 }
 
 impl STDError for Error {}
@@ -37,7 +64,13 @@ impl Display for Error {
             Error::VmmNew(e) => write!(f, "Error while creating VMM: {:?}", e),
             Error::VmmConfigure(e) => write!(f, "Error while configuring VMM: {:?}", e),
             Error::VmmRun(e) => write!(f, "Error while running VMM: {:?}", e),
+            Error::VmmProcess(e) => write!(f, "Error while running VMM process: {}", e),
+            Error::VmmExited(status) => write!(f, "VMM exited with {}", status),
             Error::NetSetupError(e) => write!(f, "Error while setting up network: {:?}", e),
+            Error::SerialSetupError(e) => {
+                write!(f, "Error while setting up serial transport: {:?}", e)
+            }
+            Error::InitramfsError(e) => write!(f, "Error while assembling initramfs: {:?}", e),
             Error::BadAgentStatus => write!(f, "Bad agent status"),
             Error::NoIPAvalaible => write!(f, "No IP address available"),
             Error::VmNotFound => write!(f, "VM not found"),
@@ -45,104 +78,607 @@ impl Display for Error {
             Error::GrpcError => write!(f, "GRPC error"),
             Error::ExecutionError => write!(f, "Execution error"),
             Error::Timeout => write!(f, "Timeout"),
+            Error::SnapshotUnsupported => write!(f, "Snapshots are not supported by the VMM"),
+            Error::LanguageNotFound => write!(f, "Language not found"),
+            Error::NoCode => write!(f, "No code to run"),
+            Error::ReloadFailed(errors) => {
+                let errors = errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+                write!(
+                    f,
+                    "Error while reloading the languages: {}",
+                    errors.join(", ")
+                )
+            }
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Args, Debug, Clone)]
 pub struct VMMOpts {
     /// Linux kernel path
+    #[arg(long)]
     pub kernel: String,
     /// Number of virtual CPUs assigned to the guest
+    #[arg(long)]
     pub cpus: u8,
     /// Memory amount (in MBytes) assigned to the guest
+    #[arg(long)]
     pub memory: u32,
     /// Stdout console file path
+    #[arg(long)]
     pub console: Option<String>,
     /// Path to the socket used for communication with the VMM
+    #[arg(long)]
     pub socket: Option<String>,
     /// initramfs path
+    #[arg(long)]
     pub initramfs: Option<String>,
     // Tap interface name
+    #[arg(long)]
     pub tap: Option<String>,
     // IP address
+    #[arg(long)]
     pub ip: Option<IpInet>,
+    /// IPv6 address, not passed to the VMM
+    #[arg(skip)]
+    pub ipv6: Option<IpInet>,
     // Gateway
+    #[arg(long)]
     pub gateway: Option<String>,
+    /// Extra arguments given to the guest along with the kernel command line
+    #[arg(long = "boot-arg")]
+    pub boot_args: Vec<String>,
+}
+
+impl VMMOpts {
+    /// The arguments of the `vmm` command running a VMM with these options
+    fn args(&self) -> Vec<String> {
+        let mut args = vec![
+            "vmm".to_string(),
+            format!("--kernel={}", self.kernel),
+            format!("--cpus={}", self.cpus),
+            format!("--memory={}", self.memory),
+        ];
+        let optional = [
+            ("console", self.console.clone()),
+            ("socket", self.socket.clone()),
+            ("initramfs", self.initramfs.clone()),
+            ("tap", self.tap.clone()),
+            ("ip", self.ip.map(|ip| ip.to_string())),
+            ("gateway", self.gateway.clone()),
+        ];
+        for (name, value) in optional {
+            if let Some(value) = value {
+                args.push(format!("--{}={}", name, value));
+            }
+        }
+        for boot_arg in &self.boot_args {
+            args.push(format!("--boot-arg={}", boot_arg));
+        }
+
+        args
+    }
+}
+
+/// Run the VMM of a VM in a child process, killed when its task is dropped or aborted
+///
+/// A VMM cannot be stopped from another thread, so each one runs in a process
+/// of its own: the `vmm` command of this binary, see [`run_in_process`].
+pub fn run(opts: VMMOpts) -> Result<VMTask, Error> {
+    let mut command = Command::new(std::env::current_exe().map_err(Error::VmmProcess)?);
+    command.args(opts.args()).kill_on_drop(true);
+    // SAFETY: prctl is async-signal-safe, no memory is touched between fork and exec
+    unsafe {
+        // The VMM does not outlive the API, even when it crashes
+        command.pre_exec(|| {
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    let mut child = command.spawn().map_err(Error::VmmProcess)?;
+    Ok(tokio::spawn(async move {
+        let status = child.wait().await.map_err(Error::VmmProcess)?;
+        if !status.success() {
+            return Err(Error::VmmExited(status));
+        }
+
+        Ok(())
+    }))
 }
 
-pub fn run(opts: VMMOpts) -> Result<JoinHandle<Result<(), Error>>, Error> {
+/// Configure a VMM and run it in the current process until the VM stops
+///
+/// The VMM builds the kernel command line on its own, the boot arguments are
+/// appended to a copy of the initramfs instead, see [`initramfs::with_boot_args`].
+pub fn run_in_process(opts: VMMOpts) -> Result<(), Error> {
     let mut vmm = VMM::new().map_err(Error::VmmNew)?;
     let tap_name = opts.tap.clone();
-    vmm.configure(
+    // With a console socket, the serial client writes the console output to the file
+    let console = match opts.socket {
+        Some(_) => None,
+        None => opts.console,
+    };
+    let boot_initramfs = match &opts.initramfs {
+        Some(initramfs) if !opts.boot_args.is_empty() => {
+            let path = format!(
+                "{}/lambdo-boot-{}.img",
+                initramfs::INITRAMFS_DIR,
+                std::process::id()
+            );
+            initramfs::with_boot_args(initramfs, &path, &opts.boot_args)
+                .map_err(Error::InitramfsError)?;
+            Some(path)
+        }
+        _ => None,
+    };
+
+    let configured = vmm.configure(
         opts.cpus,
         opts.memory,
         &opts.kernel,
-        opts.console,
-        opts.initramfs,
+        console,
+        boot_initramfs.clone().or(opts.initramfs),
         tap_name,
         opts.socket,
         true,
         Some(opts.ip.unwrap().to_string()),
         opts.gateway,
-    )
-    .map_err(Error::VmmConfigure)?;
+    );
+    // The initramfs is loaded in the memory of the guest once the VMM is configured
+    if let Some(path) = boot_initramfs {
+        std::fs::remove_file(&path).unwrap_or_else(|e| {
+            debug!("Failed to remove {}: {:?}", path, e);
+        });
+    }
+    configured.map_err(Error::VmmConfigure)?;
 
-    Ok(tokio::task::spawn_blocking(move || {
-        vmm.run(true).map_err(Error::VmmRun)
-    }))
+    vmm.run(true).map_err(Error::VmmRun)
 }
 
+#[instrument(skip_all, fields(language = %language_settings.name, vm = field::Empty))]
 pub async fn run_vm(
     state: &mut LambdoState,
     language_settings: &LanguageSettings,
+    memory: u32,
     reserved: bool,
 ) -> Result<String, Error> {
-    let ip = net::find_available_ip(state).await.map_err(|e| {
-        error!("Error while finding available IP address: {:?}", e);
-        Error::NoIPAvalaible
-    })?;
+    let ip = match state.ipam.allocate() {
+        Some(IpInet::V4(ip)) => ip,
+        _ => {
+            error!("No IPv4 address left for a new VM");
+            return Err(Error::NoIPAvalaible);
+        }
+    };
+    let ipv6 = match state.ipam_v6.as_mut() {
+        Some(ipam) => match ipam.allocate() {
+            Some(ipv6) => Some(ipv6),
+            None => {
+                error!("No IPv6 address left for a new VM");
+                release_addresses(state, Some(IpInet::V4(ip)), None);
+                return Err(Error::NoIPAvalaible);
+            }
+        },
+        None => None,
+    };
     let uuid = Uuid::new_v4().to_string();
+    Span::current().record("vm", &uuid);
+
+    let result = start_vm(
+        state,
+        language_settings,
+        memory,
+        reserved,
+        uuid.clone(),
+        ip,
+        ipv6,
+    )
+    .await;
+    if result.is_err() {
+        release_addresses(state, Some(IpInet::V4(ip)), ipv6);
+    }
 
+    result.map(|_| uuid)
+}
+
+/// Boot a VM with the given addresses and add it to the state
+async fn start_vm(
+    state: &mut LambdoState,
+    language_settings: &LanguageSettings,
+    memory: u32,
+    reserved: bool,
+    uuid: String,
+    ip: Ipv4Inet,
+    ipv6: Option<IpInet>,
+) -> Result<(), Error> {
     let config = &state.config;
     // Safe since we checked the validity of the address before
     let host_ip = Ipv4Inet::from_str(&config.api.bridge_address).unwrap();
     let tap_name = format!("tap-{}", &uuid[0..8]);
+    let socket = match config.vmm.transport {
+        LambdoVMMTransport::Grpc => None,
+        LambdoVMMTransport::Serial => Some(format!(
+            "{}/lambdo-{}.sock",
+            serial::SERIAL_SOCKET_DIR,
+            uuid
+        )),
+    };
+
+    let settings = language_settings.clone();
+    let initramfs = tokio::task::spawn_blocking(move || {
+        initramfs::assemble(initramfs::INITRAMFS_DIR, &settings)
+    })
+    .await
+    .map_err(|e| Error::InitramfsError(e.into()))?
+    .map_err(|e| {
+        error!("Error while assembling the initramfs: {:?}", e);
+        Error::InitramfsError(e)
+    })?;
 
     let opts: VMMOpts = VMMOpts {
         kernel: config.vmm.kernel.clone(),
         cpus: 1,
-        memory: 1024,
-        console: None,
-        socket: None,
-        initramfs: Some(language_settings.initramfs.clone()),
+        memory,
+        console: Some(format!("{}/lambdo-{}.log", CONSOLE_DIR, uuid)),
+        socket: socket.clone(),
+        initramfs: Some(initramfs),
         tap: Some(tap_name.clone()),
         ip: Some(IpInet::V4(ip)),
+        ipv6,
         gateway: Some(host_ip.address().to_string()),
+        boot_args: Vec::new(),
     };
 
+    if config.api.firewall {
+        firewall::allow_vm(&firewall::VMRules {
+            tap: &tap_name,
+            ip,
+            host: host_ip,
+            grpc_port: config.api.grpc_port,
+            policy: &language_settings.network,
+        })
+        .map_err(|e| {
+            error!("Error while applying the network policy: {:?}", e);
+            Error::NetSetupError(e)
+        })?;
+    }
+
     trace!("Creating VMState");
     let mut vm_state = VMState::new(
         uuid.clone(),
-        opts.clone(),
+        opts,
         language_settings.clone(),
         state.channel.0.clone(),
+        state.directory.clone(),
         reserved,
     );
+    vm_state.vm_opts.boot_args = boot_args(
+        &host_ip.address().to_string(),
+        config.api.grpc_port,
+        vm_state.token(),
+    );
+    let opts = &vm_state.vm_opts;
 
     info!(
         "Starting execution for {:?}, (language: {}, version: {})",
         &uuid, language_settings.name, language_settings.version
     );
     debug!("Launching VMM with options: {:?}", opts);
-    vm_state.vm_task = Some(run(opts)?);
+    if let Err(e) = boot_vm(state, &mut vm_state, socket.clone(), &tap_name).await {
+        // The VM is not part of the state yet, nothing else would remove its rules
+        vm_state.kill();
+        clean_up(
+            &Leftovers {
+                taps: vec![tap_name],
+                files: socket.into_iter().collect(),
+                ..Default::default()
+            },
+            state.config.api.firewall,
+        );
+        return Err(e);
+    }
+    state.vms.push(vm_state);
+    state.persist();
+
+    Ok(())
+}
+
+/// Boot a VM and attach it to the bridge
+async fn boot_vm(
+    state: &LambdoState,
+    vm_state: &mut VMState,
+    socket: Option<String>,
+    tap_name: &str,
+) -> Result<(), Error> {
+    state.boot(vm_state)?;
+
+    if let Some(socket) = socket {
+        debug!("Connecting to the console socket");
+        vm_state.serial = Some(
+            SerialClient::connect(
+                vm_state.id.clone(),
+                socket,
+                vm_state.vm_opts.console.clone(),
+                state.serial_channel.0.clone(),
+            )
+            .await?,
+        );
+    }
+
+    if vm_state.restored {
+        // The clone still answers as the snapshotted VM until it gets its own identity
+        vm_state.send_identity()?;
+    }
 
     debug!("Adding interface to bridge");
-    net::add_interface_to_bridge(&tap_name, &*state).map_err(|e| {
+    net::add_interface_to_bridge(tap_name, state).map_err(|e| {
         error!("Error while adding interface to bridge: {:?}", e);
         Error::NoIPAvalaible
-    })?;
-    state.vms.push(vm_state);
+    })
+}
+
+/// Clean up after the VMs recorded by a previous run of the API
+///
+/// The VMMs are killed along with the API, none of them survives a restart: the
+/// leftover VMs cannot be adopted, only their tap interfaces and console
+/// sockets are removed. Their addresses are free again, unless their tap
+/// interface could not be deleted.
+///
+/// # Arguments
+///
+/// * `state` - The state, before any VM is started and once its IP pools are set up
+pub fn recover(state: &mut LambdoState) -> anyhow::Result<()> {
+    let records = state.store.load()?;
+    let bridge_interfaces = net::bridge_interfaces(&state.config.api.bridge)?;
+    let leftovers = store::leftovers(&records, &bridge_interfaces);
+    clean_up(&leftovers, false);
 
-    Ok(uuid)
+    let bridge_interfaces = net::bridge_interfaces(&state.config.api.bridge)?;
+    for record in records.iter().filter(|record| {
+        record
+            .tap
+            .as_ref()
+            .is_some_and(|tap| bridge_interfaces.contains(tap))
+    }) {
+        warn!(
+            "Tap interface of VM {} is still on the bridge, keeping its addresses",
+            record.id
+        );
+        for address in [&record.ip, &record.ipv6].into_iter().flatten() {
+            match IpInet::from_str(address) {
+                Ok(IpInet::V4(ip)) => {
+                    state.ipam.claim(ip.address().into());
+                }
+                Ok(IpInet::V6(ip)) => {
+                    if let Some(ipam) = state.ipam_v6.as_mut() {
+                        ipam.claim(ip.address().into());
+                    }
+                }
+                Err(e) => debug!("Invalid address {} in the state file: {}", address, e),
+            }
+        }
+    }
+
+    for id in &leftovers.vms {
+        warn!("VM {} did not survive the restart of the API", id);
+    }
+
+    info!(
+        "Cleaned up after {} VMs and {} tap interfaces of the previous run",
+        records.len(),
+        leftovers.taps.len()
+    );
+    state.persist();
+
+    Ok(())
+}
+
+/// Tear down all the VMs before the API stops
+///
+/// The VMMs are killed, then their tap interfaces and console sockets are removed.
+///
+/// # Arguments
+///
+/// * `state` - The state, once no execution is running anymore
+pub fn teardown(state: &mut LambdoState) -> anyhow::Result<()> {
+    for vm in state.vms.iter_mut() {
+        vm.kill();
+    }
+    let records = state.vms.iter().map(VMRecord::from).collect::<Vec<_>>();
+    let bridge_interfaces = net::bridge_interfaces(&state.config.api.bridge)?;
+    let leftovers = store::leftovers(&records, &bridge_interfaces);
+    clean_up(&leftovers, state.config.api.firewall);
+    info!(
+        "Tore down {} VMs and {} tap interfaces",
+        records.len(),
+        leftovers.taps.len()
+    );
+
+    for vm in std::mem::take(&mut state.vms) {
+        if !vm.released {
+            release_addresses(state, vm.vm_opts.ip, vm.vm_opts.ipv6);
+        }
+    }
+    state.persist();
+
+    Ok(())
+}
+
+/// Kill a VM and remove it from the state
+///
+/// Its VMM process is killed, then its tap interface and addresses are given back.
+///
+/// # Arguments
+///
+/// * `state` - The state holding the VM
+/// * `id` - The ID of the VM
+pub fn kill_vm(state: &mut LambdoState, id: &str) -> Result<(), Error> {
+    let position = state
+        .vms
+        .iter()
+        .position(|vm| vm.id == id)
+        .ok_or(Error::VmNotFound)?;
+    let mut vm = state.vms.remove(position);
+
+    info!("Killing VM {}", id);
+    vm.kill();
+    clean_up(
+        &store::leftovers(&[VMRecord::from(&vm)], &[]),
+        state.config.api.firewall,
+    );
+    if !vm.released {
+        release_addresses(state, vm.vm_opts.ip, vm.vm_opts.ipv6);
+    }
+    state.persist();
+
+    Ok(())
+}
+
+/// Give back the tap interface and the addresses of a VM that ended
+///
+/// The VM stays in the state along with its console output, its addresses can be
+/// given to a new VM.
+///
+/// # Arguments
+///
+/// * `state` - The state holding the VM
+/// * `id` - The ID of the VM
+pub fn release_vm(state: &mut LambdoState, id: &str) -> Result<(), Error> {
+    let firewall = state.config.api.firewall;
+    let vm = state
+        .vms
+        .iter_mut()
+        .find(|vm| vm.id == id)
+        .ok_or(Error::VmNotFound)?;
+    if vm.released {
+        return Ok(());
+    }
+
+    debug!("Releasing VM {}", id);
+    vm.released = true;
+    let (tap, ip, ipv6) = (vm.vm_opts.tap.clone(), vm.vm_opts.ip, vm.vm_opts.ipv6);
+    clean_up(
+        &Leftovers {
+            taps: tap.into_iter().collect(),
+            ..Default::default()
+        },
+        firewall,
+    );
+    release_addresses(state, ip, ipv6);
+    state.persist();
+
+    Ok(())
+}
+
+/// Give back addresses to the IP pools
+fn release_addresses(state: &mut LambdoState, ip: Option<IpInet>, ipv6: Option<IpInet>) {
+    if let Some(ip) = ip {
+        state.ipam.release(ip.address());
+    }
+    if let (Some(ipam), Some(ipv6)) = (state.ipam_v6.as_mut(), ipv6) {
+        ipam.release(ipv6.address());
+    }
+}
+
+/// Set up the enforcement of the network policies, if enabled
+///
+/// The rules left by a previous run of the API are dropped along the way.
+///
+/// # Arguments
+///
+/// * `state` - The state, before any VM is started
+pub fn setup_firewall(state: &LambdoState) -> anyhow::Result<()> {
+    if !state.config.api.firewall {
+        warn!("The network policies of the languages are not enforced");
+        return Ok(());
+    }
+
+    firewall::setup(&state.config.api.bridge)
+}
+
+/// Remove what is left of VMs
+///
+/// # Arguments
+///
+/// * `leftovers` - The resources of the VMs
+/// * `firewall` - Whether the VMs have network policy rules to remove
+fn clean_up(leftovers: &Leftovers, firewall: bool) {
+    for tap in &leftovers.taps {
+        if firewall {
+            firewall::remove_vm(tap).unwrap_or_else(|e| {
+                debug!("Failed to remove the network policy of {}: {:?}", tap, e);
+            });
+        }
+
+        net::delete_interface(tap).unwrap_or_else(|e| {
+            debug!("Failed to delete tap interface {}: {:?}", tap, e);
+        });
+    }
+
+    for file in &leftovers.files {
+        std::fs::remove_file(file).unwrap_or_else(|e| {
+            debug!("Failed to remove {}: {:?}", file, e);
+        });
+    }
+}
+
+/// Build the boot arguments telling the agent how to reach the API
+///
+/// # Arguments
+///
+/// * `api_host` - The address of the API on the bridge
+/// * `api_port` - The port of the API gRPC server
+/// * `token` - The token identifying the VM
+pub fn boot_args(api_host: &str, api_port: u16, token: &str) -> Vec<String> {
+    vec![
+        format!("lambdo.api_host={}", api_host),
+        format!("lambdo.api_port={}", api_port),
+        format!("lambdo.token={}", token),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::{boot_args, Error, VMMOpts};
+
+    #[derive(Parser)]
+    enum Command {
+        Vmm(VMMOpts),
+    }
+
+    #[test]
+    fn test_vmm_args_round_trip() {
+        let opts = VMMOpts {
+            kernel: "/var/lib/lambdo/kernel/vmlinux.bin".to_string(),
+            cpus: 1,
+            memory: 128,
+            console: None,
+            socket: Some("/tmp/lambdo-test.sock".to_string()),
+            initramfs: Some("/var/lib/lambdo/node-12.img".to_string()),
+            tap: Some("tap-test".to_string()),
+            ip: Some("192.168.10.2/24".parse().unwrap()),
+            ipv6: None,
+            gateway: Some("192.168.10.1".to_string()),
+            boot_args: boot_args("192.168.10.1", 50051, "abcd"),
+        };
+
+        let args = std::iter::once("api".to_string()).chain(opts.args());
+        let Command::Vmm(parsed) = Command::parse_from(args);
+
+        assert_eq!(format!("{:?}", parsed), format!("{:?}", opts));
+    }
+
+    #[test]
+    fn test_reload_failed_lists_every_error() {
+        let error = Error::ReloadFailed(vec![Error::VmNotFound, Error::NoIPAvalaible]);
+
+        assert_eq!(
+            error.to_string(),
+            "Error while reloading the languages: VM not found, No IP address available"
+        );
+    }
 }
@@ -1,15 +1,14 @@
+use std::ffi::OsStr;
+use std::fs;
 use std::process::Command;
-use std::str::FromStr;
 
 use anyhow::anyhow;
 use anyhow::Result;
-use cidr::IpInet;
-use cidr::Ipv4Inet;
 use log::{debug, info, trace};
 
 use crate::vm_manager::state::LambdoState;
 
-pub(super) fn add_interface_to_bridge(interface_name: &String, state: &LambdoState) -> Result<()> {
+pub(super) fn add_interface_to_bridge(interface_name: &str, state: &LambdoState) -> Result<()> {
     let bridge_name = &state.config.api.bridge;
     debug!(
         "adding interface {} to bridge {}",
@@ -37,35 +36,43 @@ pub(super) fn add_interface_to_bridge(interface_name: &String, state: &LambdoSta
     Ok(())
 }
 
-pub(super) async fn find_available_ip(state: &LambdoState) -> Result<Ipv4Inet> {
-    let config = &state.config;
-    // Safe since we checked the validity of the address before
-    let host_ip = Ipv4Inet::from_str(&config.api.bridge_address).unwrap();
+/// List the interfaces attached to a bridge
+pub(super) fn bridge_interfaces(bridge_name: &str) -> Result<Vec<String>> {
+    let mut interfaces = Vec::new();
+    for entry in fs::read_dir("/sys/class/net")
+        .map_err(|e| anyhow!("error when listing network interfaces: {}", e))?
+    {
+        let path = entry?.path();
+        // The master link of an interface points to the bridge it is attached to
+        let attached = fs::read_link(path.join("master"))
+            .map(|master| master.file_name() == Some(OsStr::new(bridge_name)))
+            .unwrap_or(false);
 
-    let used_ip: &Vec<_> = &state
-        .vms
-        .iter()
-        .filter_map(|vm| {
-            debug!("VM {:?} has ip {:?}", vm.id, vm.vm_opts.ip);
-            match vm.vm_opts.ip {
-                // if vm.get_state() != VMStatus::Ended
-                Some(IpInet::V4(ip)) => Some(ip.address()),
-                _ => None,
+        if attached {
+            if let Some(name) = path.file_name() {
+                interfaces.push(name.to_string_lossy().to_string());
             }
-        })
-        .collect();
-    debug!("looking for available ip in {}", host_ip);
-    trace!("used ip: {:?}", used_ip);
-    let mut ip = host_ip;
-    ip.increment();
-
-    while used_ip.contains(&ip.address()) {
-        trace!("ip {} is already used, trying next one", ip);
-        if ip.increment() {
-            return Err(anyhow!("no available ip"));
         }
     }
 
-    info!("found available ip: {}", ip);
-    Ok(ip)
+    trace!("interfaces on bridge {}: {:?}", bridge_name, interfaces);
+    Ok(interfaces)
+}
+
+pub(super) fn delete_interface(interface_name: &str) -> Result<()> {
+    debug!("deleting interface {}", interface_name);
+    let output = Command::new("ip")
+        .args(["link", "delete", interface_name])
+        .output()
+        .map_err(|e| anyhow!("error when deleting interface: {}", e))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "error when deleting interface {}: {}",
+            interface_name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(())
 }
@@ -0,0 +1,393 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use anyhow::anyhow;
+use lambdo_shared::serial::{encode_frame, Decoded, FrameDecoder};
+use log::{debug, error, info, trace, warn};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+    sync::{broadcast, mpsc, oneshot},
+};
+
+use super::{
+    grpc_definitions::{serial_message::Payload, ExecuteRequest, ExecuteResponse, SerialMessage},
+    Error,
+};
+use crate::vm_manager::state::OutputSender;
+
+/// Directory in which the console sockets of the VMMs are created
+pub const SERIAL_SOCKET_DIR: &str = "/tmp";
+
+type PendingExecutions = Arc<Mutex<HashMap<String, Pending>>>;
+
+/// An execution waiting for its response
+#[derive(Debug)]
+struct Pending {
+    response: oneshot::Sender<ExecuteResponse>,
+    /// Where the output goes as the agent streams it, if it is followed
+    output: Option<OutputSender>,
+}
+
+/// A client talking to an agent over the console socket of its VMM
+///
+/// Execute responses and output are routed back to their caller, every other
+/// message sent by the agent is forwarded on the serial channel of the state.
+#[derive(Debug, Clone)]
+pub struct SerialClient {
+    writer: mpsc::UnboundedSender<SerialMessage>,
+    pending: PendingExecutions,
+}
+
+impl SerialClient {
+    /// Connect to the console socket of a VMM
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the VM the socket belongs to
+    /// * `path` - The path of the console socket
+    /// * `console` - The file capturing the console output, if any
+    /// * `events` - Where to forward the messages that are not execute responses
+    pub async fn connect(
+        id: String,
+        path: String,
+        console: Option<String>,
+        events: broadcast::Sender<(String, SerialMessage)>,
+    ) -> Result<Self, Error> {
+        info!("Connecting to console socket {} of VM {}", path, id);
+
+        let mut counter = 0;
+        let stream = loop {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(e) if counter < 10 => {
+                    debug!("Failed to connect to console socket {}: {}", path, e);
+                    counter += 1;
+                    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+                }
+                Err(e) => {
+                    error!("Failed to connect to console socket {}: {}", path, e);
+                    return Err(Error::SerialSetupError(anyhow!(
+                        "failed to connect to {}: {}",
+                        path,
+                        e
+                    )));
+                }
+            }
+        };
+        info!("Connected to console socket of VM {}", id);
+
+        let (mut reader, mut writer) = stream.into_split();
+        let (tx, mut rx) = mpsc::unbounded_channel::<SerialMessage>();
+        let pending: PendingExecutions = Arc::new(Mutex::new(HashMap::new()));
+
+        let writer_id = id.clone();
+        tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                trace!("Sending serial message to VM {}: {:?}", writer_id, message);
+                if let Err(e) = writer.write_all(&encode_frame(&message)).await {
+                    error!("Failed to write on console socket of {}: {}", writer_id, e);
+                    break;
+                }
+            }
+        });
+
+        let mut console = match console {
+            Some(console) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&console)
+                    .await
+                    .map_err(|e| {
+                        Error::SerialSetupError(anyhow!("failed to open {}: {}", console, e))
+                    })?,
+            ),
+            None => None,
+        };
+
+        let reader_pending = pending.clone();
+        tokio::spawn(async move {
+            let mut decoder = FrameDecoder::<SerialMessage>::new();
+            let mut buffer = [0; 4096];
+
+            loop {
+                match reader.read(&mut buffer).await {
+                    Ok(0) => {
+                        debug!("Console socket of VM {} closed", id);
+                        break;
+                    }
+                    Ok(read) => decoder.push(&buffer[..read]),
+                    Err(e) => {
+                        error!("Failed to read console socket of {}: {}", id, e);
+                        break;
+                    }
+                }
+
+                loop {
+                    let decoded = match decoder.next_decoded() {
+                        Ok(Some(decoded)) => decoded,
+                        Ok(None) => break,
+                        Err(e) => {
+                            warn!("Dropping serial frame of VM {}: {}", id, e);
+                            continue;
+                        }
+                    };
+
+                    match decoded {
+                        Decoded::Console(bytes) => {
+                            trace!("[{}] {}", id, String::from_utf8_lossy(&bytes));
+                            if let Some(file) = &mut console {
+                                if let Err(e) = file.write_all(&bytes).await {
+                                    warn!("Failed to capture console of VM {}: {}", id, e);
+                                }
+                            }
+                        }
+                        Decoded::Message(SerialMessage {
+                            payload: Some(Payload::ExecuteResponse(response)),
+                        }) => match lock(&reader_pending).remove(&response.id) {
+                            Some(pending) => {
+                                let _ = pending.response.send(response);
+                            }
+                            None => warn!("Unexpected execute response from VM {}", id),
+                        },
+                        // The output comes before the response, in the order it was sent
+                        Decoded::Message(SerialMessage {
+                            payload: Some(Payload::Output(chunk)),
+                        }) => match lock(&reader_pending)
+                            .get(&chunk.id)
+                            .and_then(|pending| pending.output.as_ref())
+                        {
+                            Some(output) => {
+                                let _ = output.send(chunk);
+                            }
+                            None => trace!("Nobody follows the output of request {}", chunk.id),
+                        },
+                        Decoded::Message(message) => {
+                            trace!("Received serial message from VM {}: {:?}", id, message);
+                            if let Err(e) = events.send((id.clone(), message)) {
+                                error!("Failed to forward serial message: {:?}", e);
+                            }
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            writer: tx,
+            pending,
+        })
+    }
+
+    /// Send a message to the agent
+    pub fn send(&self, message: SerialMessage) -> Result<(), Error> {
+        self.writer.send(message).map_err(|e| {
+            error!("Failed to send serial message: {}", e);
+            Error::ExecutionError
+        })
+    }
+
+    /// Send an execution request and wait for the matching response
+    ///
+    /// The request stops being waited for once the call returns or is cancelled,
+    /// for instance when the execution timed out.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request to run
+    /// * `output` - Where the output is sent as the agent streams it, if it is followed
+    pub async fn execute(
+        &self,
+        request: ExecuteRequest,
+        output: Option<OutputSender>,
+    ) -> Result<ExecuteResponse, Error> {
+        let (tx, rx) = oneshot::channel();
+        lock(&self.pending).insert(
+            request.id.clone(),
+            Pending {
+                response: tx,
+                output,
+            },
+        );
+        let _pending = PendingGuard {
+            pending: &self.pending,
+            id: request.id.clone(),
+        };
+
+        self.send(SerialMessage {
+            payload: Some(Payload::ExecuteRequest(request)),
+        })?;
+
+        let response = rx.await.map_err(|e| {
+            warn!("Console socket closed before the response: {}", e);
+            Error::ExecutionError
+        })?;
+        if !response.error.is_empty() {
+            warn!(
+                "Agent failed to run request {}: {}",
+                response.id, response.error
+            );
+            return Err(Error::ExecutionError);
+        }
+
+        Ok(response)
+    }
+}
+
+/// Forgets a pending execution when its caller stops waiting for it
+struct PendingGuard<'a> {
+    pending: &'a PendingExecutions,
+    id: String,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        lock(self.pending).remove(&self.id);
+    }
+}
+
+fn lock(pending: &PendingExecutions) -> MutexGuard<'_, HashMap<String, Pending>> {
+    pending.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use lambdo_shared::serial::{encode_frame, Decoded, FrameDecoder};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{UnixListener, UnixStream},
+        sync::{broadcast, mpsc},
+    };
+
+    use super::{lock, SerialClient};
+    use crate::vm_manager::{
+        grpc_definitions::{
+            serial_message::Payload, ExecuteRequest, ExecuteResponse, OutputChunk, SerialMessage,
+        },
+        Error,
+    };
+
+    async fn recv(stream: &mut UnixStream) -> SerialMessage {
+        let mut decoder = FrameDecoder::<SerialMessage>::new();
+        let mut buffer = [0; 4096];
+        loop {
+            if let Ok(Some(Decoded::Message(message))) = decoder.next_decoded() {
+                return message;
+            }
+            let read = stream.read(&mut buffer).await.unwrap();
+            decoder.push(&buffer[..read]);
+        }
+    }
+
+    fn request(id: &str) -> ExecuteRequest {
+        ExecuteRequest {
+            id: id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_failed_or_cancelled() {
+        let path = std::env::temp_dir().join(format!("lambdo-serial-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let (events, _) = broadcast::channel(1);
+        let client = SerialClient::connect(
+            "vm".to_string(),
+            path.to_string_lossy().to_string(),
+            None,
+            events,
+        )
+        .await
+        .unwrap();
+        let (mut agent, _) = listener.accept().await.unwrap();
+
+        let execution = tokio::spawn({
+            let client = client.clone();
+            async move { client.execute(request("failing"), None).await }
+        });
+        assert!(matches!(
+            recv(&mut agent).await.payload,
+            Some(Payload::ExecuteRequest(request)) if request.id == "failing"
+        ));
+        let response = SerialMessage {
+            payload: Some(Payload::ExecuteResponse(ExecuteResponse {
+                id: "failing".to_string(),
+                error: "Failed to spawn command".to_string(),
+                ..Default::default()
+            })),
+        };
+        agent.write_all(&encode_frame(&response)).await.unwrap();
+        assert!(matches!(
+            execution.await.unwrap(),
+            Err(Error::ExecutionError)
+        ));
+
+        // The agent never answers, the caller gives up
+        let timeout = tokio::time::timeout(
+            Duration::from_millis(50),
+            client.execute(request("stuck"), None),
+        )
+        .await;
+        assert!(timeout.is_err());
+        assert!(lock(&client.pending).is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[tokio::test]
+    async fn test_execute_streams_output() {
+        let path =
+            std::env::temp_dir().join(format!("lambdo-serial-output-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let (events, mut forwarded) = broadcast::channel(1);
+        let client = SerialClient::connect(
+            "vm".to_string(),
+            path.to_string_lossy().to_string(),
+            None,
+            events,
+        )
+        .await
+        .unwrap();
+        let (mut agent, _) = listener.accept().await.unwrap();
+
+        let (output, mut chunks) = mpsc::unbounded_channel();
+        let execution = tokio::spawn({
+            let client = client.clone();
+            async move { client.execute(request("streamed"), Some(output)).await }
+        });
+        recv(&mut agent).await;
+
+        let messages = [
+            Payload::Output(OutputChunk {
+                id: "streamed".to_string(),
+                stdout: "Hello\n".to_string(),
+                ..Default::default()
+            }),
+            Payload::ExecuteResponse(ExecuteResponse {
+                id: "streamed".to_string(),
+                ..Default::default()
+            }),
+        ];
+        for payload in messages {
+            let message = SerialMessage {
+                payload: Some(payload),
+            };
+            agent.write_all(&encode_frame(&message)).await.unwrap();
+        }
+
+        assert!(execution.await.unwrap().is_ok());
+        // The output is routed to the caller before the response, not forwarded
+        assert_eq!(chunks.try_recv().unwrap().stdout, "Hello\n");
+        assert!(chunks.recv().await.is_none());
+        assert!(forwarded.try_recv().is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
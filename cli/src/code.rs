@@ -0,0 +1,121 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use log::trace;
+
+use lambdo_client::model::CodeFile;
+
+/// Read the files of the code to run
+///
+/// The files of a directory are given with their path relative to it, the
+/// entrypoint is moved first as lambdo runs the first file.
+///
+/// # Arguments
+///
+/// * `paths` - The files and directories to upload
+/// * `entrypoint` - The file to run, needed when there are several files
+pub fn collect(paths: &[PathBuf], entrypoint: Option<&str>) -> Result<Vec<CodeFile>> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            collect_directory(path, path, &mut files)?;
+        } else {
+            let filename = path
+                .file_name()
+                .ok_or_else(|| anyhow!("{} is not a file", path.display()))?
+                .to_string_lossy()
+                .to_string();
+            files.push(read(path, filename)?);
+        }
+    }
+
+    let position = match entrypoint {
+        Some(entrypoint) => files
+            .iter()
+            .position(|file| file.filename == entrypoint)
+            .ok_or_else(|| anyhow!("the entrypoint {} is not uploaded", entrypoint))?,
+        None if files.len() == 1 => 0,
+        None if files.is_empty() => return Err(anyhow!("there is no file to upload")),
+        None => {
+            return Err(anyhow!(
+                "{} files are uploaded, choose the one to run with --entrypoint",
+                files.len()
+            ))
+        }
+    };
+    let entrypoint = files.remove(position);
+    files.insert(0, entrypoint);
+
+    Ok(files)
+}
+
+fn collect_directory(root: &Path, directory: &Path, files: &mut Vec<CodeFile>) -> Result<()> {
+    let mut entries = fs::read_dir(directory)
+        .with_context(|| format!("cannot read {}", directory.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            collect_directory(root, &path, files)?;
+            continue;
+        }
+
+        let filename = path
+            .strip_prefix(root)?
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        files.push(read(&path, filename)?);
+    }
+
+    Ok(())
+}
+
+fn read(path: &Path, filename: String) -> Result<CodeFile> {
+    trace!("Uploading {} as {}", path.display(), filename);
+    let content = fs::read_to_string(path).with_context(|| {
+        format!(
+            "cannot read {}, only text files are uploaded",
+            path.display()
+        )
+    })?;
+    Ok(CodeFile { filename, content })
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::collect;
+
+    #[test]
+    fn test_collect() {
+        let directory = std::env::temp_dir().join(format!("lambdo-cli-{}", std::process::id()));
+        fs::create_dir_all(directory.join("lib")).unwrap();
+        fs::write(directory.join("main.js"), "require('./lib/util')").unwrap();
+        fs::write(directory.join("lib/util.js"), "module.exports = {}").unwrap();
+        fs::write(directory.join("package.json"), "{}").unwrap();
+
+        let files = collect(std::slice::from_ref(&directory), Some("main.js"));
+        let ambiguous = collect(std::slice::from_ref(&directory), None);
+        let single = collect(&[directory.join("lib/util.js")], None);
+        fs::remove_dir_all(&directory).unwrap();
+
+        let filenames = files
+            .unwrap()
+            .into_iter()
+            .map(|file| file.filename)
+            .collect::<Vec<_>>();
+        assert_eq!(filenames, ["main.js", "lib/util.js", "package.json"]);
+        assert!(ambiguous.is_err());
+        let single = single.unwrap();
+        assert_eq!(single[0].filename, "util.js");
+        assert_eq!(single[0].content, "module.exports = {}");
+    }
+}
@@ -0,0 +1,330 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use clap::{Args, Parser, Subcommand};
+use env_logger::Env;
+use log::{debug, info, warn};
+
+use lambdo_client::{
+    model::{JobEvent, JobStatus, RunRequest, RunResponse},
+    Client,
+};
+
+mod code;
+
+#[derive(Parser)]
+#[clap(
+    version = "0.1",
+    author = "Polytech Montpellier - DevOps",
+    about = "Run code on a lambdo server"
+)]
+pub struct LambdoOpts {
+    /// URL of the lambdo API
+    #[clap(
+        long,
+        env = "LAMBDO_URL",
+        default_value = "http://127.0.0.1:3000",
+        global = true
+    )]
+    url: String,
+    /// API key sent with the executions
+    #[clap(long, env = "LAMBDO_API_KEY", hide_env_values = true, global = true)]
+    api_key: Option<String>,
+    /// Token of the admin endpoints
+    #[clap(
+        long,
+        env = "LAMBDO_ADMIN_TOKEN",
+        hide_env_values = true,
+        global = true
+    )]
+    admin_token: Option<String>,
+    /// Seconds after which a request is abandoned
+    #[clap(long, global = true)]
+    timeout: Option<u64>,
+    /// Number of times a request refused by a busy server is retried
+    #[clap(long, default_value_t = lambdo_client::DEFAULT_RETRIES, global = true)]
+    retries: u32,
+    #[clap(subcommand)]
+    command: LambdoCommand,
+}
+
+#[derive(Subcommand)]
+enum LambdoCommand {
+    /// Run code and exit with its status
+    Run(RunOpts),
+    /// List the languages that can be run
+    Languages,
+    /// Inspect the jobs submitted with the API key
+    #[clap(subcommand)]
+    Jobs(JobsCommand),
+    /// Call the admin endpoints
+    #[clap(subcommand)]
+    Admin(AdminCommand),
+}
+
+#[derive(Args)]
+struct RunOpts {
+    /// The language to run
+    #[clap(long = "lang")]
+    language: String,
+    /// The version of the language
+    #[clap(long)]
+    version: String,
+    /// The file given to the program on its standard input
+    #[clap(long)]
+    input: Option<PathBuf>,
+    /// The file to run, relative to the uploaded directory
+    #[clap(long)]
+    entrypoint: Option<String>,
+    /// Glob pattern of the files to return after the execution
+    #[clap(long = "artifact")]
+    artifacts: Vec<String>,
+    /// Directory to write the returned files to
+    #[clap(long)]
+    output: Option<PathBuf>,
+    /// The files and directories to upload
+    #[clap(required = true)]
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum JobsCommand {
+    /// List the jobs and their status
+    List,
+    /// Print the status of a job, and its output once it ended
+    Show { id: String },
+    /// Print the output of a job once it ends, and exit with its status
+    Follow { id: String },
+}
+
+#[derive(Subcommand)]
+enum AdminCommand {
+    /// List the VMs and the last execution they ran
+    Vms,
+    /// Kill a VM
+    Kill { id: String },
+    /// Print the console output of a VM
+    Console { id: String },
+    /// Boot VMs of a language ahead of the requests
+    Warm {
+        language: String,
+        /// The number of VMs to boot
+        #[clap(long, default_value_t = 1)]
+        count: u32,
+    },
+    /// Print the usage of the API keys
+    Keys,
+    /// Drain then stop the server
+    Drain,
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::Builder::from_env(Env::default().default_filter_or("warn")).init();
+    let options = LambdoOpts::parse();
+
+    let client = Client::new(&options.url)
+        .with_api_key(options.api_key)
+        .with_admin_token(options.admin_token)
+        .with_timeout(options.timeout.map(Duration::from_secs))
+        .with_retries(options.retries);
+
+    let result = match options.command {
+        LambdoCommand::Run(run_opts) => run(&client, run_opts).await,
+        LambdoCommand::Languages => languages(&client).await,
+        LambdoCommand::Jobs(command) => jobs(&client, command).await,
+        LambdoCommand::Admin(command) => admin(&client, command).await,
+    };
+
+    match result {
+        Ok(status) => std::process::exit(status),
+        Err(e) => {
+            eprintln!("lambdo: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Run code as a job and follow it, returning the exit status of the program
+async fn run(client: &Client, options: RunOpts) -> Result<i32> {
+    let code = code::collect(&options.paths, options.entrypoint.as_deref())?;
+    let input = match &options.input {
+        Some(input) => fs::read_to_string(input)
+            .with_context(|| format!("cannot read the input {}", input.display()))?,
+        None => String::new(),
+    };
+
+    let job = client
+        .submit_job(&RunRequest {
+            language: options.language,
+            version: options.version,
+            input,
+            code,
+            artifacts: options.artifacts,
+        })
+        .await?;
+    info!("Submitted job {}", job.id);
+
+    let status = follow(client, &job.id).await?;
+    if let Some(output) = &options.output {
+        match client.job(&job.id).await?.response {
+            Some(response) => write_artifacts(output, &response)?,
+            None => return Err(anyhow!("job {} has no result", job.id)),
+        }
+    }
+
+    Ok(status)
+}
+
+/// Print the output of a job as it comes, returning the exit status of the program
+async fn follow(client: &Client, id: &str) -> Result<i32> {
+    let mut events = client.stream(id).await?;
+    while let Some(event) = events.next().await? {
+        match event {
+            JobEvent::Queued => info!("Job {} is queued", id),
+            JobEvent::Running => info!("Job {} is running", id),
+            JobEvent::Output { stdout, stderr } => {
+                print!("{}", stdout);
+                eprint!("{}", stderr);
+                // The pieces of output do not always end a line
+                io::stdout().flush()?;
+            }
+            JobEvent::Ended { status, queued_ms } => {
+                info!(
+                    "Job {} ended with status {} after waiting {} ms",
+                    id, status, queued_ms
+                );
+                return Ok(status.into());
+            }
+        }
+    }
+
+    Err(anyhow!("the stream of job {} stopped before it ended", id))
+}
+
+/// Write the returned files to a directory
+fn write_artifacts(directory: &PathBuf, response: &RunResponse) -> Result<()> {
+    for artifact in &response.artifacts {
+        if artifact.truncated {
+            warn!(
+                "{} was not returned, its {} bytes are over the size limit",
+                artifact.path, artifact.size
+            );
+            continue;
+        }
+
+        let path = directory.join(&artifact.path);
+        if !path.starts_with(directory) || artifact.path.split('/').any(|part| part == "..") {
+            return Err(anyhow!(
+                "refusing to write {} outside of the output",
+                artifact.path
+            ));
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        debug!("Writing {}", path.display());
+        fs::write(&path, &artifact.content)
+            .with_context(|| format!("cannot write {}", path.display()))?;
+    }
+
+    Ok(())
+}
+
+async fn languages(client: &Client) -> Result<i32> {
+    for language in client.languages().await? {
+        println!("{:<16} {}", language.name, language.version);
+    }
+
+    Ok(0)
+}
+
+async fn jobs(client: &Client, command: JobsCommand) -> Result<i32> {
+    match command {
+        JobsCommand::List => {
+            println!(
+                "{:<36} {:<12} {:<8} {:<8} {:>6}",
+                "ID", "LANGUAGE", "VERSION", "STATUS", "EXIT"
+            );
+            for job in client.jobs().await? {
+                let exit = job
+                    .response
+                    .map_or("-".to_string(), |response| response.status.to_string());
+                println!(
+                    "{:<36} {:<12} {:<8} {:<8} {:>6}",
+                    job.id, job.language, job.version, job.status, exit
+                );
+            }
+        }
+        JobsCommand::Show { id } => {
+            let job = client.job(&id).await?;
+            println!(
+                "{} {} {}: {}",
+                job.id, job.language, job.version, job.status
+            );
+            if let (JobStatus::Ended, Some(response)) = (job.status, job.response) {
+                print!("{}", response.stdout);
+                eprint!("{}", response.stderr);
+                return Ok(response.status.into());
+            }
+        }
+        JobsCommand::Follow { id } => return follow(client, &id).await,
+    }
+
+    Ok(0)
+}
+
+async fn admin(client: &Client, command: AdminCommand) -> Result<i32> {
+    match command {
+        AdminCommand::Vms => {
+            println!(
+                "{:<36} {:<12} {:<8} {:<8} {:<16} {:>8}  LAST REQUEST",
+                "ID", "LANGUAGE", "VERSION", "STATUS", "IP", "AGE"
+            );
+            for vm in client.vms().await? {
+                let last_request = match (&vm.last_request, vm.last_request_age) {
+                    (Some(request), Some(age)) => format!("{} ({}s ago)", request, age),
+                    (Some(request), None) => request.clone(),
+                    _ => "-".to_string(),
+                };
+                println!(
+                    "{:<36} {:<12} {:<8} {:<8} {:<16} {:>7}s  {}",
+                    vm.id,
+                    vm.language,
+                    vm.version,
+                    vm.status,
+                    vm.ip.as_deref().unwrap_or("-"),
+                    vm.age,
+                    last_request
+                );
+            }
+        }
+        AdminCommand::Kill { id } => client.kill_vm(&id).await?,
+        AdminCommand::Console { id } => print!("{}", client.vm_console(&id).await?),
+        AdminCommand::Warm { language, count } => {
+            for id in client.warm(&language, count).await? {
+                println!("{}", id);
+            }
+        }
+        AdminCommand::Keys => {
+            println!(
+                "{:<24} {:>8} {:>8} {:>8}",
+                "NAME", "RUNNING", "TODAY", "TOTAL"
+            );
+            for key in client.keys().await? {
+                println!(
+                    "{:<24} {:>8} {:>8} {:>8}",
+                    key.name, key.running, key.today, key.total
+                );
+            }
+        }
+        AdminCommand::Drain => client.drain().await?,
+    }
+
+    Ok(0)
+}
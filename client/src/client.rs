@@ -0,0 +1,296 @@
+use std::time::Duration;
+
+use log::{debug, trace, warn};
+use reqwest::{header::RETRY_AFTER, RequestBuilder, Response};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    model::{
+        JobEvent, JobSummary, KeyUsage, LanguageSummary, RunRequest, RunResponse, VMSummary,
+        WarmResponse,
+    },
+    ClientError,
+};
+
+/// The header of the API keys
+const API_KEY_HEADER: &str = "X-Api-Key";
+
+/// The header of the ID given to an execution
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// The number of times a request lambdo did not start is retried by default
+pub const DEFAULT_RETRIES: u32 = 3;
+
+/// A client of the lambdo HTTP API
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    url: String,
+    api_key: Option<String>,
+    admin_token: Option<String>,
+    timeout: Option<Duration>,
+    retries: u32,
+}
+
+impl Client {
+    /// Create a client of the API listening at an URL, like `http://127.0.0.1:3000`
+    pub fn new(url: &str) -> Self {
+        Client {
+            http: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            api_key: None,
+            admin_token: None,
+            timeout: None,
+            retries: DEFAULT_RETRIES,
+        }
+    }
+
+    /// Send an API key with the executions and the jobs
+    pub fn with_api_key(mut self, api_key: Option<String>) -> Self {
+        self.api_key = api_key;
+        self
+    }
+
+    /// Send a bearer token with the admin requests
+    pub fn with_admin_token(mut self, admin_token: Option<String>) -> Self {
+        self.admin_token = admin_token;
+        self
+    }
+
+    /// Abandon the requests lambdo did not answer in time, the time spent
+    /// waiting in the queue of the server included
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the number of times a request lambdo did not start is retried
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Run code and wait for its result
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(RunResponse, Option<String>), ClientError>` - The response and the ID given to the execution by lambdo
+    pub async fn run(
+        &self,
+        request: &RunRequest,
+    ) -> Result<(RunResponse, Option<String>), ClientError> {
+        debug!(
+            "Running {} files of {} {}",
+            request.code.len(),
+            request.language,
+            request.version
+        );
+        let builder = self.http.post(self.endpoint("/run")).json(request);
+
+        let response = self.send(self.api_key(builder)).await?;
+        let request_id = response
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        Ok((response.json().await?, request_id))
+    }
+
+    /// Run code in the background
+    ///
+    /// # Returns
+    ///
+    /// * `Result<JobSummary, ClientError>` - The queued job, its ID is the one of the execution
+    pub async fn submit_job(&self, request: &RunRequest) -> Result<JobSummary, ClientError> {
+        debug!(
+            "Submitting {} files of {} {}",
+            request.code.len(),
+            request.language,
+            request.version
+        );
+        let builder = self.http.post(self.endpoint("/jobs")).json(request);
+        self.json(self.api_key(builder)).await
+    }
+
+    /// Get the status of a job, and its result once it ended
+    pub async fn job(&self, id: &str) -> Result<JobSummary, ClientError> {
+        let url = self.endpoint(&format!("/jobs/{}", id));
+        self.json(self.api_key(self.http.get(url))).await
+    }
+
+    /// List the jobs submitted with the API key of the client
+    pub async fn jobs(&self) -> Result<Vec<JobSummary>, ClientError> {
+        self.json(self.api_key(self.http.get(self.endpoint("/jobs"))))
+            .await
+    }
+
+    /// Follow the events of a job until it ends
+    ///
+    /// The timeout of the client covers the whole stream.
+    pub async fn stream(&self, id: &str) -> Result<JobStream, ClientError> {
+        let url = self.endpoint(&format!("/jobs/{}/stream", id));
+        let response = self.send(self.api_key(self.http.get(url))).await?;
+        Ok(JobStream {
+            response,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// List the languages that can be run
+    pub async fn languages(&self) -> Result<Vec<LanguageSummary>, ClientError> {
+        self.json(self.http.get(self.endpoint("/languages"))).await
+    }
+
+    /// List the VMs
+    pub async fn vms(&self) -> Result<Vec<VMSummary>, ClientError> {
+        self.json(self.admin(self.http.get(self.endpoint("/admin/vms"))))
+            .await
+    }
+
+    /// Kill a VM
+    pub async fn kill_vm(&self, id: &str) -> Result<(), ClientError> {
+        let url = self.endpoint(&format!("/admin/vms/{}", id));
+        self.send(self.admin(self.http.delete(url))).await?;
+        Ok(())
+    }
+
+    /// Get the console output captured for a VM
+    pub async fn vm_console(&self, id: &str) -> Result<String, ClientError> {
+        let url = self.endpoint(&format!("/admin/vms/{}/console", id));
+        Ok(self
+            .send(self.admin(self.http.get(url)))
+            .await?
+            .text()
+            .await?)
+    }
+
+    /// Boot VMs of a language ahead of the requests
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<String>, ClientError>` - The IDs of the booted VMs
+    pub async fn warm(&self, language: &str, count: u32) -> Result<Vec<String>, ClientError> {
+        let url = self.endpoint(&format!("/admin/languages/{}/warm", language));
+        let builder = self.http.post(url).query(&[("count", count)]);
+        let response: WarmResponse = self.json(self.admin(builder)).await?;
+        Ok(response.ids)
+    }
+
+    /// Get the usage of the API keys
+    pub async fn keys(&self) -> Result<Vec<KeyUsage>, ClientError> {
+        self.json(self.admin(self.http.get(self.endpoint("/admin/keys"))))
+            .await
+    }
+
+    /// Drain then stop the server
+    pub async fn drain(&self) -> Result<(), ClientError> {
+        self.send(self.admin(self.http.post(self.endpoint("/admin/drain"))))
+            .await?;
+        Ok(())
+    }
+
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}{}", self.url, path)
+    }
+
+    fn api_key(&self, builder: RequestBuilder) -> RequestBuilder {
+        match &self.api_key {
+            Some(api_key) => builder.header(API_KEY_HEADER, api_key),
+            None => builder,
+        }
+    }
+
+    fn admin(&self, builder: RequestBuilder) -> RequestBuilder {
+        match &self.admin_token {
+            Some(token) => builder.bearer_auth(token),
+            None => builder,
+        }
+    }
+
+    async fn json<T: DeserializeOwned>(&self, builder: RequestBuilder) -> Result<T, ClientError> {
+        Ok(self.send(builder).await?.json().await?)
+    }
+
+    /// Send a request, retrying it while lambdo did not start it
+    async fn send(&self, builder: RequestBuilder) -> Result<Response, ClientError> {
+        let builder = match self.timeout {
+            Some(timeout) => builder.timeout(timeout),
+            None => builder,
+        };
+
+        let mut attempt = 0;
+        loop {
+            // The bodies are JSON, the requests can always be cloned
+            let request = builder
+                .try_clone()
+                .expect("the request body cannot be cloned");
+            let error = match send(request).await {
+                Ok(response) => return Ok(response),
+                Err(e) => e,
+            };
+
+            match error.retry_delay(attempt) {
+                Some(delay) if attempt < self.retries => {
+                    warn!("{}, retrying in {} ms", error, delay.as_millis());
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                _ => return Err(error),
+            }
+        }
+    }
+}
+
+/// The events of a job, read as lambdo sends them
+pub struct JobStream {
+    response: Response,
+    /// The bytes received after the last complete event
+    buffer: Vec<u8>,
+}
+
+impl JobStream {
+    /// The next event of the job, `None` once the stream ended
+    pub async fn next(&mut self) -> Result<Option<JobEvent>, ClientError> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+                let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                return Ok(Some(serde_json::from_slice(&line)?));
+            }
+
+            match self.response.chunk().await? {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+/// Send a request once, the answers other than a success are errors
+async fn send(builder: RequestBuilder) -> Result<Response, ClientError> {
+    let response = builder.send().await?;
+    trace!(
+        "lambdo answered {} to {}",
+        response.status(),
+        response.url()
+    );
+
+    let status = response.status();
+    if !status.is_success() {
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        return Err(ClientError::from_status(
+            status,
+            retry_after.as_deref(),
+            response.text().await.unwrap_or_default(),
+        ));
+    }
+
+    Ok(response)
+}
@@ -0,0 +1,153 @@
+use std::time::Duration;
+
+use reqwest::StatusCode;
+use thiserror::Error;
+
+/// The longest wait before retrying a request, the refusals asking to wait
+/// longer, like an exceeded daily quota, are not retried
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// The wait before the first retry when lambdo does not give one
+const BASE_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// The errors of the client, one for each status code lambdo answers with
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("request to lambdo failed")]
+    Request(#[from] reqwest::Error),
+    #[error("invalid request: {0}")]
+    BadRequest(String),
+    #[error("missing or invalid credentials: {0}")]
+    Unauthorized(String),
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+    #[error("not found: {0}")]
+    NotFound(String),
+    #[error("too many executions: {message}")]
+    TooManyRequests {
+        /// The time lambdo asks to wait before trying again
+        retry_after: Option<Duration>,
+        message: String,
+    },
+    #[error("lambdo is unavailable: {0}")]
+    Unavailable(String),
+    #[error("lambdo answered {status}: {message}")]
+    Status { status: StatusCode, message: String },
+    #[error("invalid event streamed by lambdo")]
+    Event(#[from] serde_json::Error),
+}
+
+impl ClientError {
+    /// Build the error of an answer other than a success
+    ///
+    /// # Arguments
+    ///
+    /// * `status` - The status code of the answer
+    /// * `retry_after` - The `Retry-After` header, in seconds
+    /// * `message` - The body of the answer
+    pub fn from_status(status: StatusCode, retry_after: Option<&str>, message: String) -> Self {
+        match status {
+            StatusCode::BAD_REQUEST => ClientError::BadRequest(message),
+            StatusCode::UNAUTHORIZED => ClientError::Unauthorized(message),
+            StatusCode::FORBIDDEN => ClientError::Forbidden(message),
+            StatusCode::NOT_FOUND => ClientError::NotFound(message),
+            StatusCode::TOO_MANY_REQUESTS => ClientError::TooManyRequests {
+                retry_after: retry_after
+                    .and_then(|seconds| seconds.trim().parse().ok())
+                    .map(Duration::from_secs),
+                message,
+            },
+            StatusCode::SERVICE_UNAVAILABLE => ClientError::Unavailable(message),
+            status => ClientError::Status { status, message },
+        }
+    }
+
+    /// The status code lambdo answered with, if it answered
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            ClientError::Request(e) => e.status(),
+            ClientError::BadRequest(_) => Some(StatusCode::BAD_REQUEST),
+            ClientError::Unauthorized(_) => Some(StatusCode::UNAUTHORIZED),
+            ClientError::Forbidden(_) => Some(StatusCode::FORBIDDEN),
+            ClientError::NotFound(_) => Some(StatusCode::NOT_FOUND),
+            ClientError::TooManyRequests { .. } => Some(StatusCode::TOO_MANY_REQUESTS),
+            ClientError::Unavailable(_) => Some(StatusCode::SERVICE_UNAVAILABLE),
+            ClientError::Status { status, .. } => Some(*status),
+            ClientError::Event(_) => None,
+        }
+    }
+
+    /// The time to wait before retrying the request, if it can be retried
+    ///
+    /// Only the requests lambdo did not start are retried: the connection
+    /// failures, the full queues and the refusals while draining.
+    ///
+    /// # Arguments
+    ///
+    /// * `attempt` - The number of retries already made
+    pub fn retry_delay(&self, attempt: u32) -> Option<Duration> {
+        let backoff = BASE_RETRY_DELAY
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(MAX_RETRY_DELAY);
+        match self {
+            ClientError::Request(e) if e.is_connect() => Some(backoff),
+            ClientError::TooManyRequests {
+                retry_after: Some(retry_after),
+                ..
+            } => (*retry_after <= MAX_RETRY_DELAY).then_some(*retry_after),
+            ClientError::TooManyRequests {
+                retry_after: None, ..
+            }
+            | ClientError::Unavailable(_) => Some(backoff),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use reqwest::StatusCode;
+
+    use super::{ClientError, MAX_RETRY_DELAY};
+
+    #[test]
+    fn test_from_status() {
+        let error = ClientError::from_status(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some("2"),
+            "Too many executions waiting".to_string(),
+        );
+        assert!(matches!(
+            error,
+            ClientError::TooManyRequests { retry_after: Some(retry_after), .. }
+                if retry_after == Duration::from_secs(2)
+        ));
+        assert_eq!(error.status(), Some(StatusCode::TOO_MANY_REQUESTS));
+        assert_eq!(error.retry_delay(0), Some(Duration::from_secs(2)));
+
+        let quota = ClientError::from_status(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some("86400"),
+            "Daily quota exceeded".to_string(),
+        );
+        assert_eq!(quota.retry_delay(0), None);
+
+        let draining = ClientError::from_status(
+            StatusCode::SERVICE_UNAVAILABLE,
+            None,
+            "The server is shutting down".to_string(),
+        );
+        assert_eq!(draining.retry_delay(1), Some(Duration::from_secs(1)));
+        assert_eq!(draining.retry_delay(10), Some(MAX_RETRY_DELAY));
+
+        let forbidden =
+            ClientError::from_status(StatusCode::FORBIDDEN, None, "Forbidden".to_string());
+        assert!(matches!(forbidden, ClientError::Forbidden(_)));
+        assert_eq!(forbidden.retry_delay(0), None);
+
+        let teapot = ClientError::from_status(StatusCode::IM_A_TEAPOT, None, String::new());
+        assert_eq!(teapot.status(), Some(StatusCode::IM_A_TEAPOT));
+    }
+}
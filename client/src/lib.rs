@@ -0,0 +1,16 @@
+//! Client of the lambdo HTTP API
+//!
+//! The `model` module holds the types of the API, shared with the server. The
+//! HTTP client is behind the `client` feature, enabled by default.
+
+pub mod model;
+
+#[cfg(feature = "client")]
+mod client;
+#[cfg(feature = "client")]
+mod error;
+
+#[cfg(feature = "client")]
+pub use client::{Client, JobStream, DEFAULT_RETRIES};
+#[cfg(feature = "client")]
+pub use error::{ClientError, MAX_RETRY_DELAY};
@@ -0,0 +1,220 @@
+use std::fmt::{self, Display};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A file of the code to run
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CodeFile {
+    /// The path of the file, relative to the workspace
+    pub filename: String,
+    pub content: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RunRequest {
+    pub language: String,
+    pub version: String,
+    /// The standard input of the program
+    pub input: String,
+    /// The files of the code, the first one is the entrypoint
+    pub code: Vec<CodeFile>,
+    /// Glob patterns of additional artifacts to return
+    #[serde(default)]
+    pub artifacts: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RunResponse {
+    pub status: u8,
+    pub stdout: String,
+    pub stderr: String,
+    /// The stdout and stderr of the steps, in the order they were written
+    pub combined: String,
+    pub artifacts: Vec<RunArtifact>,
+    /// The time the execution waited for a slot, in milliseconds
+    pub queued_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RunArtifact {
+    /// The path of the file, relative to the workspace
+    pub path: String,
+    /// The content of the file, base64 encoded in the JSON response
+    #[serde(
+        serialize_with = "serialize_base64",
+        deserialize_with = "deserialize_base64"
+    )]
+    pub content: Vec<u8>,
+    /// The real size of the file
+    pub size: u64,
+    /// Whether the content was dropped because of the size limit
+    pub truncated: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Ended,
+}
+
+impl Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Ended => "ended",
+        };
+        f.pad(status)
+    }
+}
+
+/// An execution run in the background, as shown by the API
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JobSummary {
+    /// The ID of the job, the ID of its execution
+    pub id: String,
+    pub language: String,
+    pub version: String,
+    pub status: JobStatus,
+    /// The result of the job, once it ended
+    pub response: Option<RunResponse>,
+}
+
+/// An event of a job, as streamed by the API
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum JobEvent {
+    /// The job waits for a slot
+    Queued,
+    /// The job runs on a VM
+    Running,
+    /// A piece of the output of the job, sent as the program writes it
+    ///
+    /// The agents without the streaming capability send the output of all the steps
+    /// at once, it then comes in a single event once the program ended.
+    Output { stdout: String, stderr: String },
+    /// The job ended, the last event of the stream
+    Ended {
+        status: u8,
+        /// The time the job waited for a slot, in milliseconds
+        queued_ms: u64,
+    },
+}
+
+/// A language, as listed by the API
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LanguageSummary {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VMStatus {
+    Waiting,
+    Ready,
+    Running,
+    Ended,
+}
+
+impl Display for VMStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            VMStatus::Waiting => "waiting",
+            VMStatus::Ready => "ready",
+            VMStatus::Running => "running",
+            VMStatus::Ended => "ended",
+        };
+        f.pad(status)
+    }
+}
+
+/// A VM, as listed by the admin API
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VMSummary {
+    pub id: String,
+    pub language: String,
+    pub version: String,
+    pub status: VMStatus,
+    pub ip: Option<String>,
+    pub tap: Option<String>,
+    pub reserved: bool,
+    /// The time since the VM was created, in seconds
+    pub age: u64,
+    /// The ID of the last request sent to the VM
+    pub last_request: Option<String>,
+    /// The time since the last request was sent, in seconds
+    pub last_request_age: Option<u64>,
+}
+
+/// The usage of an API key, as shown by the admin API
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KeyUsage {
+    pub name: String,
+    /// The executions currently running
+    pub running: u32,
+    /// The executions started today
+    pub today: u64,
+    /// The executions started since lambdo started
+    pub total: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WarmResponse {
+    /// The IDs of the booted VMs
+    pub ids: Vec<String>,
+}
+
+fn serialize_base64<S: Serializer>(content: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&STANDARD.encode(content))
+}
+
+fn deserialize_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let content = String::deserialize(deserializer)?;
+    STANDARD.decode(content).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{JobEvent, RunArtifact, VMStatus};
+
+    #[test]
+    fn test_artifact_round_trip() {
+        let artifact = RunArtifact {
+            path: "a.out".to_string(),
+            content: vec![0x7f, b'E', b'L', b'F'],
+            size: 4,
+            truncated: false,
+        };
+
+        let json = serde_json::to_value(&artifact).unwrap();
+        assert_eq!(json["content"], "f0VMRg==");
+        assert_eq!(
+            serde_json::from_value::<RunArtifact>(json).unwrap(),
+            artifact
+        );
+        assert_eq!(serde_json::to_value(VMStatus::Running).unwrap(), "running");
+    }
+
+    #[test]
+    fn test_job_event_format() {
+        let event = JobEvent::Ended {
+            status: 0,
+            queued_ms: 12,
+        };
+
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"event": "ended", "status": 0, "queued_ms": 12})
+        );
+        assert_eq!(serde_json::from_value::<JobEvent>(json).unwrap(), event);
+        assert_eq!(
+            serde_json::to_value(JobEvent::Queued).unwrap(),
+            serde_json::json!({"event": "queued"})
+        );
+    }
+}
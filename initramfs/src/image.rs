@@ -1,7 +1,8 @@
 use std::{
     collections::HashMap,
-    fs::File,
+    fs::{self, File},
     io::{Cursor, Read, Write},
+    path::Path,
 };
 
 use anyhow::{anyhow, Result};
@@ -84,7 +85,7 @@ impl Image {
 
     pub fn export_to_initramfs<Handler: FileHandler>(
         &self,
-        init_path: &str,
+        init_path: Option<&str>,
         agent_path: &str,
         agent_config_path: &str,
     ) -> Result<Handler> {
@@ -98,6 +99,7 @@ impl Image {
         let mut entries: HashMap<String, (Builder, Cursor<Vec<u8>>)> = HashMap::new();
 
         for layer in self.layers.clone().into_iter() {
+            debug!("Extracting layer {}", layer.metadata.blob_sum);
             let mut archive = Archive::new(Decoder::new(layer.content)?);
 
             for entry in archive
@@ -173,25 +175,33 @@ impl Image {
             }
         }
 
-        let mut init_file =
-            Handler::open(init_path).map_err(|e| anyhow!(e).context("Failed to open init file"))?;
         let mut agent_file = Handler::open(agent_path)
-            .map_err(|e| anyhow!(e).context("Failed to open init file"))?;
+            .map_err(|e| anyhow!(e).context("Failed to open agent file"))?;
         let mut agent_config_file = Handler::open(agent_config_path)
             .map_err(|e| anyhow!(e).context("Failed to open agent config file"))?;
 
-        let mut init_content = Vec::new();
         let mut agent_content = Vec::new();
         let mut agent_config_content = Vec::new();
 
-        init_file.read_to_end(&mut init_content)?;
         agent_file.read_to_end(&mut agent_content)?;
         agent_config_file.read_to_end(&mut agent_config_content)?;
 
-        entries.insert(
-            "init".to_string(),
-            (Builder::new("init").mode(33277), Cursor::new(init_content)),
-        );
+        // Without an init script, the agent is the init of the VM
+        let init = match init_path {
+            Some(init_path) => {
+                let mut init_file = Handler::open(init_path)
+                    .map_err(|e| anyhow!(e).context("Failed to open init file"))?;
+                let mut init_content = Vec::new();
+                init_file.read_to_end(&mut init_content)?;
+
+                (Builder::new("init").mode(33277), Cursor::new(init_content))
+            }
+            None => (
+                Builder::new("init").mode(41471),
+                Cursor::new(b"agent".to_vec()),
+            ),
+        };
+        entries.insert("init".to_string(), init);
         entries.insert(
             "agent".to_string(),
             (
@@ -209,26 +219,164 @@ impl Image {
 
         info!("Writing cpio to disk");
 
-        let inputs = entries.drain().map(|(_, data)| data);
+        write_archive(entries, archive)
+    }
+}
+
+/// Path of the file listing the directories the agent mounts read-only
+const READ_ONLY_LIST_PATH: &str = "etc/lambdo/read-only";
+
+/// Write host directories to a cache archive
+///
+/// The archive is meant to be appended to the initramfs of a language, the
+/// directories are listed in `/etc/lambdo/read-only` so that the agent mounts
+/// them read-only.
+///
+/// # Arguments
+///
+/// * `path` - The path of the archive
+/// * `includes` - The host directories and where they are placed in the VMs
+pub fn export_cache<Handler: FileHandler>(
+    path: &str,
+    includes: &[(String, String)],
+) -> Result<Handler> {
+    let archive = Encoder::new(
+        Handler::create(path).map_err(|e| anyhow!(e).context("Failed to create file"))?,
+    )
+    .map_err(|e| anyhow!(e).context("Failed to create gzip encoder"))?;
+
+    let mut entries: HashMap<String, (Builder, Cursor<Vec<u8>>)> = HashMap::new();
+    let mut read_only = String::new();
+
+    let list_directory = Path::new(READ_ONLY_LIST_PATH)
+        .parent()
+        .and_then(|parent| parent.to_str())
+        .unwrap_or_default();
+    let mut included = directories(list_directory);
+    for (host_path, guest_path) in includes {
+        info!("Including {} as {}", host_path, guest_path);
+        read_only.push_str(&format!("/{}\n", guest_path.trim_matches('/')));
+        included.extend(read_include(Path::new(host_path), guest_path)?);
+    }
+
+    for (path, mode, content) in included {
+        entries.insert(
+            path.clone(),
+            (Builder::new(&path).mode(mode), Cursor::new(content)),
+        );
+    }
+    entries.insert(
+        READ_ONLY_LIST_PATH.to_string(),
+        (
+            Builder::new(READ_ONLY_LIST_PATH).mode(FILE_MODE),
+            Cursor::new(read_only.into_bytes()),
+        ),
+    );
+
+    info!("Writing cache archive to disk");
 
-        let archive =
-            write_cpio(inputs, archive).map_err(|e| anyhow!(e).context("Failed to write cpio"))?;
+    write_archive(entries, archive)
+}
+
+/// Write the entries to a compressed cpio archive
+fn write_archive<Handler: FileHandler>(
+    entries: HashMap<String, (Builder, Cursor<Vec<u8>>)>,
+    archive: Encoder<Handler>,
+) -> Result<Handler> {
+    // Parents come before their children so that the kernel can extract them
+    let mut entries = entries.into_iter().collect::<Vec<_>>();
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let inputs = entries.into_iter().map(|(_, data)| data);
+
+    let archive =
+        write_cpio(inputs, archive).map_err(|e| anyhow!(e).context("Failed to write cpio"))?;
+
+    let handler = archive
+        .finish()
+        .into_result()
+        .map_err(|e| anyhow!(e).context("Failed to finish writing cpio"))?;
 
-        let handler = archive
-            .finish()
-            .into_result()
-            .map_err(|e| anyhow!(e).context("Failed to finish writing cpio"))?;
+    debug!("Successfully wrote cpio to disk");
 
-        debug!("Successfully wrote cpio to disk");
+    Ok(handler)
+}
 
-        Ok(handler)
+/// Mode of the directories added to the archive
+const DIRECTORY_MODE: u32 = 0o40755;
+
+/// Mode of the files added to the archive
+const FILE_MODE: u32 = 0o100644;
+
+/// Read a host directory as archive entries
+///
+/// The parent directories of the guest path are part of the entries, so the
+/// directory can be extracted even if the image does not have them.
+///
+/// # Arguments
+///
+/// * `host_path` - The directory to read
+/// * `guest_path` - Where the directory is placed in the initramfs
+///
+/// # Returns
+///
+/// * `Result<Vec<(String, u32, Vec<u8>)>>` - The path, mode and content of each entry
+fn read_include(host_path: &Path, guest_path: &str) -> Result<Vec<(String, u32, Vec<u8>)>> {
+    let guest_path = guest_path.trim_matches('/');
+    let mut entries = directories(guest_path);
+
+    let mut directories = vec![host_path.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        let mut children = fs::read_dir(&directory)
+            .map_err(|e| anyhow!(e).context(format!("Failed to read {}", directory.display())))?
+            .collect::<Result<Vec<_>, _>>()?;
+        children.sort_by_key(|child| child.path());
+
+        for child in children {
+            let path = child.path();
+            let relative = path
+                .strip_prefix(host_path)
+                .map_err(|e| anyhow!(e).context("Failed to get relative path of include"))?
+                .to_string_lossy()
+                .to_string();
+            let name = format!("{}/{}", guest_path, relative);
+
+            if path.is_dir() {
+                entries.push((name, DIRECTORY_MODE, Vec::new()));
+                directories.push(path);
+            } else {
+                let content = fs::read(&path).map_err(|e| {
+                    anyhow!(e).context(format!("Failed to read {}", path.display()))
+                })?;
+                entries.push((name, FILE_MODE, content));
+            }
+        }
     }
+
+    Ok(entries)
+}
+
+/// The directory entries of a path and of its parents
+fn directories(path: &str) -> Vec<(String, u32, Vec<u8>)> {
+    let mut entries = Vec::new();
+
+    let mut parent = String::new();
+    for component in path.split('/') {
+        if !parent.is_empty() {
+            parent.push('/');
+        }
+        parent.push_str(component);
+        entries.push((parent.clone(), DIRECTORY_MODE, Vec::new()));
+    }
+
+    entries
 }
 
 #[cfg(test)]
 mod test {
-    use super::{FileHandler, Image};
+    use super::{export_cache, read_include, FileHandler, Image, DIRECTORY_MODE, FILE_MODE};
     use anyhow::Ok;
+    use cpio::newc;
+    use libflate::gzip::Decoder;
     use std::env;
     use std::io::{Read, Write};
 
@@ -282,6 +430,26 @@ mod test {
         }
     }
 
+    /// Read the path, mode and content of the entries of a compressed archive
+    fn read_archive(handler: MockFileHandler) -> Vec<(String, u32, String)> {
+        let mut archive = Decoder::new(handler).unwrap();
+        let mut entries = Vec::new();
+        loop {
+            let mut reader = newc::Reader::new(archive).unwrap();
+            if reader.entry().is_trailer() {
+                return entries;
+            }
+
+            let name = reader.entry().name().to_string();
+            let mode = reader.entry().mode();
+            let mut content = String::new();
+            reader.read_to_string(&mut content).unwrap();
+            entries.push((name, mode, content));
+
+            archive = reader.finish().unwrap();
+        }
+    }
+
     #[test]
     pub fn valid_image_name() {
         let image1 = Image::new(VALID_IMAGE_NAME);
@@ -319,7 +487,7 @@ mod test {
 
         // checks
         let handler = image.unwrap().export_to_initramfs::<MockFileHandler>(
-            image_filename.as_str(),
+            Some(image_filename.as_str()),
             agent_filename.as_str(),
             agent_config_filename.as_str(),
         );
@@ -331,4 +499,97 @@ mod test {
 
         assert_eq!(read_buf, [0x1F, 0x8b]);
     }
+
+    #[test]
+    pub fn test_initramfs_export_without_init() {
+        let image = Image::new(VALID_IMAGE_NAME);
+
+        let agent_filename = format!("{}/agent", env::temp_dir().display());
+        let agent_config_filename = format!("{}/agent_config", env::temp_dir().display());
+
+        let handler = image.unwrap().export_to_initramfs::<MockFileHandler>(
+            None,
+            agent_filename.as_str(),
+            agent_config_filename.as_str(),
+        );
+
+        // The init entry of the archive is a symlink to the agent
+        let entries = read_archive(handler.unwrap());
+        assert!(entries.contains(&("init".to_string(), 0o120777, "agent".to_string())));
+    }
+
+    #[test]
+    pub fn test_cache_export() {
+        let host_path = env::temp_dir().join(format!("lambdo-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&host_path).unwrap();
+        std::fs::write(host_path.join("left-pad.tgz"), "left-pad").unwrap();
+
+        let handler = export_cache::<MockFileHandler>(
+            "cache.img",
+            &[(
+                host_path.display().to_string(),
+                "/var/cache/npm".to_string(),
+            )],
+        );
+        std::fs::remove_dir_all(&host_path).unwrap();
+
+        let entries = read_archive(handler.unwrap());
+        assert_eq!(
+            entries
+                .iter()
+                .map(|(path, _, _)| path.as_str())
+                .collect::<Vec<_>>(),
+            [
+                "etc",
+                "etc/lambdo",
+                "etc/lambdo/read-only",
+                "var",
+                "var/cache",
+                "var/cache/npm",
+                "var/cache/npm/left-pad.tgz"
+            ]
+        );
+        assert_eq!(
+            entries[2],
+            (
+                "etc/lambdo/read-only".to_string(),
+                FILE_MODE,
+                "/var/cache/npm\n".to_string()
+            )
+        );
+        assert_eq!(entries[6].2, "left-pad");
+    }
+
+    #[test]
+    pub fn test_read_include() {
+        let host_path = env::temp_dir().join(format!("lambdo-include-{}", std::process::id()));
+        std::fs::create_dir_all(host_path.join("packages")).unwrap();
+        std::fs::write(host_path.join("packages/left-pad.tgz"), "left-pad").unwrap();
+
+        let entries = read_include(&host_path, "/var/cache/npm/").unwrap();
+        std::fs::remove_dir_all(&host_path).unwrap();
+
+        let entries = entries
+            .into_iter()
+            .map(|(path, mode, content)| (path, mode, String::from_utf8(content).unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            vec![
+                ("var".to_string(), DIRECTORY_MODE, String::new()),
+                ("var/cache".to_string(), DIRECTORY_MODE, String::new()),
+                ("var/cache/npm".to_string(), DIRECTORY_MODE, String::new()),
+                (
+                    "var/cache/npm/packages".to_string(),
+                    DIRECTORY_MODE,
+                    String::new()
+                ),
+                (
+                    "var/cache/npm/packages/left-pad.tgz".to_string(),
+                    FILE_MODE,
+                    "left-pad".to_string()
+                ),
+            ]
+        );
+    }
 }
@@ -14,11 +14,12 @@ mod registry;
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[arg(short, long)]
-    image: String,
+    #[arg(short, long, required_unless_present = "cache")]
+    image: Option<String>,
 
-    #[arg(long, default_value = "./init")]
-    init: String,
+    /// Init script of the initramfs, the agent runs as init if not set
+    #[arg(long)]
+    init: Option<String>,
 
     #[arg(long, default_value = "./agent")]
     agent: String,
@@ -26,6 +27,15 @@ struct Args {
     #[arg(long, default_value = "./config.yaml")]
     agent_config: String,
 
+    /// Write the included directories to a cache archive instead of building an initramfs
+    #[arg(long, value_name = "FILE", conflicts_with = "image")]
+    cache: Option<String>,
+
+    /// Host directory to add to the cache archive, as `<host path>:<guest path>`,
+    /// used to ship package caches and mirrors
+    #[arg(long, value_name = "HOST:GUEST", requires = "cache")]
+    include: Vec<String>,
+
     #[arg(
         short,
         long,
@@ -52,15 +62,39 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
     debug!("Running cli with arguments : {:?}", args);
 
+    if let Some(cache) = &args.cache {
+        let includes = args
+            .include
+            .iter()
+            .map(|include| {
+                include
+                    .split_once(':')
+                    .map(|(host, guest)| (host.to_string(), guest.to_string()))
+                    .ok_or_else(|| anyhow!("Invalid include {}, expected <host>:<guest>", include))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        info!("Writing cache to {} ...", cache);
+        image::export_cache::<File>(cache, &includes)
+            .map_err(|e| anyhow!(e).context("Failed to write cache to disk"))?;
+        info!("Writing done!");
+
+        return Ok(());
+    }
+
+    let image_name = args
+        .image
+        .as_deref()
+        .ok_or_else(|| anyhow!("An image is required to build an initramfs"))?;
     let mut registry = Registry::new(&args.registry_url, &args.auth_url);
 
-    info!("Downloading image {}", &args.image);
-    let image = registry.get_image(&args.image).await?;
+    info!("Downloading image {}", image_name);
+    let image = registry.get_image(image_name).await?;
     info!("Download done!");
 
     info!("Writing  to disk ...");
     image
-        .export_to_initramfs::<File>(&args.init, &args.agent, &args.agent_config)
+        .export_to_initramfs::<File>(args.init.as_deref(), &args.agent, &args.agent_config)
         .map_err(|e| anyhow!(e).context("Failed to write filesystem to disk"))?;
     info!("Writing done!");
 
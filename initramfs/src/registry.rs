@@ -1,4 +1,7 @@
-use std::io::Cursor;
+use std::{
+    fmt::{self, Display, Formatter},
+    io::Cursor,
+};
 
 use anyhow::{anyhow, Result};
 use log::{debug, info, trace};
@@ -84,18 +87,18 @@ impl TryFrom<&str> for ManifestType {
     }
 }
 
-impl ToString for ManifestType {
-    fn to_string(&self) -> String {
-        match self {
+impl Display for ManifestType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let media_type = match self {
             ManifestType::ManifestListV2 => {
-                "application/vnd.docker.distribution.manifest.list.v2+json".to_string()
-            }
-            ManifestType::ManifestV2 => {
-                "application/vnd.docker.distribution.manifest.v2+json".to_string()
+                "application/vnd.docker.distribution.manifest.list.v2+json"
             }
-            ManifestType::OCIv1List => "application/vnd.oci.image.index.v1+json".to_string(),
-            ManifestType::OCIv1 => "application/vnd.oci.image.manifest.v1+json".to_string(),
-        }
+            ManifestType::ManifestV2 => "application/vnd.docker.distribution.manifest.v2+json",
+            ManifestType::OCIv1List => "application/vnd.oci.image.index.v1+json",
+            ManifestType::OCIv1 => "application/vnd.oci.image.manifest.v1+json",
+        };
+
+        write!(f, "{}", media_type)
     }
 }
 
@@ -137,10 +140,7 @@ impl Registry {
         image_name: &str,
         image_tag: &str,
     ) -> Result<Vec<LayerMetadata>> {
-        debug!(
-            "Url: {}",
-            format!("{}/{}/manifests/{}", self.url, image_name, image_tag)
-        );
+        debug!("Url: {}/{}/manifests/{}", self.url, image_name, image_tag);
 
         let headers = vec![
             (
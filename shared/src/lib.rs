@@ -0,0 +1,16 @@
+//! The code shared by the lambdo API and the agent running in the VMs
+
+pub mod serial;
+pub mod telemetry;
+
+/// Version of the protocol spoken between lambdo and the agents
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest agent protocol version still supported by lambdo
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// File of the guest holding the `lambdo.*` boot arguments given by lambdo
+///
+/// The VMM builds the kernel command line on its own, so lambdo appends the boot
+/// arguments of a VM to its initramfs, and the agent reads them after the kernel ones.
+pub const BOOT_ARGS_PATH: &str = "/lambdo/cmdline";
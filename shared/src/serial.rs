@@ -0,0 +1,240 @@
+//! The framing of the protocol messages sent over the serial console
+//!
+//! Each message is sent as a frame made of magic bytes, the length of the
+//! payload and the payload. Everything between the frames is console output,
+//! such as the kernel logs.
+
+use std::marker::PhantomData;
+
+use prost::Message;
+use thiserror::Error;
+
+/// Magic bytes marking the beginning of a frame on the serial line
+pub const FRAME_MAGIC: [u8; 4] = [0x1b, b'L', b'M', b'B'];
+
+/// Size of a frame header: the magic bytes followed by the payload length
+pub const FRAME_HEADER_SIZE: usize = FRAME_MAGIC.len() + 4;
+
+/// Maximum size of a frame payload
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum FrameError {
+    #[error("cannot decode serial message")]
+    Decode(#[from] prost::DecodeError),
+    #[error("frame of {0} bytes exceeds the maximum frame size")]
+    FrameTooLarge(usize),
+}
+
+/// Encode a message into a frame ready to be written on the serial line
+///
+/// # Arguments
+///
+/// * `message` - The message to encode
+///
+/// # Returns
+///
+/// * `Vec<u8>` - The frame bytes
+pub fn encode_frame<M: Message>(message: &M) -> Vec<u8> {
+    let payload = message.encode_to_vec();
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+
+    frame.extend_from_slice(&FRAME_MAGIC);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+
+    frame
+}
+
+/// Something read from the serial line
+#[derive(Debug, PartialEq)]
+pub enum Decoded<M> {
+    /// Bytes that are not part of a frame, such as kernel logs
+    Console(Vec<u8>),
+    /// A complete protocol message
+    Message(M),
+}
+
+/// An incremental decoder splitting a byte stream into frames and console output
+pub struct FrameDecoder<M> {
+    buffer: Vec<u8>,
+    message: PhantomData<M>,
+}
+
+impl<M> Default for FrameDecoder<M> {
+    fn default() -> Self {
+        Self {
+            buffer: Vec::new(),
+            message: PhantomData,
+        }
+    }
+}
+
+impl<M: Message + Default> FrameDecoder<M> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append bytes read from the serial line to the decoder
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Extract the next item from the buffered bytes
+    ///
+    /// A frame that cannot be decoded is dropped, the next call goes on with
+    /// the bytes following it.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<Decoded<M>>, FrameError>` - The next item, `None` if more bytes are needed
+    pub fn next_decoded(&mut self) -> Result<Option<Decoded<M>>, FrameError> {
+        match find_magic(&self.buffer) {
+            Some(0) => {
+                if self.buffer.len() < FRAME_HEADER_SIZE {
+                    return Ok(None);
+                }
+
+                let mut length = [0; 4];
+                length.copy_from_slice(&self.buffer[FRAME_MAGIC.len()..FRAME_HEADER_SIZE]);
+                let length = u32::from_be_bytes(length) as usize;
+
+                if length > MAX_FRAME_SIZE {
+                    // Skip the magic so we can resynchronize on the next frame
+                    self.buffer.drain(..FRAME_MAGIC.len());
+                    return Err(FrameError::FrameTooLarge(length));
+                }
+
+                if self.buffer.len() < FRAME_HEADER_SIZE + length {
+                    return Ok(None);
+                }
+
+                let frame = self
+                    .buffer
+                    .drain(..FRAME_HEADER_SIZE + length)
+                    .skip(FRAME_HEADER_SIZE)
+                    .collect::<Vec<u8>>();
+
+                Ok(Some(Decoded::Message(M::decode(&frame[..])?)))
+            }
+            Some(position) => Ok(Some(Decoded::Console(
+                self.buffer.drain(..position).collect(),
+            ))),
+            None => {
+                // Keep the bytes that could be the beginning of a magic sequence
+                let keep = partial_magic_len(&self.buffer);
+                let console_len = self.buffer.len() - keep;
+                if console_len == 0 {
+                    return Ok(None);
+                }
+
+                Ok(Some(Decoded::Console(
+                    self.buffer.drain(..console_len).collect(),
+                )))
+            }
+        }
+    }
+}
+
+fn find_magic(buffer: &[u8]) -> Option<usize> {
+    buffer
+        .windows(FRAME_MAGIC.len())
+        .position(|window| window == FRAME_MAGIC)
+}
+
+fn partial_magic_len(buffer: &[u8]) -> usize {
+    (1..FRAME_MAGIC.len())
+        .rev()
+        .find(|len| buffer.ends_with(&FRAME_MAGIC[..*len]))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, PartialEq, Message)]
+    struct TestMessage {
+        #[prost(string, tag = "1")]
+        id: String,
+    }
+
+    fn test_message(id: &str) -> TestMessage {
+        TestMessage { id: id.to_string() }
+    }
+
+    /// Test that frames are extracted from console output
+    #[test]
+    fn decoder_splits_console_and_frames() {
+        let mut decoder = FrameDecoder::new();
+        let message = test_message("test");
+
+        decoder.push(b"[    0.000000] Linux version");
+        decoder.push(&encode_frame(&message));
+        decoder.push(b"login:");
+
+        assert_eq!(
+            decoder.next_decoded().unwrap(),
+            Some(Decoded::Console(b"[    0.000000] Linux version".to_vec()))
+        );
+        assert_eq!(
+            decoder.next_decoded().unwrap(),
+            Some(Decoded::Message(message))
+        );
+        assert_eq!(
+            decoder.next_decoded().unwrap(),
+            Some(Decoded::Console(b"login:".to_vec()))
+        );
+        assert_eq!(decoder.next_decoded().unwrap(), None);
+    }
+
+    /// Test that a frame split over several reads is decoded once complete
+    #[test]
+    fn decoder_waits_for_partial_frames() {
+        let mut decoder = FrameDecoder::new();
+        let message = test_message("partial");
+        let frame = encode_frame(&message);
+
+        decoder.push(b"Booting the kernel.\n");
+        decoder.push(&frame[..2]);
+        assert_eq!(
+            decoder.next_decoded().unwrap(),
+            Some(Decoded::Console(b"Booting the kernel.\n".to_vec()))
+        );
+        assert_eq!(decoder.next_decoded().unwrap(), None);
+
+        decoder.push(&frame[2..FRAME_HEADER_SIZE + 1]);
+        assert_eq!(decoder.next_decoded().unwrap(), None);
+
+        decoder.push(&frame[FRAME_HEADER_SIZE + 1..]);
+        assert_eq!(
+            decoder.next_decoded().unwrap(),
+            Some(Decoded::Message(message))
+        );
+        assert_eq!(decoder.next_decoded().unwrap(), None);
+    }
+
+    /// Test that the decoder resynchronizes after an oversized frame
+    #[test]
+    fn decoder_skips_oversized_frames() {
+        let mut decoder = FrameDecoder::<TestMessage>::new();
+        let message = test_message("next");
+
+        decoder.push(&FRAME_MAGIC);
+        decoder.push(&(MAX_FRAME_SIZE as u32 + 1).to_be_bytes());
+        decoder.push(&encode_frame(&message));
+
+        assert!(matches!(
+            decoder.next_decoded(),
+            Err(FrameError::FrameTooLarge(_))
+        ));
+        assert!(matches!(
+            decoder.next_decoded(),
+            Ok(Some(Decoded::Console(_)))
+        ));
+        assert_eq!(
+            decoder.next_decoded().unwrap(),
+            Some(Decoded::Message(message))
+        );
+    }
+}
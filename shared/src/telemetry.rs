@@ -0,0 +1,170 @@
+//! The span exporters and the trace context propagation shared by lambdo and the agent
+
+use std::{
+    collections::HashMap,
+    future::{ready, Future},
+    io::Write,
+    pin::Pin,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use opentelemetry::{
+    global,
+    trace::{TraceError, TracerProvider as _},
+    Context, KeyValue,
+};
+use opentelemetry_sdk::{
+    export::trace::{ExportResult, SpanData, SpanExporter},
+    trace::{self, Tracer, TracerProvider},
+    Resource,
+};
+use serde_json::{json, Map, Value};
+
+/// Build a tracer printing the spans on stdout, one JSON object per line
+///
+/// # Arguments
+///
+/// * `service_name` - The name under which the spans are exported
+pub fn stdout_tracer(service_name: &'static str) -> Tracer {
+    let provider = TracerProvider::builder()
+        .with_simple_exporter(JsonExporter)
+        .with_config(trace_config(service_name))
+        .build();
+    let tracer = provider.tracer(service_name);
+    global::set_tracer_provider(provider);
+
+    tracer
+}
+
+/// Build a tracer sending the spans to an OpenTelemetry collector
+///
+/// # Arguments
+///
+/// * `service_name` - The name under which the spans are exported
+/// * `endpoint` - The endpoint of the collector, the default one if not set
+#[cfg(feature = "otlp")]
+pub fn otlp_tracer(service_name: &'static str, endpoint: Option<&str>) -> Result<Tracer> {
+    use anyhow::anyhow;
+    use opentelemetry_otlp::WithExportConfig;
+
+    let mut exporter = opentelemetry_otlp::new_exporter().tonic();
+    if let Some(endpoint) = endpoint {
+        exporter = exporter.with_endpoint(endpoint);
+    }
+
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(trace_config(service_name))
+        .install_batch(opentelemetry_sdk::runtime::Tokio)
+        .map_err(|e| anyhow!("failed to set up the OTLP exporter: {}", e))
+}
+
+#[cfg(not(feature = "otlp"))]
+pub fn otlp_tracer(service_name: &'static str, _endpoint: Option<&str>) -> Result<Tracer> {
+    Err(anyhow::anyhow!(
+        "the OTLP exporter needs {} to be built with the otlp feature",
+        service_name
+    ))
+}
+
+/// Write a trace context in the map sent along a request over the serial line
+pub fn inject_context(context: &Context, map: &mut HashMap<String, String>) {
+    global::get_text_map_propagator(|propagator| propagator.inject_context(context, map));
+}
+
+/// Read the trace context sent along a request over the serial line
+pub fn extract_context(map: &HashMap<String, String>) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(map))
+}
+
+fn trace_config(service_name: &'static str) -> trace::Config {
+    trace::config().with_resource(Resource::new(vec![KeyValue::new(
+        "service.name",
+        service_name,
+    )]))
+}
+
+/// Prints the spans on stdout, one JSON object per line
+#[derive(Debug)]
+struct JsonExporter;
+
+impl SpanExporter for JsonExporter {
+    fn export(
+        &mut self,
+        batch: Vec<SpanData>,
+    ) -> Pin<Box<dyn Future<Output = ExportResult> + Send + 'static>> {
+        let mut stdout = std::io::stdout().lock();
+        let result = batch
+            .iter()
+            .try_for_each(|span| writeln!(stdout, "{}", span_to_json(span)))
+            .map_err(|e| TraceError::from(e.to_string()));
+
+        Box::pin(ready(result))
+    }
+}
+
+fn span_to_json(span: &SpanData) -> Value {
+    let attributes = |attributes: &mut dyn Iterator<Item = &KeyValue>| {
+        attributes
+            .map(|kv| (kv.key.to_string(), Value::String(kv.value.to_string())))
+            .collect::<Map<String, Value>>()
+    };
+    let micros = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_micros() as u64)
+    };
+
+    json!({
+        "trace_id": span.span_context.trace_id().to_string(),
+        "span_id": span.span_context.span_id().to_string(),
+        "parent_span_id": span.parent_span_id.to_string(),
+        "name": span.name,
+        "start_us": micros(span.start_time),
+        "end_us": micros(span.end_time),
+        "attributes": attributes(&mut span.attributes.iter()),
+        "events": span.events.iter().map(|event| json!({
+            "name": event.name,
+            "time_us": micros(event.timestamp),
+            "attributes": attributes(&mut event.attributes.iter()),
+        })).collect::<Vec<Value>>(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use opentelemetry::{
+        global,
+        trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState},
+        Context,
+    };
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+
+    use super::{extract_context, inject_context};
+
+    #[test]
+    fn test_context_round_trip() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let span_context = SpanContext::new(
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(),
+            SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        );
+        let context = Context::new().with_remote_span_context(span_context.clone());
+
+        let mut map = HashMap::new();
+        inject_context(&context, &mut map);
+        assert_eq!(
+            map.get("traceparent").unwrap(),
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        );
+
+        let extracted = extract_context(&map);
+        assert_eq!(extracted.span().span_context(), &span_context);
+    }
+}